@@ -1,38 +1,490 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::Debug;
+use std::rc::Rc;
+
 use crate::dram::DRAM;
+use crate::events::{EventQueue, Events};
+use crate::fdt::FdtWriter;
+use crate::snapshot::{SnapshotReader, SnapshotWriter};
 
 pub const DRAM_BASE: usize = 0x8000_0000;
 
-pub trait Device {
+pub trait Device: Debug {
     fn write(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()>;
 
     fn read(&self, addr: usize, size: usize) -> Result<u64, ()>;
+
+    /// Fills `buf` from `addr` onwards. Memories override this with a bulk copy.
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), ()> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.read(addr.checked_add(i).ok_or(())?, 8)? as u8;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` from `addr` onwards.
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), ()> {
+        for (i, b) in data.iter().enumerate() {
+            self.write(addr.checked_add(i).ok_or(())?, 8, *b as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Serializes the device's internal state into a snapshot section.
+    fn save(&self, out: &mut Vec<u8>);
+
+    /// Restores state written by `save`.
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), String>;
+
+    /// Adds the device's node(s) to the device tree, for a device mapped at `base`.
+    fn dt_node(&self, _base: usize, _size: usize, _fdt: &mut FdtWriter) {}
+
+    /// For memory that allocates on demand, how many 4 KiB pages are in use.
+    fn resident_pages(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether this is memory, which reads back what was written without side effects.
+    /// False for registers that must be accessed every time.
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    /// Short name for the debugger's device list.
+    fn name(&self) -> &str {
+        "device"
+    }
+
+    /// More about the device's configuration or state, if there is anything to say.
+    fn describe(&self) -> String {
+        String::new()
+    }
+
+    /// Puts the device back in its power-on state.
+    fn reset(&mut self) {}
+
+    /// Hands the device its handle on the event queue, when it is mapped.
+    fn attach(&mut self, _events: Events) {}
+
+    /// Called at emulated time `now`, in cycles, when an event the device scheduled
+    /// comes due. Devices drive their interrupt lines here.
+    fn tick(&mut self, _now: u64, _irq: &mut IrqLines) {}
+
+    /// Called when a device changed the level of an external interrupt line.
+    fn irq_changed(&mut self, _irq: &mut IrqLines) {}
+}
+
+/// Levels of the interrupt lines. Devices raise and lower them when ticked, and
+/// interrupt controllers read them to drive the hart's lines.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IrqLines {
+    /// External interrupt sources wired to the PLIC, by source number
+    external: u128,
+    /// Interrupt pending bits of the hart, as in mip
+    hart: u64,
+}
+
+impl IrqLines {
+    /// Raises or lowers external source `line`.
+    pub fn set(&mut self, line: u32, level: bool) {
+        if level {
+            self.external |= 1 << line;
+        } else {
+            self.external &= !(1 << line);
+        }
+    }
+
+    /// All external lines, one bit per source.
+    pub fn external(&self) -> u128 {
+        self.external
+    }
+
+    /// Drives bit `bit` of the hart's mip register.
+    pub fn set_hart(&mut self, bit: u32, level: bool) {
+        if level {
+            self.hart |= 1 << bit;
+        } else {
+            self.hart &= !(1 << bit);
+        }
+    }
+
+    pub fn hart(&self) -> u64 {
+        self.hart
+    }
+}
+
+/// A memory mapped device occupying `size` bytes from `base`.
+#[derive(Debug)]
+struct Mapping {
+    base: usize,
+    size: usize,
+    device: Box<dyn Device>,
 }
 
 #[derive(Debug)]
 pub struct BUS {
-    dram: DRAM, // Box<[u8]> doesn't wanna work
+    dram_base: usize,
+    dram_size: usize,
+    dram: Box<dyn Device>,
+    devices: Vec<Mapping>,
+    halt: Rc<Cell<Option<i32>>>,
+    irq: IrqLines,
+    events: Rc<RefCell<EventQueue>>,
 }
 
 impl BUS {
-    pub fn new(mem_size: usize, buffer: Vec<u8>) -> BUS {
+    pub fn new(dram_base: usize, mem_size: usize, buffer: Vec<u8>) -> BUS {
+        BUS::with_dram(dram_base, mem_size, Box::new(DRAM::new(mem_size, buffer)))
+    }
+
+    /// Uses `dram` as the `mem_size` bytes of main memory at `dram_base`.
+    pub fn with_dram(dram_base: usize, mem_size: usize, dram: Box<dyn Device>) -> BUS {
         Self {
-            dram: DRAM::new(mem_size, buffer),
+            dram_base,
+            dram_size: mem_size,
+            dram,
+            devices: vec!(),
+            halt: Rc::new(Cell::new(None)),
+            irq: IrqLines::default(),
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Start and size of DRAM.
+    pub fn dram_range(&self) -> (usize, usize) {
+        (self.dram_base, self.dram_size)
+    }
+
+    /// Maps `device` at `base`. Accesses pass it offsets relative to `base`.
+    pub fn map(&mut self, base: usize, size: usize, mut device: Box<dyn Device>) {
+        device.attach(Events::new(self.events.clone(), self.devices.len()));
+        self.devices.push(Mapping { base, size, device });
+    }
+
+    /// Shared with devices that can power the machine off, which store the exit status in it.
+    pub fn halt_handle(&self) -> Rc<Cell<Option<i32>>> {
+        self.halt.clone()
+    }
+
+    /// The exit status if a device powered the machine off.
+    pub fn halted(&self) -> Option<i32> {
+        self.halt.get()
+    }
+
+    /// Adds a node for every mapped device to the device tree.
+    pub fn dt_nodes(&self, fdt: &mut FdtWriter) {
+        for m in &self.devices {
+            m.device.dt_node(m.base, m.size, fdt);
+        }
+    }
+
+    /// Moves time forward to cycle `now`, ticks the devices whose events are due
+    /// and returns the interrupt lines.
+    pub fn advance(&mut self, now: u64) -> IrqLines {
+        self.events.borrow_mut().set_now(now);
+        loop {
+            let due = self.events.borrow_mut().pop_due();
+            let i = match due {
+                Some(i) => i,
+                None => break,
+            };
+            let lines = self.irq.external();
+            self.devices[i].device.tick(now, &mut self.irq);
+            if self.irq.external() != lines {
+                for m in self.devices.iter_mut() {
+                    m.device.irq_changed(&mut self.irq);
+                }
+            }
         }
+        self.irq
+    }
+
+    /// Resets every device. Memory keeps its contents.
+    pub fn reset(&mut self) {
+        self.dram.reset();
+        for m in self.devices.iter_mut() {
+            m.device.reset();
+        }
+        self.irq = IrqLines::default();
+    }
+
+    /// Base, size and description of main memory and every mapped device.
+    pub fn device_info(&self) -> Vec<(usize, usize, String)> {
+        let describe = |d: &dyn Device| match d.describe() {
+            desc if desc.is_empty() => d.name().to_string(),
+            desc => format!("{} ({})", d.name(), desc),
+        };
+        let mut info = vec!((self.dram_base, self.dram_size, describe(self.dram.as_ref())));
+        info.extend(self.devices.iter().map(|m| (m.base, m.size, describe(m.device.as_ref()))));
+        info
+    }
+
+    /// Base, size and resident pages of every memory region that allocates on demand.
+    pub fn memory_stats(&self) -> Vec<(usize, usize, usize)> {
+        let main = self.dram.resident_pages().map(|n| (self.dram_base, self.dram_size, n));
+        main.into_iter()
+            .chain(self.devices.iter().filter_map(|m| m.device.resident_pages().map(|n| (m.base, m.size, n))))
+            .collect()
     }
 
     pub fn write(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
-        if addr < DRAM_BASE {
+        if let Some(m) = self.devices.iter_mut().find(|m| addr.wrapping_sub(m.base) < m.size) {
+            m.device.write(addr - m.base, size, val)
+        } else if addr < self.dram_base {
             Err(())
         } else {
-            self.dram.write(addr-DRAM_BASE, size, val)
+            self.dram.write(addr-self.dram_base, size, val)
+        }
+    }
+
+    /// The bytes from `addr` to the end of the memory region or device it is in, 0 if
+    /// nothing is mapped there.
+    pub fn mapped_len(&self, addr: usize) -> usize {
+        if let Some(m) = self.devices.iter().find(|m| addr.wrapping_sub(m.base) < m.size) {
+            m.size - (addr - m.base)
+        } else if addr.wrapping_sub(self.dram_base) < self.dram_size {
+            // Devices mapped over DRAM cut it short
+            let end = self.devices.iter().map(|m| m.base).filter(|b| *b > addr)
+                .fold(self.dram_base + self.dram_size, usize::min);
+            end - addr
+        } else {
+            0
+        }
+    }
+
+    /// Whether `addr` is in main memory or a device that can be cached.
+    pub fn cacheable(&self, addr: usize) -> bool {
+        match self.devices.iter().find(|m| addr.wrapping_sub(m.base) < m.size) {
+            Some(m) => m.device.cacheable(),
+            None => addr >= self.dram_base && self.dram.cacheable(),
         }
     }
 
     pub fn read(&self, addr: usize, size: usize) -> Result<u64, ()> {
-        if addr < DRAM_BASE {
+        if let Some(m) = self.devices.iter().find(|m| addr.wrapping_sub(m.base) < m.size) {
+            m.device.read(addr - m.base, size)
+        } else if addr < self.dram_base {
             Err(())
         } else {
-            self.dram.read(addr-DRAM_BASE, size)
+            self.dram.read(addr-self.dram_base, size)
+        }
+    }
+
+    /// Reads `buf.len()` bytes from `addr`. Ranges inside a single device or DRAM are
+    /// copied in one go, anything else byte by byte.
+    pub fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), ()> {
+        let end = addr.checked_add(buf.len()).ok_or(())?;
+        if let Some(m) = self.devices.iter().find(|m| addr.wrapping_sub(m.base) < m.size) {
+            if end - m.base <= m.size {
+                return m.device.read_bytes(addr - m.base, buf);
+            }
+        } else if addr >= self.dram_base && !self.devices.iter().any(|m| m.base > addr && m.base < end) {
+            return self.dram.read_bytes(addr - self.dram_base, buf);
+        }
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.read(addr + i, 8)? as u8;
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to `addr`, in one go where the range allows it like `read_bytes`.
+    pub fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), ()> {
+        let end = addr.checked_add(data.len()).ok_or(())?;
+        if let Some(m) = self.devices.iter_mut().find(|m| addr.wrapping_sub(m.base) < m.size) {
+            if end - m.base <= m.size {
+                return m.device.write_bytes(addr - m.base, data);
+            }
+        } else if addr >= self.dram_base && !self.devices.iter().any(|m| m.base > addr && m.base < end) {
+            return self.dram.write_bytes(addr - self.dram_base, data);
+        }
+        for (i, b) in data.iter().enumerate() {
+            self.write(addr + i, 8, *b as u64)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, w: &mut SnapshotWriter) {
+        w.section(b"DRAM", |out| self.dram.save(out));
+        for m in &self.devices {
+            w.section(b"DEV ", |out| m.device.save(out));
+        }
+    }
+
+    /// Saves the devices that aren't memory, for recording what an instruction did to them.
+    pub fn save_devices(&self, w: &mut SnapshotWriter) {
+        for m in self.devices.iter().filter(|m| !m.device.cacheable()) {
+            w.section(b"DEV ", |out| m.device.save(out));
+        }
+    }
+
+    /// Restores what `save_devices` saved, as of cycle `now`.
+    pub fn restore_devices(&mut self, r: &mut SnapshotReader, now: u64) -> Result<(), String> {
+        self.events.borrow_mut().clear();
+        self.events.borrow_mut().set_now(now);
+        self.irq = IrqLines::default();
+        for m in self.devices.iter_mut().filter(|m| !m.device.cacheable()) {
+            m.device.restore(&mut r.section(b"DEV ")?)?;
+        }
+        Ok(())
+    }
+
+    /// Restores the devices as of cycle `now`. They schedule their events again as they are restored.
+    pub fn restore(&mut self, r: &mut SnapshotReader, now: u64) -> Result<(), String> {
+        self.events.borrow_mut().clear();
+        self.events.borrow_mut().set_now(now);
+        self.irq = IrqLines::default();
+        self.dram.restore(&mut r.section(b"DRAM")?)?;
+        for m in self.devices.iter_mut() {
+            m.device.restore(&mut r.section(b"DEV ")?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMER: usize = 0x1000;
+    const CONTROLLER: usize = 0x2000;
+    const LINE: u32 = 3;
+    const MEIP: u32 = 11;
+
+    /// What the test devices saw, shared with the test once they are on the bus.
+    #[derive(Debug, Default)]
+    struct Seen {
+        ticks: Vec<u64>,
+        irq_changes: u32,
+        resets: u32,
+    }
+
+    /// Writing a delay to offset 0 raises `LINE` that many cycles later, writing
+    /// offset 8 lowers it again on the next tick.
+    #[derive(Debug)]
+    struct Timer {
+        events: Events,
+        raise: bool,
+        seen: Rc<RefCell<Seen>>,
+    }
+
+    impl Device for Timer {
+        fn write(&mut self, addr: usize, _size: usize, val: u64) -> Result<(), ()> {
+            self.raise = addr == 0;
+            self.events.schedule_in(if self.raise { val } else { 0 });
+            Ok(())
+        }
+
+        fn read(&self, _addr: usize, _size: usize) -> Result<u64, ()> {
+            Ok(self.raise as u64)
+        }
+
+        fn save(&self, _out: &mut Vec<u8>) {}
+
+        fn restore(&mut self, _r: &mut SnapshotReader) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn name(&self) -> &str {
+            "timer"
+        }
+
+        fn describe(&self) -> String {
+            format!("{} ticks", self.seen.borrow().ticks.len())
+        }
+
+        fn reset(&mut self) {
+            self.raise = false;
+            self.seen.borrow_mut().resets += 1;
+        }
+
+        fn attach(&mut self, events: Events) {
+            self.events = events;
+        }
+
+        fn tick(&mut self, now: u64, irq: &mut IrqLines) {
+            self.seen.borrow_mut().ticks.push(now);
+            irq.set(LINE, self.raise);
+        }
+    }
+
+    /// Passes `LINE` on to the hart's external interrupt, like a one-source PLIC.
+    #[derive(Debug)]
+    struct Controller {
+        seen: Rc<RefCell<Seen>>,
+    }
+
+    impl Device for Controller {
+        fn write(&mut self, _addr: usize, _size: usize, _val: u64) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn read(&self, _addr: usize, _size: usize) -> Result<u64, ()> {
+            Ok(0)
+        }
+
+        fn save(&self, _out: &mut Vec<u8>) {}
+
+        fn restore(&mut self, _r: &mut SnapshotReader) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn irq_changed(&mut self, irq: &mut IrqLines) {
+            self.seen.borrow_mut().irq_changes += 1;
+            irq.set_hart(MEIP, irq.external() & 1 << LINE != 0);
         }
     }
-}
\ No newline at end of file
+
+    fn setup() -> (BUS, Rc<RefCell<Seen>>, Rc<RefCell<Seen>>) {
+        let mut bus = BUS::new(DRAM_BASE, 0x1000, vec!());
+        let (timer, controller) = (Rc::new(RefCell::new(Seen::default())), Rc::new(RefCell::new(Seen::default())));
+        bus.map(TIMER, 0x10, Box::new(Timer { events: Events::detached(), raise: false, seen: timer.clone() }));
+        bus.map(CONTROLLER, 0x10, Box::new(Controller { seen: controller.clone() }));
+        (bus, timer, controller)
+    }
+
+    /// Devices are only ticked when their event is due, and a line they change reaches
+    /// the hart through the controller.
+    #[test]
+    fn tick_and_irq() {
+        let (mut bus, timer, controller) = setup();
+        bus.write(TIMER, 64, 100).unwrap();
+        assert_eq!(bus.advance(50).hart(), 0);
+        assert!(timer.borrow().ticks.is_empty());
+        let irq = bus.advance(120);
+        assert_eq!(timer.borrow().ticks, vec![120]);
+        assert_eq!(irq.external(), 1 << LINE);
+        assert_eq!(irq.hart(), 1 << MEIP);
+        assert_eq!(controller.borrow().irq_changes, 1);
+
+        // Nothing due, nothing ticked, and the lines stay up
+        assert_eq!(bus.advance(500).hart(), 1 << MEIP);
+        assert_eq!(timer.borrow().ticks.len(), 1);
+
+        bus.write(TIMER + 8, 64, 0).unwrap();
+        let irq = bus.advance(501);
+        assert_eq!(timer.borrow().ticks, vec![120, 501]);
+        assert_eq!((irq.external(), irq.hart()), (0, 0));
+        assert_eq!(controller.borrow().irq_changes, 2);
+        // A tick that leaves the lines alone doesn't bother the controller
+        bus.write(TIMER + 8, 64, 0).unwrap();
+        bus.advance(502);
+        assert_eq!(controller.borrow().irq_changes, 2);
+    }
+
+    /// Reset reaches every device and drops the lines, and the device list shows
+    /// names and descriptions.
+    #[test]
+    fn reset_and_info() {
+        let (mut bus, timer, _) = setup();
+        bus.write(TIMER, 64, 0).unwrap();
+        assert_eq!(bus.advance(1).external(), 1 << LINE);
+        bus.reset();
+        assert_eq!(timer.borrow().resets, 1);
+        assert_eq!(bus.read(TIMER, 64), Ok(0));
+        assert_eq!(bus.advance(2).external(), 0);
+        let info = bus.device_info();
+        assert_eq!(info[0], (DRAM_BASE, 0x1000, "ram".to_string()));
+        assert_eq!(info[1], (TIMER, 0x10, "timer (1 ticks)".to_string()));
+        assert_eq!(info[2], (CONTROLLER, 0x10, "device".to_string()));
+    }
+}
@@ -0,0 +1,111 @@
+use crate::bus::{Device, IrqLines};
+use crate::events::{self, Events};
+use crate::fdt::{FdtWriter, PHANDLE_CPU_INTC};
+use crate::snapshot::SnapshotReader;
+
+const MSIP: usize = 0x0;
+const MTIMECMP: usize = 0x4000;
+const MTIME: usize = 0xBFF8;
+
+const IRQ_M_SOFT: u32 = 3;
+const IRQ_M_TIMER: u32 = 7;
+
+pub const CLINT_SIZE: usize = 0x10000;
+
+/// SiFive core-local interruptor for a single hart: software interrupt and timer registers.
+#[derive(Debug)]
+pub struct Clint {
+    msip: u32,
+    mtimecmp: u64,
+    events: Events,
+}
+
+impl Clint {
+    pub fn new() -> Clint {
+        Self {
+            msip: 0,
+            mtimecmp: u64::MAX,
+            events: Events::detached(),
+        }
+    }
+
+    fn mtime(&self) -> u64 {
+        events::time_at(self.events.now())
+    }
+
+    /// Updates the interrupt lines now and again when mtime reaches mtimecmp.
+    fn schedule(&self) {
+        self.events.schedule_in(0);
+        if self.mtimecmp != u64::MAX {
+            self.events.schedule_at(events::cycle_of(self.mtimecmp));
+        }
+    }
+}
+
+impl Device for Clint {
+    fn write(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
+        match (addr, size) {
+            (MSIP, 32) => self.msip = val as u32 & 1,
+            (MTIMECMP, 64) => self.mtimecmp = val,
+            (MTIMECMP, 32) => self.mtimecmp = (self.mtimecmp & !0xFFFF_FFFF) | (val & 0xFFFF_FFFF),
+            (a, 32) if a == MTIMECMP + 4 => self.mtimecmp = (self.mtimecmp & 0xFFFF_FFFF) | (val << 32),
+            // mtime follows emulated time and can't be set
+            (MTIME, _) | (0xBFFC, 32) => return Ok(()),
+            _ => return Err(()),
+        }
+        self.schedule();
+        Ok(())
+    }
+
+    fn read(&self, addr: usize, size: usize) -> Result<u64, ()> {
+        match (addr, size) {
+            (MSIP, 32) => Ok(self.msip as u64),
+            (MTIMECMP, 64) => Ok(self.mtimecmp),
+            (MTIMECMP, 32) => Ok(self.mtimecmp & 0xFFFF_FFFF),
+            (a, 32) if a == MTIMECMP + 4 => Ok(self.mtimecmp >> 32),
+            (MTIME, 64) => Ok(self.mtime()),
+            (MTIME, 32) => Ok(self.mtime() & 0xFFFF_FFFF),
+            (0xBFFC, 32) => Ok(self.mtime() >> 32),
+            _ => Err(()),
+        }
+    }
+
+    fn name(&self) -> &str {
+        "clint"
+    }
+
+    fn reset(&mut self) {
+        self.msip = 0;
+        self.mtimecmp = u64::MAX;
+        self.schedule();
+    }
+
+    fn attach(&mut self, events: Events) {
+        self.events = events;
+    }
+
+    fn tick(&mut self, _now: u64, irq: &mut IrqLines) {
+        irq.set_hart(IRQ_M_SOFT, self.msip != 0);
+        irq.set_hart(IRQ_M_TIMER, self.mtime() >= self.mtimecmp);
+    }
+
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.msip.to_le_bytes());
+        out.extend_from_slice(&self.mtimecmp.to_le_bytes());
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.msip = r.u32()?;
+        self.mtimecmp = r.u64()?;
+        self.schedule();
+        Ok(())
+    }
+
+    fn dt_node(&self, base: usize, size: usize, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("clint@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_reg(base as u64, size as u64);
+        fdt.property_cells("interrupts-extended", &[PHANDLE_CPU_INTC, IRQ_M_SOFT, PHANDLE_CPU_INTC, IRQ_M_TIMER]);
+        fdt.end_node();
+    }
+}
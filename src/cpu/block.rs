@@ -0,0 +1,308 @@
+// Basic-block translation. A run of straight-line instructions is turned into a list
+// of closures with their operands baked in, so running it again skips fetching,
+// decoding and the match in `execute`. A block ends with the first instruction that
+// can change control flow or machine state in other ways, which is run through
+// `execute` like before. Once a block has run often enough, runs of its instructions
+// are compiled to native code if the JIT is on.
+
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::HashMap;
+use std::fmt::{Debug, Error, Formatter};
+use std::rc::{Rc, Weak};
+
+use super::CPU;
+use super::decode::Instructions;
+use super::jit::{self, Native};
+
+const PAGE_SHIFT: usize = 12;
+/// Blocks are cut after this many instructions.
+pub const MAX_BLOCK: usize = 64;
+
+/// Runs one instruction and advances the pc past it, like `execute`.
+pub type Op = Box<dyn Fn(&mut CPU) -> Result<(), String>>;
+
+pub struct Block {
+    /// The physical address of the first instruction
+    pub start: u64,
+    pub insts: Vec<Instructions>,
+    pub ops: Vec<Op>,
+    /// How many times the block was entered
+    runs: Cell<u32>,
+    /// Compiled runs, by the index of the instruction they start at, once the block is hot
+    native: OnceCell<Vec<Option<Native>>>,
+    /// The block that ran after this one, with the cache generation it was linked in
+    next: RefCell<Option<(u64, Weak<Block>)>>,
+}
+
+impl Block {
+    pub fn new(start: u64, insts: Vec<Instructions>) -> Block {
+        let ops = insts.iter().map(|i| compile(*i)).collect();
+        Self {
+            start,
+            insts,
+            ops,
+            runs: Cell::new(0),
+            native: OnceCell::new(),
+            next: RefCell::new(None),
+        }
+    }
+
+    /// Counts a run of the block and returns its compiled runs, compiling them on the
+    /// run that makes it hot if `jit` is set. Empty until then.
+    pub fn enter(&self, jit: bool) -> &[Option<Native>] {
+        let runs = self.runs.get().saturating_add(1);
+        self.runs.set(runs);
+        if let Some(native) = self.native.get() {
+            return native;
+        }
+        if !jit || runs < jit::HOT_RUNS {
+            return &[];
+        }
+        self.native.get_or_init(|| {
+            let mut native: Vec<Option<Native>> = self.insts.iter().map(|_| None).collect();
+            let mut i = 0;
+            while i < self.insts.len() {
+                // A jump can only end a run, and blocks only have one at their end
+                let len = self.insts[i..].iter().take_while(|inst| jit::compiles(inst)).count();
+                if len >= jit::MIN_RUN {
+                    native[i] = Native::compile(&self.insts[i..i + len]);
+                }
+                i += len.max(1);
+            }
+            native
+        })
+    }
+}
+
+impl Debug for Block {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "Block {{ start: 0x{:X}, len: {} }}", self.start, self.insts.len())
+    }
+}
+
+/// Translated blocks by physical start address. Blocks never cross a page, so a store
+/// drops the blocks of the page it hits.
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    blocks: HashMap<u64, Rc<Block>>,
+    pages: HashMap<usize, Vec<u64>>,
+    /// Bumped whenever blocks are dropped, which breaks every chain link
+    pub generation: u64,
+    /// Whether hot blocks get native code
+    pub jit: bool,
+    pub translated: u64,
+    pub executed: u64,
+    /// Blocks found through the previous block's link instead of a lookup
+    pub chained: u64,
+    /// Instructions run as native code
+    pub native: u64,
+}
+
+impl BlockCache {
+    /// The block starting at `pc`, following `prev`'s link if it still holds.
+    pub fn get(&mut self, pc: u64, prev: Option<&Block>) -> Option<Rc<Block>> {
+        if let Some(prev) = prev {
+            if let Some((generation, next)) = &*prev.next.borrow() {
+                if let Some(next) = next.upgrade().filter(|n| *generation == self.generation && n.start == pc) {
+                    self.chained += 1;
+                    return Some(next);
+                }
+            }
+        }
+        let block = self.blocks.get(&pc).cloned();
+        if let (Some(prev), Some(block)) = (prev, &block) {
+            *prev.next.borrow_mut() = Some((self.generation, Rc::downgrade(block)));
+        }
+        block
+    }
+
+    pub fn insert(&mut self, block: Block) -> Rc<Block> {
+        let block = Rc::new(block);
+        self.translated += 1;
+        self.pages.entry(block.start as usize >> PAGE_SHIFT).or_default().push(block.start);
+        self.blocks.insert(block.start, block.clone());
+        block
+    }
+
+    /// Drops the blocks on the pages overlapping the `len` bytes written at `addr`.
+    pub fn invalidate(&mut self, addr: usize, len: usize) {
+        if self.pages.is_empty() || len == 0 {
+            return;
+        }
+        let (first, last) = (addr >> PAGE_SHIFT, addr.saturating_add(len - 1) >> PAGE_SHIFT);
+        let pages: Vec<usize> = if last - first < self.pages.len() {
+            (first..=last).filter(|p| self.pages.contains_key(p)).collect()
+        } else {
+            self.pages.keys().copied().filter(|p| (first..=last).contains(p)).collect()
+        };
+        for page in &pages {
+            for start in self.pages.remove(page).unwrap_or_default() {
+                self.blocks.remove(&start);
+            }
+        }
+        if !pages.is_empty() {
+            self.generation += 1;
+        }
+    }
+
+    /// Whether a block starts on the page holding physical address `addr`.
+    pub fn holds_page(&self, addr: usize) -> bool {
+        self.pages.contains_key(&(addr >> PAGE_SHIFT))
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.pages.clear();
+        self.generation += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+/// Whether a block goes on after `inst`. Branches, jumps, system instructions and
+/// anything unknown end it.
+pub fn continues(inst: &Instructions) -> bool {
+    use Instructions::*;
+    matches!(inst,
+        Add { .. } | Addw { .. } | Addi { .. } | Addiw { .. } | Slli { .. } | Srli { .. } | Srai { .. }
+        | Slliw { .. } | Srliw { .. } | Sraiw { .. } | Sub { .. } | Subw { .. } | Lui { .. }
+        | And { .. } | Andi { .. } | Or { .. } | Ori { .. } | Xor { .. } | Xori { .. }
+        | Sll { .. } | Sllw { .. } | Srl { .. } | Srlw { .. } | Sra { .. } | Sraw { .. }
+        | Slt { .. } | Slti { .. } | Sltu { .. } | Sltiu { .. }
+        | Mul { .. } | Mulh { .. } | Mulhsu { .. } | Mulhu { .. } | Mulw { .. } | Div { .. } | Divu { .. }
+        | Divw { .. } | Divuw { .. } | Rem { .. } | Remu { .. } | Remw { .. } | Remuw { .. }
+        | Lb { .. } | Lh { .. } | Lw { .. } | Ld { .. } | Lbu { .. } | Lhu { .. } | Lwu { .. }
+        | Sb { .. } | Sh { .. } | Sw { .. } | Sd { .. } | Auipc { .. } | Fence { .. })
+}
+
+/// Wraps the work of an instruction so the pc moves on afterwards, even if it failed,
+/// as `execute` does.
+fn op<F: Fn(&mut CPU) -> Result<(), String> + 'static>(f: F) -> Op {
+    Box::new(move |cpu| {
+        let result = f(cpu);
+        cpu.pc = cpu.pc.wrapping_add(4);
+        result
+    })
+}
+
+fn load(rd: usize, rs1: usize, imm: i64, size: usize, extend: fn(u64) -> u64) -> Op {
+    op(move |cpu| cpu.load_reg(rd, cpu.read_reg(rs1).wrapping_add(imm as u64), size, extend))
+}
+
+fn store(rs1: usize, rs2: usize, imm: i64, size: usize) -> Op {
+    let mask = if size == 64 { u64::MAX } else { (1 << size) - 1 };
+    op(move |cpu| cpu.store_reg(cpu.read_reg(rs1).wrapping_add(imm as u64), size, cpu.read_reg(rs2) & mask))
+}
+
+/// The handler for `inst`. Instructions without their own go through `execute`.
+fn compile(inst: Instructions) -> Op {
+    use Instructions::*;
+    match inst {
+        Add { rd, rs1, rs2 } => op(move |cpu| {
+            cpu.write_reg(rd, cpu.read_reg(rs1).wrapping_add(cpu.read_reg(rs2)));
+            Ok(())
+        }),
+        Addw { rd, rs1, rs2 } => op(move |cpu| {
+            cpu.write_reg(rd, cpu.read_reg(rs1).wrapping_add(cpu.read_reg(rs2)) as u32 as i32 as i64 as u64);
+            Ok(())
+        }),
+        Addi { rd, rs1, imm } => op(move |cpu| {
+            cpu.write_reg(rd, cpu.read_reg(rs1).wrapping_add(imm as u64));
+            Ok(())
+        }),
+        Addiw { rd, rs1, imm } => op(move |cpu| {
+            cpu.write_reg(rd, cpu.read_reg(rs1).wrapping_add(imm as u64) as u32 as i32 as i64 as u64);
+            Ok(())
+        }),
+        Slli { rd, rs1, shamt } => op(move |cpu| {
+            cpu.write_reg(rd, cpu.read_reg(rs1) << shamt);
+            Ok(())
+        }),
+        Srli { rd, rs1, shamt } => op(move |cpu| {
+            cpu.write_reg(rd, cpu.read_reg(rs1) >> shamt);
+            Ok(())
+        }),
+        Srai { rd, rs1, shamt } => op(move |cpu| {
+            cpu.write_reg(rd, ((cpu.read_reg(rs1) as i64) >> shamt) as u64);
+            Ok(())
+        }),
+        Lb { rd, rs1, imm } => load(rd, rs1, imm, 8, |v| v as i8 as i64 as u64),
+        Lh { rd, rs1, imm } => load(rd, rs1, imm, 16, |v| v as i16 as i64 as u64),
+        Lw { rd, rs1, imm } => load(rd, rs1, imm, 32, |v| v as i32 as i64 as u64),
+        Ld { rd, rs1, imm } => load(rd, rs1, imm, 64, |v| v),
+        Lbu { rd, rs1, imm } => load(rd, rs1, imm, 8, |v| v),
+        Lhu { rd, rs1, imm } => load(rd, rs1, imm, 16, |v| v),
+        Lwu { rd, rs1, imm } => load(rd, rs1, imm, 32, |v| v),
+        Sb { rs1, rs2, imm } => store(rs1, rs2, imm, 8),
+        Sh { rs1, rs2, imm } => store(rs1, rs2, imm, 16),
+        Sw { rs1, rs2, imm } => store(rs1, rs2, imm, 32),
+        Sd { rs1, rs2, imm } => store(rs1, rs2, imm, 64),
+        Fence { .. } => op(|_| Ok(())),
+        inst => Box::new(move |cpu| cpu.execute(inst)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(start: u64) -> Block {
+        Block::new(start, vec![Instructions::Addi { rd: 10, rs1: 10, imm: 1 }])
+    }
+
+    /// A write drops the blocks of the pages it touches and nothing else, and only
+    /// then breaks chain links.
+    #[test]
+    fn invalidate_pages() {
+        let mut cache = BlockCache::default();
+        let first = cache.insert(block(0x1000));
+        cache.insert(block(0x1800));
+        cache.insert(block(0x2000));
+        // The first lookup links the blocks, the second follows the link
+        cache.get(0x2000, Some(&first));
+        assert_eq!(cache.get(0x2000, Some(&first)).map(|b| b.start), Some(0x2000));
+        assert_eq!(cache.chained, 1);
+
+        cache.invalidate(0x3000, 8);
+        assert_eq!((cache.len(), cache.generation), (3, 0));
+        cache.invalidate(0x1FFC, 2);
+        assert_eq!((cache.len(), cache.generation), (1, 1));
+        assert!(cache.get(0x1800, None).is_none());
+        assert!(!cache.holds_page(0x1000));
+        assert!(cache.holds_page(0x2FFF));
+        // The link to 0x2000 was made before the invalidation
+        assert!(cache.get(0x2000, Some(&first)).is_some());
+        assert_eq!(cache.chained, 1);
+
+        // A write straddling into the next page counts for both
+        cache.invalidate(0x1FFC, 8);
+        assert_eq!((cache.len(), cache.generation), (0, 2));
+    }
+
+    /// A store into the running block takes effect the next time round the loop,
+    /// with and without native code. Exits the loop with a0 = 1 + 10.
+    #[test]
+    fn self_modifying_code() {
+        let code: Vec<u8> = [
+            0x00200393u32, // li t2, 2
+            0x00000297, // auipc t0, 0
+            0x01C2A303, // lw t1, 28(t0)
+            0x00150513, // target: addi a0, a0, 1
+            0x0062A423, // sw t1, 8(t0)
+            0xFFF38393, // addi t2, t2, -1
+            0xFE039AE3, // bnez t2, target
+            0x0000006F, // j .
+            0x00A50513, // addi a0, a0, 10
+        ].iter().flat_map(|i| i.to_le_bytes()).collect();
+        for jit in [false, true] {
+            let mut cpu = CPU::with_memory(0x8000_0000, 0x1000, code.clone());
+            cpu.set_jit(jit);
+            assert_eq!(cpu.run(100), 100);
+            assert_eq!(cpu.read_reg(10), 11);
+            assert_eq!(cpu.pc(), 0x8000_001C);
+            assert!(cpu.blocks.generation >= 2);
+        }
+    }
+}
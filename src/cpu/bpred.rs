@@ -0,0 +1,306 @@
+// Branch prediction model. Every branch and jump the program executes is checked
+// against what a predictor in the fetch stage would have guessed: the direction from
+// a static rule, a bimodal table or gshare, the target from a branch target buffer,
+// and return addresses from a return-address stack. Nothing here changes execution;
+// mispredictions are counted per branch, and charged by the pipeline model if set.
+
+use std::collections::HashMap;
+
+use super::decode::Instructions;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Backward branches taken, forward ones not
+    Static,
+    /// A 2-bit counter per entry, indexed by pc
+    Bimodal,
+    /// 2-bit counters indexed by pc xor the global history
+    Gshare,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PredictorConfig {
+    pub direction: Direction,
+    /// Counters in the bimodal or gshare table
+    pub entries: u64,
+    /// Global history bits for gshare
+    pub history: u32,
+    /// Branch target buffer entries, direct-mapped. 0 means targets are always known.
+    pub btb: u64,
+    /// Return-address stack depth, 0 for none
+    pub ras: u64,
+}
+
+impl PredictorConfig {
+    pub fn new(direction: Direction) -> PredictorConfig {
+        Self {
+            direction,
+            entries: 1024,
+            history: 10,
+            btb: 64,
+            ras: 8,
+        }
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        if !self.entries.is_power_of_two() || (self.btb != 0 && !self.btb.is_power_of_two()) {
+            return Err("predictor \"entries\" and \"btb\" must be powers of two".to_string());
+        }
+        if self.history > 32 {
+            return Err("predictor \"history\" can be at most 32 bits".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BranchStats {
+    pub executed: u64,
+    pub taken: u64,
+    pub mispredicted: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PredictorTotals {
+    pub conditional: BranchStats,
+    pub jumps: BranchStats,
+    pub returns: BranchStats,
+}
+
+#[derive(Debug)]
+pub struct Predictor {
+    config: PredictorConfig,
+    counters: Vec<u8>,
+    history: u64,
+    /// (pc, target) per entry
+    btb: Vec<Option<(u64, u64)>>,
+    ras: Vec<u64>,
+    pub totals: PredictorTotals,
+    /// Per branch pc
+    pub branches: HashMap<u64, BranchStats>,
+}
+
+/// Registers the calling convention uses for return addresses.
+fn is_link(reg: usize) -> bool {
+    reg == 1 || reg == 5
+}
+
+impl Predictor {
+    pub fn new(config: PredictorConfig) -> Predictor {
+        Self {
+            // Weakly not taken
+            counters: vec![1; config.entries as usize],
+            history: 0,
+            btb: vec![None; config.btb as usize],
+            ras: vec!(),
+            config,
+            totals: PredictorTotals::default(),
+            branches: HashMap::new(),
+        }
+    }
+
+    pub fn config(&self) -> &PredictorConfig {
+        &self.config
+    }
+
+    /// Checks the prediction for `inst` at `pc`, which went on to `next`, and learns
+    /// from it. Returns whether fetch had to be redirected, i.e. it was mispredicted.
+    /// Anything other than a branch or jump returns false.
+    pub fn observe(&mut self, pc: u64, inst: &Instructions, next: u64) -> bool {
+        use Instructions::*;
+        let taken = next != pc.wrapping_add(4);
+        let (mispredicted, kind) = match *inst {
+            Beq { imm, .. } | Bne { imm, .. } | Blt { imm, .. } | Bltu { imm, .. } | Bge { imm, .. } | Bgeu { imm, .. } => {
+                let predicted = self.predict_direction(pc, imm);
+                self.train_direction(pc, taken);
+                // A taken guess is only useful if the target is known in time
+                let wrong = predicted != taken || (taken && !self.target_known(pc, next));
+                if taken {
+                    self.btb_update(pc, next);
+                }
+                (wrong, &mut self.totals.conditional)
+            }
+            Jal { rd, .. } => {
+                if is_link(rd) {
+                    self.push_return(pc.wrapping_add(4));
+                }
+                let wrong = !self.target_known(pc, next);
+                self.btb_update(pc, next);
+                (wrong, &mut self.totals.jumps)
+            }
+            Jalr { rd, rs1, .. } if rd == 0 && is_link(rs1) && self.config.ras > 0 => {
+                let wrong = self.ras.pop() != Some(next);
+                (wrong, &mut self.totals.returns)
+            }
+            Jalr { rd, .. } => {
+                let wrong = !self.target_known(pc, next);
+                self.btb_update(pc, next);
+                if is_link(rd) {
+                    self.push_return(pc.wrapping_add(4));
+                }
+                (wrong, &mut self.totals.jumps)
+            }
+            _ => return false,
+        };
+        for stats in [kind, self.branches.entry(pc).or_default()] {
+            stats.executed += 1;
+            stats.taken += taken as u64;
+            stats.mispredicted += mispredicted as u64;
+        }
+        mispredicted
+    }
+
+    fn index(&self, pc: u64) -> usize {
+        let mut index = pc >> 2;
+        if self.config.direction == Direction::Gshare {
+            index ^= self.history;
+        }
+        (index & (self.config.entries - 1)) as usize
+    }
+
+    fn predict_direction(&self, pc: u64, offset: i64) -> bool {
+        match self.config.direction {
+            Direction::Static => offset < 0,
+            _ => self.counters[self.index(pc)] >= 2,
+        }
+    }
+
+    fn train_direction(&mut self, pc: u64, taken: bool) {
+        if self.config.direction == Direction::Static {
+            return;
+        }
+        let index = self.index(pc);
+        let counter = &mut self.counters[index];
+        *counter = if taken { (*counter + 1).min(3) } else { counter.saturating_sub(1) };
+        let mask = (1u64 << self.config.history) - 1;
+        self.history = ((self.history << 1) | taken as u64) & mask;
+    }
+
+    /// Whether the BTB has `target` for `pc`. Without a BTB targets are always known.
+    fn target_known(&self, pc: u64, target: u64) -> bool {
+        self.btb.is_empty() || self.btb[((pc >> 2) & (self.btb.len() as u64 - 1)) as usize] == Some((pc, target))
+    }
+
+    fn btb_update(&mut self, pc: u64, target: u64) {
+        if !self.btb.is_empty() {
+            let index = ((pc >> 2) & (self.btb.len() as u64 - 1)) as usize;
+            self.btb[index] = Some((pc, target));
+        }
+    }
+
+    fn push_return(&mut self, addr: u64) {
+        if self.config.ras == 0 {
+            return;
+        }
+        if self.ras.len() as u64 == self.config.ras {
+            self.ras.remove(0);
+        }
+        self.ras.push(addr);
+    }
+
+    /// Forgets what was learned, keeping the statistics.
+    pub fn reset(&mut self) {
+        let mut fresh = Predictor::new(self.config.clone());
+        fresh.totals = self.totals;
+        fresh.branches = std::mem::take(&mut self.branches);
+        *self = fresh;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BRANCH: u64 = 0x1000;
+
+    /// Runs the branch at `BRANCH` with offset `imm`, taken as `pattern` says, and
+    /// returns how often it was mispredicted.
+    fn branch(predictor: &mut Predictor, imm: i64, pattern: impl Iterator<Item = bool>) -> u64 {
+        let inst = Instructions::Bne { rs1: 5, rs2: 0, imm };
+        for taken in pattern {
+            let next = if taken { BRANCH.wrapping_add(imm as u64) } else { BRANCH + 4 };
+            predictor.observe(BRANCH, &inst, next);
+        }
+        predictor.branches[&BRANCH].mispredicted
+    }
+
+    /// A loop of ten iterations, a hundred times over.
+    fn loops() -> impl Iterator<Item = bool> {
+        (0..1000).map(|i| i % 10 != 9)
+    }
+
+    fn alternating() -> impl Iterator<Item = bool> {
+        (0..1000).map(|i| i % 2 == 0)
+    }
+
+    /// A loop branch is missed when the loop exits, plus once to fill the BTB. Global
+    /// history also learns where the exit comes, after seeing each history once.
+    #[test]
+    fn loop_branch() {
+        let run = |direction| {
+            let mut predictor = Predictor::new(PredictorConfig::new(direction));
+            let missed = branch(&mut predictor, -16, loops());
+            assert_eq!(predictor.totals.conditional.executed, 1000);
+            assert_eq!(predictor.totals.conditional.taken, 900);
+            missed
+        };
+        assert_eq!(run(Direction::Static), 101);
+        assert_eq!(run(Direction::Bimodal), 101);
+        assert!(run(Direction::Gshare) < 20);
+    }
+
+    /// Only global history catches a branch that alternates. Static prediction gets
+    /// the forward branch wrong whenever it is taken, a bimodal counter every time.
+    #[test]
+    fn alternating_branch() {
+        let missed = |direction| branch(&mut Predictor::new(PredictorConfig::new(direction)), 8, alternating());
+        assert_eq!(missed(Direction::Static), 500);
+        assert_eq!(missed(Direction::Bimodal), 1000);
+        assert!(missed(Direction::Gshare) < 20);
+    }
+
+    /// Calls and returns nested deeper than the return-address stack lose the
+    /// outermost return addresses.
+    #[test]
+    fn returns() {
+        let nest = |ras: u64| {
+            let mut predictor = Predictor::new(PredictorConfig { ras, ..PredictorConfig::new(Direction::Bimodal) });
+            for _ in 0..10 {
+                // Four nested calls, each to 0x100 past the call
+                for depth in 0..4u64 {
+                    let call = 0x1000 + 0x10 * depth;
+                    predictor.observe(call, &Instructions::Jal { rd: 1, imm: 0x100 }, call + 0x100);
+                }
+                for depth in (0..4u64).rev() {
+                    let call = 0x1000 + 0x10 * depth;
+                    predictor.observe(call + 0x140, &Instructions::Jalr { rd: 0, rs1: 1, imm: 0 }, call + 4);
+                }
+            }
+            predictor.totals
+        };
+        let deep = nest(8);
+        assert_eq!((deep.returns.executed, deep.returns.mispredicted), (40, 0));
+        // The four calls only miss the BTB the first time
+        assert_eq!((deep.jumps.executed, deep.jumps.mispredicted), (40, 4));
+        let shallow = nest(2);
+        assert_eq!(shallow.returns.mispredicted, 20);
+        // Without a stack returns are just indirect jumps through the BTB
+        let none = nest(0);
+        assert_eq!((none.returns.executed, none.jumps.executed), (0, 80));
+    }
+
+    /// An indirect jump switching between two targets keeps missing the BTB, unless
+    /// there is no BTB and targets are always known.
+    #[test]
+    fn btb() {
+        let jump = |btb: u64| {
+            let mut predictor = Predictor::new(PredictorConfig { btb, ..PredictorConfig::new(Direction::Bimodal) });
+            for i in 0..10 {
+                predictor.observe(BRANCH, &Instructions::Jalr { rd: 0, rs1: 6, imm: 0 }, 0x2000 + 0x100 * (i % 2));
+            }
+            predictor.totals.jumps.mispredicted
+        };
+        assert_eq!(jump(64), 10);
+        assert_eq!(jump(0), 0);
+    }
+}
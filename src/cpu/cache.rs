@@ -0,0 +1,287 @@
+// A cache hierarchy model: split L1 instruction and data caches in front of an optional
+// shared L2. Only tags are kept, the data itself always comes from the bus, so the
+// model can't change what the program sees. It counts hits and misses per cache and
+// can charge the cycles a miss would cost to the timing model.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheConfig {
+    pub size: u64,
+    pub ways: u64,
+    pub line: u64,
+    pub replacement: Replacement,
+    /// Write stores through to the next level instead of only marking the line dirty
+    pub write_through: bool,
+    /// Bring the line in on a write miss
+    pub write_allocate: bool,
+    /// Cycles to access this cache
+    pub latency: u64,
+}
+
+impl CacheConfig {
+    /// A write-back, write-allocate cache with LRU replacement and 64 byte lines.
+    pub fn new(size: u64, ways: u64, latency: u64) -> CacheConfig {
+        Self {
+            size,
+            ways,
+            line: 64,
+            replacement: Replacement::Lru,
+            write_through: false,
+            write_allocate: true,
+            latency,
+        }
+    }
+
+    pub fn check(&self, name: &str) -> Result<(), String> {
+        if !self.line.is_power_of_two() || self.ways == 0 || self.size == 0
+            || !self.size.is_multiple_of(self.line * self.ways) || !(self.size / (self.line * self.ways)).is_power_of_two() {
+            return Err(format!("{}: size must be a power-of-two number of sets of \"ways\" lines, with a power-of-two line size", name));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HierarchyConfig {
+    pub l1i: Option<CacheConfig>,
+    pub l1d: Option<CacheConfig>,
+    pub l2: Option<CacheConfig>,
+    /// Cycles for an access that misses every cache
+    pub memory_latency: u64,
+    /// Add miss latencies to the cycle count
+    pub timing: bool,
+}
+
+impl Default for HierarchyConfig {
+    fn default() -> Self {
+        Self {
+            l1i: Some(CacheConfig::new(16 * 1024, 4, 1)),
+            l1d: Some(CacheConfig::new(16 * 1024, 4, 1)),
+            l2: Some(CacheConfig::new(256 * 1024, 8, 10)),
+            memory_latency: 100,
+            timing: true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct CacheStats {
+    pub reads: u64,
+    pub read_misses: u64,
+    pub writes: u64,
+    pub write_misses: u64,
+    /// Dirty lines written back on eviction
+    pub writebacks: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u64,
+    /// Last use for LRU, fill time for FIFO
+    stamp: u64,
+}
+
+#[derive(Debug)]
+pub struct Cache {
+    pub name: &'static str,
+    config: CacheConfig,
+    sets: Vec<Vec<Line>>,
+    clock: u64,
+    seed: u64,
+    pub stats: CacheStats,
+}
+
+/// What an access did, for the level below.
+struct Outcome {
+    hit: bool,
+    /// Address of a dirty line pushed out to make room
+    evicted: Option<u64>,
+}
+
+impl Cache {
+    pub fn new(name: &'static str, config: CacheConfig) -> Cache {
+        let sets = (config.size / (config.line * config.ways)) as usize;
+        Self {
+            name,
+            sets: vec![vec![Line::default(); config.ways as usize]; sets],
+            config,
+            clock: 0,
+            seed: 0x2545_F491_4F6C_DD1D,
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn access(&mut self, addr: u64, write: bool) -> Outcome {
+        self.clock += 1;
+        let line_no = addr / self.config.line;
+        let set_count = self.sets.len() as u64;
+        let (index, tag) = ((line_no % set_count) as usize, line_no / set_count);
+        if write {
+            self.stats.writes += 1;
+        } else {
+            self.stats.reads += 1;
+        }
+        let config = &self.config;
+        let dirty = write && !config.write_through;
+        let hit = self.sets[index].iter().position(|l| l.valid && l.tag == tag);
+        if let Some(way) = hit {
+            let line = &mut self.sets[index][way];
+            if config.replacement == Replacement::Lru {
+                line.stamp = self.clock;
+            }
+            line.dirty |= dirty;
+            return Outcome { hit: true, evicted: None };
+        }
+        if write {
+            self.stats.write_misses += 1;
+            if !config.write_allocate {
+                return Outcome { hit: false, evicted: None };
+            }
+        } else {
+            self.stats.read_misses += 1;
+        }
+        let set = &self.sets[index];
+        let way = match set.iter().position(|l| !l.valid) {
+            Some(way) => way,
+            None if config.replacement == Replacement::Random => {
+                // xorshift, so runs are repeatable
+                self.seed ^= self.seed << 13;
+                self.seed ^= self.seed >> 7;
+                self.seed ^= self.seed << 17;
+                (self.seed % set.len() as u64) as usize
+            }
+            None => (0..set.len()).min_by_key(|&w| set[w].stamp).unwrap_or(0),
+        };
+        let old = set[way];
+        let evicted = (old.valid && old.dirty).then(|| (old.tag * set_count + index as u64) * config.line);
+        if evicted.is_some() {
+            self.stats.writebacks += 1;
+        }
+        self.sets[index][way] = Line { valid: true, dirty, tag, stamp: self.clock };
+        Outcome { hit: false, evicted }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    /// Invalidates every line, e.g. after loading a snapshot.
+    pub fn clear(&mut self) {
+        for set in self.sets.iter_mut() {
+            set.fill(Line::default());
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Hierarchy {
+    pub l1i: Option<Cache>,
+    pub l1d: Option<Cache>,
+    pub l2: Option<Cache>,
+    memory_latency: u64,
+    timing: bool,
+    /// Miss cycles not yet charged to an instruction
+    stall: u64,
+}
+
+impl Hierarchy {
+    pub fn new(config: HierarchyConfig) -> Hierarchy {
+        Self {
+            l1i: config.l1i.map(|c| Cache::new("L1I", c)),
+            l1d: config.l1d.map(|c| Cache::new("L1D", c)),
+            l2: config.l2.map(|c| Cache::new("L2", c)),
+            memory_latency: config.memory_latency,
+            timing: config.timing,
+            stall: 0,
+        }
+    }
+
+    /// An instruction fetch from `addr`.
+    pub fn fetch(&mut self, addr: u64) {
+        let mut l1 = self.l1i.take();
+        self.access_line(l1.as_mut(), addr, false);
+        self.l1i = l1;
+    }
+
+    /// A load or store of `len` bytes at `addr`, which may touch two lines.
+    pub fn data(&mut self, addr: u64, len: u64, write: bool) {
+        let mut l1 = self.l1d.take();
+        let line = l1.as_ref().or(self.l2.as_ref()).map_or(64, |c| c.config.line);
+        let (first, last) = (addr / line, addr.wrapping_add(len.max(1) - 1) / line);
+        for n in first..=last.max(first) {
+            self.access_line(l1.as_mut(), n * line, write);
+        }
+        self.l1d = l1;
+    }
+
+    /// Looks `addr` up in `l1` and on a miss in L2 and memory, adding the cycles beyond
+    /// an L1 hit to the stall. Writes going on to the next level are buffered and
+    /// don't stall, only filling a line does.
+    fn access_line(&mut self, l1: Option<&mut Cache>, addr: u64, write: bool) {
+        let mut cycles = 0;
+        match l1 {
+            Some(l1) => {
+                let outcome = l1.access(addr, write);
+                if let Some(victim) = outcome.evicted {
+                    self.next_level(victim, true);
+                }
+                let config = &l1.config;
+                if write && (config.write_through || (!outcome.hit && !config.write_allocate)) {
+                    self.next_level(addr, true);
+                }
+                if !outcome.hit && (!write || config.write_allocate) {
+                    cycles += self.next_level(addr, false);
+                }
+            }
+            None => {
+                let latency = self.next_level(addr, write);
+                if !write {
+                    cycles += latency;
+                }
+            }
+        }
+        if self.timing {
+            self.stall += cycles;
+        }
+    }
+
+    /// An access to L2, or memory without one. Returns its latency.
+    fn next_level(&mut self, addr: u64, write: bool) -> u64 {
+        match self.l2.as_mut() {
+            Some(l2) => {
+                let outcome = l2.access(addr, write);
+                // L2 victims go to memory through the write buffer too
+                if outcome.hit {
+                    l2.config.latency
+                } else {
+                    l2.config.latency + self.memory_latency
+                }
+            }
+            None => self.memory_latency,
+        }
+    }
+
+    /// The cycles to charge for the accesses since the last call.
+    pub fn take_stall(&mut self) -> u64 {
+        std::mem::take(&mut self.stall)
+    }
+
+    pub fn caches(&self) -> impl Iterator<Item = &Cache> {
+        self.l1i.iter().chain(self.l1d.iter()).chain(self.l2.iter())
+    }
+
+    pub fn clear(&mut self) {
+        for cache in self.l1i.iter_mut().chain(self.l1d.iter_mut()).chain(self.l2.iter_mut()) {
+            cache.clear();
+        }
+        self.stall = 0;
+    }
+}
@@ -3,8 +3,9 @@ use std::fmt::{Debug, Formatter};
 use crate::cpu::REG_NAMES;
 use std::convert::TryFrom;
 use strum::IntoEnumIterator;
-use strum_macros::EnumIter;
+use strum_macros::{EnumIter, EnumString};
 
+#[derive(Clone, Copy)]
 pub enum Instructions {
     Add{rd: usize, rs1: usize, rs2: usize},
     Addw{rd: usize, rs1: usize, rs2: usize},
@@ -20,15 +21,18 @@ pub enum Instructions {
     Bltu{rs1: usize, rs2: usize, imm: i64},
     Bne{rs1: usize, rs2: usize, imm: i64},
 
-    Csrrc{rd: usize, rs1: usize, csr: i64},
-    Csrrci{rd: usize, rs1: usize, csr: i64},
-    Csrrs{rd: usize, rs1: usize, csr: i64},
-    Csrrsi{rd: usize, rs1: usize, csr: i64},
-    Csrrw{rd: usize, rs1: usize, csr: i64},
-    Csrrwi{rd: usize, rs1: usize, csr: i64},
+    Csrrc{rd: usize, rs1: usize, csr: usize},
+    Csrrci{rd: usize, rs1: usize, csr: usize},
+    Csrrs{rd: usize, rs1: usize, csr: usize},
+    Csrrsi{rd: usize, rs1: usize, csr: usize},
+    Csrrw{rd: usize, rs1: usize, csr: usize},
+    Csrrwi{rd: usize, rs1: usize, csr: usize},
 
     Ebreak,
     Ecall,
+    Mret,
+    Sret,
+    Wfi,
     Fence{rd: usize, rs1: usize, succ: i64, pred: i64, fm: i64},
     FenceI,
     Jal{rd: usize, imm: i64},
@@ -42,16 +46,21 @@ pub enum Instructions {
     Ld{rd: usize, rs1: usize, imm: i64},
     Ldu{rd: usize, rs1: usize, imm: i64},
     Lui{rd: usize, imm: i64},
-    Mul,
-    Mulh,
-    Mulhsu,
-    Mulhu,
-    Div,
-    Divu,
-    Rem,
-    Remu,
-    Or,
-    Ori,
+    Mul{rd: usize, rs1: usize, rs2: usize},
+    Mulh{rd: usize, rs1: usize, rs2: usize},
+    Mulhsu{rd: usize, rs1: usize, rs2: usize},
+    Mulhu{rd: usize, rs1: usize, rs2: usize},
+    Mulw{rd: usize, rs1: usize, rs2: usize},
+    Div{rd: usize, rs1: usize, rs2: usize},
+    Divu{rd: usize, rs1: usize, rs2: usize},
+    Divw{rd: usize, rs1: usize, rs2: usize},
+    Divuw{rd: usize, rs1: usize, rs2: usize},
+    Rem{rd: usize, rs1: usize, rs2: usize},
+    Remu{rd: usize, rs1: usize, rs2: usize},
+    Remw{rd: usize, rs1: usize, rs2: usize},
+    Remuw{rd: usize, rs1: usize, rs2: usize},
+    Or{rd: usize, rs1: usize, rs2: usize},
+    Ori{rd: usize, rs1: usize, imm: i64},
     RdCycle,
     RdCycleH,
     RdTime,
@@ -62,39 +71,101 @@ pub enum Instructions {
     Sh{rs1: usize, rs2: usize, imm: i64},
     Sw{rs1: usize, rs2: usize, imm: i64},
     Sd{rs1: usize, rs2: usize, imm: i64},
-    Sll,
-    Sllw,
-    Slli,
-    Slliw,
-    Slt,
-    Slti,
-    Sltu,
-    Sltiu,
-    Sra,
-    Sraw,
-    Srai,
-    Sraiw,
-    Srl,
-    Srlw,
-    Srli,
-    Srliw,
-    Sub,
-    Subw,
-    Xor,
-    Xori,
+    Sll{rd: usize, rs1: usize, rs2: usize},
+    Sllw{rd: usize, rs1: usize, rs2: usize},
+    Slli{rd: usize, rs1: usize, shamt: u32},
+    Slliw{rd: usize, rs1: usize, shamt: u32},
+    Slt{rd: usize, rs1: usize, rs2: usize},
+    Slti{rd: usize, rs1: usize, imm: i64},
+    Sltu{rd: usize, rs1: usize, rs2: usize},
+    Sltiu{rd: usize, rs1: usize, imm: i64},
+    Sra{rd: usize, rs1: usize, rs2: usize},
+    Sraw{rd: usize, rs1: usize, rs2: usize},
+    Srai{rd: usize, rs1: usize, shamt: u32},
+    Sraiw{rd: usize, rs1: usize, shamt: u32},
+    Srl{rd: usize, rs1: usize, rs2: usize},
+    Srlw{rd: usize, rs1: usize, rs2: usize},
+    Srli{rd: usize, rs1: usize, shamt: u32},
+    Srliw{rd: usize, rs1: usize, shamt: u32},
+    Sub{rd: usize, rs1: usize, rs2: usize},
+    Subw{rd: usize, rs1: usize, rs2: usize},
+    Xor{rd: usize, rs1: usize, rs2: usize},
+    Xori{rd: usize, rs1: usize, imm: i64},
+    /// Load-reserved of `size` bits
+    Lr{rd: usize, rs1: usize, size: usize},
+    /// Store-conditional of `size` bits
+    Sc{rd: usize, rs1: usize, rs2: usize, size: usize},
+    /// Atomic read-modify-write of `size` bits
+    Amo{op: AmoOp, rd: usize, rs1: usize, rs2: usize, size: usize},
+    SfenceVma{rs1: usize, rs2: usize},
     Unknown,
 }
 
+/// The operation of an AMO instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AmoOp {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+impl AmoOp {
+    /// The value stored back, given what was in memory and what rs2 holds.
+    pub fn apply(self, old: u64, src: u64, size: usize) -> u64 {
+        // Words are compared as sign or zero extended 32 bit values
+        let (old_s, src_s) = if size == 32 {
+            (old as i32 as i64, src as i32 as i64)
+        } else {
+            (old as i64, src as i64)
+        };
+        let (old_u, src_u) = if size == 32 { (old as u32 as u64, src as u32 as u64) } else { (old, src) };
+        match self {
+            AmoOp::Swap => src,
+            AmoOp::Add => old.wrapping_add(src),
+            AmoOp::Xor => old ^ src,
+            AmoOp::And => old & src,
+            AmoOp::Or => old | src,
+            AmoOp::Min => if src_s < old_s { src } else { old },
+            AmoOp::Max => if src_s > old_s { src } else { old },
+            AmoOp::Minu => if src_u < old_u { src } else { old },
+            AmoOp::Maxu => if src_u > old_u { src } else { old },
+        }
+    }
+}
+
 #[allow(non_camel_case_types)]
-#[derive(Debug, EnumIter, Clone)]
+#[derive(Debug, EnumIter, EnumString, Clone)]
 pub enum CsrNames {
     sstatus = 0x100,
     sedeleg,
+    sie = 0x104,
+    stvec,
+    sscratch = 0x140,
+    sepc,
+    scause,
+    stval,
+    sip,
+    satp = 0x180,
 
     mstatus = 0x300,
     misa,
     medeleg,
+    mideleg,
+    mie,
+    mtvec,
+    mscratch = 0x340,
+    mepc,
+    mcause,
+    mtval,
+    mip,
 
+    time = 0xc01,
     mhartid = 0xf14,
 }
 
@@ -173,6 +244,9 @@ impl Debug for Instructions {
             }
             Ebreak => { f.write_str(format!("Ebreak").as_str()) }
             Ecall => { f.write_str(format!("Ecall").as_str()) }
+            Mret => { f.write_str(format!("Mret").as_str()) }
+            Sret => { f.write_str(format!("Sret").as_str()) }
+            Wfi => { f.write_str(format!("Wfi").as_str()) }
             Instructions::Fence { rd, rs1, succ, pred, fm } => {
                 f.write_str(format!("Fence {}, {}, succ: {}, pred: {}, fm: {}", REG_NAMES[rd], REG_NAMES[rs1], succ, pred, fm).as_str())
             }
@@ -210,16 +284,51 @@ impl Debug for Instructions {
             Instructions::Lui { rd, imm } => {
                 f.write_str(format!("Lui {}, {}", REG_NAMES[rd], imm).as_str())
             }
-            Mul => { f.write_str(format!("Mul").as_str()) }
-            Mulh => { f.write_str(format!("Mulh").as_str()) }
-            Mulhsu => { f.write_str(format!("Mulhsu").as_str()) }
-            Mulhu => { f.write_str(format!("Mulhs").as_str()) }
-            Div => { f.write_str(format!("Div").as_str()) }
-            Divu => { f.write_str(format!("Divu").as_str()) }
-            Rem => { f.write_str(format!("Rem").as_str()) }
-            Remu => { f.write_str(format!("Remu").as_str()) }
-            Or => { f.write_str(format!("Or").as_str()) }
-            Ori => { f.write_str(format!("Ori").as_str()) }
+            Mul { rd, rs1, rs2 } => {
+                f.write_str(format!("Mul {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Mulh { rd, rs1, rs2 } => {
+                f.write_str(format!("Mulh {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Mulhsu { rd, rs1, rs2 } => {
+                f.write_str(format!("Mulhsu {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Mulhu { rd, rs1, rs2 } => {
+                f.write_str(format!("Mulhu {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Mulw { rd, rs1, rs2 } => {
+                f.write_str(format!("Mulw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Div { rd, rs1, rs2 } => {
+                f.write_str(format!("Div {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Divu { rd, rs1, rs2 } => {
+                f.write_str(format!("Divu {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Divw { rd, rs1, rs2 } => {
+                f.write_str(format!("Divw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Divuw { rd, rs1, rs2 } => {
+                f.write_str(format!("Divuw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Rem { rd, rs1, rs2 } => {
+                f.write_str(format!("Rem {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Remu { rd, rs1, rs2 } => {
+                f.write_str(format!("Remu {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Remw { rd, rs1, rs2 } => {
+                f.write_str(format!("Remw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Remuw { rd, rs1, rs2 } => {
+                f.write_str(format!("Remuw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Or { rd, rs1, rs2 } => {
+                f.write_str(format!("Or {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Ori { rd, rs1, imm } => {
+                f.write_str(format!("Ori {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], imm).as_str())
+            }
             RdCycle => { f.write_str(format!("RdCycle").as_str()) }
             RdCycleH => { f.write_str(format!("RdCycleH").as_str()) }
             RdTime => { f.write_str(format!("RdTime").as_str()) }
@@ -238,32 +347,89 @@ impl Debug for Instructions {
             Instructions::Sd { rs1, rs2, imm } => {
                 f.write_str(format!("Sd {}, {}({})", REG_NAMES[rs2], imm, REG_NAMES[rs1]).as_str())
             }
-            Sll => { f.write_str(format!("Sll").as_str()) }
-            Sllw => { f.write_str(format!("Sllw").as_str()) }
-            Slli => { f.write_str(format!("Slli").as_str()) }
-            Slliw => { f.write_str(format!("Slliw").as_str()) }
-            Slt => { f.write_str(format!("Slt").as_str()) }
-            Slti => { f.write_str(format!("Slti").as_str()) }
-            Sltu => { f.write_str(format!("Slti").as_str()) }
-            Sltiu => { f.write_str(format!("Sltiu").as_str()) }
-            Sra => { f.write_str(format!("Sra").as_str()) }
-            Sraw => { f.write_str(format!("Sraw").as_str()) }
-            Srai => { f.write_str(format!("Srai").as_str()) }
-            Sraiw => { f.write_str(format!("Sraiw").as_str()) }
-            Srl => { f.write_str(format!("Srl").as_str()) }
-            Srlw => { f.write_str(format!("Srlw").as_str()) }
-            Srli => { f.write_str(format!("Srli").as_str()) }
-            Srliw => { f.write_str(format!("Srliw").as_str()) }
-            Sub => { f.write_str(format!("Sub").as_str()) }
-            Subw => { f.write_str(format!("Subw").as_str()) }
-            Xor => { f.write_str(format!("Xor").as_str()) }
-            Xori => { f.write_str(format!("Xori").as_str()) }
+            Sll { rd, rs1, rs2 } => {
+                f.write_str(format!("Sll {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Sllw { rd, rs1, rs2 } => {
+                f.write_str(format!("Sllw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Slli { rd, rs1, shamt } => {
+                f.write_str(format!("Slli {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], shamt).as_str())
+            }
+            Slliw { rd, rs1, shamt } => {
+                f.write_str(format!("Slliw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], shamt).as_str())
+            }
+            Slt { rd, rs1, rs2 } => {
+                f.write_str(format!("Slt {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Slti { rd, rs1, imm } => {
+                f.write_str(format!("Slti {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], imm).as_str())
+            }
+            Sltu { rd, rs1, rs2 } => {
+                f.write_str(format!("Sltu {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Sltiu { rd, rs1, imm } => {
+                f.write_str(format!("Sltiu {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], imm).as_str())
+            }
+            Sra { rd, rs1, rs2 } => {
+                f.write_str(format!("Sra {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Sraw { rd, rs1, rs2 } => {
+                f.write_str(format!("Sraw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Srai { rd, rs1, shamt } => {
+                f.write_str(format!("Srai {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], shamt).as_str())
+            }
+            Sraiw { rd, rs1, shamt } => {
+                f.write_str(format!("Sraiw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], shamt).as_str())
+            }
+            Srl { rd, rs1, rs2 } => {
+                f.write_str(format!("Srl {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Srlw { rd, rs1, rs2 } => {
+                f.write_str(format!("Srlw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Srli { rd, rs1, shamt } => {
+                f.write_str(format!("Srli {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], shamt).as_str())
+            }
+            Srliw { rd, rs1, shamt } => {
+                f.write_str(format!("Srliw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], shamt).as_str())
+            }
+            Sub { rd, rs1, rs2 } => {
+                f.write_str(format!("Sub {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Subw { rd, rs1, rs2 } => {
+                f.write_str(format!("Subw {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Xor { rd, rs1, rs2 } => {
+                f.write_str(format!("Xor {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
+            Xori { rd, rs1, imm } => {
+                f.write_str(format!("Xori {}, {}, {}", REG_NAMES[rd], REG_NAMES[rs1], imm).as_str())
+            }
+            Lr { rd, rs1, size } => {
+                f.write_str(format!("Lr.{} {}, ({})", width(size), REG_NAMES[rd], REG_NAMES[rs1]).as_str())
+            }
+            Sc { rd, rs1, rs2, size } => {
+                f.write_str(format!("Sc.{} {}, {}, ({})", width(size), REG_NAMES[rd], REG_NAMES[rs2], REG_NAMES[rs1]).as_str())
+            }
+            Amo { op, rd, rs1, rs2, size } => {
+                f.write_str(format!("Amo{:?}.{} {}, {}, ({})", op, width(size), REG_NAMES[rd], REG_NAMES[rs2], REG_NAMES[rs1]).as_str())
+            }
+            SfenceVma { rs1, rs2 } => {
+                f.write_str(format!("SfenceVma {}, {}", REG_NAMES[rs1], REG_NAMES[rs2]).as_str())
+            }
             Unknown => { f.write_str(format!("Unknown").as_str()) }
         };
         Ok(())
     }
 }
 
+/// The suffix for an access of `size` bits: w or d.
+fn width(size: usize) -> &'static str {
+    if size == 32 { "w" } else { "d" }
+}
+
 impl Instructions {
     pub fn from(inst: u32) -> Instructions {
         let opcode = (inst&0x7F) as u8;
@@ -271,10 +437,11 @@ impl Instructions {
         let rs1 = ((inst>>15)&0x1F) as usize;
         let rs2 = ((inst>>20)&0x1F) as usize;
         let funct3 = ((inst>>12)&0x7) as usize;
-        #[allow(unused_variables)]
         let funct7 = ((inst>>25)&0x7F) as usize;
         let itype_imm =  (((inst&0xFFF00000) as i32)>>20) as i32 as i64; // Sign extension logic (??)
         let stype_imm = (((((inst&0xFE000000) as i32)>>20) as u32) | rd as u32) as i32 as i64;
+        // CSR numbers are 12 bits and unsigned, unlike the immediate in the same place
+        let csr = (inst>>20) as usize;
         let utype_imm =  (inst&0xFFFFF000) as i32 as i64; // TODO: Check for accuracy
 
         let __btype_imm = stype_imm & !1;
@@ -291,39 +458,98 @@ impl Instructions {
             0x13 => /* OP-IMM */ {
                 match funct3 {
                     0b000 => { Addi { rd, rs1, imm: itype_imm } }
-                    _ => {
-                        println!("Unknown funct3 OP-IMM: 0x{:X}", funct3);
-                        Unknown
+                    0b001 => { Slli { rd, rs1, shamt: (inst>>20)&0x3F } }
+                    0b010 => { Slti { rd, rs1, imm: itype_imm } }
+                    0b011 => { Sltiu { rd, rs1, imm: itype_imm } }
+                    0b100 => { Xori { rd, rs1, imm: itype_imm } }
+                    0b101 => {
+                        // Bit 30 selects an arithmetic shift
+                        if inst & (1<<30) != 0 {
+                            Srai { rd, rs1, shamt: (inst>>20)&0x3F }
+                        } else {
+                            Srli { rd, rs1, shamt: (inst>>20)&0x3F }
+                        }
                     }
+                    0b110 => { Ori { rd, rs1, imm: itype_imm } }
+                    0b111 => { Andi { rd, rs1, imm: itype_imm } }
+                    _ => Unknown,
                 }
             }
-            0x33 => {
-                match funct3 {
-                    0b000 => { Add { rd, rs1, rs2 } }
-                    _ => {
-                        println!("Unknown funct3 0x33: 0x{:X}", funct3);
-                        Unknown
-                    }
+            0x33 => /* OP */ {
+                match (funct7, funct3) {
+                    (0x00, 0b000) => { Add { rd, rs1, rs2 } }
+                    (0x00, 0b001) => { Sll { rd, rs1, rs2 } }
+                    (0x00, 0b010) => { Slt { rd, rs1, rs2 } }
+                    (0x00, 0b011) => { Sltu { rd, rs1, rs2 } }
+                    (0x00, 0b100) => { Xor { rd, rs1, rs2 } }
+                    (0x00, 0b101) => { Srl { rd, rs1, rs2 } }
+                    (0x00, 0b110) => { Or { rd, rs1, rs2 } }
+                    (0x00, 0b111) => { And { rd, rs1, rs2 } }
+                    (0x20, 0b000) => { Sub { rd, rs1, rs2 } }
+                    (0x20, 0b101) => { Sra { rd, rs1, rs2 } }
+                    (0x01, 0b000) => { Mul { rd, rs1, rs2 } }
+                    (0x01, 0b001) => { Mulh { rd, rs1, rs2 } }
+                    (0x01, 0b010) => { Mulhsu { rd, rs1, rs2 } }
+                    (0x01, 0b011) => { Mulhu { rd, rs1, rs2 } }
+                    (0x01, 0b100) => { Div { rd, rs1, rs2 } }
+                    (0x01, 0b101) => { Divu { rd, rs1, rs2 } }
+                    (0x01, 0b110) => { Rem { rd, rs1, rs2 } }
+                    (0x01, 0b111) => { Remu { rd, rs1, rs2 } }
+                    _ => Unknown,
                 }
             }
             0x1B => /* OP-IMM-32 */ {
                 match funct3 {
                     0b000 => { Addiw { rd, rs1, imm: itype_imm } }
-                    _ => {
-                        println!("Unknown funct3 OP-IMM-32: 0x{:X}", funct3);
-                        Unknown
+                    0b001 => { Slliw { rd, rs1, shamt: (inst>>20)&0x1F } }
+                    0b101 => {
+                        if inst & (1<<30) != 0 {
+                            Sraiw { rd, rs1, shamt: (inst>>20)&0x1F }
+                        } else {
+                            Srliw { rd, rs1, shamt: (inst>>20)&0x1F }
+                        }
                     }
+                    _ => Unknown,
                 }
             }
-            0x3B => {
-                match funct3 {
-                    0b000 => { Addw { rd, rs1, rs2 } }
-                    _ => {
-                        println!("Unknown funct3 0x33: 0x{:X}", funct3);
-                        Unknown
-                    }
+            0x3B => /* OP-32 */ {
+                match (funct7, funct3) {
+                    (0x00, 0b000) => { Addw { rd, rs1, rs2 } }
+                    (0x00, 0b001) => { Sllw { rd, rs1, rs2 } }
+                    (0x00, 0b101) => { Srlw { rd, rs1, rs2 } }
+                    (0x20, 0b000) => { Subw { rd, rs1, rs2 } }
+                    (0x20, 0b101) => { Sraw { rd, rs1, rs2 } }
+                    (0x01, 0b000) => { Mulw { rd, rs1, rs2 } }
+                    (0x01, 0b100) => { Divw { rd, rs1, rs2 } }
+                    (0x01, 0b101) => { Divuw { rd, rs1, rs2 } }
+                    (0x01, 0b110) => { Remw { rd, rs1, rs2 } }
+                    (0x01, 0b111) => { Remuw { rd, rs1, rs2 } }
+                    _ => Unknown,
                 }
             }
+            0x2F => /* AMO */ {
+                let size = match funct3 {
+                    0b010 => 32,
+                    0b011 => 64,
+                    _ => return Unknown,
+                };
+                // The low bits of funct7 are the aq and rl ordering bits, all accesses are in order already
+                let op = match funct7 >> 2 {
+                    0b00010 => return Lr { rd, rs1, size },
+                    0b00011 => return Sc { rd, rs1, rs2, size },
+                    0b00001 => AmoOp::Swap,
+                    0b00000 => AmoOp::Add,
+                    0b00100 => AmoOp::Xor,
+                    0b01100 => AmoOp::And,
+                    0b01000 => AmoOp::Or,
+                    0b10000 => AmoOp::Min,
+                    0b10100 => AmoOp::Max,
+                    0b11000 => AmoOp::Minu,
+                    0b11100 => AmoOp::Maxu,
+                    _ => return Unknown,
+                };
+                Amo { op, rd, rs1, rs2, size }
+            }
 
             0x03 => /* Loads */ {
                 match funct3 {
@@ -334,10 +560,7 @@ impl Instructions {
                     0b100 => { Lbu { rd, rs1, imm: itype_imm } }
                     0b101 => { Lhu { rd, rs1, imm: itype_imm } }
                     0b110 => { Lwu { rd, rs1, imm: itype_imm } }
-                    _ => {
-                        println!("Unknown funct3 load: 0x{:X}", funct3);
-                        Unknown
-                    }
+                    _ => Unknown,
                 }
             }
 
@@ -347,17 +570,13 @@ impl Instructions {
                     0b001 => { Sh { rs1, rs2, imm: stype_imm } }
                     0b010 => { Sw { rs1, rs2, imm: stype_imm } }
                     0b011 => { Sd { rs1, rs2, imm: stype_imm } }
-                    _ => {
-                        println!("Unknown funct3 store: 0x{:X}", funct3);
-                        Unknown
-                    }
+                    _ => Unknown,
                 }
             }
 
             0x17 => { Auipc { rd, imm: utype_imm } }
+            0x37 => { Lui { rd, imm: utype_imm } }
             0x63 => /* Conditional jumps */ {
-                println!("\n\nfunct3 cond jmp: 0b{:03b}", funct3);
-                println!("inst: 0b{:032b}", inst);
                 match funct3  {
                     0b000 => { Beq { rs1, rs2, imm: btype_imm } }
                     0b001 => { Bne { rs1, rs2, imm: btype_imm } }
@@ -365,10 +584,7 @@ impl Instructions {
                     0b101 => { Bge { rs1, rs2, imm: btype_imm } }
                     0b110 => { Bltu { rs1, rs2, imm: btype_imm } }
                     0b111 => { Bgeu { rs1, rs2, imm: btype_imm } }
-                    _ => {
-                        println!("Unknown funct3 cond jmp: 0x{:X}", funct3);
-                        Unknown
-                    }
+                    _ => Unknown,
                 }
             }
 
@@ -376,37 +592,55 @@ impl Instructions {
 
             0x6F => { Jal { rd, imm: jtype_imm } }
 
+            0x0F => /* MISC-MEM */ {
+                match funct3 {
+                    0b000 => {
+                        let succ = ((inst >> 20) & 0xF) as i64;
+                        let pred = ((inst >> 24) & 0xF) as i64;
+                        let fm = ((inst >> 28) & 0xF) as i64;
+                        Fence { rd, rs1, succ, pred, fm }
+                    }
+                    0b001 => { FenceI }
+                    _ => Unknown,
+                }
+            }
+
             0x73 => /* SYSTEM */ {
                 match funct3 {
+                    0x0 => {
+                        match inst {
+                            0x00000073 => Ecall,
+                            0x00100073 => Ebreak,
+                            0x10200073 => Sret,
+                            0x30200073 => Mret,
+                            0x10500073 => Wfi,
+                            _ if funct7 == 0x09 && rd == 0 => SfenceVma { rs1, rs2 },
+                            _ => Unknown,
+                        }
+                    }
                     0x1 => /* CSRRW */ {
-                        Csrrw { rd, rs1, csr: itype_imm }
+                        Csrrw { rd, rs1, csr }
                     }
                     0x2 => /* CSRRS */ {
-                        Csrrs { rd, rs1, csr: itype_imm }
+                        Csrrs { rd, rs1, csr }
                     }
                     0x3 => /* CSRRC */ {
-                        Csrrc { rd, rs1, csr: itype_imm }
+                        Csrrc { rd, rs1, csr }
                     }
                     0x5 => /* CSRRWI */ {
-                        Csrrwi { rd, rs1, csr: itype_imm }
+                        Csrrwi { rd, rs1, csr }
                     }
                     0x6 => /* CSRRSI */ {
-                        Csrrsi { rd, rs1, csr: itype_imm }
+                        Csrrsi { rd, rs1, csr }
                     }
                     0x7 => /* CSRRCI */ {
-                        Csrrci { rd, rs1, csr: itype_imm }
-                    }
-                    _ => {
-                        println!("Unknown funct3 system: 0x{:X}", funct3);
-                        Unknown
+                        Csrrci { rd, rs1, csr }
                     }
+                    _ => Unknown,
                 }
             }
 
-            _ => {
-                println!("Unknown instruction: 0x{:02X}", opcode);
-                Instructions::Unknown
-            }
+            _ => Instructions::Unknown,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use super::decode::Instructions;
+
+const PAGE_SHIFT: usize = 12;
+/// Instructions are 4 bytes, so a page holds 1024 of them.
+const SLOTS: usize = 1 << (PAGE_SHIFT - 2);
+
+/// Decoded instructions by physical page, so code that runs again isn't decoded again.
+/// A store to a page drops everything cached for it.
+#[derive(Debug, Default)]
+pub struct InstCache {
+    pages: HashMap<usize, Box<[Option<Instructions>; SLOTS]>>,
+    pub hits: u64,
+    pub misses: u64,
+    /// Pages dropped because they were written to
+    pub invalidations: u64,
+}
+
+impl InstCache {
+    pub fn get(&mut self, addr: usize) -> Option<Instructions> {
+        let inst = match self.pages.get(&(addr >> PAGE_SHIFT)) {
+            Some(page) if addr.is_multiple_of(4) => page[(addr >> 2) & (SLOTS - 1)],
+            _ => None,
+        };
+        match inst {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        inst
+    }
+
+    pub fn insert(&mut self, addr: usize, inst: Instructions) {
+        if addr.is_multiple_of(4) {
+            let page = self.pages.entry(addr >> PAGE_SHIFT).or_insert_with(|| Box::new([None; SLOTS]));
+            page[(addr >> 2) & (SLOTS - 1)] = Some(inst);
+        }
+    }
+
+    /// Drops the pages overlapping the `len` bytes written at `addr`.
+    pub fn invalidate(&mut self, addr: usize, len: usize) {
+        if self.pages.is_empty() || len == 0 {
+            return;
+        }
+        let (first, last) = (addr >> PAGE_SHIFT, addr.saturating_add(len - 1) >> PAGE_SHIFT);
+        if last - first < self.pages.len() {
+            for page in first..=last {
+                if self.pages.remove(&page).is_some() {
+                    self.invalidations += 1;
+                }
+            }
+        } else {
+            let before = self.pages.len();
+            self.pages.retain(|page, _| !(first..=last).contains(page));
+            self.invalidations += (before - self.pages.len()) as u64;
+        }
+    }
+
+    /// Drops everything, as for `fence.i`.
+    pub fn flush(&mut self) {
+        self.pages.clear();
+    }
+
+    /// Number of pages holding decoded instructions.
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+}
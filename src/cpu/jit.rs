@@ -0,0 +1,674 @@
+// Native code for runs of instructions in hot blocks on x86-64 hosts. A run is
+// compiled into one function working on the CPU's register array directly. It also
+// stores each result in a log, so the caller can trace and retire the instructions
+// one by one afterwards exactly as the interpreter would. Loads and stores call back
+// into the CPU, which only does them if they hit memory. Anything else, a fault or a
+// device access, makes the code return early so the interpreter runs that instruction
+// again from the start. A branch or jump can end a run, its target goes in the log
+// after the run. CSR, system and M and A extension instructions stay with the
+// interpreter. Other hosts interpret everything.
+
+use std::fmt::{Debug, Error, Formatter};
+
+#[cfg(target_arch = "x86_64")]
+use memmap2::{Mmap, MmapMut};
+
+use super::CPU;
+use super::decode::Instructions;
+
+/// Shortest run worth compiling.
+pub const MIN_RUN: usize = 2;
+/// How many times a block runs before its runs are compiled.
+pub const HOT_RUNS: u32 = 16;
+
+/// Compiled code gets the register array, the log, the CPU for loads and stores and
+/// the pc of its first instruction, and returns how many instructions it ran.
+#[cfg(target_arch = "x86_64")]
+type NativeFn = unsafe extern "sysv64" fn(regs: *mut u64, log: *mut u64, cpu: *mut CPU, pc: u64) -> u64;
+
+/// A compiled run of instructions.
+pub struct Native {
+    pub len: usize,
+    #[cfg(target_arch = "x86_64")]
+    code: Mmap,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Native {
+    /// Compiles `insts`, which must all be `compiles` with only the last one a jump.
+    pub fn compile(insts: &[Instructions]) -> Option<Native> {
+        if insts.is_empty() || insts[..insts.len() - 1].iter().any(jumps) {
+            return None;
+        }
+        let mut asm = Assembler::default();
+        asm.prologue();
+        for (i, inst) in insts.iter().enumerate() {
+            asm.inst(*inst, i)?;
+        }
+        asm.epilogue(insts.len());
+        let mut map = MmapMut::map_anon(asm.code.len()).ok()?;
+        map.copy_from_slice(&asm.code);
+        Some(Self {
+            len: insts.len(),
+            code: map.make_exec().ok()?,
+        })
+    }
+
+    /// Runs the code on `cpu`, filling `log` with the value each instruction wrote and,
+    /// if the run ends with a jump that was reached, where it went. Returns how many
+    /// instructions ran, fewer than `len` if the next one is left to the interpreter.
+    pub fn run(&self, cpu: &mut CPU, log: &mut [u64]) -> usize {
+        assert!(log.len() > self.len);
+        let pc = cpu.pc;
+        let cpu: *mut CPU = cpu;
+        // The code only touches regs[1..32] and log[..=len] itself, and the rest of the
+        // CPU through `load` and `store` between instructions
+        unsafe {
+            let func: NativeFn = std::mem::transmute(self.code.as_ptr());
+            let regs = std::ptr::addr_of_mut!((*cpu).regs) as *mut u64;
+            func(regs, log.as_mut_ptr(), cpu, pc) as usize
+        }
+    }
+}
+
+/// Without a code generator nothing compiles, so no `Native` is ever made.
+#[cfg(not(target_arch = "x86_64"))]
+impl Native {
+    pub fn compile(_insts: &[Instructions]) -> Option<Native> {
+        None
+    }
+
+    pub fn run(&self, _cpu: &mut CPU, _log: &mut [u64]) -> usize {
+        0
+    }
+}
+
+impl Debug for Native {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
+        write!(f, "Native {{ len: {} }}", self.len)
+    }
+}
+
+/// Whether `inst` can be compiled.
+pub fn compiles(inst: &Instructions) -> bool {
+    use Instructions::*;
+    dest(inst) != 0 || jumps(inst) || matches!(inst, Sb { .. } | Sh { .. } | Sw { .. } | Sd { .. })
+        || matches!(inst,
+            Add { rd: 0, .. } | Addw { rd: 0, .. } | Addi { rd: 0, .. } | Addiw { rd: 0, .. } | Slli { rd: 0, .. }
+            | Srli { rd: 0, .. } | Srai { rd: 0, .. } | Slliw { rd: 0, .. } | Srliw { rd: 0, .. }
+            | Sraiw { rd: 0, .. } | Sub { rd: 0, .. } | Subw { rd: 0, .. } | And { rd: 0, .. }
+            | Andi { rd: 0, .. } | Or { rd: 0, .. } | Ori { rd: 0, .. } | Xor { rd: 0, .. } | Xori { rd: 0, .. }
+            | Sll { rd: 0, .. } | Sllw { rd: 0, .. } | Srl { rd: 0, .. } | Srlw { rd: 0, .. }
+            | Sra { rd: 0, .. } | Sraw { rd: 0, .. } | Slt { rd: 0, .. } | Slti { rd: 0, .. }
+            | Sltu { rd: 0, .. } | Sltiu { rd: 0, .. } | Lui { rd: 0, .. } | Auipc { rd: 0, .. })
+}
+
+/// Whether `inst` is a branch or jump, which can only end a run.
+pub fn jumps(inst: &Instructions) -> bool {
+    use Instructions::*;
+    matches!(inst, Beq { .. } | Bne { .. } | Blt { .. } | Bge { .. } | Bltu { .. } | Bgeu { .. } | Jal { .. } | Jalr { .. })
+}
+
+/// The register a compilable instruction writes, 0 for none.
+pub fn dest(inst: &Instructions) -> usize {
+    use Instructions::*;
+    match *inst {
+        Add { rd, .. } | Addw { rd, .. } | Addi { rd, .. } | Addiw { rd, .. } | Slli { rd, .. }
+        | Srli { rd, .. } | Srai { rd, .. } | Slliw { rd, .. } | Srliw { rd, .. } | Sraiw { rd, .. }
+        | Sub { rd, .. } | Subw { rd, .. } | And { rd, .. } | Andi { rd, .. } | Or { rd, .. } | Ori { rd, .. }
+        | Xor { rd, .. } | Xori { rd, .. } | Sll { rd, .. } | Sllw { rd, .. } | Srl { rd, .. } | Srlw { rd, .. }
+        | Sra { rd, .. } | Sraw { rd, .. } | Slt { rd, .. } | Slti { rd, .. } | Sltu { rd, .. } | Sltiu { rd, .. }
+        | Lui { rd, .. } | Auipc { rd, .. } | Jal { rd, .. } | Jalr { rd, .. }
+        | Lb { rd, .. } | Lh { rd, .. } | Lw { rd, .. } | Ld { rd, .. } | Lbu { rd, .. } | Lhu { rd, .. }
+        | Lwu { rd, .. } => rd,
+        _ => 0,
+    }
+}
+
+/// Called by compiled loads. Writes the value, extended to 64 bits, to `out` and
+/// returns 1, or returns 0 if the interpreter has to do the load.
+#[cfg(target_arch = "x86_64")]
+unsafe extern "sysv64" fn load(cpu: *mut CPU, addr: u64, size: u64, signed: u64, out: *mut u64) -> u64 {
+    match (*cpu).native_load(addr, size as usize) {
+        Some(val) => {
+            let shift = 64 - size;
+            *out = if signed != 0 { ((val << shift) as i64 >> shift) as u64 } else { val };
+            1
+        }
+        None => 0,
+    }
+}
+
+/// Called by compiled stores. Returns 1 if the store was done, 0 if the interpreter
+/// has to do it.
+#[cfg(target_arch = "x86_64")]
+unsafe extern "sysv64" fn store(cpu: *mut CPU, addr: u64, size: u64, val: u64) -> u64 {
+    (*cpu).native_store(addr, size as usize, val) as u64
+}
+
+/// ALU operations on rax and rcx, by their opcode for `op rax, rcx`.
+#[cfg(target_arch = "x86_64")]
+const ADD: u8 = 0x01;
+#[cfg(target_arch = "x86_64")]
+const OR: u8 = 0x09;
+#[cfg(target_arch = "x86_64")]
+const AND: u8 = 0x21;
+#[cfg(target_arch = "x86_64")]
+const SUB: u8 = 0x29;
+#[cfg(target_arch = "x86_64")]
+const XOR: u8 = 0x31;
+
+/// Shifts, by their ModRM opcode extension.
+#[cfg(target_arch = "x86_64")]
+const SHL: u8 = 4;
+#[cfg(target_arch = "x86_64")]
+const SHR: u8 = 5;
+#[cfg(target_arch = "x86_64")]
+const SAR: u8 = 7;
+
+/// Condition codes for setcc and cmovcc.
+#[cfg(target_arch = "x86_64")]
+const CC_B: u8 = 0x2;
+#[cfg(target_arch = "x86_64")]
+const CC_AE: u8 = 0x3;
+#[cfg(target_arch = "x86_64")]
+const CC_E: u8 = 0x4;
+#[cfg(target_arch = "x86_64")]
+const CC_NE: u8 = 0x5;
+#[cfg(target_arch = "x86_64")]
+const CC_L: u8 = 0xC;
+#[cfg(target_arch = "x86_64")]
+const CC_GE: u8 = 0xD;
+
+/// Emits x86-64 code. The registers array is kept in rbx, the log in r12, the CPU in
+/// r13 and the pc of the first instruction in r14. Results are computed in rax with
+/// rcx and rdx as scratch.
+#[cfg(target_arch = "x86_64")]
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+    /// Where the rel32 of each jump to the epilogue goes
+    exits: Vec<usize>,
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, val: u32) {
+        self.emit(&val.to_le_bytes());
+    }
+
+    /// Saves the callee-saved registers it uses, keeping the stack 16 byte aligned for
+    /// calls, and moves the arguments into them.
+    fn prologue(&mut self) {
+        // push rbx; push r12; push r13; push r14; sub rsp, 8
+        self.emit(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x48, 0x83, 0xEC, 0x08]);
+        // mov rbx, rdi; mov r12, rsi; mov r13, rdx; mov r14, rcx
+        self.emit(&[0x48, 0x89, 0xFB, 0x49, 0x89, 0xF4, 0x49, 0x89, 0xD5, 0x49, 0x89, 0xCE]);
+    }
+
+    /// Returns `len`, the whole run. Early exits land after the mov with their own count in eax.
+    fn epilogue(&mut self, len: usize) {
+        self.emit(&[0xB8]);
+        self.emit_u32(len as u32);
+        let end = self.code.len();
+        for at in std::mem::take(&mut self.exits) {
+            let rel = (end - (at + 4)) as u32;
+            self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+        }
+        // add rsp, 8; pop r14; pop r13; pop r12; pop rbx; ret
+        self.emit(&[0x48, 0x83, 0xC4, 0x08, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0xC3]);
+    }
+
+    /// mov `dst`, [rbx + reg * 8], or zeroes `dst` for x0. `dst` is 0 for rax, 1 for rcx.
+    fn load_reg(&mut self, dst: u8, reg: usize) {
+        if reg == 0 {
+            self.emit(&[0x31, 0xC0 | dst << 3 | dst]);
+        } else {
+            self.emit(&[0x48, 0x8B, 0x83 | dst << 3]);
+            self.emit_u32(reg as u32 * 8);
+        }
+    }
+
+    /// mov rcx, imm
+    fn imm_rcx(&mut self, imm: i64) {
+        self.emit(&[0x48, 0xB9]);
+        self.emit(&imm.to_le_bytes());
+    }
+
+    /// `op` rax, rcx
+    fn alu(&mut self, op: u8) {
+        self.emit(&[0x48, op, 0xC8]);
+    }
+
+    /// `op` rax, imm, through rcx.
+    fn alu_imm(&mut self, op: u8, imm: i64) {
+        self.imm_rcx(imm);
+        self.alu(op);
+    }
+
+    /// mov rax, r14 plus `offset`: the pc of an instruction `offset` bytes into the run.
+    fn pc(&mut self, offset: i64) {
+        self.emit(&[0x4C, 0x89, 0xF0]);
+        self.alu_imm(ADD, offset);
+    }
+
+    /// movsxd rax, eax
+    fn sign_extend_word(&mut self) {
+        self.emit(&[0x48, 0x63, 0xC0]);
+    }
+
+    /// shl/shr/sar rax (eax with `word`) by `shamt`.
+    fn shift(&mut self, ext: u8, shamt: u32, word: bool) {
+        if word {
+            self.emit(&[0xC1, 0xC0 | ext << 3, (shamt & 31) as u8]);
+            self.sign_extend_word();
+        } else {
+            self.emit(&[0x48, 0xC1, 0xC0 | ext << 3, (shamt & 63) as u8]);
+        }
+    }
+
+    /// shl/shr/sar rax (eax with `word`) by cl, which x86 masks like RISC-V does.
+    fn shift_cl(&mut self, ext: u8, word: bool) {
+        if word {
+            self.emit(&[0xD3, 0xC0 | ext << 3]);
+            self.sign_extend_word();
+        } else {
+            self.emit(&[0x48, 0xD3, 0xC0 | ext << 3]);
+        }
+    }
+
+    /// rax = 1 if rax compares to rcx with condition `cc`, else 0.
+    fn set_if(&mut self, cc: u8) {
+        // cmp rax, rcx; setcc al; movzx eax, al
+        self.emit(&[0x48, 0x39, 0xC8, 0x0F, 0x90 | cc, 0xC0, 0x0F, 0xB6, 0xC0]);
+    }
+
+    /// mov [r12 + i * 8], rax (rdx with `rdx`): log entry `i`.
+    fn log(&mut self, i: usize, rdx: bool) {
+        self.emit(&[0x49, 0x89, if rdx { 0x94 } else { 0x84 }, 0x24]);
+        self.emit_u32(i as u32 * 8);
+    }
+
+    /// Writes rax to `rd` (unless it is x0) and to log entry `i`.
+    fn store_result(&mut self, rd: usize, i: usize) {
+        if rd != 0 {
+            self.emit(&[0x48, 0x89, 0x83]);
+            self.emit_u32(rd as u32 * 8);
+        }
+        self.log(i, false);
+    }
+
+    /// Calls `func` with the CPU as its first argument and leaves the run with `i`
+    /// instructions done if it returns 0.
+    fn call(&mut self, func: u64, i: usize) {
+        // mov rdi, r13; mov rax, func; call rax; test rax, rax
+        self.emit(&[0x4C, 0x89, 0xEF, 0x48, 0xB8]);
+        self.emit(&func.to_le_bytes());
+        self.emit(&[0xFF, 0xD0, 0x48, 0x85, 0xC0]);
+        // jnz over the exit: mov eax, i; jmp epilogue
+        self.emit(&[0x75, 0x0A, 0xB8]);
+        self.emit_u32(i as u32);
+        self.emit(&[0xE9]);
+        self.exits.push(self.code.len());
+        self.emit_u32(0);
+    }
+
+    /// rsi = rs1 + imm, the address of a load or store.
+    fn address(&mut self, rs1: usize, imm: i64) {
+        self.load_reg(0, rs1);
+        self.alu_imm(ADD, imm);
+        self.emit(&[0x48, 0x89, 0xC6]);
+    }
+
+    fn load(&mut self, rd: usize, rs1: usize, imm: i64, size: u32, signed: bool, i: usize) {
+        self.address(rs1, imm);
+        // mov edx, size; mov ecx, signed; lea r8, [r12 + i * 8]
+        self.emit(&[0xBA]);
+        self.emit_u32(size);
+        self.emit(&[0xB9]);
+        self.emit_u32(signed as u32);
+        self.emit(&[0x4D, 0x8D, 0x84, 0x24]);
+        self.emit_u32(i as u32 * 8);
+        self.call(load as *const () as u64, i);
+        // mov rax, [r12 + i * 8]
+        self.emit(&[0x49, 0x8B, 0x84, 0x24]);
+        self.emit_u32(i as u32 * 8);
+        self.store_result(rd, i);
+    }
+
+    fn store(&mut self, rs1: usize, rs2: usize, imm: i64, size: u32, i: usize) {
+        self.address(rs1, imm);
+        self.load_reg(1, rs2);
+        self.emit(&[0xBA]);
+        self.emit_u32(size);
+        self.call(store as *const () as u64, i);
+    }
+
+    /// Logs the pc after a branch at `i`: its target if rax compares to rcx with `cc`.
+    fn branch(&mut self, cc: u8, imm: i64, i: usize) {
+        let offset = 4 * i as i64;
+        // cmp rax, rcx; mov rax, r14; mov rcx, taken; mov rdx, not taken
+        self.emit(&[0x48, 0x39, 0xC8, 0x4C, 0x89, 0xF0]);
+        self.imm_rcx(offset.wrapping_add(imm));
+        self.emit(&[0x48, 0xBA]);
+        self.emit(&(offset + 4).to_le_bytes());
+        // cmov (not cc) rcx, rdx; add rax, rcx
+        self.emit(&[0x48, 0x0F, 0x40 | (cc ^ 1), 0xCA]);
+        self.alu(ADD);
+        self.log(i + 1, false);
+    }
+
+    fn inst(&mut self, inst: Instructions, i: usize) -> Option<()> {
+        use Instructions::*;
+        let offset = 4 * i as i64;
+        let rd = match inst {
+            Add { rd, rs1, rs2 } | Addw { rd, rs1, rs2 } | Sub { rd, rs1, rs2 } | Subw { rd, rs1, rs2 }
+            | And { rd, rs1, rs2 } | Or { rd, rs1, rs2 } | Xor { rd, rs1, rs2 } => {
+                self.load_reg(0, rs1);
+                self.load_reg(1, rs2);
+                self.alu(match inst {
+                    Add { .. } | Addw { .. } => ADD,
+                    Sub { .. } | Subw { .. } => SUB,
+                    And { .. } => AND,
+                    Or { .. } => OR,
+                    _ => XOR,
+                });
+                if matches!(inst, Addw { .. } | Subw { .. }) {
+                    self.sign_extend_word();
+                }
+                rd
+            }
+            Addi { rd, rs1, imm } | Addiw { rd, rs1, imm } | Andi { rd, rs1, imm } | Ori { rd, rs1, imm }
+            | Xori { rd, rs1, imm } => {
+                self.load_reg(0, rs1);
+                self.alu_imm(match inst {
+                    Addi { .. } | Addiw { .. } => ADD,
+                    Andi { .. } => AND,
+                    Ori { .. } => OR,
+                    _ => XOR,
+                }, imm);
+                if matches!(inst, Addiw { .. }) {
+                    self.sign_extend_word();
+                }
+                rd
+            }
+            Slli { rd, rs1, shamt } | Srli { rd, rs1, shamt } | Srai { rd, rs1, shamt }
+            | Slliw { rd, rs1, shamt } | Srliw { rd, rs1, shamt } | Sraiw { rd, rs1, shamt } => {
+                self.load_reg(0, rs1);
+                let (ext, word) = match inst {
+                    Slli { .. } => (SHL, false),
+                    Srli { .. } => (SHR, false),
+                    Srai { .. } => (SAR, false),
+                    Slliw { .. } => (SHL, true),
+                    Srliw { .. } => (SHR, true),
+                    _ => (SAR, true),
+                };
+                self.shift(ext, shamt, word);
+                rd
+            }
+            Sll { rd, rs1, rs2 } | Srl { rd, rs1, rs2 } | Sra { rd, rs1, rs2 }
+            | Sllw { rd, rs1, rs2 } | Srlw { rd, rs1, rs2 } | Sraw { rd, rs1, rs2 } => {
+                self.load_reg(0, rs1);
+                self.load_reg(1, rs2);
+                let (ext, word) = match inst {
+                    Sll { .. } => (SHL, false),
+                    Srl { .. } => (SHR, false),
+                    Sra { .. } => (SAR, false),
+                    Sllw { .. } => (SHL, true),
+                    Srlw { .. } => (SHR, true),
+                    _ => (SAR, true),
+                };
+                self.shift_cl(ext, word);
+                rd
+            }
+            Slt { rd, rs1, rs2 } | Sltu { rd, rs1, rs2 } => {
+                self.load_reg(0, rs1);
+                self.load_reg(1, rs2);
+                self.set_if(if matches!(inst, Slt { .. }) { CC_L } else { CC_B });
+                rd
+            }
+            Slti { rd, rs1, imm } | Sltiu { rd, rs1, imm } => {
+                self.load_reg(0, rs1);
+                self.imm_rcx(imm);
+                self.set_if(if matches!(inst, Slti { .. }) { CC_L } else { CC_B });
+                rd
+            }
+            Lui { rd, imm } => {
+                self.emit(&[0x48, 0xB8]);
+                self.emit(&imm.to_le_bytes());
+                rd
+            }
+            Auipc { rd, imm } => {
+                self.pc(offset.wrapping_add(imm));
+                rd
+            }
+            Lb { rd, rs1, imm } => { self.load(rd, rs1, imm, 8, true, i); return Some(()); }
+            Lh { rd, rs1, imm } => { self.load(rd, rs1, imm, 16, true, i); return Some(()); }
+            Lw { rd, rs1, imm } => { self.load(rd, rs1, imm, 32, true, i); return Some(()); }
+            Ld { rd, rs1, imm } => { self.load(rd, rs1, imm, 64, false, i); return Some(()); }
+            Lbu { rd, rs1, imm } => { self.load(rd, rs1, imm, 8, false, i); return Some(()); }
+            Lhu { rd, rs1, imm } => { self.load(rd, rs1, imm, 16, false, i); return Some(()); }
+            Lwu { rd, rs1, imm } => { self.load(rd, rs1, imm, 32, false, i); return Some(()); }
+            Sb { rs1, rs2, imm } => { self.store(rs1, rs2, imm, 8, i); return Some(()); }
+            Sh { rs1, rs2, imm } => { self.store(rs1, rs2, imm, 16, i); return Some(()); }
+            Sw { rs1, rs2, imm } => { self.store(rs1, rs2, imm, 32, i); return Some(()); }
+            Sd { rs1, rs2, imm } => { self.store(rs1, rs2, imm, 64, i); return Some(()); }
+            Beq { rs1, rs2, imm } | Bne { rs1, rs2, imm } | Blt { rs1, rs2, imm }
+            | Bge { rs1, rs2, imm } | Bltu { rs1, rs2, imm } | Bgeu { rs1, rs2, imm } => {
+                self.load_reg(0, rs1);
+                self.load_reg(1, rs2);
+                self.branch(match inst {
+                    Beq { .. } => CC_E,
+                    Bne { .. } => CC_NE,
+                    Blt { .. } => CC_L,
+                    Bge { .. } => CC_GE,
+                    Bltu { .. } => CC_B,
+                    _ => CC_AE,
+                }, imm, i);
+                return Some(());
+            }
+            Jal { rd, imm } => {
+                self.pc(offset.wrapping_add(imm));
+                self.log(i + 1, false);
+                self.pc(offset + 4);
+                rd
+            }
+            Jalr { rd, rs1, imm } => {
+                // The target comes from rs1 before the link overwrites it
+                self.load_reg(0, rs1);
+                self.alu_imm(ADD, imm);
+                self.alu_imm(AND, -2);
+                self.log(i + 1, false);
+                self.pc(offset + 4);
+                rd
+            }
+            _ => return None,
+        };
+        if rd >= 32 {
+            return None;
+        }
+        self.store_result(rd, i);
+        Some(())
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x8000_0000;
+    const VALUES: [u64; 10] = [
+        0, 1, 0x7FFF_FFFF, 0x8000_0000, 0xFFFF_FFFF, 0x1_0000_0000,
+        0x7FFF_FFFF_FFFF_FFFF, 0x8000_0000_0000_0000, u64::MAX, 0x1234_5678_9ABC_DEF0,
+    ];
+    const IMMS: [i64; 6] = [0, 1, -1, 2047, -2048, 0x555];
+    const SHAMTS: [u32; 6] = [0, 1, 31, 32, 33, 63];
+    /// Offsets from x8, which points into the middle of memory, for loads and stores
+    const OFFSETS: [i64; 5] = [0, 8, -16, 2040, -2048];
+    const BRANCH_OFFSETS: [i64; 3] = [8, -4, 0];
+
+    /// Every compilable instruction that doesn't jump, with x0, distinct and repeated
+    /// registers.
+    fn instructions() -> Vec<Instructions> {
+        use Instructions::*;
+        let mut insts = vec!();
+        for &(rd, rs1, rs2) in &[(5, 6, 7), (0, 6, 7), (5, 0, 7), (5, 6, 0), (6, 6, 6), (5, 0, 0)] {
+            insts.extend_from_slice(&[
+                Add { rd, rs1, rs2 }, Addw { rd, rs1, rs2 }, Sub { rd, rs1, rs2 }, Subw { rd, rs1, rs2 },
+                And { rd, rs1, rs2 }, Or { rd, rs1, rs2 }, Xor { rd, rs1, rs2 },
+                Sll { rd, rs1, rs2 }, Srl { rd, rs1, rs2 }, Sra { rd, rs1, rs2 },
+                Sllw { rd, rs1, rs2 }, Srlw { rd, rs1, rs2 }, Sraw { rd, rs1, rs2 },
+                Slt { rd, rs1, rs2 }, Sltu { rd, rs1, rs2 },
+            ]);
+            for &imm in &IMMS {
+                insts.extend_from_slice(&[
+                    Addi { rd, rs1, imm }, Addiw { rd, rs1, imm }, Andi { rd, rs1, imm }, Ori { rd, rs1, imm },
+                    Xori { rd, rs1, imm }, Slti { rd, rs1, imm }, Sltiu { rd, rs1, imm },
+                    Lui { rd, imm: imm << 12 }, Auipc { rd, imm: imm << 12 },
+                ]);
+            }
+            for &shamt in &SHAMTS {
+                insts.extend_from_slice(&[Slli { rd, rs1, shamt }, Srli { rd, rs1, shamt }, Srai { rd, rs1, shamt }]);
+                if shamt < 32 {
+                    insts.extend_from_slice(&[Slliw { rd, rs1, shamt }, Srliw { rd, rs1, shamt }, Sraiw { rd, rs1, shamt }]);
+                }
+            }
+            for &imm in &OFFSETS {
+                let rs1 = 8;
+                insts.extend_from_slice(&[
+                    Lb { rd, rs1, imm }, Lh { rd, rs1, imm }, Lw { rd, rs1, imm }, Ld { rd, rs1, imm },
+                    Lbu { rd, rs1, imm }, Lhu { rd, rs1, imm }, Lwu { rd, rs1, imm },
+                    Sb { rs1, rs2, imm }, Sh { rs1, rs2, imm }, Sw { rs1, rs2, imm }, Sd { rs1, rs2, imm },
+                ]);
+            }
+        }
+        insts
+    }
+
+    /// Every branch and jump, which end a run.
+    fn jumps() -> Vec<Instructions> {
+        use Instructions::*;
+        let mut insts = vec!();
+        for &(rs1, rs2) in &[(6, 7), (7, 6), (6, 6), (0, 7), (6, 0)] {
+            for &imm in &BRANCH_OFFSETS {
+                insts.extend_from_slice(&[
+                    Beq { rs1, rs2, imm }, Bne { rs1, rs2, imm }, Blt { rs1, rs2, imm },
+                    Bge { rs1, rs2, imm }, Bltu { rs1, rs2, imm }, Bgeu { rs1, rs2, imm },
+                ]);
+            }
+        }
+        for &rd in &[0, 1, 6] {
+            for &imm in &BRANCH_OFFSETS {
+                insts.push(Jal { rd, imm });
+                insts.push(Jalr { rd, rs1: 6, imm: imm + 1 });
+            }
+        }
+        insts
+    }
+
+    /// A CPU with x6 and x7 set to `a` and `b`, x8 pointing into memory, x9 at
+    /// nothing and the pc in memory. Memory holds a pattern.
+    fn cpu(a: u64, b: u64) -> CPU {
+        let mem = [0x81, 0x7F, 0xFF, 0x00, 0x12, 0xF4, 0x56, 0x9A, 0x33, 0xC8].repeat(0x334);
+        let mut cpu = CPU::with_memory(BASE as usize, 0x2000, vec!());
+        cpu.load_mem(BASE as usize, &mem[..0x2000]).unwrap();
+        for (reg, val) in cpu.regs.iter_mut().enumerate().skip(1) {
+            *val = match reg {
+                6 => a,
+                7 => b,
+                8 => BASE + 0x1000,
+                9 => 0x10,
+                r => r as u64,
+            };
+        }
+        cpu.pc = BASE + 0x100;
+        cpu
+    }
+
+    fn memory(cpu: &CPU) -> Vec<u8> {
+        cpu.read_bytes(BASE as usize, 0x2000).unwrap()
+    }
+
+    /// Runs `insts`, compiled to `native`, and through `execute`, which must leave the
+    /// same registers, memory and pc.
+    fn compare(native: &Native, insts: &[Instructions], a: u64, b: u64) {
+        let mut interpreted = cpu(a, b);
+        let mut log = vec!();
+        for inst in insts {
+            interpreted.execute(*inst).unwrap();
+            log.push(interpreted.regs[dest(inst)]);
+        }
+        let mut compiled = cpu(a, b);
+        let mut native_log = [0; 65];
+        assert_eq!(native.run(&mut compiled, &mut native_log), insts.len());
+        let what = format!("{:?} with 0x{:X}, 0x{:X}", insts, a, b);
+        assert_eq!(compiled.regs, interpreted.regs, "{}", what);
+        if insts.iter().any(|inst| dest(inst) == 0) {
+            assert!(memory(&compiled) == memory(&interpreted), "{}", what);
+        }
+        for (i, inst) in insts.iter().enumerate() {
+            if dest(inst) != 0 {
+                assert_eq!(native_log[i], log[i], "log of {:?} in {}", inst, what);
+            }
+        }
+        let next = if super::jumps(insts.last().unwrap()) { native_log[insts.len()] } else { interpreted.pc };
+        assert_eq!(next, interpreted.pc, "{}", what);
+    }
+
+    #[test]
+    fn single_instructions() {
+        for inst in instructions().into_iter().chain(jumps()) {
+            let native = Native::compile(&[inst]).unwrap();
+            for &a in &VALUES {
+                for &b in &VALUES {
+                    compare(&native, &[inst], a, b);
+                }
+            }
+        }
+    }
+
+    /// Runs of instructions that feed each other, picked by a fixed pseudo-random
+    /// sequence, half of them ending with a jump.
+    #[test]
+    fn runs() {
+        let insts = instructions();
+        let jumps = jumps();
+        let mut seed: u64 = 1;
+        let mut next = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as usize
+        };
+        for _ in 0..1000 {
+            let len = 1 + next() % 12;
+            let mut run: Vec<Instructions> = (0..len).map(|_| insts[next() % insts.len()]).collect();
+            if next() % 2 == 0 {
+                run.push(jumps[next() % jumps.len()]);
+            }
+            let native = Native::compile(&run).unwrap();
+            compare(&native, &run, VALUES[next() % VALUES.len()], VALUES[next() % VALUES.len()]);
+        }
+    }
+
+    /// A load or store that would fault stops the run before it, with the instructions
+    /// before it done and nothing after it.
+    #[test]
+    fn fault_restart() {
+        use Instructions::*;
+        for faulting in &[Ld { rd: 5, rs1: 9, imm: 0 }, Sd { rs1: 9, rs2: 6, imm: 8 }] {
+            let insts = [Addi { rd: 6, rs1: 6, imm: 1 }, Sd { rs1: 8, rs2: 6, imm: 0 }, *faulting, Addi { rd: 7, rs1: 7, imm: 1 }];
+            let native = Native::compile(&insts).unwrap();
+            let mut expected = cpu(1, 2);
+            expected.execute(insts[0]).unwrap();
+            expected.execute(insts[1]).unwrap();
+            let mut compiled = cpu(1, 2);
+            let mut log = [0; 65];
+            assert_eq!(native.run(&mut compiled, &mut log), 2);
+            assert_eq!(compiled.regs, expected.regs);
+            assert!(memory(&compiled) == memory(&expected));
+        }
+    }
+}
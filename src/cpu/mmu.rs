@@ -0,0 +1,128 @@
+// Sv39 address translation. The page tables are walked on every access, nothing is
+// cached, so there is no TLB to go stale and sfence.vma has nothing to flush but
+// decoded code. The walk sets the accessed and dirty bits itself, storing the page
+// table entry like the program would, so reverse stepping undoes it.
+
+use super::{CPU, CSR_MSTATUS, MSTATUS_MPP, MSTATUS_MPP_SHIFT, PRV_M, PRV_U};
+use super::{CAUSE_FETCH_FAULT, CAUSE_LOAD_FAULT, CAUSE_STORE_FAULT};
+use super::{CAUSE_FETCH_PAGE_FAULT, CAUSE_LOAD_PAGE_FAULT, CAUSE_STORE_PAGE_FAULT};
+
+pub const CSR_SATP: usize = 0x180;
+const SATP_MODE_SHIFT: u64 = 60;
+const SATP_MODE_BARE: u64 = 0;
+const SATP_MODE_SV39: u64 = 8;
+const PPN_MASK: u64 = (1 << 44) - 1;
+
+const MSTATUS_MPRV: u64 = 1 << 17;
+const MSTATUS_SUM: u64 = 1 << 18;
+const MSTATUS_MXR: u64 = 1 << 19;
+
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+const PAGE_SHIFT: u64 = 12;
+const LEVELS: u64 = 3;
+/// Each level indexes its table with 9 bits of the virtual page number.
+const LEVEL_BITS: u64 = 9;
+
+/// What a translated address is used for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl Access {
+    fn page_fault(self) -> u64 {
+        match self {
+            Access::Fetch => CAUSE_FETCH_PAGE_FAULT,
+            Access::Load => CAUSE_LOAD_PAGE_FAULT,
+            Access::Store => CAUSE_STORE_PAGE_FAULT,
+        }
+    }
+
+    fn access_fault(self) -> u64 {
+        match self {
+            Access::Fetch => CAUSE_FETCH_FAULT,
+            Access::Load => CAUSE_LOAD_FAULT,
+            Access::Store => CAUSE_STORE_FAULT,
+        }
+    }
+}
+
+/// Whether `satp` may hold `val`. Writes with a mode that isn't implemented are ignored.
+pub fn satp_valid(val: u64) -> bool {
+    matches!(val >> SATP_MODE_SHIFT, SATP_MODE_BARE | SATP_MODE_SV39)
+}
+
+impl CPU {
+    /// Whether instructions are fetched through the page tables.
+    pub(super) fn paging(&self) -> bool {
+        self.privilege != PRV_M && self.csrs[CSR_SATP] >> SATP_MODE_SHIFT == SATP_MODE_SV39
+    }
+
+    /// Translates `vaddr` for `access` at the current privilege level. Returns the
+    /// physical address, or the cause of the exception to raise.
+    pub(super) fn translate_addr(&mut self, vaddr: u64, access: Access) -> Result<u64, u64> {
+        let status = self.csrs[CSR_MSTATUS];
+        // With MPRV set, M-mode loads and stores use the privilege level in MPP
+        let privilege = match access {
+            Access::Load | Access::Store if self.privilege == PRV_M && status & MSTATUS_MPRV != 0 => {
+                (status & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT
+            }
+            _ => self.privilege,
+        };
+        let satp = self.csrs[CSR_SATP];
+        if privilege == PRV_M || satp >> SATP_MODE_SHIFT != SATP_MODE_SV39 {
+            return Ok(vaddr);
+        }
+        // The upper bits must all be copies of bit 38
+        if ((vaddr as i64) << 25 >> 25) as u64 != vaddr {
+            return Err(access.page_fault());
+        }
+        let mut table = (satp & PPN_MASK) << PAGE_SHIFT;
+        for level in (0..LEVELS).rev() {
+            let shift = PAGE_SHIFT + LEVEL_BITS * level;
+            let pte_addr = table + ((vaddr >> shift) & ((1 << LEVEL_BITS) - 1)) * 8;
+            let pte = self.bus.read(pte_addr as usize, 64).map_err(|_| access.access_fault())?;
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault());
+            }
+            let ppn = (pte >> 10) & PPN_MASK;
+            if pte & (PTE_R | PTE_X) == 0 {
+                // Points to the table of the next level
+                table = ppn << PAGE_SHIFT;
+                continue;
+            }
+            let permitted = match access {
+                Access::Fetch => pte & PTE_X != 0,
+                Access::Load => pte & PTE_R != 0 || (status & MSTATUS_MXR != 0 && pte & PTE_X != 0),
+                Access::Store => pte & PTE_W != 0,
+            };
+            // S-mode only reaches user pages with SUM set, and never runs code from them
+            let user = pte & PTE_U != 0;
+            let reachable = if privilege == PRV_U {
+                user
+            } else {
+                !user || (status & MSTATUS_SUM != 0 && access != Access::Fetch)
+            };
+            // A superpage must be aligned to its size
+            let aligned = ppn & ((1 << (LEVEL_BITS * level)) - 1) == 0;
+            if !permitted || !reachable || !aligned {
+                return Err(access.page_fault());
+            }
+            let updated = pte | PTE_A | if access == Access::Store { PTE_D } else { 0 };
+            if updated != pte {
+                self.store(pte_addr as usize, 64, updated).map_err(|_| access.access_fault())?;
+            }
+            return Ok(ppn << PAGE_SHIFT | vaddr & ((1 << shift) - 1));
+        }
+        Err(access.page_fault())
+    }
+}
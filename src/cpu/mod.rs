@@ -1,21 +1,146 @@
 #![allow(dead_code)]
 
 use std::fmt::{Display, Error, Formatter};
-use decode::Instructions;
+use std::str::FromStr;
+use std::cell::Cell;
+use std::rc::Rc;
+use decode::{AmoOp, Instructions};
 
 use crate::{dram, bus};
 use crate::bus::{Device, DRAM_BASE};
+use crate::elf::{Elf, SymbolTable};
+use crate::events;
+use crate::fdt::FdtWriter;
+use crate::snapshot::{put_option, SnapshotReader, SnapshotWriter};
+use crate::linux::LinuxUser;
+use crate::htif::Htif;
+use crate::sbi::{self, Sbi};
+use crate::semihosting::{Semihosting, SEMIHOST_ENTRY, SEMIHOST_EXIT};
+use block::{Block, BlockCache};
+use bpred::{Predictor, PredictorConfig};
+use cache::{Hierarchy, HierarchyConfig};
+use icache::InstCache;
+use mmu::Access;
+use pipeline::{Pipeline, PipelineConfig, PipelineStats};
+use record::{Change, Outside, Recording, StepRecord};
 
+mod block;
+pub mod bpred;
+pub mod cache;
 mod decode;
+mod icache;
+mod jit;
+mod mmu;
+pub mod pipeline;
+mod record;
 
 const MiB: usize = 1024*1024;
 
+const MISA: usize = 0x301;
+/// MXL = 64 bit with the base integer ISA.
+/// RV64IMA with supervisor and user mode
+const MISA_RV64IMA: u64 = 2 << 62 | 1 << 0 | 1 << 8 | 1 << 12 | 1 << 18 | 1 << 20;
+const CSR_COUNT: usize = 4096;
+/// How many bytes `fill_mem` writes at once
+const FILL_CHUNK: usize = 64 * 1024;
+const CSR_SSTATUS: usize = 0x100;
+const CSR_SIE: usize = 0x104;
+const CSR_STVEC: usize = 0x105;
+const CSR_SEPC: usize = 0x141;
+const CSR_SCAUSE: usize = 0x142;
+const CSR_STVAL: usize = 0x143;
+const CSR_SIP: usize = 0x144;
+const CSR_MSTATUS: usize = 0x300;
+const CSR_MEDELEG: usize = 0x302;
+const CSR_MIDELEG: usize = 0x303;
+const CSR_MIE: usize = 0x304;
+const CSR_MTVEC: usize = 0x305;
+const CSR_MEPC: usize = 0x341;
+const CSR_MCAUSE: usize = 0x342;
+const CSR_MTVAL: usize = 0x343;
+const CSR_MIP: usize = 0x344;
+const CSR_MCYCLE: usize = 0xB00;
+const CSR_MINSTRET: usize = 0xB02;
+const CSR_CYCLE: usize = 0xC00;
+const CSR_INSTRET: usize = 0xC02;
+
+const MSTATUS_SIE: u64 = 1 << 1;
+const MSTATUS_MIE: u64 = 1 << 3;
+const MSTATUS_SPIE: u64 = 1 << 5;
+const MSTATUS_MPIE: u64 = 1 << 7;
+const MSTATUS_SPP: u64 = 1 << 8;
+const MSTATUS_MPP_SHIFT: u64 = 11;
+const MSTATUS_MPP: u64 = 3 << MSTATUS_MPP_SHIFT;
+/// The mstatus bits sstatus shows: SIE, SPIE, SPP, FS, SUM, MXR and UXL.
+const SSTATUS_MASK: u64 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | 3 << 13 | 1 << 18 | 1 << 19 | 3 << 32;
+
+const MIP_SSIP: u64 = 1 << 1;
+const MIP_STIP: u64 = 1 << 5;
+const MIP_SEIP: u64 = 1 << 9;
+/// The mip bits driven by devices: machine software, timer and external, and supervisor external.
+const MIP_DEVICE: u64 = 1 << 3 | 1 << 7 | 1 << 9 | 1 << 11;
+/// Interrupt causes from highest to lowest priority: external, software, timer,
+/// machine level before supervisor level.
+const INTERRUPT_ORDER: [u64; 6] = [11, 3, 7, 9, 1, 5];
+const CAUSE_INTERRUPT: u64 = 1 << 63;
+
+pub const CAUSE_FETCH_FAULT: u64 = 1;
+pub const CAUSE_ILLEGAL_INSTRUCTION: u64 = 2;
+pub const CAUSE_BREAKPOINT: u64 = 3;
+pub const CAUSE_LOAD_MISALIGNED: u64 = 4;
+pub const CAUSE_LOAD_FAULT: u64 = 5;
+pub const CAUSE_STORE_MISALIGNED: u64 = 6;
+pub const CAUSE_STORE_FAULT: u64 = 7;
+/// Ecall from U-mode, the S and M-mode causes follow at their privilege level's offset.
+pub const CAUSE_ECALL: u64 = 8;
+pub const CAUSE_FETCH_PAGE_FAULT: u64 = 12;
+pub const CAUSE_LOAD_PAGE_FAULT: u64 = 13;
+pub const CAUSE_STORE_PAGE_FAULT: u64 = 15;
+
+/// Exceptions the built-in SBI leaves to S-mode: everything but ecalls from S and M-mode.
+const SBI_MEDELEG: u64 = 0x1FF | 1 << 12 | 1 << 13 | 1 << 15;
+
+pub const PRV_U: u64 = 0;
+pub const PRV_S: u64 = 1;
+pub const PRV_M: u64 = 3;
+
+/// The host layers as saved in a snapshot: Linux, semihosting, HTIF and SBI.
+type HostLayers = (Option<LinuxUser>, Option<Semihosting>, Option<Htif>, Option<Sbi>);
+
 #[derive(Debug)]
 pub struct CPU {
     regs: [u64; 32],
     pc: u64,
+    csrs: [u64; CSR_COUNT],
     running: bool,
+    /// Privilege level: PRV_U, PRV_S or PRV_M
+    privilege: u64,
+    /// The address of the last load-reserved, until a store-conditional uses it up
+    reservation: Option<u64>,
+    /// The physical address the current instruction was fetched from
+    fetch_addr: u64,
     bus: bus::BUS,
+    symbols: SymbolTable,
+    recording: Option<Recording>,
+    pending: Vec<Change>,
+    /// Whether the recorded step accessed a device or took an interrupt, and whether
+    /// a host layer handled a request. Only then is the outside state saved.
+    touched_devices: bool,
+    touched_host: bool,
+    linux: Option<LinuxUser>,
+    semihosting: Option<Semihosting>,
+    htif: Option<Htif>,
+    sbi: Option<Sbi>,
+    /// Emulated time in cycles, one per instruction unless a pipeline model is set
+    cycles: u64,
+    instret: u64,
+    pipeline: Option<Pipeline>,
+    caches: Option<Hierarchy>,
+    predictor: Option<Predictor>,
+    icache: InstCache,
+    blocks: BlockCache,
+    /// Print every instruction and register write
+    trace: bool,
 }
 
 impl Display for CPU {
@@ -31,6 +156,30 @@ impl Display for CPU {
     }
 }
 
+/// Display unit for `CPU::examine`.
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq)]
+pub enum MemFormat {
+    Byte,
+    Half,
+    Word,
+    Double,
+    Str,
+    Inst,
+}
+
+impl MemFormat {
+    /// Size of one unit in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            MemFormat::Byte => 1,
+            MemFormat::Half => 2,
+            MemFormat::Word | MemFormat::Inst => 4,
+            MemFormat::Double => 8,
+            MemFormat::Str => 1,
+        }
+    }
+}
+
 pub const REG_NAMES: [&str; 32] = [
     "zero", "ra", "sp", "gp",
     "tp", "t0", "t1", "t2",
@@ -42,17 +191,374 @@ pub const REG_NAMES: [&str; 32] = [
     "t3", "t4", "t5", "t6"
 ];
 
+/// Looks up an integer register by ABI name (`a0`, `fp`) or by number (`x10`).
+pub fn reg_from_name(name: &str) -> Option<usize> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(pos) = REG_NAMES.iter().position(|r| *r == name) {
+        return Some(pos);
+    }
+    match name.strip_prefix('x').map(|n| n.parse::<usize>()) {
+        Some(Ok(n)) if n < REG_NAMES.len() => Some(n),
+        _ => None,
+    }
+}
+
+/// Looks up a CSR by name (`mstatus`) and returns its number.
+pub fn csr_from_name(name: &str) -> Option<usize> {
+    decode::CsrNames::from_str(name).ok().map(|c| c as usize)
+}
+
 impl CPU {
     pub fn new(buffer: Vec<u8>) -> CPU {
         let mem_size = 128*MiB;
         //let mem_size = 1536;
+        CPU::with_memory(DRAM_BASE, mem_size, buffer)
+    }
+
+    /// Creates a CPU with `mem_size` bytes of DRAM mapped at `dram_base`, starting there.
+    pub fn with_memory(dram_base: usize, mem_size: usize, buffer: Vec<u8>) -> CPU {
+        CPU::with_bus(dram_base, mem_size, bus::BUS::new(dram_base, mem_size, buffer))
+    }
+
+    /// Like `with_memory`, but with `dram` backing main memory instead of a plain DRAM.
+    pub fn with_dram(dram_base: usize, mem_size: usize, dram: Box<dyn Device>) -> CPU {
+        CPU::with_bus(dram_base, mem_size, bus::BUS::with_dram(dram_base, mem_size, dram))
+    }
+
+    fn with_bus(dram_base: usize, mem_size: usize, bus: bus::BUS) -> CPU {
         let mut regs = [0 as u64; 32];
-        regs[2] = (mem_size+DRAM_BASE) as u64;
+        regs[2] = (mem_size+dram_base) as u64;
+        let mut csrs = [0; CSR_COUNT];
+        csrs[MISA] = MISA_RV64IMA;
         Self {
             regs,
-            pc: DRAM_BASE as u64,
+            pc: dram_base as u64,
+            csrs,
             running: true,
-            bus: bus::BUS::new(mem_size, buffer),
+            privilege: PRV_M,
+            reservation: None,
+            fetch_addr: 0,
+            bus,
+            symbols: SymbolTable::default(),
+            recording: None,
+            pending: vec!(),
+            touched_devices: false,
+            touched_host: false,
+            linux: None,
+            semihosting: None,
+            htif: None,
+            sbi: None,
+            cycles: 0,
+            instret: 0,
+            pipeline: None,
+            caches: None,
+            predictor: None,
+            icache: InstCache::default(),
+            blocks: BlockCache::default(),
+            trace: false,
+        }
+    }
+
+    /// Copies the loadable segments of `elf` into memory and jumps to its entry point.
+    pub fn load_elf(&mut self, elf: &Elf) -> Result<(), String> {
+        for seg in &elf.segments {
+            // Memory starts zeroed, so only the file-backed part needs copying
+            if self.load_mem(seg.paddr as usize, elf.segment_data(seg)).is_err() {
+                return Err(format!("Segment at 0x{:X} does not fit in memory", seg.paddr));
+            }
+        }
+        self.pc = elf.entry;
+        Ok(())
+    }
+
+    /// Serializes the whole machine: registers, pc, CSRs and every device on the bus.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = SnapshotWriter::new();
+        w.section(b"CPU ", |out| {
+            for r in self.regs.iter() {
+                out.extend_from_slice(&r.to_le_bytes());
+            }
+            out.extend_from_slice(&self.pc.to_le_bytes());
+            out.push(self.running as u8);
+            for c in self.csrs.iter() {
+                out.extend_from_slice(&c.to_le_bytes());
+            }
+            out.extend_from_slice(&self.cycles.to_le_bytes());
+            out.extend_from_slice(&self.instret.to_le_bytes());
+            out.push(self.privilege as u8);
+            put_option(out, self.reservation);
+        });
+        self.bus.save(&mut w);
+        self.save_host(&mut w);
+        w.finish()
+    }
+
+    /// Saves the host layers, each only if present.
+    fn save_host(&self, w: &mut SnapshotWriter) {
+        if let Some(linux) = &self.linux {
+            w.section(b"LNUX", |out| linux.save(out));
+        }
+        if let Some(semihosting) = &self.semihosting {
+            w.section(b"SEMI", |out| semihosting.save(out));
+        }
+        if let Some(htif) = &self.htif {
+            w.section(b"HTIF", |out| htif.save(out));
+        }
+        if let Some(sbi) = &self.sbi {
+            w.section(b"SBI ", |out| sbi.save(out));
+        }
+    }
+
+    /// Reads the host layers saved by `save_host`, without installing them.
+    fn read_host(r: &mut SnapshotReader) -> Result<HostLayers, String> {
+        Ok((
+            r.optional_section(b"LNUX")?.map(|mut r| LinuxUser::restore(&mut r)).transpose()?,
+            r.optional_section(b"SEMI")?.map(|mut r| Semihosting::restore(&mut r)).transpose()?,
+            r.optional_section(b"HTIF")?.map(|mut r| Htif::restore(&mut r)).transpose()?,
+            r.optional_section(b"SBI ")?.map(|mut r| Sbi::restore(&mut r)).transpose()?,
+        ))
+    }
+
+    /// Replaces the host layers with the ones saved by `save_host`.
+    fn restore_host(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let (linux, semihosting, htif, sbi) = CPU::read_host(r)?;
+        self.linux = linux;
+        self.semihosting = semihosting;
+        self.htif = htif;
+        self.sbi = sbi;
+        Ok(())
+    }
+
+    /// Restores a machine saved with `snapshot`, including the host layers it had.
+    /// Any recorded history is dropped. On error the machine is left as it was.
+    pub fn restore(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = SnapshotReader::new(data)?;
+        let mut cpu = r.section(b"CPU ")?;
+        let mut regs = [0; 32];
+        for reg in regs.iter_mut() {
+            *reg = cpu.u64()?;
+        }
+        let pc = cpu.u64()?;
+        let running = cpu.u8()? != 0;
+        let mut csrs = [0; CSR_COUNT];
+        for csr in csrs.iter_mut() {
+            *csr = cpu.u64()?;
+        }
+        let cycles = cpu.u64()?;
+        let instret = cpu.u64()?;
+        let privilege = match cpu.u8()? as u64 {
+            p @ (PRV_U | PRV_S | PRV_M) => p,
+            p => return Err(format!("Snapshot has invalid privilege level {}", p)),
+        };
+        let reservation = cpu.option()?;
+
+        // Devices restore in place, so keep their current state to put back on error
+        let mut backup = SnapshotWriter::new();
+        self.bus.save(&mut backup);
+        let backup = backup.finish();
+        let host = self.bus.restore(&mut r, cycles).and_then(|_| {
+            let host = CPU::read_host(&mut r)?;
+            if r.remaining() > 0 {
+                return Err("Snapshot has sections this machine doesn't".to_string());
+            }
+            Ok(host)
+        });
+        let (linux, semihosting, htif, sbi) = match host {
+            Ok(host) => host,
+            Err(e) => {
+                let mut r = SnapshotReader::new(&backup)?;
+                self.bus.restore(&mut r, self.cycles)?;
+                return Err(e);
+            }
+        };
+
+        self.regs = regs;
+        self.pc = pc;
+        self.running = running;
+        self.csrs = csrs;
+        self.cycles = cycles;
+        self.instret = instret;
+        self.privilege = privilege;
+        self.reservation = reservation;
+        self.linux = linux;
+        self.semihosting = semihosting;
+        self.htif = htif;
+        self.sbi = sbi;
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            pipeline.reset();
+        }
+        if let Some(caches) = self.caches.as_mut() {
+            caches.clear();
+        }
+        if let Some(predictor) = self.predictor.as_mut() {
+            predictor.reset();
+        }
+        self.flush_code();
+        if self.recording.is_some() {
+            self.recording = Some(Recording::default());
+        }
+        Ok(())
+    }
+
+    pub fn save_snapshot(&self, path: &str) -> Result<(), String> {
+        std::fs::write(path, self.snapshot()).map_err(|e| format!("Error writing {}: {}", path, e))
+    }
+
+    pub fn load_snapshot(&mut self, path: &str) -> Result<(), String> {
+        let data = std::fs::read(path).map_err(|e| format!("Error opening {}: {}", path, e))?;
+        self.restore(&data)
+    }
+
+    /// Services `ecall` as Linux system calls from now on.
+    pub fn set_linux(&mut self, linux: LinuxUser) {
+        self.linux = Some(linux);
+    }
+
+    /// Services semihosting requests made with `ebreak` from now on.
+    pub fn set_semihosting(&mut self, semihosting: Semihosting) {
+        self.semihosting = Some(semihosting);
+    }
+
+    /// Watches `tohost` for HTIF commands after every instruction from now on.
+    pub fn set_htif(&mut self, htif: Htif) {
+        self.htif = Some(htif);
+    }
+
+    /// The ISA string for the extensions enabled in misa, e.g. "rv64ima_zicsr". Bits
+    /// of extensions that aren't implemented are left out.
+    pub fn isa_string(&self) -> String {
+        let misa = self.csrs[MISA] & MISA_RV64IMA;
+        let mut isa = format!("rv{}", 16 << (misa >> 62));
+        // Single letter extensions go in canonical order
+        for ext in "iemafdqlcbkjtpvh".chars() {
+            if misa & 1 << (ext as u8 - b'a') != 0 {
+                isa.push(ext);
+            }
+        }
+        isa.push_str("_zicsr");
+        isa
+    }
+
+    /// Adds a node for every device on the bus to the device tree.
+    pub fn dt_nodes(&self, fdt: &mut FdtWriter) {
+        self.bus.dt_nodes(fdt);
+    }
+
+    /// Start and size of DRAM.
+    pub fn dram_range(&self) -> (usize, usize) {
+        self.bus.dram_range()
+    }
+
+    /// Base, size and description of main memory and every device on the bus.
+    pub fn device_info(&self) -> Vec<(usize, usize, String)> {
+        self.bus.device_info()
+    }
+
+    /// Hits, misses and invalidated pages of the decoded-instruction cache, and the pages it holds.
+    pub fn icache_stats(&self) -> (u64, u64, u64, usize) {
+        (self.icache.hits, self.icache.misses, self.icache.invalidations, self.icache.pages())
+    }
+
+    /// Prints every executed instruction and the registers it writes.
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    /// Compiles hot code to native instructions where the host supports it.
+    pub fn set_jit(&mut self, jit: bool) {
+        self.blocks.jit = jit;
+        self.blocks.flush();
+    }
+
+    /// Times instructions with an in-order pipeline model instead of one cycle each.
+    pub fn set_pipeline(&mut self, config: Option<PipelineConfig>) {
+        self.pipeline = config.map(Pipeline::new);
+    }
+
+    /// Models instruction and data caches on the way to memory.
+    pub fn set_caches(&mut self, config: Option<HierarchyConfig>) {
+        self.caches = config.map(Hierarchy::new);
+    }
+
+    pub fn caches(&self) -> Option<&Hierarchy> {
+        self.caches.as_ref()
+    }
+
+    /// Checks every branch and jump against a branch predictor model.
+    pub fn set_predictor(&mut self, config: Option<PredictorConfig>) {
+        self.predictor = config.map(Predictor::new);
+    }
+
+    pub fn predictor(&self) -> Option<&Predictor> {
+        self.predictor.as_ref()
+    }
+
+    pub fn pipeline_stats(&self) -> Option<PipelineStats> {
+        self.pipeline.as_ref().map(|p| p.stats.clone())
+    }
+
+    /// Blocks translated, run and reached through a chain link, instructions run as
+    /// native code, and the blocks cached.
+    pub fn block_stats(&self) -> (u64, u64, u64, u64, usize) {
+        (self.blocks.translated, self.blocks.executed, self.blocks.chained, self.blocks.native, self.blocks.len())
+    }
+
+    /// Puts every device back in its power-on state.
+    pub fn reset_devices(&mut self) {
+        self.bus.reset();
+    }
+
+    /// Base, size and resident pages of the memory regions that allocate on demand.
+    pub fn memory_stats(&self) -> Vec<(usize, usize, usize)> {
+        self.bus.memory_stats()
+    }
+
+    /// Maps a memory mapped device at `base` on the bus.
+    pub fn map_device(&mut self, base: usize, size: usize, device: Box<dyn Device>) {
+        self.bus.map(base, size, device);
+    }
+
+    /// Where devices that power the machine off store the exit status.
+    pub fn halt_handle(&self) -> Rc<Cell<Option<i32>>> {
+        self.bus.halt_handle()
+    }
+
+    /// Services `ecall` from S-mode as SBI calls from now on. The SBI stands in for
+    /// M-mode firmware, so the hart drops to S-mode with the supervisor interrupts and
+    /// the exceptions S-mode handles itself delegated to it.
+    pub fn set_sbi(&mut self, sbi: Sbi) {
+        self.sbi = Some(sbi);
+        self.csrs[CSR_MIDELEG] = MIP_SSIP | MIP_STIP | MIP_SEIP;
+        self.csrs[CSR_MEDELEG] = SBI_MEDELEG;
+        self.privilege = PRV_S;
+    }
+
+    /// The status the program passed to exit, if it has exited.
+    pub fn exit_code(&self) -> Option<i32> {
+        self.linux.as_ref().and_then(|l| l.exit_code())
+            .or_else(|| self.semihosting.as_ref().and_then(|s| s.exit_code()))
+            .or_else(|| self.htif.as_ref().and_then(|h| h.exit_code()))
+            .or_else(|| self.sbi.as_ref().and_then(|s| s.exit_code()))
+            .or_else(|| self.bus.halted())
+    }
+
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Formats `addr` followed by its `<symbol+offset>`, if any.
+    pub fn describe(&self, addr: u64) -> String {
+        let sym = self.symbols.describe(addr);
+        if sym.is_empty() {
+            format!("0x{:X}", addr)
+        } else {
+            format!("0x{:X} {}", addr, sym)
         }
     }
 
@@ -76,25 +582,432 @@ impl CPU {
     }
 
     pub fn print_mem_reg(&self, addr: usize, len: usize) {
-        for i in addr..(addr + len) {
-            print!("{:02X} ", self.bus.read(i, 8).unwrap());
+        for i in addr..addr.saturating_add(len) {
+            match self.bus.read(i, 8) {
+                Ok(b) => print!("{:02X} ", b),
+                Err(_) => print!("?? "),
+            }
         }
         println!();
     }
 
-    fn fetch(&self) -> Result<u64, ()> {
-        self.bus.read(self.pc as usize, 32)
+    /// Prints `count` units of memory starting at `addr`, gdb `x` style.
+    pub fn examine(&self, addr: usize, count: usize, fmt: MemFormat) {
+        match fmt {
+            MemFormat::Str => {
+                let mut addr = addr;
+                for _ in 0..count {
+                    let start = addr;
+                    let mut s = String::new();
+                    loop {
+                        match self.bus.read(addr, 8) {
+                            Ok(0) => break,
+                            Ok(b) => s.push(b as u8 as char),
+                            Err(_) => {
+                                println!("0x{:X}: <unmapped>", addr);
+                                return;
+                            }
+                        }
+                        addr = addr.wrapping_add(1);
+                    }
+                    addr = addr.wrapping_add(1);
+                    println!("{}: {:?}", self.describe(start as u64), s);
+                }
+            }
+            MemFormat::Inst => {
+                self.disassemble(addr, count);
+            }
+            _ => {
+                let size = fmt.size();
+                let per_line = 16 / size;
+                let mut addr = addr;
+                for i in 0..count {
+                    if i % per_line == 0 {
+                        if i != 0 {
+                            println!();
+                        }
+                        print!("{}:", self.describe(addr as u64));
+                    }
+                    match self.bus.read(addr, size * 8) {
+                        Ok(v) => print!(" {:0width$X}", v, width = size * 2),
+                        Err(_) => {
+                            println!(" <unmapped>");
+                            return;
+                        }
+                    }
+                    addr = addr.wrapping_add(size);
+                }
+                println!();
+            }
+        }
+    }
+
+    /// Prints `count` decoded instructions starting at `addr`, marking the one at pc.
+    pub fn disassemble(&self, addr: usize, count: usize) {
+        for i in 0..count {
+            let a = addr.wrapping_add(i * 4);
+            let marker = if a as u64 == self.pc { "=>" } else { "  " };
+            match self.bus.read(a, 32) {
+                Ok(raw) => println!("{} {}: {:08X}  {:?}", marker, self.describe(a as u64), raw, Instructions::from(raw as u32)),
+                Err(_) => println!("{} {}: <unmapped>", marker, self.describe(a as u64)),
+            }
+        }
+    }
+
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    /// Where a call at pc returns to, if the instruction there is a jal or jalr
+    /// linking through ra.
+    pub fn call_return(&self) -> Option<u64> {
+        match Instructions::from(self.bus.read(self.pc as usize, 32).ok()? as u32) {
+            Instructions::Jal { rd: 1, .. } | Instructions::Jalr { rd: 1, .. } => Some(self.pc.wrapping_add(4)),
+            _ => None,
+        }
+    }
+
+    pub fn set_reg(&mut self, reg: usize, val: u64) {
+        if reg == 0 || reg >= self.regs.len() {
+            return;
+        }
+        self.discard_future();
+        self.regs[reg] = val;
+    }
+
+    pub fn set_pc(&mut self, pc: u64) {
+        self.discard_future();
+        self.pc = pc;
+    }
+
+    pub fn set_csr(&mut self, csr: usize, val: u64) -> Result<(), ()> {
+        if csr >= self.csrs.len() {
+            return Err(());
+        }
+        self.discard_future();
+        self.csrs[csr] = self.counter_base(csr, val);
+        Ok(())
+    }
+
+    pub fn read_mem(&self, addr: usize, size: usize) -> Result<u64, ()> {
+        self.bus.read(addr, size)
+    }
+
+    /// Reads `len` bytes, which must all lie in one memory region or device, so a
+    /// length from the program can't make the host allocate more than is mapped.
+    pub fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, ()> {
+        if len > self.mapped_len(addr) {
+            return Err(());
+        }
+        let mut buf = vec![0; len];
+        self.bus.read_bytes(addr, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// The bytes from `addr` to the end of the memory region or device it is in, 0 if unmapped.
+    pub fn mapped_len(&self, addr: usize) -> usize {
+        self.bus.mapped_len(addr)
+    }
+
+    /// Writes `data` to memory on behalf of the running program, so the write is recorded.
+    pub fn store_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), ()> {
+        if self.recording.is_none() {
+            self.code_written(addr, data.len());
+            return self.bus.write_bytes(addr, data);
+        }
+        for (i, b) in data.iter().enumerate() {
+            self.store(addr.wrapping_add(i), 8, *b as u64)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_mem(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
+        self.discard_future();
+        self.code_written(addr, size / 8);
+        self.bus.write(addr, size, val)
+    }
+
+    /// Fills `len` bytes at `addr` with `val`, a chunk at a time so a bad length fails
+    /// without allocating all of it first.
+    pub fn fill_mem(&mut self, addr: usize, len: usize, val: u8) -> Result<(), ()> {
+        self.discard_future();
+        self.code_written(addr, len);
+        let chunk = vec![val; len.min(FILL_CHUNK)];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(FILL_CHUNK);
+            self.bus.write_bytes(addr.checked_add(done).ok_or(())?, &chunk[..n])?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    pub fn load_mem(&mut self, addr: usize, data: &[u8]) -> Result<(), ()> {
+        self.discard_future();
+        self.code_written(addr, data.len());
+        self.bus.write_bytes(addr, data)
+    }
+
+    /// Drops decoded instructions and translated blocks for memory that was written.
+    fn code_written(&mut self, addr: usize, len: usize) {
+        self.icache.invalidate(addr, len);
+        self.blocks.invalidate(addr, len);
     }
 
-    fn write_reg(&mut self, reg: usize, val: u64) {
+    /// Drops all decoded instructions and translated blocks, as fence.i and sfence.vma do.
+    pub fn flush_code(&mut self) {
+        self.icache.flush();
+        self.blocks.flush();
+    }
+
+    /// Writes a register on behalf of the running program, recorded for reverse stepping.
+    pub fn write_reg(&mut self, reg: usize, val: u64) {
         if reg == 0 || reg > self.regs.len() {
             return;
         }
-        println!("\tWriting \"{}\" to {}", val, REG_NAMES[reg]);
+        if self.trace {
+            println!("\tWriting \"{}\" to {}", val, REG_NAMES[reg]);
+        }
+        if self.recording.is_some() {
+            self.pending.push(Change::Reg { reg, old: self.regs[reg], new: val });
+        }
         self.regs[reg] = val;
     }
 
-    fn read_reg(&self, reg: usize) -> u64 {
+    pub fn read_csr(&self, csr: usize) -> u64 {
+        let csr = csr & (CSR_COUNT - 1);
+        match csr {
+            // Counts along with the CLINT's mtime, so the SBI's timer sees it even while it runs
+            sbi::CSR_TIME => events::time_at(self.cycles),
+            CSR_MCYCLE | CSR_CYCLE => self.cycles.wrapping_add(self.csrs[CSR_MCYCLE]),
+            CSR_MINSTRET | CSR_INSTRET => self.instret.wrapping_add(self.csrs[CSR_MINSTRET]),
+            // The supervisor CSRs below are views of the machine ones
+            CSR_SSTATUS => self.csrs[CSR_MSTATUS] & SSTATUS_MASK,
+            CSR_SIE => self.csrs[CSR_MIE] & self.csrs[CSR_MIDELEG],
+            CSR_SIP => self.csrs[CSR_MIP] & self.csrs[CSR_MIDELEG],
+            _ => self.csrs[csr],
+        }
+    }
+
+    /// The counters count on their own, so writing one only stores where it restarts from.
+    fn counter_base(&self, csr: usize, val: u64) -> u64 {
+        match csr {
+            CSR_MCYCLE => val.wrapping_sub(self.cycles),
+            CSR_MINSTRET => val.wrapping_sub(self.instret),
+            _ => val,
+        }
+    }
+
+    /// Writes a CSR on behalf of the running program, recorded for reverse stepping.
+    pub fn write_csr(&mut self, csr: usize, val: u64) {
+        let csr = csr & (CSR_COUNT - 1);
+        let merge = |old: u64, mask: u64| old & !mask | val & mask;
+        let (csr, val) = match csr {
+            CSR_SSTATUS => (CSR_MSTATUS, merge(self.csrs[CSR_MSTATUS], SSTATUS_MASK)),
+            CSR_SIE => (CSR_MIE, merge(self.csrs[CSR_MIE], self.csrs[CSR_MIDELEG])),
+            // Only the software interrupt can be raised from S-mode
+            CSR_SIP => (CSR_MIP, merge(self.csrs[CSR_MIP], self.csrs[CSR_MIDELEG] & MIP_SSIP)),
+            // The extensions can't be switched on or off
+            MISA => return,
+            // Other translation modes aren't implemented, setting one does nothing
+            mmu::CSR_SATP if !mmu::satp_valid(val) => return,
+            // 2 isn't a privilege level, MPP keeps its old value instead
+            CSR_MSTATUS if (val & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT == 2 => {
+                (csr, merge(self.csrs[CSR_MSTATUS], !MSTATUS_MPP))
+            }
+            _ => (csr, self.counter_base(csr, val)),
+        };
+        if self.recording.is_some() {
+            self.pending.push(Change::Csr { csr, old: self.csrs[csr], new: val });
+        }
+        self.csrs[csr] = val;
+    }
+
+    /// Whether the current privilege level may access `csr`. Bits 9:8 of the number
+    /// hold the lowest level allowed, and the ones starting 0b11 are read-only.
+    fn csr_allowed(&self, csr: usize, write: bool) -> bool {
+        (csr >> 8 & 3) as u64 <= self.privilege && !(write && csr >> 10 == 3)
+    }
+
+    fn set_privilege(&mut self, privilege: u64) {
+        if self.recording.is_some() {
+            self.pending.push(Change::Priv { old: self.privilege, new: privilege });
+        }
+        self.privilege = privilege;
+    }
+
+    /// Enters the trap handler for `cause`, returning to `epc`. The trap goes to S-mode
+    /// if it's delegated and the hart isn't in M-mode. Returns false and changes
+    /// nothing if the trap vector is zero, i.e. no handler was set up.
+    fn trap(&mut self, cause: u64, tval: u64, epc: u64) -> bool {
+        let code = cause & !CAUSE_INTERRUPT;
+        let interrupt = cause & CAUSE_INTERRUPT != 0;
+        let deleg = self.csrs[if interrupt { CSR_MIDELEG } else { CSR_MEDELEG }];
+        let to_s = self.privilege <= PRV_S && deleg & 1 << code != 0;
+        let (tvec, epc_csr, cause_csr, tval_csr) = if to_s {
+            (self.csrs[CSR_STVEC], CSR_SEPC, CSR_SCAUSE, CSR_STVAL)
+        } else {
+            (self.csrs[CSR_MTVEC], CSR_MEPC, CSR_MCAUSE, CSR_MTVAL)
+        };
+        let base = tvec & !3;
+        if base == 0 {
+            return false;
+        }
+        self.reservation = None;
+        self.write_csr(epc_csr, epc);
+        self.write_csr(cause_csr, cause);
+        self.write_csr(tval_csr, tval);
+        // The interrupt enable is stacked and cleared, and the old level saved
+        let status = self.csrs[CSR_MSTATUS];
+        let status = if to_s {
+            let spie = if status & MSTATUS_SIE != 0 { MSTATUS_SPIE } else { 0 };
+            let spp = if self.privilege == PRV_S { MSTATUS_SPP } else { 0 };
+            status & !(MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP) | spie | spp
+        } else {
+            let mpie = if status & MSTATUS_MIE != 0 { MSTATUS_MPIE } else { 0 };
+            status & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP) | mpie | self.privilege << MSTATUS_MPP_SHIFT
+        };
+        self.write_csr(CSR_MSTATUS, status);
+        self.set_privilege(if to_s { PRV_S } else { PRV_M });
+        // Vectored mode sends each interrupt to its own entry
+        self.pc = if interrupt && tvec & 3 == 1 { base.wrapping_add(4 * code) } else { base };
+        true
+    }
+
+    /// Raises exception `cause` for the instruction being executed. Returns `err` if
+    /// there is no handler, which stops the machine like before traps were modelled.
+    fn exception(&mut self, cause: u64, tval: u64, err: &str) -> Result<(), String> {
+        if !self.trap(cause, tval, self.pc) {
+            return Err(err.to_string());
+        }
+        self.pc = self.pc.wrapping_sub(4); // To negate the +4 after
+        Ok(())
+    }
+
+    /// Takes the highest priority interrupt that is pending, enabled in mie and not
+    /// masked at the current privilege level. Runs between instructions.
+    fn take_interrupt(&mut self) {
+        let pending = self.csrs[CSR_MIP] & self.csrs[CSR_MIE];
+        if pending == 0 || !self.running {
+            return;
+        }
+        let status = self.csrs[CSR_MSTATUS];
+        let m_enabled = self.privilege < PRV_M || status & MSTATUS_MIE != 0;
+        let s_enabled = self.privilege < PRV_S || (self.privilege == PRV_S && status & MSTATUS_SIE != 0);
+        for code in INTERRUPT_ORDER.iter() {
+            let enabled = if self.csrs[CSR_MIDELEG] & 1 << code != 0 { s_enabled } else { m_enabled };
+            if pending & 1 << code != 0 && enabled && self.trap(CAUSE_INTERRUPT | code, 0, self.pc) {
+                self.touched_devices = true;
+                return;
+            }
+        }
+    }
+
+    /// Returns from a trap handler at `privilege`, restoring the interrupt enable
+    /// and privilege level the trap saved.
+    fn trap_return(&mut self, privilege: u64) {
+        let status = self.csrs[CSR_MSTATUS];
+        let (status, privilege, epc) = if privilege == PRV_M {
+            let mie = if status & MSTATUS_MPIE != 0 { MSTATUS_MIE } else { 0 };
+            let mpp = (status & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT;
+            (status & !(MSTATUS_MIE | MSTATUS_MPP) | mie | MSTATUS_MPIE, mpp, self.csrs[CSR_MEPC])
+        } else {
+            let sie = if status & MSTATUS_SPIE != 0 { MSTATUS_SIE } else { 0 };
+            let spp = if status & MSTATUS_SPP != 0 { PRV_S } else { PRV_U };
+            (status & !(MSTATUS_SIE | MSTATUS_SPP) | sie | MSTATUS_SPIE, spp, self.csrs[CSR_SEPC])
+        };
+        self.write_csr(CSR_MSTATUS, status);
+        self.set_privilege(privilege);
+        self.pc = (epc & !3).wrapping_sub(4); // To negate the +4 after
+    }
+
+    /// Loads from virtual address `addr`, returning the exception cause if that fails.
+    fn load_virt(&mut self, addr: u64, size: usize) -> Result<u64, u64> {
+        let paddr = self.translate_addr(addr, Access::Load)?;
+        self.load(paddr as usize, size).map_err(|_| CAUSE_LOAD_FAULT)
+    }
+
+    /// Stores to virtual address `addr`, returning the exception cause if that fails.
+    fn store_virt(&mut self, addr: u64, size: usize, val: u64) -> Result<(), u64> {
+        let paddr = self.translate_addr(addr, Access::Store)?;
+        self.store(paddr as usize, size, val).map_err(|_| CAUSE_STORE_FAULT)
+    }
+
+    /// Loads into `rd` for a load instruction, raising a fault if that fails.
+    fn load_reg(&mut self, rd: usize, addr: u64, size: usize, extend: fn(u64) -> u64) -> Result<(), String> {
+        match self.load_virt(addr, size) {
+            Ok(val) => {
+                self.write_reg(rd, extend(val));
+                Ok(())
+            }
+            Err(cause) => self.exception(cause, addr, "Read error!"),
+        }
+    }
+
+    /// Stores for a store instruction, raising a fault if that fails.
+    fn store_reg(&mut self, addr: u64, size: usize, val: u64) -> Result<(), String> {
+        match self.store_virt(addr, size, val) {
+            Ok(()) => Ok(()),
+            Err(cause) => self.exception(cause, addr, "Write error!"),
+        }
+    }
+
+    /// Runs an AMO: loads the old value into `rd` and stores `op` applied to it and `src`.
+    /// It needs write permission, so faults are reported as store faults.
+    fn amo(&mut self, op: AmoOp, rd: usize, addr: u64, src: u64, size: usize) -> Result<(), String> {
+        let paddr = match self.translate_addr(addr, Access::Store) {
+            Ok(paddr) => paddr as usize,
+            Err(cause) => return self.exception(cause, addr, "Write error!"),
+        };
+        let old = match self.load(paddr, size) {
+            Ok(old) => old,
+            Err(_) => return self.exception(CAUSE_STORE_FAULT, addr, "Write error!"),
+        };
+        let mask = if size == 64 { u64::MAX } else { (1 << size) - 1 };
+        if self.store(paddr, size, op.apply(old, src, size) & mask).is_err() {
+            return self.exception(CAUSE_STORE_FAULT, addr, "Write error!");
+        }
+        self.write_reg(rd, if size == 32 { old as i32 as i64 as u64 } else { old });
+        Ok(())
+    }
+
+    /// Reads memory on behalf of the running program, through the cache model if there is one.
+    fn load(&mut self, addr: usize, size: usize) -> Result<u64, ()> {
+        if let Some(caches) = self.caches.as_mut() {
+            if self.bus.cacheable(addr) {
+                caches.data(addr as u64, size as u64 / 8, false);
+            }
+        }
+        let val = self.bus.read(addr, size)?;
+        // What a device returns is input from outside the program
+        if self.recording.is_some() && !self.bus.cacheable(addr) {
+            self.pending.push(Change::Io { addr, size, write: false, val });
+            self.touched_devices = true;
+        }
+        Ok(val)
+    }
+
+    fn store(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
+        if let Some(caches) = self.caches.as_mut() {
+            if self.bus.cacheable(addr) {
+                caches.data(addr as u64, size as u64 / 8, true);
+            }
+        }
+        // Only memory can be read back for the old value, reading a device register could change it
+        if self.recording.is_some() {
+            if self.bus.cacheable(addr) {
+                let old = self.bus.read(addr, size)?;
+                self.pending.push(Change::Mem { addr, size, old, new: val });
+            } else {
+                self.pending.push(Change::Io { addr, size, write: true, val });
+                self.touched_devices = true;
+            }
+        }
+        self.code_written(addr, size / 8);
+        self.bus.write(addr, size, val)
+    }
+
+    pub fn read_reg(&self, reg: usize) -> u64 {
         if reg == 0 || reg > self.regs.len() {
             return 0;
         }
@@ -119,147 +1032,466 @@ impl CPU {
                 self.write_reg(rd, self.read_reg(rs1).wrapping_add(imm as u64) as u32 as i32 as i64 as u64);
                 Ok(())
             }
-            Instructions::Lb { rd, rs1, imm } => {
-                let val = self.bus.read(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 8);
-                if val.is_err() {
-                    Err("Read error!".to_string())
+            Instructions::Slli { rd, rs1, shamt } => {
+                self.write_reg(rd, self.read_reg(rs1) << shamt);
+                Ok(())
+            }
+            Instructions::Srli { rd, rs1, shamt } => {
+                self.write_reg(rd, self.read_reg(rs1) >> shamt);
+                Ok(())
+            }
+            Instructions::Srai { rd, rs1, shamt } => {
+                self.write_reg(rd, ((self.read_reg(rs1) as i64) >> shamt) as u64);
+                Ok(())
+            }
+            Instructions::Slliw { rd, rs1, shamt } => {
+                self.write_reg(rd, ((self.read_reg(rs1) as u32) << shamt) as i32 as i64 as u64);
+                Ok(())
+            }
+            Instructions::Srliw { rd, rs1, shamt } => {
+                self.write_reg(rd, ((self.read_reg(rs1) as u32) >> shamt) as i32 as i64 as u64);
+                Ok(())
+            }
+            Instructions::Sraiw { rd, rs1, shamt } => {
+                self.write_reg(rd, ((self.read_reg(rs1) as i32) >> shamt) as i64 as u64);
+                Ok(())
+            }
+            Instructions::Lui { rd, imm } => {
+                self.write_reg(rd, imm as u64);
+                Ok(())
+            }
+            Instructions::Sub { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, a.wrapping_sub(b));
+                Ok(())
+            }
+            Instructions::Subw { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, a.wrapping_sub(b) as i32 as i64 as u64);
+                Ok(())
+            }
+            Instructions::And { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, a & b);
+                Ok(())
+            }
+            Instructions::Or { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, a | b);
+                Ok(())
+            }
+            Instructions::Xor { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, a ^ b);
+                Ok(())
+            }
+            Instructions::Andi { rd, rs1, imm } => {
+                self.write_reg(rd, self.read_reg(rs1) & imm as u64);
+                Ok(())
+            }
+            Instructions::Ori { rd, rs1, imm } => {
+                self.write_reg(rd, self.read_reg(rs1) | imm as u64);
+                Ok(())
+            }
+            Instructions::Xori { rd, rs1, imm } => {
+                self.write_reg(rd, self.read_reg(rs1) ^ imm as u64);
+                Ok(())
+            }
+            Instructions::Sll { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, a << (b & 63));
+                Ok(())
+            }
+            Instructions::Srl { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, a >> (b & 63));
+                Ok(())
+            }
+            Instructions::Sra { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, ((a as i64) >> (b & 63)) as u64);
+                Ok(())
+            }
+            Instructions::Sllw { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, ((a as u32) << (b & 31)) as i32 as i64 as u64);
+                Ok(())
+            }
+            Instructions::Srlw { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, ((a as u32) >> (b & 31)) as i32 as i64 as u64);
+                Ok(())
+            }
+            Instructions::Sraw { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, ((a as i32) >> (b & 31)) as i64 as u64);
+                Ok(())
+            }
+            Instructions::Slt { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, ((a as i64) < (b as i64)) as u64);
+                Ok(())
+            }
+            Instructions::Sltu { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, (a < b) as u64);
+                Ok(())
+            }
+            Instructions::Slti { rd, rs1, imm } => {
+                self.write_reg(rd, ((self.read_reg(rs1) as i64) < imm) as u64);
+                Ok(())
+            }
+            Instructions::Sltiu { rd, rs1, imm } => {
+                self.write_reg(rd, (self.read_reg(rs1) < imm as u64) as u64);
+                Ok(())
+            }
+
+            // M extension. Dividing by zero and overflowing give fixed results instead of trapping
+            Instructions::Mul { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, a.wrapping_mul(b));
+                Ok(())
+            }
+            Instructions::Mulh { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, ((a as i64 as i128 * b as i64 as i128) >> 64) as u64);
+                Ok(())
+            }
+            Instructions::Mulhsu { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, ((a as i64 as i128).wrapping_mul(b as i128) >> 64) as u64);
+                Ok(())
+            }
+            Instructions::Mulhu { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, ((a as u128 * b as u128) >> 64) as u64);
+                Ok(())
+            }
+            Instructions::Mulw { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, (a as i32).wrapping_mul(b as i32) as i64 as u64);
+                Ok(())
+            }
+            Instructions::Div { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, if b == 0 { u64::MAX } else { (a as i64).wrapping_div(b as i64) as u64 });
+                Ok(())
+            }
+            Instructions::Divu { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, a.checked_div(b).unwrap_or(u64::MAX));
+                Ok(())
+            }
+            Instructions::Divw { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, if b as i32 == 0 { u64::MAX } else { (a as i32).wrapping_div(b as i32) as i64 as u64 });
+                Ok(())
+            }
+            Instructions::Divuw { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, (a as u32).checked_div(b as u32).map_or(u64::MAX, |q| q as i32 as i64 as u64));
+                Ok(())
+            }
+            Instructions::Rem { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, if b == 0 { a } else { (a as i64).wrapping_rem(b as i64) as u64 });
+                Ok(())
+            }
+            Instructions::Remu { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, if b == 0 { a } else { a % b });
+                Ok(())
+            }
+            Instructions::Remw { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, if b as i32 == 0 { a as i32 as i64 as u64 } else { (a as i32).wrapping_rem(b as i32) as i64 as u64 });
+                Ok(())
+            }
+            Instructions::Remuw { rd, rs1, rs2 } => {
+                let (a, b) = (self.read_reg(rs1), self.read_reg(rs2));
+                self.write_reg(rd, if b as u32 == 0 { a as i32 as i64 as u64 } else { (a as u32 % b as u32) as i32 as i64 as u64 });
+                Ok(())
+            }
+
+            // A extension. There is only one hart, so an atomic is a load and a store
+            Instructions::Lr { rd, rs1, size } => {
+                let addr = self.read_reg(rs1);
+                if !addr.is_multiple_of(size as u64 / 8) {
+                    self.exception(CAUSE_LOAD_MISALIGNED, addr, "Misaligned load-reserved!")
                 } else {
-                    self.write_reg(rd, val.unwrap()as i8 as i64 as u64);
-                    Ok(())
+                    self.reservation = Some(addr);
+                    self.load_reg(rd, addr, size, if size == 32 { |v| v as i32 as i64 as u64 } else { |v| v })
                 }
             }
-            Instructions::Lh { rd, rs1, imm } => {
-                let val = self.bus.read(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 16);
-                if val.is_err() {
-                    Err("Read error!".to_string())
+            Instructions::Sc { rd, rs1, rs2, size } => {
+                let addr = self.read_reg(rs1);
+                if !addr.is_multiple_of(size as u64 / 8) {
+                    self.exception(CAUSE_STORE_MISALIGNED, addr, "Misaligned store-conditional!")
+                } else if self.reservation.take() == Some(addr) {
+                    match self.store_virt(addr, size, self.read_reg(rs2)) {
+                        Ok(()) => {
+                            self.write_reg(rd, 0);
+                            Ok(())
+                        }
+                        Err(cause) => self.exception(cause, addr, "Write error!"),
+                    }
                 } else {
-                    self.write_reg(rd, val.unwrap()as i16 as i64 as u64);
+                    self.write_reg(rd, 1);
                     Ok(())
                 }
             }
-            Instructions::Lw { rd, rs1, imm } => {
-                let val = self.bus.read(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 32);
-                if val.is_err() {
-                    Err("Read error!".to_string())
+            Instructions::Amo { op, rd, rs1, rs2, size } => {
+                let addr = self.read_reg(rs1);
+                if !addr.is_multiple_of(size as u64 / 8) {
+                    self.exception(CAUSE_STORE_MISALIGNED, addr, "Misaligned AMO!")
                 } else {
-                    self.write_reg(rd, val.unwrap() as i32 as i64 as u64);
-                    Ok(())
+                    self.amo(op, rd, addr, self.read_reg(rs2), size)
                 }
             }
+            Instructions::Lb { rd, rs1, imm } => {
+                self.load_reg(rd, self.read_reg(rs1).wrapping_add(imm as u64), 8, |v| v as i8 as i64 as u64)
+            }
+            Instructions::Lh { rd, rs1, imm } => {
+                self.load_reg(rd, self.read_reg(rs1).wrapping_add(imm as u64), 16, |v| v as i16 as i64 as u64)
+            }
+            Instructions::Lw { rd, rs1, imm } => {
+                self.load_reg(rd, self.read_reg(rs1).wrapping_add(imm as u64), 32, |v| v as i32 as i64 as u64)
+            }
             Instructions::Ld { rd, rs1, imm } => {
-                let val = self.bus.read(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 64);
-                if val.is_err() {
-                    Err("Read error!".to_string())
-                } else {
-                    self.write_reg(rd, val.unwrap());
-                    Ok(())
-                }
+                self.load_reg(rd, self.read_reg(rs1).wrapping_add(imm as u64), 64, |v| v)
             }
 
             Instructions::Lbu { rd, rs1, imm } => {
-                let val = self.bus.read(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 8);
-                if val.is_err() {
-                    Err("Read error!".to_string())
-                } else {
-                    self.write_reg(rd, val.unwrap() as u64);
-                    Ok(())
-                }
+                self.load_reg(rd, self.read_reg(rs1).wrapping_add(imm as u64), 8, |v| v)
             }
             Instructions::Lhu { rd, rs1, imm } => {
-                let val = self.bus.read(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 16);
-                if val.is_err() {
-                    Err("Read error!".to_string())
-                } else {
-                    self.write_reg(rd, val.unwrap() as u64);
-                    Ok(())
-                }
+                self.load_reg(rd, self.read_reg(rs1).wrapping_add(imm as u64), 16, |v| v)
             }
             Instructions::Lwu { rd, rs1, imm } => {
-                let val = self.bus.read(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 32);
-                if val.is_err() {
-                    Err("Read error!".to_string())
-                } else {
-                    self.write_reg(rd, val.unwrap() as u64);
-                    Ok(())
-                }
+                self.load_reg(rd, self.read_reg(rs1).wrapping_add(imm as u64), 32, |v| v)
             }
 
             Instructions::Sb { rs1, rs2, imm } => {
-                if self.bus.write(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 8, self.read_reg(rs2) as u8 as u64).is_err() {
-                    Err("Write error!".to_string())
-                } else {
-                    Ok(())
-                }
+                self.store_reg(self.read_reg(rs1).wrapping_add(imm as u64), 8, self.read_reg(rs2) as u8 as u64)
             }
             Instructions::Sh { rs1, rs2, imm } => {
-                if self.bus.write(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 16, self.read_reg(rs2) as u16 as u64).is_err() {
-                    Err("Write error!".to_string())
-                } else {
-                    Ok(())
-                }
+                self.store_reg(self.read_reg(rs1).wrapping_add(imm as u64), 16, self.read_reg(rs2) as u16 as u64)
             }
             Instructions::Sw { rs1, rs2, imm } => {
-                if self.bus.write(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 32, self.read_reg(rs2) as u32 as u64).is_err() {
-                    Err("Write error!".to_string())
-                } else {
-                    Ok(())
-                }
+                self.store_reg(self.read_reg(rs1).wrapping_add(imm as u64), 32, self.read_reg(rs2) as u32 as u64)
             }
             Instructions::Sd { rs1, rs2, imm } => {
-                if self.bus.write(self.read_reg(rs1).wrapping_add(imm as u64) as usize, 64, self.read_reg(rs2)).is_err() {
-                    Err("Write error!".to_string())
-                } else {
-                    Ok(())
-                }
+                self.store_reg(self.read_reg(rs1).wrapping_add(imm as u64), 64, self.read_reg(rs2))
             }
 
             Instructions::Auipc { rd, imm } => {
-                println!("\tAuipc imm: 0x{:X}", imm);
-                self.write_reg(rd, self.pc + (imm as u64));
+                if self.trace {
+                    println!("\tAuipc imm: 0x{:X}", imm);
+                }
+                self.write_reg(rd, self.pc.wrapping_add(imm as u64));
                 Ok(())
             }
 
             Instructions::Jalr { rd, rs1, imm } => {
                 let rs1_val = self.read_reg(rs1);
-                self.write_reg(rd, self.pc + 4);
-                println!("\tself.pc: 0x{:02X}", self.pc);
-                self.pc = imm as u64 + rs1_val;
+                self.write_reg(rd, self.pc.wrapping_add(4));
+                let pc = self.pc;
+                self.pc = rs1_val.wrapping_add(imm as u64);
                 self.pc &= !1;
-                println!("\tself.pc: 0x{:02X}", self.pc);
+                if self.trace {
+                    println!("\tself.pc: 0x{:02X}", pc);
+                    println!("\tself.pc: 0x{:02X}", self.pc);
+                }
                 self.pc = self.pc.wrapping_sub(4); // To negate the +4 after
                 Ok(())
             }
 
             Instructions::Jal { rd, imm } => {
-                self.set(rd, self.pc + 4);
+                self.write_reg(rd, self.pc.wrapping_add(4));
                 self.pc = self.pc.wrapping_add(imm.wrapping_sub(4) as u64);
                 Ok(())
             }
 
             Instructions::Beq { rs1, rs2, imm } => {
                 if self.read_reg(rs1) == self.read_reg(rs2) {
-                    self.pc = self.pc.wrapping_add(imm as u64 - 4);
+                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
                 }
                 Ok(())
             }
 
             Instructions::Bne { rs1, rs2, imm } => {
                 if self.read_reg(rs1) != self.read_reg(rs2) {
-                    self.pc = self.pc.wrapping_add(imm as u64 - 4);
+                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                }
+                Ok(())
+            }
+
+            Instructions::Blt { rs1, rs2, imm } => {
+                if (self.read_reg(rs1) as i64) < (self.read_reg(rs2) as i64) {
+                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                }
+                Ok(())
+            }
+
+            Instructions::Bge { rs1, rs2, imm } => {
+                if (self.read_reg(rs1) as i64) >= (self.read_reg(rs2) as i64) {
+                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                }
+                Ok(())
+            }
+
+            Instructions::Bgeu { rs1, rs2, imm } => {
+                if self.read_reg(rs1) >= self.read_reg(rs2) {
+                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
                 }
                 Ok(())
             }
 
             Instructions::Bltu { rs1, rs2, imm } => {
-                println!("\tBltu {} < {}, {}", self.read_reg(rs1), self.read_reg(rs2), imm);
+                if self.trace {
+                    println!("\tBltu {} < {}, {}", self.read_reg(rs1), self.read_reg(rs2), imm);
+                }
                 if self.read_reg(rs1) < self.read_reg(rs2) {
-                    self.pc = self.pc.wrapping_add(imm as u64 - 4);
+                    self.pc = self.pc.wrapping_add(imm as u64).wrapping_sub(4);
+                }
+                Ok(())
+            }
+
+            Instructions::Ecall => {
+                if let Some(mut linux) = self.linux.take() {
+                    self.touched_host = true;
+                    let result = linux.syscall(self);
+                    self.linux = Some(linux);
+                    match result {
+                        Ok(Some(ret)) => {
+                            self.write_reg(10, ret);
+                            Ok(())
+                        }
+                        Ok(None) => {
+                            self.running = false;
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    }
+                } else if self.privilege == PRV_S && self.sbi.is_some() {
+                    let mut sbi = self.sbi.take().unwrap();
+                    self.touched_host = true;
+                    let result = sbi.call(self);
+                    self.sbi = Some(sbi);
+                    result.map(|running| self.running = running)
+                } else {
+                    self.exception(CAUSE_ECALL + self.privilege, 0, "Ecall not implemented!")
+                }
+            }
+            Instructions::Fence { .. } => {
+                // Memory accesses are performed in order already
+                Ok(())
+            }
+            Instructions::FenceI => {
+                self.flush_code();
+                Ok(())
+            }
+            Instructions::Ebreak => {
+                // Semihosting calls are marked by the instructions around the ebreak
+                let entry = self.bus.read(self.pc.wrapping_sub(4) as usize, 32);
+                let exit = self.bus.read(self.pc.wrapping_add(4) as usize, 32);
+                let marked = entry == Ok(SEMIHOST_ENTRY as u64) && exit == Ok(SEMIHOST_EXIT as u64);
+                match self.semihosting.take() {
+                    Some(mut semihosting) if marked => {
+                        self.touched_host = true;
+                        let result = semihosting.call(self);
+                        self.semihosting = Some(semihosting);
+                        match result {
+                            Ok(Some(ret)) => {
+                                self.write_reg(10, ret);
+                                Ok(())
+                            }
+                            Ok(None) => {
+                                self.running = false;
+                                Ok(())
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    semihosting => {
+                        self.semihosting = semihosting;
+                        self.exception(CAUSE_BREAKPOINT, self.pc, "Ebreak not implemented!")
+                    }
+                }
+            }
+
+            Instructions::Mret if self.privilege == PRV_M => {
+                self.trap_return(PRV_M);
+                Ok(())
+            }
+            Instructions::Sret if self.privilege >= PRV_S => {
+                self.trap_return(PRV_S);
+                Ok(())
+            }
+            // Interrupts are checked after every instruction anyway
+            Instructions::Wfi => Ok(()),
+            // Translations aren't cached, but decoded code is by virtual address
+            Instructions::SfenceVma { .. } if self.privilege >= PRV_S => {
+                self.flush_code();
+                Ok(())
+            }
+
+            Instructions::Csrrw { csr, .. } | Instructions::Csrrwi { csr, .. } if !self.csr_allowed(csr, true) => {
+                self.exception(CAUSE_ILLEGAL_INSTRUCTION, 0, "Illegal CSR access!")
+            }
+            Instructions::Csrrs { rs1, csr, .. } | Instructions::Csrrc { rs1, csr, .. }
+            | Instructions::Csrrsi { rs1, csr, .. } | Instructions::Csrrci { rs1, csr, .. }
+                if !self.csr_allowed(csr, rs1 != 0) => {
+                self.exception(CAUSE_ILLEGAL_INSTRUCTION, 0, "Illegal CSR access!")
+            }
+            Instructions::Csrrw { rd, rs1, csr } => {
+                let old = self.read_csr(csr);
+                self.write_csr(csr, self.read_reg(rs1));
+                self.write_reg(rd, old);
+                Ok(())
+            }
+            Instructions::Csrrs { rd, rs1, csr } => {
+                let old = self.read_csr(csr);
+                if rs1 != 0 {
+                    self.write_csr(csr, old | self.read_reg(rs1));
+                }
+                self.write_reg(rd, old);
+                Ok(())
+            }
+            Instructions::Csrrc { rd, rs1, csr } => {
+                let old = self.read_csr(csr);
+                if rs1 != 0 {
+                    self.write_csr(csr, old & !self.read_reg(rs1));
+                }
+                self.write_reg(rd, old);
+                Ok(())
+            }
+            // For the immediate forms, rs1 holds the zero-extended immediate
+            Instructions::Csrrwi { rd, rs1, csr } => {
+                let old = self.read_csr(csr);
+                self.write_csr(csr, rs1 as u64);
+                self.write_reg(rd, old);
+                Ok(())
+            }
+            Instructions::Csrrsi { rd, rs1, csr } => {
+                let old = self.read_csr(csr);
+                if rs1 != 0 {
+                    self.write_csr(csr, old | rs1 as u64);
+                }
+                self.write_reg(rd, old);
+                Ok(())
+            }
+            Instructions::Csrrci { rd, rs1, csr } => {
+                let old = self.read_csr(csr);
+                if rs1 != 0 {
+                    self.write_csr(csr, old & !(rs1 as u64));
                 }
+                self.write_reg(rd, old);
                 Ok(())
             }
 
             _ => {
-                Err("Instruction not implemented!".to_string())
+                self.exception(CAUSE_ILLEGAL_INSTRUCTION, 0, "Instruction not implemented!")
             }
         };
         self.pc = self.pc.wrapping_add(4);
@@ -270,20 +1502,437 @@ impl CPU {
         if !self.running {
             return;
         }
+        if self.recording.as_ref().is_some_and(|r| r.is_replaying()) {
+            self.replay_step();
+            return;
+        }
+        let pc = self.pc;
+        let (cycles, instret) = (self.cycles, self.instret);
+        if self.recording.as_ref().is_some_and(|r| r.outside.is_none()) {
+            let outside = self.outside_state();
+            self.recording.as_mut().unwrap().outside = Some(outside);
+        }
+        self.touched_devices = false;
+        self.touched_host = false;
+        self.step_inner();
+        if self.recording.is_some() {
+            self.pending.push(Change::Time {
+                old_cycles: cycles,
+                new_cycles: self.cycles,
+                old_instret: instret,
+                new_instret: self.instret,
+            });
+            self.record_outside();
+        }
+        if let Some(rec) = self.recording.as_mut() {
+            rec.push(StepRecord {
+                pc,
+                next_pc: self.pc,
+                stopped: !self.running,
+                changes: std::mem::take(&mut self.pending),
+            });
+        }
+    }
+
+    fn step_inner(&mut self) {
         // Fetch, decode, execute:
-        let raw_opcode = self.fetch();
-        if raw_opcode.is_err() {
+        let pc = self.pc;
+        let inst = match self.decode_at(pc) {
+            Ok(inst) => inst,
+            Err(cause) => {
+                if !self.trap(cause, pc, pc) {
+                    self.running = false;
+                    println!("\nError fetching instruction at {}, exiting.\n", self.describe(self.pc));
+                }
+                return;
+            }
+        };
+        if self.trace {
+            println!("\n{} inst: {:?}", self.describe(pc), inst);
+        }
+        let status = self.execute(inst);
+        self.retire(pc, &inst, status);
+        self.take_interrupt();
+    }
+
+    /// Fetches and decodes the instruction at `pc`, or returns the cause of the fault.
+    /// Decoded instructions are cached by address, which only holds without paging.
+    fn decode_at(&mut self, pc: u64) -> Result<Instructions, u64> {
+        let paging = self.paging();
+        if !paging {
+            if let Some(inst) = self.icache.get(pc as usize) {
+                self.fetch_addr = pc;
+                return Ok(inst);
+            }
+        }
+        let addr = self.translate_addr(pc, Access::Fetch)?;
+        self.fetch_addr = addr;
+        let raw_opcode = self.bus.read(addr as usize, 32).map_err(|_| CAUSE_FETCH_FAULT)?;
+        let inst = decode::Instructions::from(raw_opcode as u32);
+        if !paging {
+            self.icache.insert(pc as usize, inst);
+        }
+        Ok(inst)
+    }
+
+    /// Finishes the instruction at `pc`: stops on an error, otherwise lets time pass
+    /// and gives devices and the host layers their turn.
+    fn retire(&mut self, pc: u64, inst: &Instructions, status: Result<(), String>) {
+        if let Err(e) = status {
             self.running = false;
-            println!("\nError fetching instruction at 0x{:X}, exiting.\n", self.pc);
+            println!("Error at {}: {}", self.describe(pc), e);
             return;
         }
-        let inst = decode::Instructions::from(raw_opcode.unwrap() as u32);
-        println!("\n{:02X} inst: {:?}", self.pc, inst);
-        let status = self.execute(inst);
-        if status.is_err() {
+        // Without a predictor, fetch just carries on with the next instruction
+        let redirected = match self.predictor.as_mut() {
+            Some(predictor) => predictor.observe(pc, inst, self.pc),
+            None => self.pc != pc.wrapping_add(4),
+        };
+        self.cycles += match self.pipeline.as_mut() {
+            Some(pipeline) => pipeline.retire(inst, redirected),
+            None => 1,
+        };
+        // Like the data side, the instruction cache sees physical addresses
+        if let Some(caches) = self.caches.as_mut() {
+            if self.bus.cacheable(self.fetch_addr as usize) {
+                caches.fetch(self.fetch_addr);
+            }
+            self.cycles += caches.take_stall();
+        }
+        self.instret += 1;
+        let irq = self.bus.advance(self.cycles);
+        let mip = (self.csrs[CSR_MIP] & !MIP_DEVICE) | (irq.hart() & MIP_DEVICE);
+        if mip != self.csrs[CSR_MIP] {
+            self.write_csr(CSR_MIP, mip);
+        }
+        if let Some(mut sbi) = self.sbi.take() {
+            if sbi.poll(self) {
+                self.touched_host = true;
+            }
+            self.sbi = Some(sbi);
+        }
+        if self.bus.halted().is_some() {
             self.running = false;
-            println!("Error: {}", status.err().unwrap());
         }
+        if let Some(mut htif) = self.htif.take() {
+            if htif.requested(self) {
+                self.touched_host = true;
+            }
+            match htif.poll(self) {
+                Ok(running) => self.running = running,
+                Err(e) => {
+                    self.running = false;
+                    println!("Error at {}: {}", self.describe(pc), e);
+                }
+            }
+            self.htif = Some(htif);
+        }
+    }
+
+    /// Runs compiled code for `insts`, then traces (with `trace` set) and retires the
+    /// instructions it got through one at a time. Compiled code only does loads and
+    /// stores that hit memory, so nothing in `retire` can tell they ran early. Returns
+    /// how many ran, the next one is left to the interpreter if that falls short of the run.
+    fn run_native(&mut self, native: &jit::Native, insts: &[Instructions], fetch: u64) -> usize {
+        let mut log = [0; block::MAX_BLOCK + 1];
+        let done = native.run(self, &mut log);
+        for (i, inst) in insts[..done].iter().enumerate() {
+            let pc = self.pc;
+            if self.trace {
+                println!("\n{} inst: {:?}", self.describe(pc), inst);
+                let rd = jit::dest(inst);
+                if rd != 0 {
+                    println!("\tWriting \"{}\" to {}", log[i], REG_NAMES[rd]);
+                }
+            }
+            // A jump ending the run logged where it went
+            self.pc = if jit::jumps(inst) { log[i + 1] } else { pc.wrapping_add(4) };
+            self.fetch_addr = fetch.wrapping_add(4 * i as u64);
+            self.retire(pc, inst, Ok(()));
+        }
+        self.blocks.native += done as u64;
+        // The whole run has already happened, so an interrupt can only come after it
+        self.take_interrupt();
+        done
+    }
+
+    /// A load for compiled code from virtual address `addr`, or None if the interpreter
+    /// has to do it because it faults or reaches a device.
+    fn native_load(&mut self, addr: u64, size: usize) -> Option<u64> {
+        let paddr = self.translate_addr(addr, Access::Load).ok()? as usize;
+        if !self.bus.cacheable(paddr) {
+            return None;
+        }
+        self.load(paddr, size).ok()
+    }
+
+    /// A store for compiled code to virtual address `addr`. Returns false if the
+    /// interpreter has to do it because it faults, reaches a device or writes to a page
+    /// with translated code, which ends the running block.
+    fn native_store(&mut self, addr: u64, size: usize, val: u64) -> bool {
+        let paddr = match self.translate_addr(addr, Access::Store) {
+            Ok(paddr) => paddr as usize,
+            Err(_) => return false,
+        };
+        let last = paddr.wrapping_add(size / 8 - 1);
+        if !self.bus.cacheable(paddr) || self.blocks.holds_page(paddr) || self.blocks.holds_page(last) {
+            return false;
+        }
+        let mask = if size == 64 { u64::MAX } else { (1 << size) - 1 };
+        self.store(paddr, size, val & mask).is_ok()
+    }
+
+    /// Translates the block at `pc`, which is at physical address `start`, or None if
+    /// nothing there can be fetched.
+    fn translate(&mut self, pc: u64, start: u64) -> Option<Rc<Block>> {
+        let mut insts = vec!();
+        let mut addr = pc;
+        loop {
+            let inst = match self.decode_at(addr) {
+                Ok(inst) => inst,
+                Err(_) if insts.is_empty() => return None,
+                Err(_) => break,
+            };
+            insts.push(inst);
+            addr = addr.wrapping_add(4);
+            if !block::continues(&inst) || insts.len() == block::MAX_BLOCK || addr.is_multiple_of(4096) {
+                break;
+            }
+        }
+        Some(self.blocks.insert(Block::new(start, insts)))
+    }
+
+    /// Runs up to `max` instructions a translated block at a time and returns how many
+    /// ran. Every instruction still sees devices, interrupts and the host layers just
+    /// like `step`. A store into translated code ends the running block, so the next
+    /// instruction is translated again from what is now in memory. While recording,
+    /// this falls back to `step`.
+    pub fn run(&mut self, max: usize) -> usize {
+        let mut count = 0;
+        if self.recording.is_some() {
+            while self.running && count < max {
+                self.step();
+                count += 1;
+            }
+            return count;
+        }
+        let mut prev: Option<Rc<Block>> = None;
+        while self.running && count < max {
+            // Blocks are found by physical address, so they stay valid when the mapping changes
+            let pc = self.pc;
+            let block = match self.translate_addr(pc, Access::Fetch) {
+                Ok(start) => match self.blocks.get(start, prev.as_deref()) {
+                    Some(block) => Some(block),
+                    None => self.translate(pc, start),
+                },
+                Err(_) => None,
+            };
+            let block = match block {
+                Some(block) => block,
+                None => {
+                    // Let step report the fault
+                    self.step_inner();
+                    return count + 1;
+                }
+            };
+            self.blocks.executed += 1;
+            let native = block.enter(self.blocks.jit);
+            let generation = self.blocks.generation;
+            // Where compiled code stopped short, to be run by the interpreter instead
+            let mut bailed = None;
+            let mut i = 0;
+            while i < block.insts.len() {
+                let compiled = native.get(i).and_then(Option::as_ref).filter(|n| max - count >= n.len);
+                match compiled {
+                    Some(native) if bailed != Some(i) => {
+                        let done = self.run_native(native, &block.insts[i..i + native.len], block.start + 4 * i as u64);
+                        if done < native.len {
+                            bailed = Some(i + done);
+                        }
+                        i += done;
+                        count += done;
+                    }
+                    _ => {
+                        let pc = self.pc;
+                        if self.trace {
+                            println!("\n{} inst: {:?}", self.describe(pc), block.insts[i]);
+                        }
+                        let status = (block.ops[i])(self);
+                        self.fetch_addr = block.start + 4 * i as u64;
+                        self.retire(pc, &block.insts[i], status);
+                        self.take_interrupt();
+                        i += 1;
+                        count += 1;
+                    }
+                }
+                // A trap or a host layer may have moved the pc
+                if !self.running || count == max || self.blocks.generation != generation
+                    || self.pc != pc.wrapping_add(4 * i as u64) {
+                    break;
+                }
+            }
+            prev = Some(block);
+        }
+        count
+    }
+
+    /// Starts recording every executed instruction so it can be stepped back over.
+    pub fn start_recording(&mut self) {
+        if self.recording.is_none() {
+            self.recording = Some(Recording::default());
+        }
+    }
+
+    /// Stops recording and throws away the history.
+    pub fn stop_recording(&mut self) {
+        self.recording = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording.is_some()
+    }
+
+    pub fn save_recording(&self, path: &str) -> Result<(), String> {
+        match &self.recording {
+            Some(rec) => rec.save(path),
+            None => Err("Not recording".to_string()),
+        }
+    }
+
+    /// Loads a saved recording to be replayed from the current state by `step`.
+    pub fn load_recording(&mut self, path: &str) -> Result<(), String> {
+        self.recording = Some(Recording::load(path)?);
+        Ok(())
+    }
+
+    fn discard_future(&mut self) {
+        if let Some(rec) = self.recording.as_mut() {
+            rec.discard_future();
+            rec.outside = None;
+        }
+    }
+
+    fn save_devices(&self) -> Vec<u8> {
+        let mut devices = SnapshotWriter::new();
+        self.bus.save_devices(&mut devices);
+        devices.finish()
+    }
+
+    fn save_host_state(&self) -> Vec<u8> {
+        let mut host = SnapshotWriter::new();
+        self.save_host(&mut host);
+        host.finish()
+    }
+
+    /// The state a step can change besides registers, memory and time: the devices
+    /// and the host layers, serialized.
+    fn outside_state(&self) -> Outside {
+        Outside { now: self.cycles, devices: self.save_devices(), host: self.save_host_state() }
+    }
+
+    /// Records the device and host state changes of a step that touched them. Their
+    /// old state is the one the last such step left, whatever happened to them in
+    /// between can only be seen through a device access, a host request or an interrupt.
+    fn record_outside(&mut self) {
+        let mut outside = match self.recording.as_mut().and_then(|r| r.outside.take()) {
+            Some(outside) => outside,
+            None => return,
+        };
+        if self.touched_devices {
+            let new = self.save_devices();
+            let old = std::mem::replace(&mut outside.devices, new.clone());
+            let old_now = std::mem::replace(&mut outside.now, self.cycles);
+            if new != old {
+                self.pending.push(Change::Devices { old, new, old_now, new_now: self.cycles });
+            }
+        }
+        if self.touched_host {
+            let new = self.save_host_state();
+            let old = std::mem::replace(&mut outside.host, new.clone());
+            if new != old {
+                self.pending.push(Change::Host { old, new });
+            }
+        }
+        if let Some(rec) = self.recording.as_mut() {
+            rec.outside = Some(outside);
+        }
+    }
+
+    fn apply(&mut self, change: &Change, undo: bool) {
+        let pick = |old, new| if undo { old } else { new };
+        match change {
+            Change::Reg { reg, old, new } => self.regs[*reg] = pick(*old, *new),
+            Change::Csr { csr, old, new } => self.csrs[*csr] = pick(*old, *new),
+            Change::Mem { addr, size, old, new } => {
+                self.code_written(*addr, size / 8);
+                let _ = self.bus.write(*addr, *size, pick(*old, *new));
+            }
+            // Device accesses aren't repeated, their effect is in the device state
+            Change::Io { .. } => {}
+            Change::Devices { old, new, old_now, new_now } => {
+                let now = pick(*old_now, *new_now);
+                let state = if undo { old } else { new };
+                let restored = SnapshotReader::new(state).and_then(|mut r| self.bus.restore_devices(&mut r, now));
+                if let Err(e) = restored {
+                    println!("Error restoring device state: {}", e);
+                }
+            }
+            Change::Host { old, new } => {
+                let state = if undo { old } else { new };
+                if let Err(e) = SnapshotReader::new(state).and_then(|mut r| self.restore_host(&mut r)) {
+                    println!("Error restoring host state: {}", e);
+                }
+            }
+            Change::Time { old_cycles, new_cycles, old_instret, new_instret } => {
+                self.cycles = pick(*old_cycles, *new_cycles);
+                self.instret = pick(*old_instret, *new_instret);
+            }
+            Change::Priv { old, new } => self.privilege = pick(*old, *new),
+        }
+    }
+
+    /// Undoes the last recorded instruction. Returns false if there is nothing to undo.
+    pub fn reverse_step(&mut self) -> bool {
+        let step = match self.recording.as_mut() {
+            Some(rec) if rec.cursor > 0 => {
+                rec.cursor -= 1;
+                rec.outside = None;
+                rec.steps[rec.cursor].clone()
+            }
+            _ => return false,
+        };
+        for change in step.changes.iter().rev() {
+            self.apply(change, true);
+        }
+        self.pc = step.pc;
+        self.running = true;
+        println!("\n{} (reversed)", self.describe(self.pc));
+        true
+    }
+
+    /// Re-applies the next recorded instruction instead of executing it.
+    fn replay_step(&mut self) {
+        let pc = self.pc;
+        let step = match self.recording.as_mut() {
+            Some(rec) if rec.steps[rec.cursor].pc != pc => {
+                println!("Recording diverges at 0x{:X}, executing from here", pc);
+                rec.discard_future();
+                self.step();
+                return;
+            }
+            Some(rec) => {
+                rec.cursor += 1;
+                rec.outside = None;
+                rec.steps[rec.cursor - 1].clone()
+            }
+            None => return,
+        };
+        println!("\n{} (replayed)", self.describe(step.pc));
+        for change in &step.changes {
+            self.apply(change, false);
+        }
+        self.pc = step.next_pc;
+        self.running = !step.stopped;
     }
 
     fn set(&mut self, reg: usize, value: u64) {
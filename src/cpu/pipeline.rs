@@ -0,0 +1,273 @@
+// Cycle-approximate timing for a classic in-order pipeline (fetch, decode, execute,
+// memory, writeback). Instructions enter execute one per cycle unless a source
+// register isn't ready yet, a multi-cycle unit is busy, or fetch was redirected.
+// Results are tracked per register, so load-use stalls and long multiply/divide
+// latencies both fall out of the same scoreboard.
+
+use super::decode::Instructions;
+
+/// Latencies and penalties in cycles, defaulting to a small embedded core.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineConfig {
+    /// Pipeline depth; the first instruction and every flush pay to refill it
+    pub stages: u64,
+    pub alu: u64,
+    /// Cycles until a loaded value can be used, so 2 means one load-use bubble
+    pub load: u64,
+    pub store: u64,
+    pub mul: u64,
+    /// Division isn't pipelined, so it also holds up the instructions after it
+    pub div: u64,
+    /// Extra cycles for refetching after a branch resolved in execute went another
+    /// way than fetch did: every taken branch, or every mispredicted one with a predictor
+    pub branch_penalty: u64,
+    /// The same for jal, resolved in decode. jalr pays the branch penalty.
+    pub jump_penalty: u64,
+    /// CSR accesses, ecall and fence.i drain the pipeline before the next instruction
+    pub serialize: bool,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            stages: 5,
+            alu: 1,
+            load: 2,
+            store: 1,
+            mul: 3,
+            div: 20,
+            branch_penalty: 2,
+            jump_penalty: 1,
+            serialize: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Class {
+    Alu,
+    Load,
+    Store,
+    Mul,
+    Div,
+    Branch,
+    Jump,
+    IndirectJump,
+    System,
+}
+
+/// Where the cycles went.
+#[derive(Debug, Default, Clone)]
+pub struct PipelineStats {
+    pub instructions: u64,
+    pub cycles: u64,
+    /// Waiting on a register written by an earlier instruction, e.g. a load
+    pub data_stalls: u64,
+    /// Waiting on the divider
+    pub structural_stalls: u64,
+    /// Refetching after taken branches and jumps
+    pub control_stalls: u64,
+    /// Filling the pipeline at the start and after serializing instructions
+    pub fill_cycles: u64,
+}
+
+#[derive(Debug)]
+pub struct Pipeline {
+    config: PipelineConfig,
+    /// The cycle the next instruction could enter execute
+    next: u64,
+    /// The cycle each register's latest value is ready for execute
+    ready: [u64; 32],
+    pub stats: PipelineStats,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Pipeline {
+        let fill = config.stages.saturating_sub(1);
+        Self {
+            config,
+            next: fill,
+            ready: [0; 32],
+            stats: PipelineStats { fill_cycles: fill, cycles: fill, ..PipelineStats::default() },
+        }
+    }
+
+    /// Accounts for `inst` and returns the cycles it added. `redirected` says whether
+    /// fetch had to start over somewhere else after it.
+    pub fn retire(&mut self, inst: &Instructions, redirected: bool) -> u64 {
+        let class = classify(inst);
+        let (rd, sources) = registers(inst);
+        let before = self.next;
+        let ready = sources.iter().filter(|&&r| r != 0).map(|&r| self.ready[r]).max().unwrap_or(0);
+        let issue = self.next.max(ready);
+        self.stats.data_stalls += issue - self.next;
+
+        let c = &self.config;
+        let latency = match class {
+            Class::Load => c.load,
+            Class::Store => c.store,
+            Class::Mul => c.mul,
+            Class::Div => c.div,
+            _ => c.alu,
+        }.max(1);
+        if rd != 0 {
+            self.ready[rd] = issue + latency;
+        }
+        self.next = issue + 1;
+        if class == Class::Div {
+            self.stats.structural_stalls += latency - 1;
+            self.next += latency - 1;
+        }
+        let penalty = match class {
+            Class::Jump if redirected => c.jump_penalty,
+            Class::IndirectJump | Class::Branch if redirected => c.branch_penalty,
+            _ => 0,
+        };
+        self.stats.control_stalls += penalty;
+        self.next += penalty;
+        if class == Class::System && c.serialize {
+            // Wait for everything in flight, then refill behind it
+            let drained = self.ready.iter().copied().max().unwrap_or(0).max(self.next);
+            let fill = c.stages.saturating_sub(2);
+            self.stats.fill_cycles += drained - self.next + fill;
+            self.next = drained + fill;
+        }
+
+        let cycles = self.next - before;
+        self.stats.instructions += 1;
+        self.stats.cycles += cycles;
+        cycles
+    }
+
+    /// Empties the pipeline, as after a reset or snapshot load.
+    pub fn reset(&mut self) {
+        *self = Pipeline::new(self.config.clone());
+    }
+}
+
+fn classify(inst: &Instructions) -> Class {
+    use Instructions::*;
+    match inst {
+        Lb { .. } | Lbu { .. } | Lh { .. } | Lhu { .. } | Lw { .. } | Lwu { .. } | Ld { .. } | Ldu { .. }
+        | Lr { .. } | Amo { .. } => Class::Load,
+        Sb { .. } | Sh { .. } | Sw { .. } | Sd { .. } | Sc { .. } => Class::Store,
+        Mul { .. } | Mulh { .. } | Mulhsu { .. } | Mulhu { .. } | Mulw { .. } => Class::Mul,
+        Div { .. } | Divu { .. } | Divw { .. } | Divuw { .. } | Rem { .. } | Remu { .. } | Remw { .. } | Remuw { .. } => Class::Div,
+        Beq { .. } | Bne { .. } | Blt { .. } | Bltu { .. } | Bge { .. } | Bgeu { .. } => Class::Branch,
+        Jal { .. } => Class::Jump,
+        Jalr { .. } => Class::IndirectJump,
+        Csrrw { .. } | Csrrs { .. } | Csrrc { .. } | Csrrwi { .. } | Csrrsi { .. } | Csrrci { .. }
+        | Ecall | Ebreak | Mret | Sret | Wfi | FenceI | SfenceVma { .. } | RdCycle | RdCycleH | RdTime | RdTimeH | RdInstRet | RdInstRetH => Class::System,
+        _ => Class::Alu,
+    }
+}
+
+/// The register `inst` writes and the registers it reads, with 0 for none.
+fn registers(inst: &Instructions) -> (usize, [usize; 2]) {
+    use Instructions::*;
+    let (rd, sources) = match *inst {
+        Add { rd, rs1, rs2 } | Addw { rd, rs1, rs2 } | And { rd, rs1, rs2 } | Or { rd, rs1, rs2 } | Xor { rd, rs1, rs2 }
+        | Sub { rd, rs1, rs2 } | Subw { rd, rs1, rs2 } | Sll { rd, rs1, rs2 } | Sllw { rd, rs1, rs2 }
+        | Srl { rd, rs1, rs2 } | Srlw { rd, rs1, rs2 } | Sra { rd, rs1, rs2 } | Sraw { rd, rs1, rs2 }
+        | Slt { rd, rs1, rs2 } | Sltu { rd, rs1, rs2 } => (rd, [rs1, rs2]),
+        Mul { rd, rs1, rs2 } | Mulh { rd, rs1, rs2 } | Mulhsu { rd, rs1, rs2 } | Mulhu { rd, rs1, rs2 }
+        | Mulw { rd, rs1, rs2 } | Div { rd, rs1, rs2 } | Divu { rd, rs1, rs2 } | Divw { rd, rs1, rs2 }
+        | Divuw { rd, rs1, rs2 } | Rem { rd, rs1, rs2 } | Remu { rd, rs1, rs2 } | Remw { rd, rs1, rs2 }
+        | Remuw { rd, rs1, rs2 } => (rd, [rs1, rs2]),
+        Sc { rd, rs1, rs2, .. } | Amo { rd, rs1, rs2, .. } => (rd, [rs1, rs2]),
+        Addi { rd, rs1, .. } | Addiw { rd, rs1, .. } | Andi { rd, rs1, .. } | Ori { rd, rs1, .. } | Xori { rd, rs1, .. }
+        | Slti { rd, rs1, .. } | Sltiu { rd, rs1, .. } | Lr { rd, rs1, .. } | Jalr { rd, rs1, .. }
+        | Lb { rd, rs1, .. } | Lbu { rd, rs1, .. } | Lh { rd, rs1, .. } | Lhu { rd, rs1, .. }
+        | Lw { rd, rs1, .. } | Lwu { rd, rs1, .. } | Ld { rd, rs1, .. } | Ldu { rd, rs1, .. } => (rd, [rs1, 0]),
+        Slli { rd, rs1, .. } | Srli { rd, rs1, .. } | Srai { rd, rs1, .. }
+        | Slliw { rd, rs1, .. } | Srliw { rd, rs1, .. } | Sraiw { rd, rs1, .. } => (rd, [rs1, 0]),
+        Csrrw { rd, rs1, .. } | Csrrs { rd, rs1, .. } | Csrrc { rd, rs1, .. } => (rd, [rs1, 0]),
+        Csrrwi { rd, .. } | Csrrsi { rd, .. } | Csrrci { rd, .. } => (rd, [0, 0]),
+        Auipc { rd, .. } | Lui { rd, .. } | Jal { rd, .. } => (rd, [0, 0]),
+        Beq { rs1, rs2, .. } | Bne { rs1, rs2, .. } | Blt { rs1, rs2, .. } | Bltu { rs1, rs2, .. }
+        | Bge { rs1, rs2, .. } | Bgeu { rs1, rs2, .. } => (0, [rs1, rs2]),
+        Sb { rs1, rs2, .. } | Sh { rs1, rs2, .. } | Sw { rs1, rs2, .. } | Sd { rs1, rs2, .. } => (0, [rs1, rs2]),
+        _ => (0, [0, 0]),
+    };
+    let valid = |r: usize| if r < 32 { r } else { 0 };
+    (valid(rd), sources.map(valid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Instructions::*;
+
+    /// Retires `insts`, none of them redirecting fetch, and returns the cycles each took.
+    fn run(pipeline: &mut Pipeline, insts: &[Instructions]) -> Vec<u64> {
+        insts.iter().map(|inst| pipeline.retire(inst, false)).collect()
+    }
+
+    /// Using a loaded value right away costs one bubble, a cycle later nothing.
+    #[test]
+    fn load_use() {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        let cycles = run(&mut pipeline, &[
+            Add { rd: 1, rs1: 2, rs2: 3 },
+            Ld { rd: 5, rs1: 1, imm: 0 },
+            Add { rd: 6, rs1: 5, rs2: 0 },
+            Ld { rd: 7, rs1: 1, imm: 8 },
+            Add { rd: 8, rs1: 1, rs2: 1 },
+            Add { rd: 9, rs1: 7, rs2: 0 },
+        ]);
+        assert_eq!(cycles, vec![1, 1, 2, 1, 1, 1]);
+        let stats = &pipeline.stats;
+        assert_eq!((stats.instructions, stats.data_stalls, stats.fill_cycles), (6, 1, 4));
+        assert_eq!(stats.cycles, 4 + 7);
+    }
+
+    /// The divider holds up everything behind it, a multiply only its users.
+    #[test]
+    fn multi_cycle_units() {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        let cycles = run(&mut pipeline, &[
+            Mul { rd: 1, rs1: 2, rs2: 3 },
+            Add { rd: 4, rs1: 2, rs2: 3 },
+            Add { rd: 5, rs1: 1, rs2: 0 },
+            Div { rd: 6, rs1: 2, rs2: 3 },
+            Add { rd: 7, rs1: 2, rs2: 3 },
+            Add { rd: 8, rs1: 6, rs2: 0 },
+        ]);
+        assert_eq!(cycles, vec![1, 1, 2, 20, 1, 1]);
+        assert_eq!((pipeline.stats.data_stalls, pipeline.stats.structural_stalls), (1, 19));
+    }
+
+    /// Only redirected branches and jumps pay, jal less than branches and jalr.
+    #[test]
+    fn control() {
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        let branch = Beq { rs1: 0, rs2: 0, imm: 8 };
+        assert_eq!(pipeline.retire(&branch, false), 1);
+        assert_eq!(pipeline.retire(&branch, true), 3);
+        assert_eq!(pipeline.retire(&Jal { rd: 1, imm: 8 }, true), 2);
+        assert_eq!(pipeline.retire(&Jal { rd: 1, imm: 8 }, false), 1);
+        assert_eq!(pipeline.retire(&Jalr { rd: 0, rs1: 1, imm: 0 }, true), 3);
+        assert_eq!(pipeline.stats.control_stalls, 5);
+    }
+
+    /// A CSR access waits for results in flight and refills the pipeline, unless
+    /// serializing is turned off.
+    #[test]
+    fn serialize() {
+        let insts = [
+            Mul { rd: 1, rs1: 2, rs2: 3 },
+            Csrrs { rd: 5, rs1: 0, csr: 0x300 },
+            Add { rd: 6, rs1: 1, rs2: 0 },
+        ];
+        let mut pipeline = Pipeline::new(PipelineConfig::default());
+        assert_eq!(run(&mut pipeline, &insts), vec![1, 5, 1]);
+        assert_eq!(pipeline.stats.fill_cycles, 4 + 4);
+
+        let mut pipeline = Pipeline::new(PipelineConfig { serialize: false, ..PipelineConfig::default() });
+        // Now the multiply is still running when its result is used
+        assert_eq!(run(&mut pipeline, &insts), vec![1, 1, 2]);
+        assert_eq!(pipeline.stats.fill_cycles, 4);
+        pipeline.reset();
+        assert_eq!((pipeline.stats.instructions, pipeline.stats.cycles), (0, 4));
+    }
+}
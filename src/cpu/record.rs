@@ -0,0 +1,184 @@
+use std::convert::TryInto;
+use std::fs;
+
+use super::{CSR_COUNT, PRV_M, PRV_S, PRV_U};
+
+const RECORD_MAGIC: &[u8; 8] = b"RVRECORD";
+const RECORD_VERSION: u32 = 5;
+
+/// One side effect of an instruction, with the value before and after.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Reg { reg: usize, old: u64, new: u64 },
+    Csr { csr: usize, old: u64, new: u64 },
+    /// A store to memory. Device registers can't be read back, so their stores are `Io`.
+    Mem { addr: usize, size: usize, old: u64, new: u64 },
+    /// A device access: the value a load got from it or a store wrote to it. These are
+    /// never repeated, the `Devices` change of the step carries their effect.
+    Io { addr: usize, size: usize, write: bool, val: u64 },
+    /// The state of the devices around a step that accessed them, as of the given cycle
+    Devices { old: Vec<u8>, new: Vec<u8>, old_now: u64, new_now: u64 },
+    /// The state of the host layers around a step that changed them
+    Host { old: Vec<u8>, new: Vec<u8> },
+    /// The cycle and retired instruction counts
+    Time { old_cycles: u64, new_cycles: u64, old_instret: u64, new_instret: u64 },
+    /// The privilege level, changed by taking a trap or returning from one
+    Priv { old: u64, new: u64 },
+}
+
+/// Everything one executed instruction changed.
+#[derive(Debug, Clone)]
+pub struct StepRecord {
+    pub pc: u64,
+    pub next_pc: u64,
+    pub stopped: bool,
+    pub changes: Vec<Change>,
+}
+
+/// The execution history, with a cursor that moves back on reverse steps and
+/// forward again when recorded steps are replayed.
+#[derive(Debug, Default)]
+pub struct Recording {
+    pub steps: Vec<StepRecord>,
+    pub cursor: usize,
+    /// The device and host state as of the last step that saved them, None until a
+    /// live step needs it. Only steps that touch them save them again.
+    pub outside: Option<Outside>,
+}
+
+/// Serialized device and host state, the devices as of cycle `now`.
+#[derive(Debug)]
+pub struct Outside {
+    pub now: u64,
+    pub devices: Vec<u8>,
+    pub host: Vec<u8>,
+}
+
+/// The lengths of two state blobs, packed into one value.
+fn blob_len(old: &[u8], new: &[u8]) -> u64 {
+    old.len() as u64 | (new.len() as u64) << 32
+}
+
+impl Recording {
+    pub fn is_replaying(&self) -> bool {
+        self.cursor < self.steps.len()
+    }
+
+    /// Drops the steps after the cursor, e.g. because the state was edited by hand.
+    pub fn discard_future(&mut self) {
+        self.steps.truncate(self.cursor);
+    }
+
+    pub fn push(&mut self, step: StepRecord) {
+        self.discard_future();
+        self.steps.push(step);
+        self.cursor = self.steps.len();
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let mut out = vec!();
+        out.extend_from_slice(RECORD_MAGIC);
+        out.extend_from_slice(&RECORD_VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.steps.len() as u64).to_le_bytes());
+        for step in &self.steps {
+            out.extend_from_slice(&step.pc.to_le_bytes());
+            out.extend_from_slice(&step.next_pc.to_le_bytes());
+            out.push(step.stopped as u8);
+            out.extend_from_slice(&(step.changes.len() as u32).to_le_bytes());
+            for change in &step.changes {
+                let (tag, a, b, old, new) = match change {
+                    Change::Reg { reg, old, new } => (0u8, *reg as u64, 0, *old, *new),
+                    Change::Csr { csr, old, new } => (1, *csr as u64, 0, *old, *new),
+                    Change::Mem { addr, size, old, new } => (2, *addr as u64, *size as u64, *old, *new),
+                    Change::Io { addr, size, write, val } => (3, *addr as u64, *size as u64, *write as u64, *val),
+                    Change::Devices { old, new, old_now, new_now } => {
+                        (4, blob_len(old, new), 0, *old_now, *new_now)
+                    }
+                    Change::Host { old, new } => (5, blob_len(old, new), 0, 0, 0),
+                    Change::Time { old_cycles, new_cycles, old_instret, new_instret } => {
+                        (6, *old_instret, *new_instret, *old_cycles, *new_cycles)
+                    }
+                    Change::Priv { old, new } => (7, 0, 0, *old, *new),
+                };
+                out.push(tag);
+                for v in &[a, b, old, new] {
+                    out.extend_from_slice(&v.to_le_bytes());
+                }
+                // State changes are followed by the old and new state
+                if let Change::Devices { old, new, .. } | Change::Host { old, new } = change {
+                    out.extend_from_slice(old);
+                    out.extend_from_slice(new);
+                }
+            }
+        }
+        fs::write(path, out).map_err(|e| format!("Error writing {}: {}", path, e))
+    }
+
+    /// Loads a recording saved with `save`, positioned at its start for replay.
+    pub fn load(path: &str) -> Result<Recording, String> {
+        let data = fs::read(path).map_err(|e| format!("Error opening {}: {}", path, e))?;
+        let mut pos = 0;
+        let mut take = |n: usize| -> Result<&[u8], String> {
+            let b = data.get(pos..pos + n).ok_or_else(|| format!("{} is truncated", path))?;
+            pos += n;
+            Ok(b)
+        };
+        if take(8)? != RECORD_MAGIC {
+            return Err(format!("{} is not a recording", path));
+        }
+        let version = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        if version != RECORD_VERSION {
+            return Err(format!("Unsupported recording version {}", version));
+        }
+        let count = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let mut steps = vec!();
+        for _ in 0..count {
+            let pc = u64::from_le_bytes(take(8)?.try_into().unwrap());
+            let next_pc = u64::from_le_bytes(take(8)?.try_into().unwrap());
+            let stopped = take(1)?[0] != 0;
+            let n = u32::from_le_bytes(take(4)?.try_into().unwrap());
+            let mut changes = vec!();
+            for _ in 0..n {
+                let tag = take(1)?[0];
+                let mut v = [0u64; 4];
+                for x in v.iter_mut() {
+                    *x = u64::from_le_bytes(take(8)?.try_into().unwrap());
+                }
+                let (a, b) = (v[0] as usize, v[1] as usize);
+                changes.push(match tag {
+                    0 if a > 0 && a < 32 => Change::Reg { reg: a, old: v[2], new: v[3] },
+                    1 if a < CSR_COUNT => Change::Csr { csr: a, old: v[2], new: v[3] },
+                    2 | 3 if [8, 16, 32, 64].contains(&b) => {
+                        if tag == 2 {
+                            Change::Mem { addr: a, size: b, old: v[2], new: v[3] }
+                        } else {
+                            Change::Io { addr: a, size: b, write: v[2] != 0, val: v[3] }
+                        }
+                    }
+                    4 | 5 => {
+                        // The lengths of the old and new state are packed into the first value
+                        let old = take(v[0] as u32 as usize)?.to_vec();
+                        let new = take((v[0] >> 32) as usize)?.to_vec();
+                        if tag == 4 {
+                            Change::Devices { old, new, old_now: v[2], new_now: v[3] }
+                        } else {
+                            Change::Host { old, new }
+                        }
+                    }
+                    6 => Change::Time { old_instret: v[0], new_instret: v[1], old_cycles: v[2], new_cycles: v[3] },
+                    7 if [v[2], v[3]].iter().all(|p| [PRV_U, PRV_S, PRV_M].contains(p)) => {
+                        Change::Priv { old: v[2], new: v[3] }
+                    }
+                    t @ (0..=3 | 7) => return Err(format!("Invalid change of type {} in {}: {:X?}", t, path, v)),
+                    t => return Err(format!("Unknown change type {} in {}", t, path)),
+                });
+            }
+            steps.push(StepRecord { pc, next_pc, stopped, changes });
+        }
+        Ok(Self {
+            steps,
+            cursor: 0,
+            outside: None,
+        })
+    }
+}
@@ -1,6 +1,9 @@
 #![allow(dead_code)]
 
 use crate::bus::Device;
+use crate::snapshot::SnapshotReader;
+
+const PAGE_SIZE: usize = 4096;
 
 #[derive(Debug)]
 pub struct DRAM {
@@ -9,30 +12,76 @@ pub struct DRAM {
 
 impl Device for DRAM {
     fn write(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
-        match size {
-            8 => { self.write_8(addr, val as u8) }
-            16 => { self.write_16(addr, val as u16) }
-            32 => { self.write_32(addr, val as u32) }
-            64 => { self.write_64(addr, val) }
-            _ => {
-                Err(())
-            }
-        }
+        let len = access_len(size)?;
+        self.slice_mut(addr, len)?.copy_from_slice(&val.to_le_bytes()[..len]);
+        Ok(())
     }
 
     fn read(&self, addr: usize, size: usize) -> Result<u64, ()> {
-        match size {
-            8 => { self.read_8(addr) }
-            16 => { self.read_16(addr) }
-            32 => { self.read_32(addr) }
-            64 => { self.read_64(addr) }
-            _ => {
-                Err(())
-            }
+        let len = access_len(size)?;
+        let mut bytes = [0; 8];
+        bytes[..len].copy_from_slice(self.slice(addr, len)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), ()> {
+        buf.copy_from_slice(self.slice(addr, buf.len())?);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), ()> {
+        self.slice_mut(addr, data.len())?.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "ram"
+    }
+
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    fn save(&self, out: &mut Vec<u8>) {
+        save_pages(&self.dram, out);
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let size = r.u64()? as usize;
+        if size != self.dram.len() {
+            return Err(format!("Snapshot has {} bytes of memory for a {} byte RAM", size, self.dram.len()));
         }
+        self.dram.fill(0);
+        restore_pages(&mut self.dram, r)
     }
 }
 
+/// Writes the size of `mem` followed by its pages. Only pages containing non-zero
+/// bytes are stored, as most of memory is usually untouched.
+pub fn save_pages(mem: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(mem.len() as u64).to_le_bytes());
+    for (i, page) in mem.chunks(PAGE_SIZE).enumerate() {
+        if page.iter().any(|b| *b != 0) {
+            out.extend_from_slice(&(i as u64).to_le_bytes());
+            out.extend_from_slice(page);
+        }
+    }
+}
+
+/// Copies the pages written by `save_pages` into `mem`, after the size has been read.
+pub fn restore_pages(mem: &mut [u8], r: &mut SnapshotReader) -> Result<(), String> {
+    while r.remaining() > 0 {
+        let page = r.u64()?;
+        let start = (page as usize).saturating_mul(PAGE_SIZE);
+        let len = PAGE_SIZE.min(mem.len().saturating_sub(start));
+        if len == 0 {
+            return Err(format!("Snapshot page {} is outside of memory", page));
+        }
+        mem[start..start + len].copy_from_slice(r.bytes(len)?);
+    }
+    Ok(())
+}
+
 impl DRAM {
     pub fn new(mem_size: usize, buffer: Vec<u8>) -> DRAM {
         let mut memory = vec![0; mem_size];
@@ -42,96 +91,97 @@ impl DRAM {
         }
     }
 
-    pub fn write_8(&mut self, addr: usize, val: u8) -> Result<(), ()> {
-        // TODO: Paging
-        println!("\tWriting \"{:02X}\" to addr 0x{:X}", val, addr);
-        if addr >= self.dram.len() {
-            Err(())
-        } else {
-            self.dram[addr] = val;
-            Ok(())
-        }
+    pub fn size(&self) -> usize {
+        self.dram.len()
     }
 
-    pub fn write_16(&mut self, addr: usize, val: u16) -> Result<(), ()> {
-        let lower = val as u8;
-        let upper = (val>>8) as u8;
-        if self.write_8(addr, lower).is_err() {
-            return Err(());
-        }
-        self.write_8(addr.overflowing_add(1).0, upper)
+    /// The `len` bytes at `addr`, or an error if any of them is outside of memory.
+    fn slice(&self, addr: usize, len: usize) -> Result<&[u8], ()> {
+        let end = addr.checked_add(len).ok_or(())?;
+        self.dram.get(addr..end).ok_or(())
     }
 
-    pub fn write_32(&mut self, addr: usize, val: u32) -> Result<(), ()> {
-        let lower = val as u16;
-        let upper = (val>>16) as u16;
-        if self.write_16(addr, lower).is_err() {
-            return Err(());
-        }
-        self.write_16(addr.overflowing_add(2).0, upper)
+    fn slice_mut(&mut self, addr: usize, len: usize) -> Result<&mut [u8], ()> {
+        let end = addr.checked_add(len).ok_or(())?;
+        self.dram.get_mut(addr..end).ok_or(())
     }
+}
 
-    pub fn write_64(&mut self, addr: usize, val: u64) -> Result<(), ()> {
-        let lower = val as u32;
-        let upper = (val>>32) as u32;
-        if self.write_32(addr, lower).is_err() {
-            return Err(());
-        }
-        self.write_32(addr.overflowing_add(4).0, upper)
+/// Bytes moved by an access of `size` bits.
+pub fn access_len(size: usize) -> Result<usize, ()> {
+    match size {
+        8 | 16 | 32 | 64 => Ok(size / 8),
+        _ => Err(()),
     }
+}
 
-    pub fn read_8(&self, addr: usize) -> Result<u64, ()> {
-        // TODO: Paging
-        if addr >= self.dram.len() {
-            Err(())
-        } else {
-            //println!("\tReading \"{:02X}\" from addr 0x{:X}", self.ram[addr-DRAM_BASE], addr);
-            Ok(self.dram[addr] as u64)
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::BUS;
+    use crate::sparse::SparseMemory;
+
+    /// Every access size is a little-endian view of the same bytes.
+    #[test]
+    fn access_sizes() {
+        let mut dram = DRAM::new(64, vec!());
+        dram.write(8, 64, 0x0807_0605_0403_0201).unwrap();
+        assert_eq!(dram.read(8, 8), Ok(0x01));
+        assert_eq!(dram.read(9, 16), Ok(0x0302));
+        assert_eq!(dram.read(11, 32), Ok(0x0706_0504));
+        assert_eq!(dram.read(8, 64), Ok(0x0807_0605_0403_0201));
+        dram.write(10, 16, 0xAABB).unwrap();
+        assert_eq!(dram.read(8, 64), Ok(0x0807_0605_AABB_0201));
+        assert_eq!(dram.read(8, 24), Err(()));
+        assert_eq!(dram.write(8, 0, 0), Err(()));
     }
 
-    pub fn read_16(&self, addr: usize) -> Result<u64, ()> {
-        let _retval = self.read_8(addr);
-        if _retval.is_err() {
-            return Err(());
-        }
-        let mut retval = _retval.unwrap() as u16;
-        let _add = self.read_8(addr.overflowing_add(1).0);
-        if _add.is_err() {
-            return Err(());
-        }
-        let add = _add.unwrap() as u16;
-        retval = retval | add<<8;
-        Ok(retval as u64)
+    /// Accesses reaching past the end fail without touching memory.
+    #[test]
+    fn bounds() {
+        let mut dram = DRAM::new(64, vec![0xFF; 64]);
+        assert_eq!(dram.read(56, 64), Ok(u64::MAX));
+        assert_eq!(dram.read(60, 64), Err(()));
+        assert_eq!(dram.write(60, 64, 0), Err(()));
+        assert_eq!(dram.read(60, 32), Ok(0xFFFF_FFFF));
+        assert_eq!(dram.read(usize::MAX, 8), Err(()));
+        assert_eq!(dram.write_bytes(63, &[0, 0]), Err(()));
+        assert_eq!(dram.read(63, 8), Ok(0xFF));
+        let mut buf = [0; 8];
+        assert_eq!(dram.read_bytes(usize::MAX - 2, &mut buf), Err(()));
     }
 
-    pub fn read_32(&self, addr: usize) -> Result<u64, ()> {
-        let _retval = self.read_16(addr);
-        if _retval.is_err() {
-            return Err(());
-        }
-        let mut retval = _retval.unwrap() as u32;
-        let _add = self.read_16(addr.overflowing_add(2).0);
-        if _add.is_err() {
-            return Err(());
-        }
-        let add = _add.unwrap() as u32;
-        retval = retval | add<<16;
-        Ok(retval as u64)
+    /// Bulk transfers copy whole ranges and match single accesses.
+    #[test]
+    fn bulk() {
+        let mut dram = DRAM::new(0x2000, vec!());
+        let data: Vec<u8> = (0..=255).cycle().take(0x1000).collect();
+        dram.write_bytes(0x800, &data).unwrap();
+        let mut back = vec![0; 0x1000];
+        dram.read_bytes(0x800, &mut back).unwrap();
+        assert_eq!(back, data);
+        assert_eq!(dram.read(0x8FE, 16), Ok(0xFFFE));
+        assert_eq!(dram.size(), 0x2000);
     }
 
-    pub fn read_64(&self, addr: usize) -> Result<u64, ()> {
-        let _retval = self.read_32(addr);
-        if _retval.is_err() {
-            return Err(());
-        }
-        let mut retval = _retval.unwrap() as u64;
-        let _add = self.read_32(addr.overflowing_add(4).0);
-        if _add.is_err() {
-            return Err(());
-        }
-        let add = _add.unwrap() as u64;
-        retval = retval | add<<32;
-        Ok(retval)
+    /// On the bus, a range inside DRAM or one device goes in one piece, one that
+    /// crosses into another device is split, and one that runs into nothing fails.
+    #[test]
+    fn bus_transfers() {
+        let mut bus = BUS::new(0x1000, 0x3000, vec!());
+        bus.map(0x2000, 0x1000, Box::new(SparseMemory::new(0x1000, vec!())));
+        let data: Vec<u8> = (1..=32).collect();
+        bus.write_bytes(0x1FF0, &data).unwrap();
+        assert_eq!(bus.read(0x1FF0, 8), Ok(1));
+        assert_eq!(bus.read(0x2000, 8), Ok(17));
+        let mut back = [0; 32];
+        bus.read_bytes(0x1FF0, &mut back).unwrap();
+        assert_eq!(&back[..], &data[..]);
+        // The device hides the DRAM behind it
+        assert_eq!(bus.read(0x2FF8, 64), Ok(0));
+        bus.write_bytes(0x2FFC, &[9; 8]).unwrap();
+        assert_eq!(bus.read(0x3000, 32), Ok(0x0909_0909));
+        assert_eq!(bus.write_bytes(0x3FF8, &[1; 16]), Err(()));
+        assert_eq!(bus.read_bytes(0xFF8, &mut back), Err(()));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,501 @@
+// Parsing of the DWARF `.debug_line` section (versions 2 to 5) into address-to-line rows.
+
+use std::convert::TryFrom;
+
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_SET_BASIC_BLOCK: u8 = 7;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+#[derive(Debug, Clone)]
+pub struct LineRow {
+    pub addr: u64,
+    pub file: usize,
+    pub line: u64,
+    pub is_stmt: bool,
+}
+
+/// One contiguous run of rows, ending at the address of its end_sequence entry.
+#[derive(Debug)]
+struct Sequence {
+    rows: Vec<LineRow>,
+    end: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct LineTable {
+    files: Vec<String>,
+    sequences: Vec<Sequence>,
+}
+
+/// The string sections `DW_FORM_strp` and `DW_FORM_line_strp` refer to.
+pub struct StrSections<'a> {
+    pub debug_str: &'a [u8],
+    pub debug_line_str: &'a [u8],
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, String> {
+        let b = *self.data.get(self.pos).ok_or("debug_line truncated")?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let b = self.pos.checked_add(n).and_then(|end| self.data.get(self.pos..end)).ok_or("debug_line truncated")?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    fn uint(&mut self, n: usize) -> Result<u64, String> {
+        let b = self.bytes(n)?;
+        Ok(b.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64))
+    }
+
+    fn uleb(&mut self) -> Result<u64, String> {
+        let mut val = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7F) as u64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, String> {
+        let mut val = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            if shift < 64 {
+                val |= ((b & 0x7F) as i64) << shift;
+            }
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    val |= -1 << shift;
+                }
+                return Ok(val);
+            }
+        }
+    }
+
+    /// The position `len` bytes on, which must not wrap around.
+    fn skip(&self, len: u64) -> Result<usize, String> {
+        usize::try_from(len).ok().and_then(|len| self.pos.checked_add(len))
+            .ok_or_else(|| "debug_line length out of range".to_string())
+    }
+
+    fn cstr(&mut self) -> Result<String, String> {
+        let rest = self.data.get(self.pos..).ok_or("debug_line truncated")?;
+        let end = rest.iter().position(|b| *b == 0).ok_or("Unterminated string in debug_line")?;
+        self.pos += end + 1;
+        Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+    }
+}
+
+fn str_at(section: &[u8], off: u64) -> String {
+    match section.get(off as usize..) {
+        Some(rest) => {
+            let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+            String::from_utf8_lossy(&rest[..end]).into_owned()
+        }
+        None => String::new(),
+    }
+}
+
+fn join_path(dir: &str, file: &str) -> String {
+    if dir.is_empty() || file.starts_with('/') {
+        file.to_string()
+    } else {
+        format!("{}/{}", dir.trim_end_matches('/'), file)
+    }
+}
+
+/// Reads a DWARF 5 entry list, returning (path, directory index) per entry.
+fn read_entries(r: &mut Reader, strs: &StrSections, offset_size: usize) -> Result<Vec<(String, usize)>, String> {
+    let format_count = r.u8()?;
+    let mut formats = vec!();
+    for _ in 0..format_count {
+        formats.push((r.uleb()?, r.uleb()?));
+    }
+    let count = r.uleb()?;
+    let mut entries = vec!();
+    for _ in 0..count {
+        let mut path = String::new();
+        let mut dir = 0;
+        for (content, form) in &formats {
+            let mut val = 0;
+            let mut s = None;
+            match *form {
+                DW_FORM_STRING => s = Some(r.cstr()?),
+                DW_FORM_LINE_STRP => s = Some(str_at(strs.debug_line_str, r.uint(offset_size)?)),
+                DW_FORM_STRP => s = Some(str_at(strs.debug_str, r.uint(offset_size)?)),
+                DW_FORM_UDATA => val = r.uleb()?,
+                DW_FORM_DATA1 => val = r.uint(1)?,
+                DW_FORM_DATA2 => val = r.uint(2)?,
+                DW_FORM_DATA4 => val = r.uint(4)?,
+                DW_FORM_DATA8 => val = r.uint(8)?,
+                DW_FORM_DATA16 => { r.bytes(16)?; }
+                DW_FORM_BLOCK => {
+                    let len = r.uleb()? as usize;
+                    r.bytes(len)?;
+                }
+                f => return Err(format!("Unsupported form 0x{:X} in debug_line header", f)),
+            }
+            match *content {
+                DW_LNCT_PATH => path = s.unwrap_or_default(),
+                DW_LNCT_DIRECTORY_INDEX => dir = val as usize,
+                _ => {}
+            }
+        }
+        entries.push((path, dir));
+    }
+    Ok(entries)
+}
+
+impl LineTable {
+    pub fn parse(data: &[u8], strs: &StrSections) -> Result<LineTable, String> {
+        let mut table = LineTable::default();
+        let mut r = Reader { data, pos: 0 };
+        while r.pos < data.len() {
+            let mut unit_length = r.uint(4)?;
+            let mut offset_size = 4;
+            if unit_length == 0xFFFF_FFFF {
+                unit_length = r.uint(8)?;
+                offset_size = 8;
+            }
+            let unit_end = r.skip(unit_length)?;
+            table.parse_unit(&mut r, strs, offset_size, unit_end)?;
+            r.pos = unit_end;
+        }
+        for seq in table.sequences.iter_mut() {
+            seq.rows.sort_by_key(|row| row.addr);
+        }
+        table.sequences.sort_by_key(|seq| seq.rows[0].addr);
+        Ok(table)
+    }
+
+    fn parse_unit(&mut self, r: &mut Reader, strs: &StrSections, offset_size: usize, unit_end: usize) -> Result<(), String> {
+        let version = r.uint(2)?;
+        if !(2..=5).contains(&version) {
+            return Err(format!("Unsupported debug_line version {}", version));
+        }
+        let mut address_size = 8;
+        if version >= 5 {
+            address_size = r.u8()? as usize;
+            let _segment_selector_size = r.u8()?;
+        }
+        let header_length = r.uint(offset_size)?;
+        let program_start = r.skip(header_length)?;
+        let min_inst_length = r.u8()? as u64;
+        if version >= 4 {
+            let _max_ops_per_inst = r.u8()?;
+        }
+        let default_is_stmt = r.u8()? != 0;
+        let line_base = r.u8()? as i8 as i64;
+        let line_range = r.u8()? as u64;
+        let opcode_base = r.u8()?;
+        let std_lengths = r.bytes(opcode_base.saturating_sub(1) as usize)?.to_vec();
+        if line_range == 0 {
+            return Err("debug_line header has a line_range of 0".to_string());
+        }
+
+        // Map this unit's file numbers to indices into `self.files`
+        let mut files = vec!();
+        if version >= 5 {
+            let dirs = read_entries(r, strs, offset_size)?;
+            for (path, dir) in read_entries(r, strs, offset_size)? {
+                let dir = dirs.get(dir).map(|d| d.0.as_str()).unwrap_or("");
+                files.push(self.add_file(join_path(dir, &path)));
+            }
+        } else {
+            let mut dirs = vec!(String::new());
+            loop {
+                let dir = r.cstr()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(dir);
+            }
+            // File numbers start at 1 before DWARF 5
+            files.push(usize::MAX);
+            loop {
+                let name = r.cstr()?;
+                if name.is_empty() {
+                    break;
+                }
+                let dir = r.uleb()? as usize;
+                r.uleb()?;
+                r.uleb()?;
+                let dir = dirs.get(dir).map(|d| d.as_str()).unwrap_or("");
+                files.push(self.add_file(join_path(dir, &name)));
+            }
+        }
+        r.pos = program_start;
+
+        // The file register starts at 1 in every version, even though DWARF 5 numbers files from 0
+        let reset_file = 1;
+        let mut addr = 0u64;
+        let mut file = reset_file;
+        let mut line = 1i64;
+        let mut is_stmt = default_is_stmt;
+        let mut rows: Vec<LineRow> = vec!();
+        while r.pos < unit_end {
+            let mut emit = false;
+            let op = r.u8()?;
+            if op >= opcode_base {
+                let adj = (op - opcode_base) as u64;
+                addr = addr.wrapping_add(adj / line_range * min_inst_length);
+                line = line.wrapping_add(line_base + (adj % line_range) as i64);
+                emit = true;
+            } else {
+                match op {
+                    0 => {
+                        let len = r.uleb()?;
+                        let end = r.skip(len)?;
+                        match r.u8()? {
+                            DW_LNE_END_SEQUENCE => {
+                                if !rows.is_empty() {
+                                    self.sequences.push(Sequence { rows: std::mem::take(&mut rows), end: addr });
+                                }
+                                addr = 0;
+                                file = reset_file;
+                                line = 1;
+                                is_stmt = default_is_stmt;
+                            }
+                            DW_LNE_SET_ADDRESS => addr = r.uint(address_size)?,
+                            DW_LNE_DEFINE_FILE => {
+                                let name = r.cstr()?;
+                                files.push(self.add_file(name));
+                            }
+                            _ => {}
+                        }
+                        r.pos = end;
+                    }
+                    DW_LNS_COPY => emit = true,
+                    DW_LNS_ADVANCE_PC => addr = addr.wrapping_add(r.uleb()?.wrapping_mul(min_inst_length)),
+                    DW_LNS_ADVANCE_LINE => line = line.wrapping_add(r.sleb()?),
+                    DW_LNS_SET_FILE => file = r.uleb()? as usize,
+                    DW_LNS_SET_COLUMN => { r.uleb()?; }
+                    DW_LNS_NEGATE_STMT => is_stmt = !is_stmt,
+                    DW_LNS_SET_BASIC_BLOCK => {}
+                    DW_LNS_CONST_ADD_PC => {
+                        let adj = (255 - opcode_base) as u64;
+                        addr = addr.wrapping_add(adj / line_range * min_inst_length);
+                    }
+                    DW_LNS_FIXED_ADVANCE_PC => addr = addr.wrapping_add(r.uint(2)?),
+                    op => {
+                        // Skip the operands of opcodes we don't care about
+                        for _ in 0..std_lengths[op as usize - 1] {
+                            r.uleb()?;
+                        }
+                    }
+                }
+            }
+            if emit {
+                rows.push(LineRow {
+                    addr,
+                    file: files.get(file).copied().unwrap_or(usize::MAX),
+                    line: line as u64,
+                    is_stmt,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn add_file(&mut self, path: String) -> usize {
+        match self.files.iter().position(|f| *f == path) {
+            Some(i) => i,
+            None => {
+                self.files.push(path);
+                self.files.len() - 1
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sequences.is_empty()
+    }
+
+    pub fn file_name(&self, file: usize) -> &str {
+        self.files.get(file).map(|f| f.as_str()).unwrap_or("??")
+    }
+
+    /// Finds the row covering `addr`.
+    pub fn find(&self, addr: u64) -> Option<&LineRow> {
+        let seq = self.sequences.iter().find(|s| s.rows[0].addr <= addr && addr < s.end)?;
+        let idx = match seq.rows.binary_search_by_key(&addr, |row| row.addr) {
+            Ok(mut i) => {
+                // Several rows can share an address; the last one describes it
+                while i + 1 < seq.rows.len() && seq.rows[i + 1].addr == addr {
+                    i += 1;
+                }
+                i
+            }
+            Err(i) => i - 1,
+        };
+        Some(&seq.rows[idx])
+    }
+
+    /// Finds the first statement address for `line` in a file whose path ends with `file`.
+    /// If the line has no code, the next line that does is used instead.
+    pub fn addr_of(&self, file: &str, line: u64) -> Option<u64> {
+        let matches = |f: usize| {
+            let path = self.file_name(f);
+            path == file || path.ends_with(&format!("/{}", file))
+        };
+        self.sequences.iter()
+            .flat_map(|s| s.rows.iter())
+            .filter(|row| row.is_stmt && row.line >= line && matches(row.file))
+            .min_by_key(|row| (row.line, row.addr))
+            .map(|row| row.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_STRS: StrSections = StrSections { debug_str: &[], debug_line_str: &[] };
+    /// line_base -5, line_range 14, opcode_base 13 and the standard opcode lengths
+    const PARAMS: [u8; 15] = [0xFB, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+    /// A unit with 32-bit lengths around `header` (after header_length) and `program`.
+    fn unit(version: u16, header: &[u8], program: &[u8]) -> Vec<u8> {
+        let mut prefix = version.to_le_bytes().to_vec();
+        if version >= 5 {
+            prefix.extend_from_slice(&[8, 0]);
+        }
+        let mut body = prefix;
+        body.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        body.extend_from_slice(header);
+        body.extend_from_slice(program);
+        let mut data = (body.len() as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&body);
+        data
+    }
+
+    /// min_inst_length 1, max_ops 1, default_is_stmt 1, then the common parameters
+    fn params() -> Vec<u8> {
+        let mut header = vec!(1, 1, 1);
+        header.extend_from_slice(&PARAMS);
+        header
+    }
+
+    fn set_address(addr: u64) -> Vec<u8> {
+        let mut op = vec!(0, 9, DW_LNE_SET_ADDRESS);
+        op.extend_from_slice(&addr.to_le_bytes());
+        op
+    }
+
+    fn row(table: &LineTable, addr: u64) -> Option<(&str, u64)> {
+        table.find(addr).map(|row| (table.file_name(row.file), row.line))
+    }
+
+    #[test]
+    fn version4() {
+        let mut header = params();
+        header.extend_from_slice(b"dir\0\0a.c\0\x01\0\0b.c\0\0\0\0\0");
+        let mut program = set_address(0x8000_0000);
+        program.push(DW_LNS_COPY);
+        // Special opcode: 4 bytes and one line on
+        program.push(13 + (1 + 5) + 14 * 4);
+        program.extend_from_slice(&[DW_LNS_SET_FILE, 2, DW_LNS_ADVANCE_LINE, 9, DW_LNS_ADVANCE_PC, 4, DW_LNS_COPY]);
+        program.extend_from_slice(&[DW_LNS_ADVANCE_PC, 4, 0, 1, DW_LNE_END_SEQUENCE]);
+        let table = LineTable::parse(&unit(4, &header, &program), &NO_STRS).unwrap();
+        assert_eq!(row(&table, 0x8000_0000), Some(("dir/a.c", 1)));
+        assert_eq!(row(&table, 0x8000_0006), Some(("dir/a.c", 2)));
+        assert_eq!(row(&table, 0x8000_000B), Some(("b.c", 11)));
+        assert_eq!(row(&table, 0x8000_000C), None);
+        assert_eq!(table.addr_of("a.c", 2), Some(0x8000_0004));
+        assert_eq!(table.addr_of("b.c", 5), Some(0x8000_0008));
+    }
+
+    /// Files are numbered from 0 in DWARF 5, but the program still starts with file 1.
+    #[test]
+    fn version5() {
+        let mut header = params();
+        // Directories: path as a string
+        header.extend_from_slice(&[1, DW_LNCT_PATH as u8, DW_FORM_STRING as u8, 2]);
+        header.extend_from_slice(b"/src\0inc\0");
+        // Files: path as a string, directory as a ULEB
+        header.extend_from_slice(&[2, DW_LNCT_PATH as u8, DW_FORM_STRING as u8]);
+        header.extend_from_slice(&[DW_LNCT_DIRECTORY_INDEX as u8, DW_FORM_UDATA as u8, 2]);
+        header.extend_from_slice(b"main.c\0\0util.h\0\x01");
+        let mut program = set_address(0x1000);
+        program.push(DW_LNS_COPY);
+        program.extend_from_slice(&[DW_LNS_SET_FILE, 0, DW_LNS_ADVANCE_PC, 8, DW_LNS_COPY]);
+        program.extend_from_slice(&[DW_LNS_ADVANCE_PC, 4, 0, 1, DW_LNE_END_SEQUENCE]);
+        let table = LineTable::parse(&unit(5, &header, &program), &NO_STRS).unwrap();
+        assert_eq!(row(&table, 0x1000), Some(("inc/util.h", 1)));
+        assert_eq!(row(&table, 0x1008), Some(("/src/main.c", 1)));
+        assert_eq!(row(&table, 0x100C), None);
+    }
+
+    /// Lengths from the section that run off its end are errors, not panics.
+    #[test]
+    fn corrupt() {
+        let mut header = params();
+        header.extend_from_slice(b"\0a.c\0\0\0\0\0");
+        let mut program = set_address(0x1000);
+        program.extend_from_slice(&[DW_LNS_COPY, 0, 1, DW_LNE_END_SEQUENCE]);
+        let good = unit(4, &header, &program);
+        assert!(LineTable::parse(&good, &NO_STRS).is_ok());
+        for len in 1..good.len() {
+            assert!(LineTable::parse(&good[..len], &NO_STRS).is_err(), "truncated to {}", len);
+        }
+        // A 64-bit unit length and a header length that wrap around
+        let mut data = vec!(0xFF, 0xFF, 0xFF, 0xFF);
+        data.extend_from_slice(&u64::MAX.to_le_bytes());
+        data.extend_from_slice(&4u16.to_le_bytes());
+        data.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert!(LineTable::parse(&data, &NO_STRS).is_err());
+        data[4..12].copy_from_slice(&100u64.to_le_bytes());
+        assert!(LineTable::parse(&data, &NO_STRS).is_err());
+        // An extended opcode that claims to be huge
+        let mut program = set_address(0x1000);
+        program.extend_from_slice(&[0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01, 0]);
+        assert!(LineTable::parse(&unit(4, &header, &program), &NO_STRS).is_err());
+        // Advancing the line and address by huge amounts wraps
+        let mut program = set_address(0x1000);
+        program.extend_from_slice(&[DW_LNS_ADVANCE_LINE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00]);
+        program.extend_from_slice(&[DW_LNS_ADVANCE_PC, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x01]);
+        program.extend_from_slice(&[DW_LNS_COPY, 0, 1, DW_LNE_END_SEQUENCE]);
+        assert!(LineTable::parse(&unit(4, &header, &program), &NO_STRS).is_ok());
+    }
+}
@@ -0,0 +1,422 @@
+#![allow(dead_code)]
+
+use line::{LineTable, StrSections};
+
+pub mod line;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_RISCV: u16 = 0xF3;
+
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const STT_SECTION: u8 = 3;
+const STT_FILE: u8 = 4;
+const SHN_UNDEF: u16 = 0;
+const PHDR_SIZE: usize = 56;
+const SHDR_SIZE: usize = 64;
+
+#[derive(Debug)]
+pub struct Segment {
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub offset: usize,
+    pub file_size: usize,
+    pub mem_size: usize,
+}
+
+#[derive(Debug)]
+pub struct Section {
+    pub name: String,
+    pub addr: u64,
+    pub offset: usize,
+    pub size: usize,
+    pub kind: u32,
+    pub link: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialOrd, PartialEq)]
+pub enum SymbolKind {
+    Func,
+    Object,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+    pub kind: SymbolKind,
+}
+
+/// Symbols sorted by address, for name lookup and `function+offset` resolution.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> SymbolTable {
+        // Drop assembler-local labels and mapping symbols
+        symbols.retain(|s| !s.name.is_empty() && !s.name.starts_with(".L") && !s.name.starts_with('$'));
+        symbols.sort_by_key(|s| s.addr);
+        Self {
+            symbols,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|s| s.name == name).map(|s| s.addr)
+    }
+
+    /// Finds the symbol containing `addr`, returning it with the offset into it.
+    pub fn resolve(&self, addr: u64) -> Option<(&Symbol, u64)> {
+        let idx = match self.symbols.binary_search_by_key(&addr, |s| s.addr) {
+            Ok(mut i) => {
+                // Prefer the last of several symbols sharing an address
+                while i + 1 < self.symbols.len() && self.symbols[i + 1].addr == addr {
+                    i += 1;
+                }
+                i
+            }
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let sym = &self.symbols[idx];
+        let offset = addr - sym.addr;
+        if sym.size != 0 && offset >= sym.size {
+            return None;
+        }
+        Some((sym, offset))
+    }
+
+    /// Formats `addr` as `<name+offset>`, or an empty string if no symbol covers it.
+    pub fn describe(&self, addr: u64) -> String {
+        match self.resolve(addr) {
+            Some((sym, 0)) => format!("<{}>", sym.name),
+            Some((sym, off)) => format!("<{}+{}>", sym.name, off),
+            None => String::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Elf {
+    pub entry: u64,
+    pub phoff: u64,
+    pub phentsize: usize,
+    pub phnum: usize,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+    pub symbols: SymbolTable,
+    data: Vec<u8>,
+}
+
+fn read_u16(data: &[u8], off: usize) -> Result<u16, String> {
+    off.checked_add(2).and_then(|end| data.get(off..end))
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| format!("ELF truncated at 0x{:X}", off))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32, String> {
+    off.checked_add(4).and_then(|end| data.get(off..end))
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| format!("ELF truncated at 0x{:X}", off))
+}
+
+fn read_u64(data: &[u8], off: usize) -> Result<u64, String> {
+    off.checked_add(8).and_then(|end| data.get(off..end))
+        .map(|b| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+        .ok_or_else(|| format!("ELF truncated at 0x{:X}", off))
+}
+
+/// The offset of entry `i` of a table of `size` byte entries at `base`.
+fn entry_offset(base: usize, i: usize, size: usize) -> Result<usize, String> {
+    i.checked_mul(size)
+        .and_then(|off| base.checked_add(off))
+        .ok_or_else(|| format!("ELF table at 0x{:X} out of range", base))
+}
+
+/// Checks that a table of `num` entries of `size` bytes at `base` lies inside the file
+/// and that its entries hold at least `min` bytes, so no field offset can overflow.
+fn check_table(data: &[u8], base: usize, num: usize, size: usize, min: usize) -> Result<(), String> {
+    if num > 0 && (size < min || entry_offset(base, num, size)? > data.len()) {
+        return Err(format!("ELF table at 0x{:X} out of range", base));
+    }
+    Ok(())
+}
+
+fn read_str(data: &[u8], off: usize) -> String {
+    match data.get(off..) {
+        Some(rest) => {
+            let end = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+            String::from_utf8_lossy(&rest[..end]).into_owned()
+        }
+        None => String::new(),
+    }
+}
+
+impl Elf {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.len() >= 4 && data[..4] == ELF_MAGIC
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Elf, String> {
+        if !Elf::is_elf(&data) {
+            return Err("Not an ELF file".to_string());
+        }
+        if data.len() < 64 || data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB {
+            return Err("Only little-endian ELF64 is supported".to_string());
+        }
+        if read_u16(&data, 18)? != EM_RISCV {
+            return Err("Not a RISC-V ELF".to_string());
+        }
+        let entry = read_u64(&data, 24)?;
+        let phoff = read_u64(&data, 32)? as usize;
+        let shoff = read_u64(&data, 40)? as usize;
+        let phentsize = read_u16(&data, 54)? as usize;
+        let phnum = read_u16(&data, 56)? as usize;
+        let shentsize = read_u16(&data, 58)? as usize;
+        let shnum = read_u16(&data, 60)? as usize;
+        let shstrndx = read_u16(&data, 62)? as usize;
+        check_table(&data, phoff, phnum, phentsize, PHDR_SIZE)?;
+        check_table(&data, shoff, shnum, shentsize, SHDR_SIZE)?;
+
+        let mut segments = vec!();
+        for i in 0..phnum {
+            let ph = entry_offset(phoff, i, phentsize)?;
+            if read_u32(&data, ph)? != PT_LOAD {
+                continue;
+            }
+            let seg = Segment {
+                offset: read_u64(&data, ph + 8)? as usize,
+                vaddr: read_u64(&data, ph + 16)?,
+                paddr: read_u64(&data, ph + 24)?,
+                file_size: read_u64(&data, ph + 32)? as usize,
+                mem_size: read_u64(&data, ph + 40)? as usize,
+            };
+            if seg.offset.saturating_add(seg.file_size) > data.len() {
+                return Err(format!("Segment at 0x{:X} extends past end of file", seg.vaddr));
+            }
+            segments.push(seg);
+        }
+
+        let mut sections = vec!();
+        for i in 0..shnum {
+            let sh = entry_offset(shoff, i, shentsize)?;
+            sections.push(Section {
+                name: String::new(),
+                kind: read_u32(&data, sh + 4)?,
+                addr: read_u64(&data, sh + 16)?,
+                offset: read_u64(&data, sh + 24)? as usize,
+                size: read_u64(&data, sh + 32)? as usize,
+                link: read_u32(&data, sh + 40)?,
+            });
+            let name_off = read_u32(&data, sh)? as usize;
+            if shstrndx < shnum {
+                let strtab = read_u64(&data, entry_offset(shoff, shstrndx, shentsize)?.saturating_add(24))? as usize;
+                sections[i].name = read_str(&data, strtab.saturating_add(name_off));
+            }
+        }
+
+        let mut symbols = vec!();
+        for sec in sections.iter().filter(|s| s.kind == SHT_SYMTAB) {
+            let strtab = match sections.get(sec.link as usize) {
+                Some(s) => s.offset,
+                None => continue,
+            };
+            match sec.offset.checked_add(sec.size) {
+                Some(end) if end <= data.len() => {}
+                _ => return Err("Symbol table extends past end of file".to_string()),
+            }
+            for i in 0..sec.size / 24 {
+                let sym = sec.offset + i * 24;
+                let info = *data.get(sym + 4).ok_or("Symbol table truncated")?;
+                let kind = match info & 0xF {
+                    STT_FUNC => SymbolKind::Func,
+                    STT_OBJECT => SymbolKind::Object,
+                    STT_SECTION | STT_FILE => continue,
+                    _ => SymbolKind::Other,
+                };
+                if read_u16(&data, sym + 6)? == SHN_UNDEF {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name: read_str(&data, strtab.saturating_add(read_u32(&data, sym)? as usize)),
+                    addr: read_u64(&data, sym + 8)?,
+                    size: read_u64(&data, sym + 16)?,
+                    kind,
+                });
+            }
+        }
+
+        Ok(Self {
+            entry,
+            phoff: phoff as u64,
+            phentsize,
+            phnum,
+            segments,
+            sections,
+            symbols: SymbolTable::new(symbols),
+            data,
+        })
+    }
+
+    pub fn segment_data(&self, seg: &Segment) -> &[u8] {
+        &self.data[seg.offset..seg.offset + seg.file_size]
+    }
+
+    /// Returns the contents of the section called `name`, if present.
+    pub fn section(&self, name: &str) -> Option<&[u8]> {
+        self.sections.iter()
+            .find(|s| s.name == name)
+            .and_then(|s| self.data.get(s.offset..s.offset.checked_add(s.size)?))
+    }
+
+    /// Parses the `.debug_line` section, or returns an empty table if there is none.
+    pub fn line_table(&self) -> Result<LineTable, String> {
+        match self.section(".debug_line") {
+            Some(data) => {
+                let strs = StrSections {
+                    debug_str: self.section(".debug_str").unwrap_or(&[]),
+                    debug_line_str: self.section(".debug_line_str").unwrap_or(&[]),
+                };
+                LineTable::parse(data, &strs)
+            }
+            None => Ok(LineTable::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHOFF: usize = 0x1A0;
+
+    fn put(data: &mut Vec<u8>, off: usize, bytes: &[u8]) {
+        if data.len() < off + bytes.len() {
+            data.resize(off + bytes.len(), 0);
+        }
+        data[off..off + bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Section header `i`, with its address, offset and size in `place`.
+    fn section(data: &mut Vec<u8>, i: usize, name: u32, kind: u32, place: [u64; 3], link: u32) {
+        let sh = SHOFF + i * SHDR_SIZE;
+        let [addr, offset, size] = place;
+        put(data, sh, &name.to_le_bytes());
+        put(data, sh + 4, &kind.to_le_bytes());
+        put(data, sh + 16, &addr.to_le_bytes());
+        put(data, sh + 24, &offset.to_le_bytes());
+        put(data, sh + 32, &size.to_le_bytes());
+        put(data, sh + 40, &link.to_le_bytes());
+    }
+
+    fn symbol(data: &mut Vec<u8>, off: usize, name: u32, info: u8, shndx: u16, addr: u64, size: u64) {
+        put(data, off, &name.to_le_bytes());
+        put(data, off + 4, &[info]);
+        put(data, off + 6, &shndx.to_le_bytes());
+        put(data, off + 8, &addr.to_le_bytes());
+        put(data, off + 16, &size.to_le_bytes());
+    }
+
+    /// An executable with one loadable segment, a function `main` and an object `value`.
+    fn elf() -> Vec<u8> {
+        let mut data = vec!();
+        put(&mut data, 0, &ELF_MAGIC);
+        put(&mut data, 4, &[ELFCLASS64, ELFDATA2LSB, 1]);
+        put(&mut data, 18, &EM_RISCV.to_le_bytes());
+        put(&mut data, 24, &0x8000_0000u64.to_le_bytes());
+        put(&mut data, 32, &64u64.to_le_bytes());
+        put(&mut data, 40, &(SHOFF as u64).to_le_bytes());
+        for (off, v) in [(54, PHDR_SIZE), (56, 1), (58, SHDR_SIZE), (60, 5), (62, 4)] {
+            put(&mut data, off, &(v as u16).to_le_bytes());
+        }
+        // The program header
+        put(&mut data, 64, &PT_LOAD.to_le_bytes());
+        for (off, v) in [(8, 0x100u64), (16, 0x8000_0000), (24, 0x8000_0000), (32, 8), (40, 0x20)] {
+            put(&mut data, 64 + off, &v.to_le_bytes());
+        }
+        put(&mut data, 0x100, &[0x13, 0, 0, 0, 0x6F, 0, 0, 0]);
+        put(&mut data, 0x108, b"\0main\0value\0");
+        symbol(&mut data, 0x138, 1, STT_FUNC, 1, 0x8000_0000, 8);
+        symbol(&mut data, 0x150, 6, STT_OBJECT, 1, 0x8000_0010, 4);
+        put(&mut data, 0x168, b"\0.text\0.symtab\0.strtab\0.shstrtab\0");
+        section(&mut data, 1, 1, 1, [0x8000_0000, 0x100, 8], 0);
+        section(&mut data, 2, 7, SHT_SYMTAB, [0, 0x120, 72], 3);
+        section(&mut data, 3, 15, 3, [0, 0x108, 12], 0);
+        section(&mut data, 4, 23, 3, [0, 0x168, 33], 0);
+        data.resize(SHOFF + 5 * SHDR_SIZE, 0);
+        data
+    }
+
+    #[test]
+    fn parse() {
+        let elf = Elf::parse(elf()).unwrap();
+        assert_eq!(elf.entry, 0x8000_0000);
+        assert_eq!(elf.segments.len(), 1);
+        assert_eq!(elf.segment_data(&elf.segments[0]), &[0x13, 0, 0, 0, 0x6F, 0, 0, 0]);
+        assert_eq!(elf.segments[0].mem_size, 0x20);
+        let names: Vec<&str> = elf.sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["", ".text", ".symtab", ".strtab", ".shstrtab"]);
+        assert_eq!(elf.section(".text").unwrap().len(), 8);
+        assert_eq!(elf.symbols.lookup("main"), Some(0x8000_0000));
+        assert_eq!(elf.symbols.lookup("value"), Some(0x8000_0010));
+    }
+
+    /// Offsets and sizes from the file that run off its end are errors, not panics.
+    #[test]
+    fn parse_corrupt() {
+        let corrupt = |off: usize, bytes: &[u8]| {
+            let mut data = elf();
+            put(&mut data, off, bytes);
+            Elf::parse(data)
+        };
+        assert!(corrupt(40, &(usize::MAX as u64 - 8).to_le_bytes()).is_err());
+        assert!(corrupt(40, &0x1000u64.to_le_bytes()).is_err());
+        assert!(corrupt(32, &u64::MAX.to_le_bytes()).is_err());
+        assert!(corrupt(58, &8u16.to_le_bytes()).is_err());
+        assert!(corrupt(54, &8u16.to_le_bytes()).is_err());
+        assert!(corrupt(64 + 32, &u64::MAX.to_le_bytes()).is_err());
+        assert!(corrupt(SHOFF + 2 * SHDR_SIZE + 24, &u64::MAX.to_le_bytes()).is_err());
+        assert!(corrupt(18, &0x3Eu16.to_le_bytes()).is_err());
+        let mut data = elf();
+        data.truncate(SHOFF + 100);
+        assert!(Elf::parse(data).is_err());
+        assert!(Elf::parse(elf()[..40].to_vec()).is_err());
+    }
+
+    fn sym(name: &str, addr: u64, size: u64) -> Symbol {
+        Symbol { name: name.to_string(), addr, size, kind: SymbolKind::Func }
+    }
+
+    #[test]
+    fn resolve() {
+        let table = SymbolTable::new(vec!(
+            sym("b", 0x200, 0x10), sym("a", 0x100, 0x20), sym("alias", 0x200, 0),
+            sym("open", 0x300, 0), sym(".Llocal", 0x180, 0), sym("$x", 0x100, 0),
+        ));
+        assert!(table.resolve(0xFF).is_none());
+        assert_eq!(table.resolve(0x100).map(|(s, off)| (s.name.as_str(), off)), Some(("a", 0)));
+        assert_eq!(table.resolve(0x11F).map(|(s, off)| (s.name.as_str(), off)), Some(("a", 0x1F)));
+        // Past the end of a sized symbol, and the local label doesn't count
+        assert!(table.resolve(0x180).is_none());
+        // The last of several symbols at one address wins
+        assert_eq!(table.resolve(0x208).map(|(s, off)| (s.name.as_str(), off)), Some(("alias", 8)));
+        // A symbol without a size covers everything after it
+        assert_eq!(table.resolve(0x1000).map(|(s, off)| (s.name.as_str(), off)), Some(("open", 0xD00)));
+        assert_eq!(table.describe(0x104), "<a+4>");
+        assert_eq!(table.describe(0x300), "<open>");
+        assert_eq!(table.describe(0x10), "");
+        assert_eq!(table.lookup("$x"), None);
+    }
+}
@@ -0,0 +1,103 @@
+// Virtual time and the event queue devices schedule wake-ups on. Devices are only
+// ticked when an event they asked for comes due instead of after every instruction,
+// and as time counts instructions rather than following the host clock, their
+// timing is the same on every run.
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+use crate::sbi::TIMEBASE_FREQ;
+
+/// Emulated cycles per second. Every instruction takes one cycle.
+pub const CPU_FREQ: u64 = 100_000_000;
+const CYCLES_PER_TICK: u64 = CPU_FREQ / TIMEBASE_FREQ;
+
+/// The value of the `time` counter (and the CLINT's mtime) at cycle `cycles`.
+pub fn time_at(cycles: u64) -> u64 {
+    cycles / CYCLES_PER_TICK
+}
+
+/// The first cycle at which the `time` counter reads `time`.
+pub fn cycle_of(time: u64) -> u64 {
+    time.saturating_mul(CYCLES_PER_TICK)
+}
+
+/// Pending wake-ups, ordered by time and then by device so runs are repeatable.
+#[derive(Debug, Default)]
+pub struct EventQueue {
+    now: u64,
+    events: BTreeSet<(u64, usize)>,
+}
+
+impl EventQueue {
+    pub fn new() -> Rc<RefCell<EventQueue>> {
+        Rc::new(RefCell::new(EventQueue::default()))
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn set_now(&mut self, now: u64) {
+        self.now = now;
+    }
+
+    /// Wakes `device` at cycle `time`, or as soon as possible if that has passed.
+    /// Asking twice for the same time wakes it once.
+    pub fn schedule(&mut self, time: u64, device: usize) {
+        self.events.insert((time, device));
+    }
+
+    /// Removes the next event due by now and returns its device.
+    pub fn pop_due(&mut self) -> Option<usize> {
+        match self.events.first() {
+            Some(&(time, device)) if time <= self.now => {
+                self.events.remove(&(time, device));
+                Some(device)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+/// A device's handle on the event queue, given to it when it is mapped on the bus.
+#[derive(Debug, Clone)]
+pub struct Events {
+    queue: Rc<RefCell<EventQueue>>,
+    device: usize,
+}
+
+impl Events {
+    pub fn new(queue: Rc<RefCell<EventQueue>>, device: usize) -> Events {
+        Self {
+            queue,
+            device,
+        }
+    }
+
+    /// A handle for a device that isn't on a bus yet. Its events never fire.
+    pub fn detached() -> Events {
+        Events::new(EventQueue::new(), 0)
+    }
+
+    /// The current time in cycles.
+    pub fn now(&self) -> u64 {
+        self.queue.borrow().now()
+    }
+
+    /// Ticks the device at cycle `time`.
+    pub fn schedule_at(&self, time: u64) {
+        self.queue.borrow_mut().schedule(time, self.device);
+    }
+
+    /// Ticks the device `delay` cycles from now. A delay of 0 ticks it before the next instruction.
+    pub fn schedule_in(&self, delay: u64) {
+        let now = self.now();
+        self.schedule_at(now.saturating_add(delay));
+    }
+}
@@ -0,0 +1,136 @@
+// Flattened device tree (DTB) writer, following the devicetree specification v0.4.
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+// Phandles of the nodes other nodes refer to
+pub const PHANDLE_CPU_INTC: u32 = 1;
+pub const PHANDLE_PLIC: u32 = 2;
+pub const PHANDLE_SYSCON: u32 = 3;
+
+/// Builds a device tree node by node. Nodes are opened with `begin_node`, get
+/// their properties and children, and are closed with `end_node`.
+pub struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    depth: usize,
+}
+
+impl FdtWriter {
+    pub fn new() -> FdtWriter {
+        Self {
+            structure: vec!(),
+            strings: vec!(),
+            depth: 0,
+        }
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let nameoff = self.string_offset(name);
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(nameoff);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, val: u32) {
+        self.property(name, &val.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, val: u64) {
+        self.property(name, &val.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes: Vec<u8> = cells.iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property(name, &bytes);
+    }
+
+    /// A `reg` property with two address and two size cells.
+    pub fn property_reg(&mut self, base: u64, size: u64) {
+        let bytes: Vec<u8> = [base, size].iter().flat_map(|c| c.to_be_bytes()).collect();
+        self.property("reg", &bytes);
+    }
+
+    pub fn property_string(&mut self, name: &str, val: &str) {
+        self.property_strings(name, &[val]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, vals: &[&str]) {
+        let mut bytes = vec!();
+        for v in vals {
+            bytes.extend_from_slice(v.as_bytes());
+            bytes.push(0);
+        }
+        self.property(name, &bytes);
+    }
+
+    /// Closes the tree and lays out the blob: header, empty reservation map, structure and strings.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "unclosed device tree node");
+        self.token(FDT_END);
+        let rsvmap = HEADER_SIZE;
+        let rsvmap_size = 16;
+        let off_struct = rsvmap + rsvmap_size;
+        let off_strings = off_struct + self.structure.len();
+        let total = off_strings + self.strings.len();
+        let header = [
+            FDT_MAGIC, total as u32, off_struct as u32, off_strings as u32, rsvmap as u32,
+            FDT_VERSION, FDT_LAST_COMP_VERSION, 0, self.strings.len() as u32, self.structure.len() as u32,
+        ];
+        let mut out: Vec<u8> = header.iter().flat_map(|v| v.to_be_bytes()).collect();
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&self.structure);
+        out.extend_from_slice(&self.strings);
+        out
+    }
+
+    fn token(&mut self, val: u32) {
+        self.structure.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    /// Property names are stored once in the strings block.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let needle: Vec<u8> = name.bytes().chain(Some(0)).collect();
+        let mut start = 0;
+        for s in self.strings.split_inclusive(|b| *b == 0) {
+            if s == needle.as_slice() {
+                return start as u32;
+            }
+            start += s.len();
+        }
+        self.strings.extend_from_slice(&needle);
+        start as u32
+    }
+}
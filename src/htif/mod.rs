@@ -0,0 +1,184 @@
+// Berkeley Host-Target Interface, as used by Spike, riscv-tests and the proxy kernel.
+// The program writes a command to `tohost`: the device in bits 63:56, the command
+// in 55:48 and a payload in 47:0. Responses are written to `fromhost`.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+use crate::cpu::CPU;
+use crate::linux::LinuxUser;
+use crate::snapshot::{put_option, SnapshotReader};
+
+const DEV_SYSCALL: u64 = 0;
+const DEV_CONSOLE: u64 = 1;
+
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+const PAYLOAD_MASK: u64 = (1 << 48) - 1;
+
+#[derive(Debug)]
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    proxy: LinuxUser,
+    exit_code: Option<i32>,
+}
+
+impl Htif {
+    /// Proxied brk and mmap hand out memory between `heap_start`, the end of the
+    /// program, and `heap_end`, below its stack.
+    pub fn new(tohost: u64, fromhost: Option<u64>, heap_start: u64, heap_end: u64) -> Htif {
+        Self {
+            tohost,
+            fromhost,
+            proxy: LinuxUser::new(heap_start, heap_end),
+            exit_code: None,
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Saves the mailbox addresses, exit status and the proxy's open files.
+    pub fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.tohost.to_le_bytes());
+        put_option(out, self.fromhost);
+        put_option(out, self.exit_code.map(|c| c as u64));
+        self.proxy.save(out);
+    }
+
+    pub fn restore(r: &mut SnapshotReader) -> Result<Htif, String> {
+        Ok(Self {
+            tohost: r.u64()?,
+            fromhost: r.option()?,
+            exit_code: r.option()?.map(|c| c as i32),
+            proxy: LinuxUser::restore(r)?,
+        })
+    }
+
+    /// Whether the program has written a command to `tohost` for `poll` to handle.
+    pub fn requested(&self, cpu: &CPU) -> bool {
+        !matches!(cpu.read_mem(self.tohost as usize, 64), Ok(0) | Err(_))
+    }
+
+    /// Handles a pending command in `tohost`, if there is one. Returns false once the program exited.
+    pub fn poll(&mut self, cpu: &mut CPU) -> Result<bool, String> {
+        let cmd = match cpu.read_mem(self.tohost as usize, 64) {
+            Ok(0) | Err(_) => return Ok(true),
+            Ok(cmd) => cmd,
+        };
+        let (dev, op, payload) = (cmd >> 56, (cmd >> 48) & 0xFF, cmd & PAYLOAD_MASK);
+        Htif::store(cpu, self.tohost, 0)?;
+        let resp = match (dev, op) {
+            (DEV_SYSCALL, 0) if payload & 1 == 1 => {
+                self.exit_code = Some((payload >> 1) as i32);
+                return Ok(false);
+            }
+            (DEV_SYSCALL, 0) => {
+                // pk style syscall: a buffer of the number and seven arguments, the result goes in its first word
+                let buf = cpu.read_bytes(payload as usize, 64)
+                    .map_err(|_| format!("HTIF syscall buffer at 0x{:X} is unmapped", payload))?;
+                let args: Vec<u64> = buf.chunks(8).map(|c| u64::from_le_bytes(c.try_into().unwrap())).collect();
+                match self.proxy.dispatch(cpu, args[0], &args[1..])? {
+                    Some(ret) => Htif::store(cpu, payload, ret)?,
+                    None => {
+                        self.exit_code = self.proxy.exit_code();
+                        return Ok(false);
+                    }
+                }
+                1
+            }
+            (DEV_CONSOLE, CONSOLE_PUTCHAR) => {
+                let _ = io::stdout().write_all(&[payload as u8]);
+                let _ = io::stdout().flush();
+                0x100 | (payload & 0xFF)
+            }
+            (DEV_CONSOLE, CONSOLE_GETCHAR) => {
+                let mut c = [0u8; 1];
+                match io::stdin().read(&mut c) {
+                    Ok(1) => c[0] as u64,
+                    _ => PAYLOAD_MASK,
+                }
+            }
+            _ => {
+                println!("Unknown HTIF command: device {} command {} payload 0x{:X}", dev, op, payload);
+                return Ok(true);
+            }
+        };
+        if let Some(fromhost) = self.fromhost {
+            Htif::store(cpu, fromhost, dev << 56 | op << 48 | resp)?;
+        }
+        Ok(true)
+    }
+
+    fn store(cpu: &mut CPU, addr: u64, val: u64) -> Result<(), String> {
+        cpu.store_bytes(addr as usize, &val.to_le_bytes())
+            .map_err(|_| format!("HTIF write to unmapped address 0x{:X}", addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOHOST: u64 = 0x1000;
+    const FROMHOST: u64 = 0x1040;
+
+    fn setup(fromhost: Option<u64>) -> (CPU, Htif) {
+        let cpu = CPU::with_memory(0, 0x10000, vec!());
+        (cpu, Htif::new(TOHOST, fromhost, 0x8000, 0xC000))
+    }
+
+    /// Writes `cmd` to tohost and polls.
+    fn send(htif: &mut Htif, cpu: &mut CPU, cmd: u64) -> bool {
+        cpu.store_bytes(TOHOST as usize, &cmd.to_le_bytes()).unwrap();
+        assert!(htif.requested(cpu));
+        htif.poll(cpu).unwrap()
+    }
+
+    /// Nothing happens until tohost is written, then the command is taken and
+    /// answered in fromhost.
+    #[test]
+    fn console() {
+        let (mut cpu, mut htif) = setup(Some(FROMHOST));
+        assert!(!htif.requested(&cpu));
+        assert!(htif.poll(&mut cpu).unwrap());
+        assert_eq!(cpu.read_mem(FROMHOST as usize, 64), Ok(0));
+
+        assert!(send(&mut htif, &mut cpu, DEV_CONSOLE << 56 | CONSOLE_PUTCHAR << 48 | b'x' as u64));
+        assert_eq!(cpu.read_mem(TOHOST as usize, 64), Ok(0));
+        assert_eq!(cpu.read_mem(FROMHOST as usize, 64), Ok(DEV_CONSOLE << 56 | CONSOLE_PUTCHAR << 48 | 0x100 | b'x' as u64));
+        assert!(!htif.requested(&cpu));
+    }
+
+    /// Without a fromhost address commands are still taken, just not answered.
+    #[test]
+    fn no_fromhost() {
+        let (mut cpu, mut htif) = setup(None);
+        assert!(send(&mut htif, &mut cpu, DEV_CONSOLE << 56 | CONSOLE_PUTCHAR << 48 | b'\n' as u64));
+        assert_eq!(cpu.read_mem(TOHOST as usize, 64), Ok(0));
+        assert_eq!(cpu.read_mem(FROMHOST as usize, 64), Ok(0));
+    }
+
+    /// A proxied syscall gets its result in the first word of its buffer.
+    #[test]
+    fn syscall() {
+        let (mut cpu, mut htif) = setup(Some(FROMHOST));
+        // brk(0) reports the start of the heap
+        let args: Vec<u8> = [214u64, 0, 0, 0, 0, 0, 0, 0].iter().flat_map(|a| a.to_le_bytes()).collect();
+        cpu.store_bytes(0x2000, &args).unwrap();
+        assert!(send(&mut htif, &mut cpu, 0x2000));
+        assert_eq!(cpu.read_mem(0x2000, 64), Ok(0x8000));
+        assert_eq!(cpu.read_mem(FROMHOST as usize, 64), Ok(1));
+    }
+
+    /// An odd payload to device 0 exits with the payload shifted right by one.
+    #[test]
+    fn exit() {
+        let (mut cpu, mut htif) = setup(Some(FROMHOST));
+        assert!(!send(&mut htif, &mut cpu, 5 << 1 | 1));
+        assert_eq!(htif.exit_code(), Some(5));
+    }
+}
@@ -0,0 +1,826 @@
+// Linux user-mode emulation: sets up the initial process stack for a static riscv64 ELF
+// and services its `ecall` system calls with host equivalents.
+
+use std::collections::hash_map::RandomState;
+use std::fs::{self, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cpu::CPU;
+use crate::elf::Elf;
+use crate::snapshot::{put_blob, put_option, SnapshotReader};
+
+const PAGE_SIZE: u64 = 4096;
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+const SYS_GETCWD: u64 = 17;
+const SYS_IOCTL: u64 = 29;
+const SYS_FACCESSAT: u64 = 48;
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_READLINKAT: u64 = 78;
+const SYS_NEWFSTATAT: u64 = 79;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX: u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_TGKILL: u64 = 131;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_GETRANDOM: u64 = 278;
+
+const ENOENT: i64 = 2;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOTTY: i64 = 25;
+const ESPIPE: i64 = 29;
+const ERANGE: i64 = 34;
+const ENOSYS: i64 = 38;
+
+const IOV_MAX: u64 = 1024;
+
+const AT_FDCWD: i64 = -100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+const O_ACCMODE: u64 = 3;
+const O_WRONLY: u64 = 1;
+const O_RDWR: u64 = 2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const CLOCK_REALTIME: u64 = 0;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_BASE: u64 = 7;
+const AT_FLAGS: u64 = 8;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_SECURE: u64 = 23;
+const AT_RANDOM: u64 = 25;
+
+/// One bit per single-letter extension like misa has them: A, I and M.
+const HWCAP_IMA: u64 = 1 << 0 | 1 << 8 | 1 << 12;
+
+const S_IFCHR: u32 = 0o020000;
+
+/// A host file opened on behalf of the guest, with what it takes to open it again
+/// when a snapshot is restored.
+#[derive(Debug)]
+pub struct OpenFile {
+    pub file: fs::File,
+    path: String,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+impl OpenFile {
+    pub fn new(file: fs::File, path: &str, read: bool, write: bool, append: bool) -> OpenFile {
+        Self {
+            file,
+            path: path.to_string(),
+            read,
+            write,
+            append,
+        }
+    }
+
+    /// Saves the path, access mode and file position.
+    pub fn save(&self, out: &mut Vec<u8>) {
+        put_blob(out, self.path.as_bytes());
+        out.extend_from_slice(&[self.read as u8, self.write as u8, self.append as u8]);
+        let pos = (&self.file).stream_position().unwrap_or(0);
+        out.extend_from_slice(&pos.to_le_bytes());
+    }
+
+    /// Opens the file saved by `save` again, without creating or truncating it.
+    pub fn restore(r: &mut SnapshotReader) -> Result<OpenFile, String> {
+        let path = r.string()?;
+        let (read, write, append) = (r.u8()? != 0, r.u8()? != 0, r.u8()? != 0);
+        let pos = r.u64()?;
+        let mut file = OpenOptions::new().read(read).write(write).append(append).open(&path)
+            .map_err(|e| format!("Error reopening {}: {}", path, e))?;
+        file.seek(SeekFrom::Start(pos)).map_err(|e| format!("Error seeking in {}: {}", path, e))?;
+        Ok(OpenFile::new(file, &path, read, write, append))
+    }
+}
+
+#[derive(Debug)]
+enum HostFile {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(OpenFile),
+}
+
+#[derive(Debug)]
+pub struct LinuxUser {
+    files: Vec<Option<HostFile>>,
+    brk_start: u64,
+    brk: u64,
+    mmap_bottom: u64,
+    start: Instant,
+    exit_code: Option<i32>,
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn align_up(val: u64, align: u64) -> u64 {
+    (val + align - 1) & !(align - 1)
+}
+
+fn errno(e: &io::Error) -> u64 {
+    (-(e.raw_os_error().unwrap_or(EINVAL as i32) as i64)) as u64
+}
+
+fn err(e: i64) -> u64 {
+    (-e) as u64
+}
+
+/// Reads a NUL-terminated string from guest memory.
+fn read_cstr(cpu: &CPU, addr: u64) -> Result<String, u64> {
+    let mut s = vec!();
+    let mut addr = addr as usize;
+    loop {
+        match cpu.read_mem(addr, 8) {
+            Ok(0) => break,
+            Ok(b) => s.push(b as u8),
+            Err(_) => return Err(err(EFAULT)),
+        }
+        addr = addr.wrapping_add(1);
+    }
+    Ok(String::from_utf8_lossy(&s).into_owned())
+}
+
+impl LinuxUser {
+    /// Builds the initial stack (argc, argv, envp and auxv) below `stack_top` and
+    /// points `sp` at it. `elf` must already be loaded into `cpu`.
+    pub fn setup(cpu: &mut CPU, elf: &Elf, args: &[String], env: &[String], stack_top: u64) -> Result<LinuxUser, String> {
+        let mut sp = stack_top;
+        let push = |cpu: &mut CPU, sp: &mut u64, data: &[u8]| -> Result<u64, String> {
+            *sp -= data.len() as u64;
+            cpu.load_mem(*sp as usize, data).map_err(|_| "Stack does not fit in memory".to_string())?;
+            Ok(*sp)
+        };
+
+        let mut random = [0u8; 16];
+        random[..8].copy_from_slice(&random_u64().to_le_bytes());
+        random[8..].copy_from_slice(&random_u64().to_le_bytes());
+        let random_addr = push(cpu, &mut sp, &random)?;
+        let mut argv = vec!();
+        for a in args {
+            argv.push(push(cpu, &mut sp, format!("{}\0", a).as_bytes())?);
+        }
+        let mut envp = vec!();
+        for e in env {
+            envp.push(push(cpu, &mut sp, format!("{}\0", e).as_bytes())?);
+        }
+
+        let phdr = elf.segments.iter()
+            .find(|s| (s.offset as u64) <= elf.phoff && elf.phoff < (s.offset + s.file_size) as u64)
+            .map(|s| s.vaddr + elf.phoff - s.offset as u64)
+            .unwrap_or(0);
+        let auxv = [
+            (AT_PHDR, phdr),
+            (AT_PHENT, elf.phentsize as u64),
+            (AT_PHNUM, elf.phnum as u64),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_BASE, 0),
+            (AT_FLAGS, 0),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, HWCAP_IMA),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random_addr),
+            (AT_NULL, 0),
+        ];
+
+        let mut table = vec!(argv.len() as u64);
+        table.extend(argv.iter());
+        table.push(0);
+        table.extend(envp.iter());
+        table.push(0);
+        for (k, v) in auxv.iter() {
+            table.push(*k);
+            table.push(*v);
+        }
+        let bytes: Vec<u8> = table.iter().flat_map(|v| v.to_le_bytes()).collect();
+        // argc has to sit at a 16 byte aligned sp
+        sp = (sp - bytes.len() as u64) & !0xF;
+        cpu.load_mem(sp as usize, &bytes).map_err(|_| "Stack does not fit in memory".to_string())?;
+        cpu.set_reg(2, sp);
+
+        let brk_start = elf.segments.iter()
+            .map(|s| align_up(s.vaddr + s.mem_size as u64, PAGE_SIZE))
+            .max()
+            .unwrap_or(0);
+        let mmap_top = align_up(stack_top, PAGE_SIZE) - STACK_SIZE;
+        Ok(LinuxUser::new(brk_start, mmap_top))
+    }
+
+    /// A process with the standard streams open, its heap at `brk_start` and
+    /// anonymous mappings growing down from `mmap_top`.
+    pub fn new(brk_start: u64, mmap_top: u64) -> LinuxUser {
+        Self {
+            files: vec!(Some(HostFile::Stdin), Some(HostFile::Stdout), Some(HostFile::Stderr)),
+            brk_start,
+            brk: brk_start,
+            mmap_bottom: mmap_top,
+            start: Instant::now(),
+            exit_code: None,
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Saves the memory layout, exit status and open files.
+    pub fn save(&self, out: &mut Vec<u8>) {
+        for v in [self.brk_start, self.brk, self.mmap_bottom] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        put_option(out, self.exit_code.map(|c| c as u64));
+        out.extend_from_slice(&(self.files.len() as u64).to_le_bytes());
+        for f in &self.files {
+            match f {
+                None => out.push(0),
+                Some(HostFile::Stdin) => out.push(1),
+                Some(HostFile::Stdout) => out.push(2),
+                Some(HostFile::Stderr) => out.push(3),
+                Some(HostFile::File(f)) => {
+                    out.push(4);
+                    f.save(out);
+                }
+            }
+        }
+    }
+
+    pub fn restore(r: &mut SnapshotReader) -> Result<LinuxUser, String> {
+        let mut linux = LinuxUser::new(r.u64()?, 0);
+        linux.brk = r.u64()?;
+        linux.mmap_bottom = r.u64()?;
+        linux.exit_code = r.option()?.map(|c| c as i32);
+        let count = r.u64()?;
+        linux.files.clear();
+        for _ in 0..count {
+            linux.files.push(match r.u8()? {
+                0 => None,
+                1 => Some(HostFile::Stdin),
+                2 => Some(HostFile::Stdout),
+                3 => Some(HostFile::Stderr),
+                4 => Some(HostFile::File(OpenFile::restore(r)?)),
+                t => return Err(format!("Unknown file type {} in snapshot", t)),
+            });
+        }
+        Ok(linux)
+    }
+
+    /// Handles the system call in a7 with arguments in a0-a5. Returns the value for a0,
+    /// or None if the process exited.
+    pub fn syscall(&mut self, cpu: &mut CPU) -> Result<Option<u64>, String> {
+        let nr = cpu.read_reg(17);
+        let a: Vec<u64> = (10..16).map(|r| cpu.read_reg(r)).collect();
+        self.dispatch(cpu, nr, &a)
+    }
+
+    /// Handles system call `nr` with the arguments in `a`, which must hold at least six values.
+    pub fn dispatch(&mut self, cpu: &mut CPU, nr: u64, a: &[u64]) -> Result<Option<u64>, String> {
+        let ret = match nr {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_code = Some(a[0] as i32);
+                return Ok(None);
+            }
+            SYS_READ => self.read(cpu, a[0], a[1], a[2]),
+            SYS_WRITE => self.write(cpu, a[0], a[1], a[2]),
+            SYS_READV | SYS_WRITEV => {
+                if a[2] > IOV_MAX {
+                    return Ok(Some(err(EINVAL)));
+                }
+                let mut total = 0;
+                for i in 0..a[2] {
+                    let iov = (a[1] as usize).wrapping_add(i as usize * 16);
+                    let (base, len) = match (cpu.read_mem(iov, 64), cpu.read_mem(iov.wrapping_add(8), 64)) {
+                        (Ok(base), Ok(len)) => (base, len),
+                        _ => return Ok(Some(err(EFAULT))),
+                    };
+                    let n = if nr == SYS_READV {
+                        self.read(cpu, a[0], base, len)
+                    } else {
+                        self.write(cpu, a[0], base, len)
+                    };
+                    if (n as i64) < 0 {
+                        return Ok(Some(if total == 0 { n } else { total }));
+                    }
+                    total += n;
+                    if n < len {
+                        break;
+                    }
+                }
+                total
+            }
+            SYS_OPENAT => self.openat(cpu, a[0], a[1], a[2], a[3]),
+            SYS_CLOSE => {
+                match self.files.get_mut(a[0] as usize) {
+                    Some(f) if f.is_some() => {
+                        *f = None;
+                        0
+                    }
+                    _ => err(EBADF),
+                }
+            }
+            SYS_LSEEK => {
+                let pos = match a[2] {
+                    0 => SeekFrom::Start(a[1]),
+                    1 => SeekFrom::Current(a[1] as i64),
+                    2 => SeekFrom::End(a[1] as i64),
+                    _ => return Ok(Some(err(EINVAL))),
+                };
+                match self.files.get_mut(a[0] as usize) {
+                    Some(Some(HostFile::File(f))) => f.file.seek(pos).unwrap_or_else(|e| errno(&e)),
+                    Some(Some(_)) => err(ESPIPE),
+                    _ => err(EBADF),
+                }
+            }
+            SYS_FSTAT => self.fstat(cpu, a[0], a[1]),
+            SYS_NEWFSTATAT => {
+                let path = match read_cstr(cpu, a[1]) {
+                    Ok(p) => p,
+                    Err(e) => return Ok(Some(e)),
+                };
+                if path.is_empty() && a[3] & AT_EMPTY_PATH != 0 {
+                    self.fstat(cpu, a[0], a[2])
+                } else {
+                    match fs::metadata(&path) {
+                        Ok(m) => self.write_stat(cpu, a[2], &Stat::from(&m)),
+                        Err(e) => errno(&e),
+                    }
+                }
+            }
+            SYS_FACCESSAT => {
+                match read_cstr(cpu, a[1]) {
+                    Ok(path) if fs::metadata(&path).is_ok() => 0,
+                    Ok(_) => err(ENOENT),
+                    Err(e) => e,
+                }
+            }
+            SYS_GETCWD => {
+                let cwd = std::env::current_dir().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default();
+                let bytes = format!("{}\0", cwd).into_bytes();
+                if bytes.len() as u64 > a[1] {
+                    err(ERANGE)
+                } else if cpu.store_bytes(a[0] as usize, &bytes).is_err() {
+                    err(EFAULT)
+                } else {
+                    bytes.len() as u64
+                }
+            }
+            SYS_READLINKAT => err(ENOENT),
+            SYS_IOCTL => err(ENOTTY),
+            SYS_BRK => {
+                if a[0] >= self.brk_start && a[0] < self.mmap_bottom {
+                    self.brk = a[0];
+                }
+                self.brk
+            }
+            SYS_MMAP => self.mmap(cpu, a[0], a[1], a[3], a[4], a[5]),
+            SYS_MUNMAP | SYS_MPROTECT => 0,
+            SYS_CLOCK_GETTIME => {
+                let (sec, nsec) = if a[0] == CLOCK_REALTIME {
+                    let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                    (t.as_secs(), t.subsec_nanos() as u64)
+                } else {
+                    let t = self.start.elapsed();
+                    (t.as_secs(), t.subsec_nanos() as u64)
+                };
+                self.store_u64s(cpu, a[1], &[sec, nsec])
+            }
+            SYS_GETTIMEOFDAY => {
+                let t = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                if a[0] == 0 {
+                    0
+                } else {
+                    self.store_u64s(cpu, a[0], &[t.as_secs(), t.subsec_micros() as u64])
+                }
+            }
+            SYS_UNAME => {
+                let mut buf = vec![0u8; 65 * 6];
+                for (i, field) in ["Linux", "riscv-emu", "6.1.0", "#1", "riscv64", ""].iter().enumerate() {
+                    buf[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+                }
+                if cpu.store_bytes(a[0] as usize, &buf).is_err() { err(EFAULT) } else { 0 }
+            }
+            SYS_GETRANDOM => {
+                // Fewer bytes than asked for are fine, more than the buffer can hold aren't
+                let len = a[1].min(cpu.mapped_len(a[0] as usize) as u64);
+                if len == 0 && a[1] != 0 {
+                    return Ok(Some(err(EFAULT)));
+                }
+                let bytes: Vec<u8> = (0..len).map(|_| random_u64() as u8).collect();
+                if cpu.store_bytes(a[0] as usize, &bytes).is_err() { err(EFAULT) } else { len }
+            }
+            SYS_PRLIMIT64 => {
+                if a[3] != 0 {
+                    // Report an 8 MiB stack and no limit on anything else
+                    let limit = if a[1] == 3 { STACK_SIZE } else { u64::MAX };
+                    self.store_u64s(cpu, a[3], &[limit, u64::MAX])
+                } else {
+                    0
+                }
+            }
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => std::process::id() as u64,
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => 0,
+            SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK | SYS_FUTEX => 0,
+            SYS_TGKILL => {
+                // Only used by abort(), so end the process like SIGABRT would
+                self.exit_code = Some(128 + a[2] as i32);
+                return Ok(None);
+            }
+            _ => {
+                println!("Unimplemented syscall {} (args {:X?})", nr, a);
+                err(ENOSYS)
+            }
+        };
+        Ok(Some(ret))
+    }
+
+    fn store_u64s(&self, cpu: &mut CPU, addr: u64, vals: &[u64]) -> u64 {
+        let bytes: Vec<u8> = vals.iter().flat_map(|v| v.to_le_bytes()).collect();
+        if cpu.store_bytes(addr as usize, &bytes).is_err() { err(EFAULT) } else { 0 }
+    }
+
+    fn read(&mut self, cpu: &mut CPU, fd: u64, buf: u64, count: u64) -> u64 {
+        // A short read is allowed, so read no more than fits where buf is mapped
+        let mapped = cpu.mapped_len(buf as usize) as u64;
+        if mapped == 0 && count != 0 {
+            return err(EFAULT);
+        }
+        let count = count.min(mapped);
+        let mut data = vec![0u8; count as usize];
+        let n = match self.files.get_mut(fd as usize) {
+            Some(Some(HostFile::Stdin)) => io::stdin().read(&mut data),
+            Some(Some(HostFile::File(f))) => f.file.read(&mut data),
+            _ => return err(EBADF),
+        };
+        match n {
+            Ok(n) => {
+                if cpu.store_bytes(buf as usize, &data[..n]).is_err() {
+                    return err(EFAULT);
+                }
+                n as u64
+            }
+            Err(e) => errno(&e),
+        }
+    }
+
+    fn write(&mut self, cpu: &mut CPU, fd: u64, buf: u64, count: u64) -> u64 {
+        let data = match cpu.read_bytes(buf as usize, count as usize) {
+            Ok(d) => d,
+            Err(_) => return err(EFAULT),
+        };
+        let n = match self.files.get_mut(fd as usize) {
+            Some(Some(HostFile::Stdout)) => io::stdout().write(&data).and_then(|n| io::stdout().flush().map(|_| n)),
+            Some(Some(HostFile::Stderr)) => io::stderr().write(&data),
+            Some(Some(HostFile::File(f))) => f.file.write(&data),
+            _ => return err(EBADF),
+        };
+        n.map(|n| n as u64).unwrap_or_else(|e| errno(&e))
+    }
+
+    fn openat(&mut self, cpu: &CPU, dirfd: u64, path: u64, flags: u64, mode: u64) -> u64 {
+        let path = match read_cstr(cpu, path) {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        if dirfd as i64 != AT_FDCWD && !path.starts_with('/') {
+            return err(EBADF);
+        }
+        let mut opts = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => opts.write(true),
+            O_RDWR => opts.read(true).write(true),
+            _ => opts.read(true),
+        };
+        opts.append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0)
+            .mode(mode as u32);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                opts.create_new(true);
+            } else {
+                opts.create(true);
+            }
+        }
+        let access = flags & O_ACCMODE;
+        match opts.open(&path) {
+            Ok(f) => {
+                let f = OpenFile::new(f, &path, access != O_WRONLY, access != 0, flags & O_APPEND != 0);
+                let fd = match self.files.iter().position(|f| f.is_none()) {
+                    Some(fd) => fd,
+                    None => {
+                        self.files.push(None);
+                        self.files.len() - 1
+                    }
+                };
+                self.files[fd] = Some(HostFile::File(f));
+                fd as u64
+            }
+            Err(e) => errno(&e),
+        }
+    }
+
+    fn fstat(&mut self, cpu: &mut CPU, fd: u64, statbuf: u64) -> u64 {
+        let stat = match self.files.get(fd as usize) {
+            Some(Some(HostFile::File(f))) => match f.file.metadata() {
+                Ok(m) => Stat::from(&m),
+                Err(e) => return errno(&e),
+            },
+            Some(Some(_)) => Stat { mode: S_IFCHR | 0o620, blksize: 1024, ..Stat::default() },
+            _ => return err(EBADF),
+        };
+        self.write_stat(cpu, statbuf, &stat)
+    }
+
+    /// Writes `stat` in the layout of the riscv64 `struct stat`.
+    fn write_stat(&self, cpu: &mut CPU, addr: u64, st: &Stat) -> u64 {
+        let mut buf = vec!();
+        buf.extend_from_slice(&st.dev.to_le_bytes());
+        buf.extend_from_slice(&st.ino.to_le_bytes());
+        buf.extend_from_slice(&st.mode.to_le_bytes());
+        buf.extend_from_slice(&st.nlink.to_le_bytes());
+        buf.extend_from_slice(&st.uid.to_le_bytes());
+        buf.extend_from_slice(&st.gid.to_le_bytes());
+        buf.extend_from_slice(&st.rdev.to_le_bytes());
+        buf.extend_from_slice(&0u64.to_le_bytes());
+        buf.extend_from_slice(&st.size.to_le_bytes());
+        buf.extend_from_slice(&st.blksize.to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes());
+        buf.extend_from_slice(&st.blocks.to_le_bytes());
+        for (sec, nsec) in [st.atime, st.mtime, st.ctime].iter() {
+            buf.extend_from_slice(&sec.to_le_bytes());
+            buf.extend_from_slice(&nsec.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0u8; 8]);
+        if cpu.store_bytes(addr as usize, &buf).is_err() { err(EFAULT) } else { 0 }
+    }
+
+    fn mmap(&mut self, cpu: &mut CPU, addr: u64, len: u64, flags: u64, fd: u64, offset: u64) -> u64 {
+        if len == 0 {
+            return err(EINVAL);
+        }
+        let len = match len.checked_add(PAGE_SIZE - 1) {
+            Some(end) => end & !(PAGE_SIZE - 1),
+            None => return err(ENOMEM),
+        };
+        let addr = if flags & MAP_FIXED != 0 {
+            addr
+        } else {
+            // Mappings are handed out top-down below the stack and never reused
+            match self.mmap_bottom.checked_sub(len) {
+                Some(bottom) if bottom >= self.brk => self.mmap_bottom = bottom,
+                _ => return err(ENOMEM),
+            }
+            self.mmap_bottom
+        };
+        if len > cpu.mapped_len(addr as usize) as u64 {
+            return err(ENOMEM);
+        }
+        let mut data = vec![0u8; len as usize];
+        if flags & MAP_ANONYMOUS == 0 {
+            match self.files.get_mut(fd as usize) {
+                Some(Some(HostFile::File(OpenFile { file: f, .. }))) => {
+                    let read = f.seek(SeekFrom::Start(offset)).and_then(|_| {
+                        let mut n = 0;
+                        while n < data.len() {
+                            match f.read(&mut data[n..])? {
+                                0 => break,
+                                m => n += m,
+                            }
+                        }
+                        Ok(())
+                    });
+                    if let Err(e) = read {
+                        return errno(&e);
+                    }
+                }
+                _ => return err(EBADF),
+            }
+        } else if flags & MAP_FIXED == 0 {
+            // Fresh anonymous memory below the stack is still zeroed
+            return addr;
+        }
+        if cpu.store_bytes(addr as usize, &data).is_err() {
+            return err(ENOMEM);
+        }
+        addr
+    }
+}
+
+#[derive(Default)]
+struct Stat {
+    dev: u64,
+    ino: u64,
+    mode: u32,
+    nlink: u32,
+    uid: u32,
+    gid: u32,
+    rdev: u64,
+    size: i64,
+    blksize: i32,
+    blocks: i64,
+    atime: (i64, i64),
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl From<&fs::Metadata> for Stat {
+    fn from(m: &fs::Metadata) -> Stat {
+        Stat {
+            dev: m.dev(),
+            ino: m.ino(),
+            mode: m.mode(),
+            nlink: m.nlink() as u32,
+            uid: m.uid(),
+            gid: m.gid(),
+            rdev: m.rdev(),
+            size: m.size() as i64,
+            blksize: m.blksize() as i32,
+            blocks: m.blocks() as i64,
+            atime: (m.atime(), m.atime_nsec()),
+            mtime: (m.mtime(), m.mtime_nsec()),
+            ctime: (m.ctime(), m.ctime_nsec()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotWriter;
+
+    /// Room for the stack and the mappings below it.
+    const MEM_SIZE: u64 = 16 * 1024 * 1024;
+
+    /// Sets up a process for an ELF with nothing to load, its stack at the top of memory.
+    fn process(args: &[&str], env: &[&str]) -> (CPU, LinuxUser) {
+        let mut data = vec![0u8; 64];
+        data[..4].copy_from_slice(b"\x7FELF");
+        data[4] = 2; // ELFCLASS64
+        data[5] = 1; // little-endian
+        data[18..20].copy_from_slice(&243u16.to_le_bytes()); // EM_RISCV
+        data[24..32].copy_from_slice(&0x1000u64.to_le_bytes());
+        let elf = Elf::parse(data).unwrap();
+        let mut cpu = CPU::with_memory(0, MEM_SIZE as usize, vec!());
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        let env: Vec<String> = env.iter().map(|e| e.to_string()).collect();
+        let linux = LinuxUser::setup(&mut cpu, &elf, &args, &env, MEM_SIZE).unwrap();
+        (cpu, linux)
+    }
+
+    fn call(linux: &mut LinuxUser, cpu: &mut CPU, nr: u64, a: &[u64]) -> u64 {
+        cpu.set_reg(17, nr);
+        for r in 0..6 {
+            cpu.set_reg(10 + r, a.get(r).copied().unwrap_or(0));
+        }
+        linux.syscall(cpu).unwrap().unwrap()
+    }
+
+    /// argc, argv, envp and auxv are laid out from a 16 byte aligned sp.
+    #[test]
+    fn stack_layout() {
+        let (cpu, _) = process(&["prog", "-x"], &["A=1"]);
+        let sp = cpu.read_reg(2);
+        assert_eq!(sp % 16, 0);
+        let word = |i: u64| cpu.read_mem((sp + 8 * i) as usize, 64).unwrap();
+        assert_eq!(word(0), 2);
+        assert_eq!(read_cstr(&cpu, word(1)), Ok("prog".to_string()));
+        assert_eq!(read_cstr(&cpu, word(2)), Ok("-x".to_string()));
+        assert_eq!(word(3), 0);
+        assert_eq!(read_cstr(&cpu, word(4)), Ok("A=1".to_string()));
+        assert_eq!(word(5), 0);
+        let mut auxv = vec!();
+        let mut i = 6;
+        while word(i) != AT_NULL {
+            auxv.push((word(i), word(i + 1)));
+            i += 2;
+        }
+        let aux = |key| auxv.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        assert_eq!(aux(AT_ENTRY), Some(0x1000));
+        assert_eq!(aux(AT_PAGESZ), Some(PAGE_SIZE));
+        assert_eq!(aux(AT_HWCAP), Some(1 << 0 | 1 << 8 | 1 << 12));
+        let random = aux(AT_RANDOM).unwrap();
+        assert!(random > sp && random + 16 <= MEM_SIZE);
+    }
+
+    /// Files are created, written, read back, mapped and closed through the host.
+    #[test]
+    fn file_syscalls() {
+        let (mut cpu, mut linux) = process(&["prog"], &[]);
+        let path = std::env::temp_dir().join(format!("riscv-emu-linux-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        cpu.load_mem(0x1000, format!("{}\0", path).as_bytes()).unwrap();
+        cpu.load_mem(0x2000, b"hello").unwrap();
+
+        let fd = call(&mut linux, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u64, 0x1000, O_RDWR | O_CREAT | O_TRUNC, 0o644]);
+        assert_eq!(fd, 3);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_WRITE, &[fd, 0x2000, 5]), 5);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_LSEEK, &[fd, 0, 0]), 0);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[fd, 0x3000, 16]), 5);
+        assert_eq!(cpu.read_bytes(0x3000, 5).unwrap(), b"hello");
+        let addr = call(&mut linux, &mut cpu, SYS_MMAP, &[0, 5, 1, 2, fd, 0]);
+        assert_eq!(addr % PAGE_SIZE, 0);
+        assert_eq!(cpu.read_bytes(addr as usize, 6).unwrap(), b"hello\0");
+        assert_eq!(call(&mut linux, &mut cpu, SYS_CLOSE, &[fd]), 0);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_CLOSE, &[fd]), err(EBADF));
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[fd, 0x3000, 16]), err(EBADF));
+        assert_eq!(fs::read(&path).unwrap(), b"hello");
+
+        fs::remove_file(&path).unwrap();
+        let fd = call(&mut linux, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u64, 0x1000, 0, 0]);
+        assert_eq!(fd, err(ENOENT));
+    }
+
+    /// A restored process finds its files open at the same offsets, and its mappings where they were.
+    #[test]
+    fn save_restore() {
+        let (mut cpu, mut linux) = process(&["prog"], &[]);
+        let path = std::env::temp_dir().join(format!("riscv-emu-linux-save-{}", std::process::id()));
+        fs::write(&path, b"hello").unwrap();
+        cpu.load_mem(0x1000, format!("{}\0", path.to_str().unwrap()).as_bytes()).unwrap();
+        let fd = call(&mut linux, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u64, 0x1000, 0, 0]);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[fd, 0x2000, 2]), 2);
+        let first = call(&mut linux, &mut cpu, SYS_MMAP, &[0, 1, 3, MAP_ANONYMOUS | 2, u64::MAX, 0]);
+
+        let mut w = SnapshotWriter::new();
+        w.section(b"LNUX", |out| linux.save(out));
+        let data = w.finish();
+        let mut r = SnapshotReader::new(&data).unwrap();
+        let mut restored = LinuxUser::restore(&mut r.section(b"LNUX").unwrap()).unwrap();
+        assert_eq!(call(&mut restored, &mut cpu, SYS_READ, &[fd, 0x2000, 16]), 3);
+        assert_eq!(cpu.read_bytes(0x2000, 3).unwrap(), b"llo");
+        let second = call(&mut restored, &mut cpu, SYS_MMAP, &[0, 1, 3, MAP_ANONYMOUS | 2, u64::MAX, 0]);
+        assert_eq!(second, first - PAGE_SIZE);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// brk stays between the end of the program and the mappings, which grow down.
+    #[test]
+    fn memory_syscalls() {
+        let (mut cpu, mut linux) = process(&["prog"], &[]);
+        let start = call(&mut linux, &mut cpu, SYS_BRK, &[0]);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_BRK, &[start + 0x10000]), start + 0x10000);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_BRK, &[MEM_SIZE]), start + 0x10000);
+
+        let first = call(&mut linux, &mut cpu, SYS_MMAP, &[0, 5000, 3, MAP_ANONYMOUS | 2, u64::MAX, 0]);
+        assert_eq!(first, MEM_SIZE - STACK_SIZE - 2 * PAGE_SIZE);
+        let second = call(&mut linux, &mut cpu, SYS_MMAP, &[0, 1, 3, MAP_ANONYMOUS | 2, u64::MAX, 0]);
+        assert_eq!(second, first - PAGE_SIZE);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_MMAP, &[0, MEM_SIZE, 3, MAP_ANONYMOUS | 2, u64::MAX, 0]), err(ENOMEM));
+        assert_eq!(call(&mut linux, &mut cpu, SYS_MMAP, &[0, 0, 3, MAP_ANONYMOUS | 2, u64::MAX, 0]), err(EINVAL));
+    }
+}
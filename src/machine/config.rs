@@ -0,0 +1,392 @@
+use std::fs;
+
+use crate::bus::DRAM_BASE;
+use crate::clint::CLINT_SIZE;
+use crate::cpu::bpred::{Direction, PredictorConfig};
+use crate::cpu::cache::{CacheConfig, HierarchyConfig, Replacement};
+use crate::cpu::pipeline::PipelineConfig;
+use crate::plic::{PLIC_SIZE, PLIC_SOURCES};
+use crate::rom::BOOT_ROM_SIZE;
+use crate::syscon::SYSCON_SIZE;
+use crate::uart::UART_SIZE;
+use crate::virtio::VIRTIO_SIZE;
+use super::json::Json;
+
+/// The single letter extensions the CPU implements.
+const IMPLEMENTED_EXTENSIONS: &str = "ima";
+
+/// A region of RAM or ROM. ROMs are loaded from `file`, RAM with a `file` is a mapping of it.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub base: u64,
+    pub size: u64,
+    pub file: Option<String>,
+    /// Allocate the RAM a page at a time as it is written.
+    pub sparse: bool,
+    /// Write RAM stores through to `file` instead of keeping a private copy.
+    pub shared: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    Clint,
+    Plic,
+    Uart,
+    Virtio,
+    Syscon,
+}
+
+impl DeviceKind {
+    /// The type as written in a machine description.
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceKind::Clint => "clint",
+            DeviceKind::Plic => "plic",
+            DeviceKind::Uart => "uart",
+            DeviceKind::Virtio => "virtio",
+            DeviceKind::Syscon => "syscon",
+        }
+    }
+
+    /// How much of the address space the device takes up.
+    pub fn size(self) -> u64 {
+        (match self {
+            DeviceKind::Clint => CLINT_SIZE,
+            DeviceKind::Plic => PLIC_SIZE,
+            DeviceKind::Uart => UART_SIZE,
+            DeviceKind::Virtio => VIRTIO_SIZE,
+            DeviceKind::Syscon => SYSCON_SIZE,
+        }) as u64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceConfig {
+    pub kind: DeviceKind,
+    pub base: u64,
+    pub irq: u32,
+}
+
+/// Everything that makes up a board: memory map, devices and harts.
+#[derive(Debug, Clone)]
+pub struct MachineConfig {
+    pub name: String,
+    pub harts: u64,
+    pub isa: String,
+    /// The pc at reset. Without one the boot ROM, the program's entry point or the start of RAM is used.
+    pub reset_vector: Option<u64>,
+    /// Where to put a boot ROM that sets up a0/a1 and jumps to the program.
+    pub boot_rom: Option<u64>,
+    /// The first region is the main memory the program is loaded into.
+    pub ram: Vec<Region>,
+    pub roms: Vec<Region>,
+    pub devices: Vec<DeviceConfig>,
+    /// Time instructions with a pipeline model of the core instead of one cycle each.
+    pub pipeline: Option<PipelineConfig>,
+    /// Model caches between the core and memory.
+    pub caches: Option<HierarchyConfig>,
+    /// Check branches against a predictor model of the core.
+    pub predictor: Option<PredictorConfig>,
+}
+
+/// The predictor called `name`, with default table sizes.
+pub fn predictor(name: &str) -> Result<PredictorConfig, String> {
+    match name {
+        "static" => Ok(PredictorConfig::new(Direction::Static)),
+        "bimodal" => Ok(PredictorConfig::new(Direction::Bimodal)),
+        "gshare" => Ok(PredictorConfig::new(Direction::Gshare)),
+        _ => Err(format!("unknown branch predictor \"{}\", expected static, bimodal or gshare", name)),
+    }
+}
+
+impl MachineConfig {
+    /// A board laid out like QEMU's virt machine, starting in a boot ROM at 0x1000.
+    pub fn virt() -> MachineConfig {
+        use DeviceKind::*;
+        let mut devices = vec!(
+            DeviceConfig { kind: Syscon, base: 0x10_0000, irq: 0 },
+            DeviceConfig { kind: Clint, base: 0x200_0000, irq: 0 },
+            DeviceConfig { kind: Plic, base: 0xC00_0000, irq: 0 },
+            DeviceConfig { kind: Uart, base: 0x1000_0000, irq: 10 },
+        );
+        for i in 0..8 {
+            devices.push(DeviceConfig { kind: Virtio, base: 0x1000_1000 + i * 0x1000, irq: 1 + i as u32 });
+        }
+        Self {
+            name: "virt".to_string(),
+            harts: 1,
+            isa: "rv64ima".to_string(),
+            reset_vector: Some(0x1000),
+            boot_rom: Some(0x1000),
+            ram: vec!(Region { base: DRAM_BASE as u64, size: 128 * 1024 * 1024, file: None, sparse: false, shared: false }),
+            roms: vec!(),
+            devices,
+            pipeline: None,
+            caches: None,
+            predictor: None,
+        }
+    }
+
+    /// `spec` is either the name of a built-in board or the path of a JSON description.
+    pub fn load(spec: &str) -> Result<MachineConfig, String> {
+        match spec {
+            "virt" => Ok(MachineConfig::virt()),
+            path => {
+                let text = fs::read_to_string(path).map_err(|e| format!("Error opening {}: {}", path, e))?;
+                MachineConfig::from_json(&text).map_err(|e| format!("{}: {}", path, e))
+            }
+        }
+    }
+
+    /// Reads a description like:
+    ///
+    /// ```json
+    /// { "name": "myboard", "harts": 1, "isa": "rv64ima", "boot_rom": "0x1000",
+    ///   "ram": [{ "base": "0x80000000", "size": "64M" }, { "base": "0x100000000", "size": "16G", "sparse": true },
+    ///           { "base": "0x200000000", "size": "1M", "file": "shm.bin", "shared": true }],
+    ///   "roms": [{ "base": "0x1000", "file": "boot.bin" }],
+    ///   "devices": [{ "type": "uart", "base": "0x10000000", "irq": 10 }],
+    ///   "pipeline": { "load": 3, "branch_penalty": 3 },
+    ///   "caches": { "l1d": { "size": "32K", "ways": 8, "write": "through", "allocate": false },
+    ///               "l2": false, "memory_latency": 80 },
+    ///   "branch_predictor": { "type": "gshare", "entries": 4096, "history": 12, "btb": 128, "ras": 16 } }
+    /// ```
+    ///
+    /// `"pipeline": true` uses the default latencies, `"caches": true` the default
+    /// hierarchy. Cache levels that aren't mentioned keep their defaults. A predictor can
+    /// also be given just by type, e.g. `"branch_predictor": "bimodal"`.
+    pub fn from_json(text: &str) -> Result<MachineConfig, String> {
+        let root = Json::parse(text)?;
+        let num = |obj: &Json, key: &str| -> Result<Option<u64>, String> {
+            match obj.get(key) {
+                None => Ok(None),
+                Some(v) => v.as_u64().map(Some).ok_or_else(|| format!("\"{}\" must be a number", key)),
+            }
+        };
+        let list = |key: &str| -> Result<&[Json], String> {
+            match root.get(key) {
+                None => Ok(&[]),
+                Some(v) => v.as_array().ok_or_else(|| format!("\"{}\" must be a list", key)),
+            }
+        };
+        let region = |obj: &Json, rom: bool| -> Result<Region, String> {
+            let base = num(obj, "base")?.ok_or("memory regions need a \"base\"")?;
+            let file = obj.get("file").and_then(|f| f.as_str()).map(|f| f.to_string());
+            let size = match (num(obj, "size")?, &file) {
+                (Some(size), _) => size,
+                (None, Some(file)) => fs::metadata(file).map_err(|e| format!("{}: {}", file, e))?.len(),
+                _ => return Err("memory regions need a \"size\"".to_string()),
+            };
+            let flag = |key: &str| match obj.get(key) {
+                None => Ok(false),
+                Some(Json::Bool(b)) => Ok(*b),
+                Some(_) => Err(format!("\"{}\" must be true or false", key)),
+            };
+            let (sparse, shared) = (flag("sparse")?, flag("shared")?);
+            if !rom && sparse && file.is_some() {
+                return Err("a RAM region can't be both sparse and backed by a file".to_string());
+            }
+            Ok(Region { base, size, file, sparse, shared })
+        };
+
+        let ram = list("ram")?.iter().map(|r| region(r, false)).collect::<Result<Vec<_>, _>>()?;
+        if ram.is_empty() {
+            return Err("the machine needs at least one \"ram\" region".to_string());
+        }
+        let roms = list("roms")?.iter().map(|r| region(r, true)).collect::<Result<Vec<_>, _>>()?;
+        let devices = list("devices")?.iter().map(|d| {
+            let kind = match d.get("type").and_then(|t| t.as_str()) {
+                Some("clint") => DeviceKind::Clint,
+                Some("plic") => DeviceKind::Plic,
+                Some("uart") => DeviceKind::Uart,
+                Some("virtio") => DeviceKind::Virtio,
+                Some("syscon") => DeviceKind::Syscon,
+                Some(t) => return Err(format!("unknown device type \"{}\"", t)),
+                None => return Err("devices need a \"type\"".to_string()),
+            };
+            let base = num(d, "base")?.ok_or("devices need a \"base\"")?;
+            // Source 0 means no interrupt on the PLIC, so lines start at 1
+            let irq = match (kind, num(d, "irq")?) {
+                (DeviceKind::Uart | DeviceKind::Virtio, Some(irq)) if (1..PLIC_SOURCES as u64).contains(&irq) => irq as u32,
+                (DeviceKind::Uart | DeviceKind::Virtio, _) => {
+                    return Err(format!("{} devices need an \"irq\" from 1 to {}", kind.name(), PLIC_SOURCES - 1));
+                }
+                (_, None) => 0,
+                (_, Some(_)) => return Err(format!("{} devices don't have an \"irq\"", kind.name())),
+            };
+            Ok(DeviceConfig { kind, base, irq })
+        }).collect::<Result<Vec<_>, String>>()?;
+
+        let pipeline = match root.get("pipeline") {
+            None | Some(Json::Bool(false)) => None,
+            Some(Json::Bool(true)) => Some(PipelineConfig::default()),
+            Some(p @ Json::Object(_)) => {
+                let mut c = PipelineConfig::default();
+                for (key, field) in [("stages", &mut c.stages), ("alu", &mut c.alu), ("load", &mut c.load),
+                                     ("store", &mut c.store), ("mul", &mut c.mul), ("div", &mut c.div),
+                                     ("branch_penalty", &mut c.branch_penalty), ("jump_penalty", &mut c.jump_penalty)] {
+                    if let Some(v) = num(p, key)? {
+                        *field = v;
+                    }
+                }
+                match p.get("serialize") {
+                    None => {}
+                    Some(Json::Bool(b)) => c.serialize = *b,
+                    Some(_) => return Err("\"serialize\" must be true or false".to_string()),
+                }
+                Some(c)
+            }
+            Some(_) => return Err("\"pipeline\" must be true, false or an object".to_string()),
+        };
+
+        let caches = match root.get("caches") {
+            None | Some(Json::Bool(false)) => None,
+            Some(Json::Bool(true)) => Some(HierarchyConfig::default()),
+            Some(c @ Json::Object(_)) => {
+                let mut h = HierarchyConfig::default();
+                for (key, level) in [("l1i", &mut h.l1i), ("l1d", &mut h.l1d), ("l2", &mut h.l2)] {
+                    match c.get(key) {
+                        None => {}
+                        Some(Json::Bool(false)) => *level = None,
+                        Some(obj @ Json::Object(_)) => {
+                            let mut cache = level.clone().unwrap_or_else(|| CacheConfig::new(16 * 1024, 4, 1));
+                            for (field, val) in [("size", &mut cache.size), ("ways", &mut cache.ways),
+                                                 ("line", &mut cache.line), ("latency", &mut cache.latency)] {
+                                if let Some(v) = num(obj, field)? {
+                                    *val = v;
+                                }
+                            }
+                            cache.replacement = match obj.get("replacement").map(|r| r.as_str()) {
+                                None => cache.replacement,
+                                Some(Some("lru")) => Replacement::Lru,
+                                Some(Some("fifo")) => Replacement::Fifo,
+                                Some(Some("random")) => Replacement::Random,
+                                Some(_) => return Err(format!("{}: \"replacement\" must be \"lru\", \"fifo\" or \"random\"", key)),
+                            };
+                            cache.write_through = match obj.get("write").map(|w| w.as_str()) {
+                                None => cache.write_through,
+                                Some(Some("back")) => false,
+                                Some(Some("through")) => true,
+                                Some(_) => return Err(format!("{}: \"write\" must be \"back\" or \"through\"", key)),
+                            };
+                            match obj.get("allocate") {
+                                None => {}
+                                Some(Json::Bool(b)) => cache.write_allocate = *b,
+                                Some(_) => return Err(format!("{}: \"allocate\" must be true or false", key)),
+                            }
+                            cache.check(key)?;
+                            *level = Some(cache);
+                        }
+                        Some(_) => return Err(format!("\"{}\" must be false or an object", key)),
+                    }
+                }
+                if let Some(v) = num(c, "memory_latency")? {
+                    h.memory_latency = v;
+                }
+                match c.get("timing") {
+                    None => {}
+                    Some(Json::Bool(b)) => h.timing = *b,
+                    Some(_) => return Err("\"timing\" must be true or false".to_string()),
+                }
+                Some(h)
+            }
+            Some(_) => return Err("\"caches\" must be true, false or an object".to_string()),
+        };
+
+        let predictor = match root.get("branch_predictor") {
+            None => None,
+            Some(Json::Str(name)) => Some(predictor(name)?),
+            Some(p @ Json::Object(_)) => {
+                let name = p.get("type").and_then(|t| t.as_str()).ok_or("the branch predictor needs a \"type\"")?;
+                let mut c = predictor(name)?;
+                for (key, field) in [("entries", &mut c.entries), ("btb", &mut c.btb), ("ras", &mut c.ras)] {
+                    if let Some(v) = num(p, key)? {
+                        *field = v;
+                    }
+                }
+                if let Some(v) = num(p, "history")? {
+                    c.history = v as u32;
+                }
+                c.check()?;
+                Some(c)
+            }
+            Some(_) => return Err("\"branch_predictor\" must be a type or an object".to_string()),
+        };
+
+        let boot_rom = num(&root, "boot_rom")?;
+        let isa = match root.get("isa") {
+            Some(isa) => isa.as_str().ok_or("\"isa\" must be a string")?.to_lowercase(),
+            None => "rv64ima".to_string(),
+        };
+        let config = Self {
+            name: root.get("name").and_then(|n| n.as_str()).unwrap_or("custom").to_string(),
+            harts: num(&root, "harts")?.unwrap_or(1),
+            isa,
+            reset_vector: num(&root, "reset_vector")?.or(boot_rom),
+            boot_rom,
+            ram,
+            roms,
+            devices,
+            pipeline,
+            caches,
+            predictor,
+        };
+        config.check_layout()?;
+        Ok(config)
+    }
+
+    /// Makes sure every memory region and device has a size, fits in the address space
+    /// and doesn't overlap another.
+    fn check_layout(&self) -> Result<(), String> {
+        let mut ranges = vec!();
+        for (what, regions) in [("RAM", &self.ram), ("ROM", &self.roms)] {
+            for r in regions.iter() {
+                ranges.push((format!("{} at 0x{:X}", what, r.base), r.base, r.size));
+            }
+        }
+        if let Some(base) = self.boot_rom {
+            ranges.push((format!("boot ROM at 0x{:X}", base), base, BOOT_ROM_SIZE as u64));
+        }
+        for d in &self.devices {
+            ranges.push((format!("{} at 0x{:X}", d.kind.name(), d.base), d.base, d.kind.size()));
+        }
+        for (name, base, size) in &ranges {
+            if *size == 0 {
+                return Err(format!("{} is empty", name));
+            }
+            if base.checked_add(*size).is_none() {
+                return Err(format!("{} runs past the end of the address space", name));
+            }
+        }
+        ranges.sort_by_key(|r| r.1);
+        for pair in ranges.windows(2) {
+            let ((a, a_base, a_size), (b, b_base, _)) = (&pair[0], &pair[1]);
+            if a_base + a_size > *b_base {
+                return Err(format!("{} overlaps {}", a, b));
+            }
+        }
+        Ok(())
+    }
+
+    /// The misa value for the ISA string, e.g. "rv64ima_zicsr". Extensions that aren't
+    /// implemented are rejected.
+    pub fn misa(&self) -> Result<u64, String> {
+        let exts = self.isa.strip_prefix("rv64")
+            .ok_or_else(|| format!("unsupported ISA \"{}\", only rv64 is emulated", self.isa))?;
+        // Supervisor and user mode are always there
+        let mut misa = 2 << 62 | 1 << (b's' - b'a') | 1 << (b'u' - b'a');
+        for c in exts.split('_').next().unwrap_or("").chars() {
+            let letters = if c == 'g' { "imafd" } else { "" };
+            for c in letters.chars().chain(Some(c).filter(|&c| c != 'g')) {
+                if !c.is_ascii_lowercase() {
+                    return Err(format!("bad extension '{}' in ISA \"{}\"", c, self.isa));
+                }
+                if !IMPLEMENTED_EXTENSIONS.contains(c) {
+                    return Err(format!("extension '{}' in ISA \"{}\" is not implemented", c, self.isa));
+                }
+                misa |= 1 << (c as u8 - b'a');
+            }
+        }
+        Ok(misa)
+    }
+}
@@ -0,0 +1,204 @@
+// A small JSON parser, enough for machine configuration files.
+
+use std::str::Chars;
+use std::iter::Peekable;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    /// Kept as written so 64 bit addresses don't lose precision.
+    Number(String),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut p = Parser { chars: text.chars().peekable(), line: 1 };
+        let val = p.value()?;
+        p.skip_ws();
+        match p.chars.next() {
+            None => Ok(val),
+            Some(c) => Err(p.error(&format!("unexpected '{}' after the value", c))),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// A number, or a string holding a hex number or a size like "128M".
+    pub fn as_u64(&self) -> Option<u64> {
+        let s = match self {
+            Json::Number(s) | Json::Str(s) => s.as_str(),
+            _ => return None,
+        };
+        if let Some(hex) = s.strip_prefix("0x") {
+            return u64::from_str_radix(&hex.replace('_', ""), 16).ok();
+        }
+        let (num, mult) = match s.char_indices().last() {
+            Some((i, 'K')) => (&s[..i], 1 << 10),
+            Some((i, 'M')) => (&s[..i], 1 << 20),
+            Some((i, 'G')) => (&s[..i], 1 << 30),
+            _ => (s, 1),
+        };
+        num.parse::<u64>().ok()?.checked_mul(mult)
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, msg: &str) -> String {
+        format!("line {}: {}", self.line, msg)
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            if c == '\n' {
+                self.line += 1;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn expect(&mut self, want: char) -> Result<(), String> {
+        self.skip_ws();
+        match self.chars.next() {
+            Some(c) if c == want => Ok(()),
+            Some(c) => Err(self.error(&format!("expected '{}', found '{}'", want, c))),
+            None => Err(self.error(&format!("expected '{}', found the end", want))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_ws();
+        match self.chars.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Json::Str(self.string()?)),
+            Some(c) if c.is_ascii_digit() || *c == '-' => {
+                let mut s = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || "+-.".contains(c)) {
+                        break;
+                    }
+                    s.push(c);
+                    self.chars.next();
+                }
+                Ok(Json::Number(s))
+            }
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if !c.is_ascii_alphabetic() {
+                        break;
+                    }
+                    word.push(c);
+                    self.chars.next();
+                }
+                match word.as_str() {
+                    "true" => Ok(Json::Bool(true)),
+                    "false" => Ok(Json::Bool(false)),
+                    "null" => Ok(Json::Null),
+                    _ => Err(self.error(&format!("unexpected '{}'", word))),
+                }
+            }
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut fields = vec!();
+        self.skip_ws();
+        if self.chars.peek() == Some(&'}') {
+            self.chars.next();
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_ws();
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_ws();
+            match self.chars.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Json::Object(fields)),
+                _ => return Err(self.error("expected ',' or '}' in object")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = vec!();
+        self.skip_ws();
+        if self.chars.peek() == Some(&']') {
+            self.chars.next();
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_ws();
+            match self.chars.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected ',' or ']' in array")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.chars.next() != Some('"') {
+            return Err(self.error("expected a string"));
+        }
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.chars.next()).collect();
+                        let c = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32);
+                        s.push(c.ok_or_else(|| self.error("bad \\u escape"))?);
+                    }
+                    Some(c) => s.push(c),
+                    None => break,
+                },
+                Some('\n') => return Err(self.error("newline in string")),
+                Some(c) => s.push(c),
+                None => break,
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+}
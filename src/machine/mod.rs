@@ -0,0 +1,619 @@
+// Machine profiles: which devices sit where on the bus and how a payload is started.
+
+pub mod config;
+pub mod json;
+
+use crate::bus::Device;
+use crate::clint::{Clint, CLINT_SIZE};
+use crate::cpu::CPU;
+use crate::dram::DRAM;
+use crate::fdt::{FdtWriter, PHANDLE_CPU_INTC};
+use crate::mapped::MappedMemory;
+use crate::plic::{Plic, PLIC_SIZE};
+use crate::rom::{Rom, BOOT_ROM_SIZE};
+use crate::sbi::TIMEBASE_FREQ;
+use crate::sparse::SparseMemory;
+use crate::syscon::{Syscon, SYSCON_SIZE};
+use crate::uart::{Uart, UART_SIZE};
+use crate::virtio::{VirtioMmio, VIRTIO_SIZE};
+use config::{DeviceKind, MachineConfig, Region};
+
+/// The device tree goes at the top of DRAM, in the last 2 MiB like QEMU does.
+const DTB_ALIGN: u64 = 2 * 1024 * 1024;
+const PAGE_SIZE: u64 = 4096;
+
+/// Where the boot payloads were placed in memory.
+#[derive(Debug)]
+pub struct BootInfo {
+    pub dtb: u64,
+    pub dtb_size: usize,
+    pub initrd: Option<(u64, u64)>,
+}
+
+/// Creates a CPU with the memory and devices of `config`, with `buffer` loaded at the
+/// start of its main memory. A boot ROM jumps to `entry`, or to the start of main memory
+/// without one. `console_input` connects stdin to the UARTs.
+pub fn build(config: &MachineConfig, buffer: Vec<u8>, entry: Option<u64>, console_input: bool) -> Result<CPU, String> {
+    if config.harts != 1 {
+        return Err(format!("{} harts requested, only one is supported", config.harts));
+    }
+    let main = &config.ram[0];
+    let mut cpu = if main.sparse || main.file.is_some() {
+        let mut cpu = CPU::with_dram(main.base as usize, main.size as usize, ram(main)?);
+        cpu.load_mem(main.base as usize, &buffer).map_err(|_| "Program does not fit in memory".to_string())?;
+        cpu
+    } else {
+        CPU::with_memory(main.base as usize, main.size as usize, buffer)
+    };
+    // Unlike a bare CPU, a board comes out of reset without a stack
+    cpu.set_reg(2, 0);
+    cpu.set_csr(0x301, config.misa()?).unwrap();
+    cpu.set_pipeline(config.pipeline.clone());
+    cpu.set_caches(config.caches.clone());
+    cpu.set_predictor(config.predictor.clone());
+    for region in &config.ram[1..] {
+        cpu.map_device(region.base as usize, region.size as usize, ram(region)?);
+    }
+    for rom in &config.roms {
+        let data = match &rom.file {
+            Some(path) => std::fs::read(path).map_err(|e| format!("Error opening {}: {}", path, e))?,
+            None => vec!(),
+        };
+        if data.len() as u64 > rom.size {
+            return Err(format!("ROM image {:?} is larger than its region", rom.file));
+        }
+        cpu.map_device(rom.base as usize, rom.size as usize, Box::new(Rom::new(rom.size as usize, data)));
+    }
+    if let Some(base) = config.boot_rom {
+        let rom = Rom::boot(entry.unwrap_or(main.base), dtb_address(&cpu));
+        cpu.map_device(base as usize, BOOT_ROM_SIZE, Box::new(rom));
+    }
+    for dev in &config.devices {
+        let base = dev.base as usize;
+        match dev.kind {
+            DeviceKind::Clint => cpu.map_device(base, CLINT_SIZE, Box::new(Clint::new())),
+            DeviceKind::Plic => cpu.map_device(base, PLIC_SIZE, Box::new(Plic::new())),
+            DeviceKind::Uart => cpu.map_device(base, UART_SIZE, Box::new(Uart::new(console_input, dev.irq))),
+            DeviceKind::Virtio => cpu.map_device(base, VIRTIO_SIZE, Box::new(VirtioMmio::new(dev.irq))),
+            DeviceKind::Syscon => {
+                let halt = cpu.halt_handle();
+                cpu.map_device(base, SYSCON_SIZE, Box::new(Syscon::new(halt)))
+            }
+        }
+    }
+    if let Some(pc) = config.reset_vector {
+        cpu.set_pc(pc);
+    }
+    Ok(cpu)
+}
+
+/// Where `boot` puts the device tree.
+fn dtb_address(cpu: &CPU) -> u64 {
+    let (base, size) = cpu.dram_range();
+    ((base + size) as u64 - DTB_ALIGN) & !(DTB_ALIGN - 1)
+}
+
+/// The memory backing a RAM region.
+fn ram(region: &Region) -> Result<Box<dyn Device>, String> {
+    let size = region.size as usize;
+    Ok(match &region.file {
+        Some(path) => Box::new(MappedMemory::open(path, size, region.shared)?),
+        None if region.sparse => Box::new(SparseMemory::new(size, vec!())),
+        None => Box::new(DRAM::new(size, vec!())),
+    })
+}
+
+/// Describes the hart, memory and every device on the bus as a device tree blob.
+pub fn device_tree(cpu: &CPU, config: &MachineConfig, initrd: Option<(u64, u64)>, bootargs: &str) -> Vec<u8> {
+    let mut fdt = FdtWriter::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", &format!("riscv-emu,{}", config.name));
+
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", bootargs);
+    if let Some(uart) = config.devices.iter().find(|d| d.kind == DeviceKind::Uart) {
+        fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", uart.base));
+    }
+    if let Some((start, end)) = initrd {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    for region in &config.ram {
+        fdt.begin_node(&format!("memory@{:x}", region.base));
+        fdt.property_string("device_type", "memory");
+        fdt.property_reg(region.base, region.size);
+        fdt.end_node();
+    }
+
+    let hartid = cpu.read_csr(0xF14) as u32;
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", TIMEBASE_FREQ as u32);
+    fdt.begin_node(&format!("cpu@{:x}", hartid));
+    fdt.property_string("device_type", "cpu");
+    fdt.property_u32("reg", hartid);
+    fdt.property_string("status", "okay");
+    fdt.property_string("compatible", "riscv");
+    fdt.property_string("riscv,isa", &cpu.isa_string());
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_string("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", PHANDLE_CPU_INTC);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+    cpu.dt_nodes(&mut fdt);
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+/// Places the initrd and device tree at the top of DRAM. Without a device tree
+/// file one is generated. Without a boot ROM to do it, the boot registers are set
+/// up here: hart ID in a0 and the device tree address in a1.
+pub fn boot(cpu: &mut CPU, config: &MachineConfig, dtb: Option<Vec<u8>>, initrd: Option<&[u8]>, bootargs: &str)
+            -> Result<BootInfo, String> {
+    let (base, size) = cpu.dram_range();
+    let top = (base + size) as u64;
+    let dtb_addr = dtb_address(cpu);
+    let initrd = match initrd {
+        Some(initrd) => {
+            let start = dtb_addr.checked_sub(initrd.len() as u64).ok_or("Initrd does not fit in memory")?
+                & !(PAGE_SIZE - 1);
+            cpu.load_mem(start as usize, initrd).map_err(|_| "Initrd does not fit in memory".to_string())?;
+            Some((start, start + initrd.len() as u64))
+        }
+        None => None,
+    };
+    let dtb = dtb.unwrap_or_else(|| device_tree(cpu, config, initrd, bootargs));
+    if dtb.len() as u64 > top - dtb_addr {
+        return Err("Device tree does not fit in memory".to_string());
+    }
+    cpu.load_mem(dtb_addr as usize, &dtb).map_err(|_| "Device tree does not fit in memory".to_string())?;
+    if config.boot_rom.is_none() {
+        let hartid = cpu.read_csr(0xF14);
+        cpu.set_reg(10, hartid);
+        cpu.set_reg(11, dtb_addr);
+    }
+    Ok(BootInfo {
+        dtb: dtb_addr,
+        dtb_size: dtb.len(),
+        initrd,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sbi::Sbi;
+
+    fn payload(code: &[u32]) -> Vec<u8> {
+        code.iter().flat_map(|i| i.to_le_bytes()).collect()
+    }
+
+    /// Builds the virt machine with `code` at the start of DRAM, started by the boot ROM.
+    fn virt(code: &[u32]) -> (CPU, BootInfo) {
+        let config = MachineConfig::virt();
+        let mut cpu = build(&config, payload(code), None, false).unwrap();
+        let info = boot(&mut cpu, &config, None, None, "").unwrap();
+        (cpu, info)
+    }
+
+    /// auipc and jalr with negative offsets wrap around instead of overflowing. Exits
+    /// with 42 if both land where they should.
+    #[test]
+    fn negative_offsets() {
+        let code = [
+            0xFFFFF297, // auipc t0, 0xfffff
+            0x7FFFF337, // lui t1, 0x7ffff
+            0x02629263, // bne t0, t1, fail
+            0x00000397, // auipc t2, 0
+            0x01038393, // addi t2, t2, 16
+            0xFFC380E7, // jalr ra, -4(t2)
+            0x02A00513, // li a0, 42
+            0x40138E33, // sub t3, t2, ra
+            0x00400E93, // li t4, 4
+            0x01DE1463, // bne t3, t4, fail
+            0x0080006F, // j exit
+            0x00100513, // fail: li a0, 1
+            0x01051513, // exit: slli a0, a0, 16
+            0x000032B7, // lui t0, 3
+            0x3332829B, // addiw t0, t0, 819
+            0x00556533, // or a0, a0, t0
+            0x001002B7, // lui t0, 256
+            0x00A2A023, // sw a0, 0(t0)
+            0x0000006F, // j .
+        ];
+        let (mut cpu, _) = virt(&code);
+        assert_eq!(run(&mut cpu), Some(42));
+    }
+
+    /// A branch to itself spins in place.
+    #[test]
+    fn branch_to_self() {
+        let (mut cpu, _) = virt(&[0x00000063]); // beq zero, zero, .
+        let (base, _) = cpu.dram_range();
+        cpu.run(1000);
+        assert!(cpu.is_running());
+        assert_eq!(cpu.pc(), base as u64);
+    }
+
+    /// Only implemented extensions make it into misa and the device tree.
+    #[test]
+    fn isa_extensions() {
+        let mut config = MachineConfig::virt();
+        let (cpu, _) = virt(&[]);
+        assert_eq!(cpu.isa_string(), "rv64ima_zicsr");
+        for isa in &["rv64imac", "rv64gc", "rv64imafd"] {
+            config.isa = isa.to_string();
+            assert!(config.misa().is_err(), "{} accepted", isa);
+        }
+    }
+
+    /// Interrupt lines have to exist on the PLIC and regions can't overlap or wrap around.
+    #[test]
+    fn config_layout() {
+        let config = |devices: &str, ram: &str| {
+            MachineConfig::from_json(&format!("{{ \"ram\": [{}], \"devices\": [{}] }}", ram, devices))
+        };
+        let ram = r#"{ "base": "0x80000000", "size": "0x1000000" }"#;
+        assert!(config(r#"{ "type": "uart", "base": "0x10000000", "irq": 10 }"#, ram).is_ok());
+        for irq in &["0", "96", "200", "4294967306"] {
+            let uart = format!(r#"{{ "type": "uart", "base": "0x10000000", "irq": {} }}"#, irq);
+            assert!(config(&uart, ram).is_err(), "irq {} accepted", irq);
+        }
+        assert!(config(r#"{ "type": "uart", "base": "0x10000000" }"#, ram).is_err());
+        assert!(config(r#"{ "type": "clint", "base": "0x2000000", "irq": 3 }"#, ram).is_err());
+        assert!(config(r#"{ "type": "clint", "base": "0x80000000" }"#, ram).is_err());
+        assert!(config("", r#"{ "base": "0x80000000", "size": "0" }"#).is_err());
+        assert!(config("", r#"{ "base": "0xFFFFFFFFFFFFF000", "size": "0x2000" }"#).is_err());
+        assert!(config("", &format!(r#"{}, {{ "base": "0x80FFF000", "size": "0x2000" }}"#, ram)).is_err());
+    }
+
+    fn run(cpu: &mut CPU) -> Option<i32> {
+        cpu.run(100_000);
+        cpu.exit_code()
+    }
+
+    /// Checks the device tree magic in a1, takes a CLINT timer interrupt, then has the
+    /// UART raise a THR empty interrupt through the PLIC and claims it. Exits with 42.
+    #[test]
+    fn boot_interrupts() {
+        let code = [
+            0x0005E283, // lwu t0, 0(a1)
+            0x000EE337, // lui t1, 238
+            0xFE13031B, // addiw t1, t1, -31
+            0x00C31313, // slli t1, t1, 12
+            0xDD030313, // addi t1, t1, -560
+            0x0C629063, // bne t0, t1, fail
+            0x00000297, // auipc t0, 0
+            0x03C28293, // addi t0, t0, 60
+            0x30529073, // csrw mtvec, t0
+            0x0200C2B7, // lui t0, 8204
+            0xFF82829B, // addiw t0, t0, -8
+            0x0002B303, // ld t1, 0(t0)
+            0x06430313, // addi t1, t1, 100
+            0x020042B7, // lui t0, 8196
+            0x0062B023, // sd t1, 0(t0)
+            0x000012B7, // lui t0, 1
+            0x8802829B, // addiw t0, t0, -1920
+            0x30429073, // csrw mie, t0
+            0x30046073, // csrsi mstatus, 8
+            0x10500073, // 1: wfi
+            0xFFDFF06F, // j 1b
+            0x342022F3, // handler: csrr t0, mcause
+            0xFFF00313, // li t1, -1
+            0x03F31313, // slli t1, t1, 63
+            0x00730313, // addi t1, t1, 7
+            0x02628A63, // beq t0, t1, timer
+            0xFFF00313, // li t1, -1
+            0x03F31313, // slli t1, t1, 63
+            0x00B30313, // addi t1, t1, 11
+            0x06629063, // bne t0, t1, fail
+            0x0C2002B7, // lui t0, 49664
+            0x0042829B, // addiw t0, t0, 4
+            0x0002A303, // lw t1, 0(t0)
+            0x00A00393, // li t2, 10
+            0x04731663, // bne t1, t2, fail
+            0x0062A023, // sw t1, 0(t0)
+            0x02A00513, // li a0, 42
+            0x0440006F, // j exit
+            0x020042B7, // timer: lui t0, 8196
+            0xFFF00313, // li t1, -1
+            0x0062B023, // sd t1, 0(t0)
+            0x0C0002B7, // lui t0, 49152
+            0x0282829B, // addiw t0, t0, 40
+            0x00100313, // li t1, 1
+            0x0062A023, // sw t1, 0(t0)
+            0x0C0022B7, // lui t0, 49154
+            0x40000313, // li t1, 1024
+            0x0062A023, // sw t1, 0(t0)
+            0x100002B7, // lui t0, 65536
+            0x0012829B, // addiw t0, t0, 1
+            0x00200313, // li t1, 2
+            0x00628023, // sb t1, 0(t0)
+            0x30200073, // mret
+            0x00100513, // fail: li a0, 1
+            0x01051513, // exit: slli a0, a0, 16
+            0x000032B7, // lui t0, 3
+            0x3332829B, // addiw t0, t0, 819
+            0x00556533, // or a0, a0, t0
+            0x001002B7, // lui t0, 256
+            0x00A2A023, // sw a0, 0(t0)
+            0x0000006F, // j .
+        ];
+        let (mut cpu, _) = virt(&code);
+        assert_eq!(run(&mut cpu), Some(42));
+    }
+
+    /// A snapshot taken between lr and sc restores into a fresh machine with the
+    /// reservation still held, and both machines exit the same way.
+    #[test]
+    fn snapshot_round_trip() {
+        let code = [
+            0x00000297, // auipc t0, 0
+            0x10028293, // addi t0, t0, 256
+            0x1002B32F, // lr.d t1, (t0)
+            0x1862B3AF, // sc.d t2, t1, (t0)
+            0x02A38513, // addi a0, t2, 42
+            0x01051513, // slli a0, a0, 16
+            0x000032B7, // lui t0, 3
+            0x3332829B, // addiw t0, t0, 819
+            0x00556533, // or a0, a0, t0
+            0x001002B7, // lui t0, 256
+            0x00A2A023, // sw a0, 0(t0)
+            0x0000006F, // j .
+        ];
+        let (mut cpu, _) = virt(&code);
+        let (base, _) = cpu.dram_range();
+        for _ in 0..100 {
+            if cpu.pc() == base as u64 + 12 {
+                break;
+            }
+            cpu.step();
+        }
+        assert_eq!(cpu.pc(), base as u64 + 12);
+        let snap = cpu.snapshot();
+
+        let (mut copy, _) = virt(&[]);
+        copy.restore(&snap).unwrap();
+        assert!(copy.snapshot() == snap);
+        assert_eq!(run(&mut copy), Some(42));
+        assert_eq!(run(&mut cpu), Some(42));
+    }
+
+    /// A snapshot that fails to restore leaves the machine as it was.
+    #[test]
+    fn snapshot_truncated() {
+        let (cpu, _) = virt(&[0x00000063]);
+        let snap = cpu.snapshot();
+        let (mut other, _) = virt(&[0x0000006F]);
+        for _ in 0..10 {
+            other.step();
+        }
+        let before = other.snapshot();
+        assert!(other.restore(&snap[..snap.len() - 1]).is_err());
+        assert!(other.snapshot() == before);
+    }
+
+    /// Reverse stepping a recording with device writes gets back to the exact starting
+    /// state, and replaying it gets back to the end.
+    #[test]
+    fn record_reverse() {
+        let code = [
+            0x020042B7, // lui t0, 0x2004
+            0x00500313, // li t1, 5
+            0x0062B023, // loop: sd t1, 0(t0)
+            0xFFF30313, // addi t1, t1, -1
+            0xFE031CE3, // bnez t1, loop
+            0x0000006F, // j .
+        ];
+        let (mut cpu, _) = virt(&code);
+        let start = cpu.snapshot();
+        cpu.start_recording();
+        for _ in 0..60 {
+            cpu.step();
+        }
+        let end = cpu.snapshot();
+        while cpu.reverse_step() {}
+        assert!(cpu.snapshot() == start);
+        for _ in 0..60 {
+            cpu.step();
+        }
+        assert!(cpu.snapshot() == end);
+    }
+
+    /// Maps DRAM again at 0x40000000 with a gigapage, drops to S-mode through it and
+    /// stores there, then faults on an unmapped load. The M-mode handler adds 100 for the
+    /// faulting address, 50 for the store landing in DRAM and 1 for the accessed and
+    /// dirty bits, and exits with the sum plus the cause, 13.
+    #[test]
+    fn boot_paging() {
+        let code = [
+            0x00000297, // auipc t0, 0
+            0x07C28293, // addi t0, t0, 124
+            0x30529073, // csrw mtvec, t0
+            0x00080337, // lui t1, 128
+            0x0013031B, // addiw t1, t1, 1
+            0x00C31313, // slli t1, t1, 12
+            0x200003B7, // lui t2, 131072
+            0x0CF3839B, // addiw t2, t2, 207
+            0x00733423, // sd t2, 8(t1)
+            0x00733823, // sd t2, 16(t1)
+            0x00C35E13, // srli t3, t1, 12
+            0xFFF00E93, // li t4, -1
+            0x03FE9E93, // slli t4, t4, 63
+            0x01DE6E33, // or t3, t3, t4
+            0x180E1073, // csrw satp, t3
+            0x00001EB7, // lui t4, 1
+            0x800E8E9B, // addiw t4, t4, -2048
+            0x300EA073, // csrs mstatus, t4
+            0x00000F17, // auipc t5, 0
+            0x018F0F13, // addi t5, t5, 24
+            0x40000FB7, // lui t6, 262144
+            0x41FF0F33, // sub t5, t5, t6
+            0x341F1073, // csrw mepc, t5
+            0x30200073, // mret
+            0x40002637, // smode: lui a2, 262146
+            0x02A00513, // li a0, 42
+            0x00A63023, // sd a0, 0(a2)
+            0x00300693, // li a3, 3
+            0x01E69693, // slli a3, a3, 30
+            0x0006B703, // ld a4, 0(a3)
+            0x0000006F, // j .
+            0x34202473, // mhandler: csrr s0, mcause
+            0x34302373, // csrr t1, mtval
+            0x00300393, // li t2, 3
+            0x01E39393, // slli t2, t2, 30
+            0x00731463, // bne t1, t2, 1f
+            0x06440413, // addi s0, s0, 100
+            0x40001E37, // 1: lui t3, 262145
+            0x001E1E13, // slli t3, t3, 1
+            0x000E3E03, // ld t3, 0(t3)
+            0x02A00393, // li t2, 42
+            0x007E1463, // bne t3, t2, 1f
+            0x03240413, // addi s0, s0, 50
+            0x00080E37, // 1: lui t3, 128
+            0x001E0E1B, // addiw t3, t3, 1
+            0x00CE1E13, // slli t3, t3, 12
+            0x008E0E13, // addi t3, t3, 8
+            0x000E3E03, // ld t3, 0(t3)
+            0x0C0E7E13, // andi t3, t3, 192
+            0x0C000393, // li t2, 192
+            0x007E1463, // bne t3, t2, 1f
+            0x00140413, // addi s0, s0, 1
+            0x01041513, // 1: slli a0, s0, 16
+            0x000032B7, // lui t0, 3
+            0x3332829B, // addiw t0, t0, 819
+            0x00556533, // or a0, a0, t0
+            0x00100337, // lui t1, 256
+            0x00A32023, // sw a0, 0(t1)
+            0x0000006F, // j .
+        ];
+        let (mut cpu, _) = virt(&code);
+        assert_eq!(run(&mut cpu), Some(164));
+    }
+
+    /// Maps DRAM and the low devices with gigapages, then loops over loads and stores
+    /// in S-mode long enough for the JIT to compile it. Exits with 32 with or without
+    /// the JIT, which must have run most of the loop under paging.
+    #[test]
+    fn jit_paging() {
+        let code = [
+            0x00000497, // auipc s1, 0
+            0x000022B7, // lui t0, 2
+            0x00548933, // add s2, s1, t0
+            0x0C700313, // li t1, 0xc7
+            0x00693023, // sd t1, 0(s2)
+            0x00C4D313, // srli t1, s1, 12
+            0x00A31313, // slli t1, t1, 10
+            0x0CF36313, // ori t1, t1, 0xcf
+            0x00693823, // sd t1, 16(s2)
+            0x00C95313, // srli t1, s2, 12
+            0xFFF00393, // li t2, -1
+            0x03F39393, // slli t2, t2, 63
+            0x00736333, // or t1, t1, t2
+            0x18031073, // csrw satp, t1
+            0x000012B7, // lui t0, 1
+            0x8002829B, // addiw t0, t0, -2048
+            0x3002A073, // csrs mstatus, t0
+            0x00000297, // auipc t0, 0
+            0x01028293, // addi t0, t0, 16
+            0x34129073, // csrw mepc, t0
+            0x30200073, // mret
+            0x000032B7, // smode: lui t0, 3
+            0x005489B3, // add s3, s1, t0
+            0x0C800313, // li t1, 200
+            0x00000513, // li a0, 0
+            0x0009B383, // loop: ld t2, 0(s3)
+            0x00338393, // addi t2, t2, 3
+            0x0079B023, // sd t2, 0(s3)
+            0x00754533, // xor a0, a0, t2
+            0x0073FE13, // andi t3, t2, 7
+            0x01C50533, // add a0, a0, t3
+            0xFFF30313, // addi t1, t1, -1
+            0xFE0312E3, // bnez t1, loop
+            0x0FF57513, // andi a0, a0, 255
+            0x01051513, // slli a0, a0, 16
+            0x000032B7, // lui t0, 3
+            0x3332829B, // addiw t0, t0, 819
+            0x00556533, // or a0, a0, t0
+            0x001002B7, // lui t0, 256
+            0x00A2A023, // sw a0, 0(t0)
+            0x0000006F, // j .
+        ];
+        for jit in [false, true] {
+            let (mut cpu, _) = virt(&code);
+            cpu.set_jit(jit);
+            assert_eq!(run(&mut cpu), Some(32));
+            let (_, _, _, native, _) = cpu.block_stats();
+            if jit && cfg!(target_arch = "x86_64") {
+                assert!(native > 1000, "{} instructions run natively", native);
+            } else {
+                assert_eq!(native, 0);
+            }
+        }
+    }
+
+    /// An S-mode payload under the SBI: reads the time, sets a timer through the SBI,
+    /// takes the supervisor timer interrupt and shuts down with reason 0, or 1 on failure.
+    #[test]
+    fn boot_sbi_timer() {
+        let code = [
+            0x00000317, // auipc t1, 0
+            0x04C30313, // addi t1, t1, 76
+            0x10531073, // csrw stvec, t1
+            0x03200E93, // li t4, 50
+            0xFFFE8E93, // 0: addi t4, t4, -1
+            0xFE0E9EE3, // bnez t4, 0b
+            0xC0102573, // rdtime a0
+            0x04050663, // beqz a0, fail
+            0x06450513, // addi a0, a0, 100
+            0x544958B7, // lui a7, 345237
+            0xD458889B, // addiw a7, a7, -699
+            0x00000813, // li a6, 0
+            0x00000073, // ecall
+            0x02051A63, // bnez a0, fail
+            0x02000313, // li t1, 32
+            0x10431073, // csrw sie, t1
+            0x10016073, // csrsi sstatus, 2
+            0x10500073, // 1: wfi
+            0xFFDFF06F, // j 1b
+            0x142023F3, // handler: csrr t2, scause
+            0xFFF00E13, // li t3, -1
+            0x03FE1E13, // slli t3, t3, 63
+            0x005E0E13, // addi t3, t3, 5
+            0x01C39663, // bne t2, t3, fail
+            0x00000593, // li a1, 0
+            0x0080006F, // j exit
+            0x00100593, // fail: li a1, 1
+            0x535258B7, // exit: lui a7, 341285
+            0x3548889B, // addiw a7, a7, 852
+            0x00000813, // li a6, 0
+            0x00000513, // li a0, 0
+            0x00000073, // ecall
+            0x0000006F, // j .
+        ];
+        let (mut cpu, info) = virt(&code);
+        // Like --sbi, which starts the payload itself instead of the boot ROM
+        let (base, _) = cpu.dram_range();
+        cpu.set_pc(base as u64);
+        cpu.set_reg(11, info.dtb);
+        cpu.set_sbi(Sbi::new());
+        assert_eq!(run(&mut cpu), Some(0));
+    }
+}
@@ -1,22 +1,111 @@
 use std::{env, fs};
 
 use crate::Cmd::*;
-use crate::Flags::{File, Interactive};
+use crate::Flags::{File, Interactive, UserMode, Semihost, ToHost, FromHost, BuiltinSbi, Jit, Trace, Pipeline, Caches, BranchPredictor, Machine, Dtb, Initrd, BootArgs, DumpDtb};
+use crate::cpu::MemFormat;
+use crate::cpu::bpred::{BranchStats, Predictor};
+use crate::cpu::cache::{Hierarchy, HierarchyConfig};
+use crate::cpu::pipeline::{PipelineConfig, PipelineStats};
+use crate::elf::{Elf, SymbolTable};
+use crate::elf::line::LineTable;
+use crate::snapshot::SnapshotReader;
+use crate::linux::LinuxUser;
+use crate::semihosting::Semihosting;
+use crate::htif::Htif;
+use crate::machine::config::{self, MachineConfig};
+use crate::sbi::Sbi;
+
+/// Guest memory for Linux user mode, mapped from address 0 with the stack at the top.
+const USER_MEM_SIZE: usize = 256 * 1024 * 1024;
+
+/// Unless the program has a way to exit (user mode, semihosting, SBI or HTIF), execution stops after this many instructions.
+const STEP_LIMIT: usize = 500;
+
+/// Space below the top of memory kept for the stack of bare-metal programs, whose heap
+/// semihosting and the HTIF syscall proxy place between the program and the stack.
+const TARGET_STACK_SIZE: u64 = 1024 * 1024;
 
 mod cpu;
 mod dram;
+mod sparse;
+mod mapped;
+mod rom;
 mod bus;
+mod elf;
+mod snapshot;
+mod linux;
+mod semihosting;
+mod htif;
+mod sbi;
+mod clint;
+mod plic;
+mod uart;
+mod virtio;
+mod syscon;
+mod machine;
+mod fdt;
+mod events;
 
 #[derive(Debug)]
 #[derive(PartialOrd, PartialEq)]
 enum Flags {
     Interactive,
+    UserMode,
+    Semihost,
+    ToHost{addr: u64},
+    FromHost{addr: u64},
+    BuiltinSbi,
+    Jit,
+    Trace,
+    Pipeline,
+    Caches,
+    BranchPredictor{kind: String},
+    Machine{spec: String},
+    Dtb{path: String},
+    Initrd{path: String},
+    BootArgs{args: String},
+    DumpDtb{path: String},
     File{path: String},
 }
 
 fn parse_arg(args: &Vec<String>, index: usize) -> Vec<Flags> {
     let mut i = 0;
     let mut opts: Vec<Flags> = vec!();
+    if let Some(opt) = args[index].strip_prefix("--") {
+        if opt == "sbi" {
+            opts.push(BuiltinSbi);
+            return opts;
+        }
+        if opt == "jit" {
+            opts.push(Jit);
+            return opts;
+        }
+        if opt == "trace" {
+            opts.push(Trace);
+            return opts;
+        }
+        if opt == "pipeline" {
+            opts.push(Pipeline);
+            return opts;
+        }
+        if opt == "caches" {
+            opts.push(Caches);
+            return opts;
+        }
+        // Other long options take a value, e.g. --tohost=0x80001000
+        match opt.split_once('=') {
+            Some(("tohost", v)) if parse_num(v).is_some() => opts.push(ToHost{addr: parse_num(v).unwrap()}),
+            Some(("fromhost", v)) if parse_num(v).is_some() => opts.push(FromHost{addr: parse_num(v).unwrap()}),
+            Some(("machine", spec)) => opts.push(Machine{spec: spec.to_string()}),
+            Some(("bpred", kind)) => opts.push(BranchPredictor{kind: kind.to_string()}),
+            Some(("dtb", path)) => opts.push(Dtb{path: path.to_string()}),
+            Some(("initrd", path)) => opts.push(Initrd{path: path.to_string()}),
+            Some(("bootargs", args)) => opts.push(BootArgs{args: args.to_string()}),
+            Some(("dump-dtb", path)) => opts.push(DumpDtb{path: path.to_string()}),
+            _ => println!("Unknown arg: {}", args[index]),
+        }
+        return opts;
+    }
     for ch in args[index].chars() {
         if i == 0 && ch != '-' {
             opts.push(File{path: args[index].clone()});
@@ -30,6 +119,12 @@ fn parse_arg(args: &Vec<String>, index: usize) -> Vec<Flags> {
             'i' => /* Interactive */ {
                 opts.push(Interactive);
             }
+            'u' => /* Linux user mode */ {
+                opts.push(UserMode);
+            }
+            's' => /* Semihosting */ {
+                opts.push(Semihost);
+            }
             _ => {
                 println!("Unknown arg: {}", ch);
             }
@@ -59,13 +154,233 @@ fn get_input() -> String {
 #[derive(PartialOrd, PartialEq)]
 enum Cmd {
     Step,
+    StepInst,
+    Next,
+    List,
     PrintAll,
     PrintRegs,
     PrintMemRegion{ addr: usize, len: usize},
+    SetReg{ reg: usize, val: u64 },
+    SetPc{ val: u64 },
+    SetCsr{ csr: usize, val: u64 },
+    WriteMem{ addr: usize, size: usize, val: u64 },
+    FillMem{ addr: usize, len: usize, val: u8 },
+    LoadFile{ path: String, addr: usize },
+    Examine{ addr: Option<usize>, count: usize, fmt: MemFormat },
+    Disassemble{ count: usize },
+    InfoMemory,
+    InfoDevices,
+    InfoIcache,
+    InfoPipeline,
+    InfoCache,
+    InfoBranches,
+    ResetDevices,
+    Break{ addr: u64 },
+    Delete{ addr: u64 },
+    Continue,
+    Watch{ addr: u64, size: usize },
+    Unwatch{ addr: u64 },
+    Record{ action: RecordAction },
+    SaveSnapshot{ path: String },
+    LoadSnapshot{ path: String },
+    ReverseStep,
+    ReverseContinue,
     Nothing,
 }
 
-fn parse_cmd(s: String) -> Cmd {
+#[derive(Debug)]
+#[derive(PartialOrd, PartialEq)]
+enum RecordAction {
+    Start,
+    Stop,
+    Save{ path: String },
+    Load{ path: String },
+}
+
+/// Parses a decimal number, falling back to hex (with or without `0x`). A leading `-` negates.
+fn parse_num(s: &str) -> Option<u64> {
+    if let Some(n) = s.strip_prefix('-') {
+        return parse_num(n).map(|v| v.wrapping_neg());
+    }
+    if let Ok(v) = s.parse::<u64>() {
+        return Some(v);
+    }
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+/// Parses a number or a symbol name with an optional `+offset`, e.g. `main+8`.
+fn parse_addr(s: &str, syms: &SymbolTable) -> Option<u64> {
+    let (name, off) = match s.split_once('+') {
+        Some((name, off)) => (name, parse_num(off)?),
+        None => (s, 0),
+    };
+    match syms.lookup(name) {
+        Some(addr) => Some(addr.wrapping_add(off)),
+        None => parse_num(s),
+    }
+}
+
+fn parse_set(c: &[&str], syms: &SymbolTable) -> Cmd {
+    if c.len() >= 4 && c[1] == "csr" {
+        let csr = cpu::csr_from_name(c[2]).or_else(|| parse_num(c[2]).map(|c| c as usize));
+        return match (csr, parse_addr(c[3], syms)) {
+            (Some(csr), Some(val)) => SetCsr { csr, val },
+            _ => {
+                println!("Not valid csr or value: {:?} {:?}", c[2], c[3]);
+                Nothing
+            }
+        };
+    }
+    if c.len() < 3 {
+        println!("Usage: set <reg|pc|csr> <value> or set csr <name|num> <value>");
+        return Nothing;
+    }
+    let val = match parse_addr(c[2], syms) {
+        Some(v) => v,
+        None => {
+            println!("Not valid value: {:?}", c[2]);
+            return Nothing;
+        }
+    };
+    let name = c[1];
+    if name == "pc" {
+        return SetPc { val };
+    }
+    if let Some(reg) = cpu::reg_from_name(name) {
+        return SetReg { reg, val };
+    }
+    if let Some(csr) = cpu::csr_from_name(name) {
+        return SetCsr { csr, val };
+    }
+    println!("Unknown register: {:?}", name);
+    Nothing
+}
+
+fn parse_write(c: &[&str], syms: &SymbolTable) -> Cmd {
+    if c.len() < 4 {
+        println!("Usage: write <b|h|w|d> <addr> <value>");
+        return Nothing;
+    }
+    let size = match c[1] {
+        "b" => 8,
+        "h" => 16,
+        "w" => 32,
+        "d" => 64,
+        s => {
+            println!("Unknown size: {:?}", s);
+            return Nothing;
+        }
+    };
+    match (parse_addr(c[2], syms), parse_addr(c[3], syms)) {
+        (Some(addr), Some(val)) => WriteMem { addr: addr as usize, size, val },
+        _ => {
+            println!("Not valid address or value: {:?} {:?}", c[2], c[3]);
+            Nothing
+        }
+    }
+}
+
+fn parse_fill(c: &[&str], syms: &SymbolTable) -> Cmd {
+    if c.len() < 4 {
+        println!("Usage: fill <addr> <len> <byte>");
+        return Nothing;
+    }
+    match (parse_addr(c[1], syms), parse_num(c[2]), parse_num(c[3])) {
+        (Some(addr), Some(len), Some(val)) => FillMem { addr: addr as usize, len: len as usize, val: val as u8 },
+        _ => {
+            println!("Not valid fill arguments: {:?}", &c[1..]);
+            Nothing
+        }
+    }
+}
+
+/// Parses `x/<count>[x]<b|h|w|d|s|i> [addr]`. Without an address, examines at pc.
+fn parse_examine(c: &[&str], syms: &SymbolTable) -> Cmd {
+    let spec = c[0].split_once('/').map(|(_, s)| s).unwrap_or("");
+    let digits = spec.chars().take_while(|ch| ch.is_ascii_digit()).count();
+    let count = spec[..digits].parse::<usize>().unwrap_or(1);
+    // Values are always shown in hex, so gdb's `x` radix letter is accepted and ignored
+    let unit = spec[digits..].replace('x', "");
+    let fmt = match unit.as_str() {
+        "b" => MemFormat::Byte,
+        "h" => MemFormat::Half,
+        "" | "w" => MemFormat::Word,
+        "d" | "g" => MemFormat::Double,
+        "s" => MemFormat::Str,
+        "i" => MemFormat::Inst,
+        f => {
+            println!("Unknown format: {:?}", f);
+            return Nothing;
+        }
+    };
+    if c.len() < 2 {
+        return Examine { addr: None, count, fmt };
+    }
+    match parse_addr(c[1], syms) {
+        Some(addr) => Examine { addr: Some(addr as usize), count, fmt },
+        None => {
+            println!("Not valid address: {:?}", c[1]);
+            Nothing
+        }
+    }
+}
+
+fn parse_watch(c: &[&str], syms: &SymbolTable) -> Cmd {
+    let addr = match c.get(1).and_then(|a| parse_addr(a, syms)) {
+        Some(addr) => addr,
+        None => {
+            println!("Usage: watch <addr|symbol> [1|2|4|8]");
+            return Nothing;
+        }
+    };
+    match c.get(2).map(|l| parse_num(l)).unwrap_or(Some(8)) {
+        Some(len) if [1, 2, 4, 8].contains(&len) => Watch { addr, size: len as usize * 8 },
+        _ => {
+            println!("Watch length must be 1, 2, 4 or 8 bytes");
+            Nothing
+        }
+    }
+}
+
+fn parse_record(c: &[&str]) -> Cmd {
+    let action = match (c.get(1).copied(), c.get(2)) {
+        (None, _) | (Some("start"), _) => RecordAction::Start,
+        (Some("stop"), _) => RecordAction::Stop,
+        (Some("save"), Some(path)) => RecordAction::Save { path: path.to_string() },
+        (Some("load"), Some(path)) => RecordAction::Load { path: path.to_string() },
+        _ => {
+            println!("Usage: record [start|stop|save <path>|load <path>]");
+            return Nothing;
+        }
+    };
+    Record { action }
+}
+
+fn parse_load(c: &[&str], syms: &SymbolTable) -> Cmd {
+    if c.len() < 3 {
+        println!("Usage: load <path> <addr>");
+        return Nothing;
+    }
+    match parse_addr(c[2], syms) {
+        Some(addr) => LoadFile { path: c[1].to_string(), addr: addr as usize },
+        None => {
+            println!("Not valid address: {:?}", c[2]);
+            Nothing
+        }
+    }
+}
+
+/// Parses a breakpoint location: `file:line`, a symbol or an address.
+fn parse_location(s: &str, syms: &SymbolTable, lines: &LineTable) -> Option<u64> {
+    if let Some((file, line)) = s.rsplit_once(':') {
+        if let Ok(line) = line.parse::<u64>() {
+            return lines.addr_of(file, line);
+        }
+    }
+    parse_addr(s, syms)
+}
+
+fn parse_cmd(s: String, syms: &SymbolTable, lines: &LineTable) -> Cmd {
     let c = s.split_whitespace().collect::<Vec<&str>>();
     for mut i in 0..c.len() {
         match c[i] {
@@ -82,15 +397,13 @@ fn parse_cmd(s: String) -> Cmd {
                         if i >= c.len()-1 {
                             return PrintMemRegion { addr: 0, len: 16 };
                         }
-                        let mut _f = c[i+1].parse::<u64>();
-                        if _f.is_err() {
-                            _f = u64::from_str_radix(c[i+1].trim_start_matches("0x"), 16);
-                            if _f.is_err() {
+                        let f = match parse_addr(c[i+1], syms) {
+                            Some(f) => f as usize,
+                            None => {
                                 println!("Not valid address: {:?}", c[i+1]);
                                 return Nothing;
                             }
-                        }
-                        let f = _f.unwrap() as usize;
+                        };
                         if i >= c.len()-2 {
                             return PrintMemRegion { addr: f, len: 16 };
                         }
@@ -114,6 +427,103 @@ fn parse_cmd(s: String) -> Cmd {
             "s" | "step" => {
                 return Step;
             }
+            "si" | "stepi" => {
+                return StepInst;
+            }
+            "n" | "next" => {
+                return Next;
+            }
+            "l" | "list" => {
+                return List;
+            }
+            "set" => {
+                return parse_set(&c[i..], syms);
+            }
+            "w" | "write" => {
+                return parse_write(&c[i..], syms);
+            }
+            "fill" => {
+                return parse_fill(&c[i..], syms);
+            }
+            "load" => {
+                return parse_load(&c[i..], syms);
+            }
+            x if x == "x" || x.starts_with("x/") => {
+                return parse_examine(&c[i..], syms);
+            }
+            "b" | "break" => {
+                return match c.get(i+1).and_then(|a| parse_location(a, syms, lines)) {
+                    Some(addr) => Break { addr },
+                    None => {
+                        println!("Usage: break <addr|symbol|file:line>");
+                        Nothing
+                    }
+                };
+            }
+            "d" | "delete" => {
+                return match c.get(i+1).and_then(|a| parse_addr(a, syms)) {
+                    Some(addr) => Delete { addr },
+                    None => {
+                        println!("Usage: delete <addr|symbol>");
+                        Nothing
+                    }
+                };
+            }
+            "c" | "continue" => {
+                return Continue;
+            }
+            "watch" => {
+                return parse_watch(&c[i..], syms);
+            }
+            "unwatch" => {
+                return match c.get(i+1).and_then(|a| parse_addr(a, syms)) {
+                    Some(addr) => Unwatch { addr },
+                    None => {
+                        println!("Usage: unwatch <addr|symbol>");
+                        Nothing
+                    }
+                };
+            }
+            "record" => {
+                return parse_record(&c[i..]);
+            }
+            "snapshot" => {
+                return match (c.get(i+1).copied(), c.get(i+2)) {
+                    (Some("save"), Some(path)) => SaveSnapshot { path: path.to_string() },
+                    (Some("load"), Some(path)) => LoadSnapshot { path: path.to_string() },
+                    _ => {
+                        println!("Usage: snapshot <save|load> <path>");
+                        Nothing
+                    }
+                };
+            }
+            "rs" | "reverse-step" => {
+                return ReverseStep;
+            }
+            "rc" | "reverse-continue" => {
+                return ReverseContinue;
+            }
+            "disas" | "disassemble" => {
+                let count = c.get(i+1).and_then(|n| parse_num(n)).unwrap_or(10);
+                return Disassemble { count: count as usize };
+            }
+            "reset" => {
+                return ResetDevices;
+            }
+            "info" => {
+                return match c.get(i+1).copied() {
+                    Some("memory") | Some("mem") => InfoMemory,
+                    Some("devices") | Some("dev") => InfoDevices,
+                    Some("icache") => InfoIcache,
+                    Some("pipeline") => InfoPipeline,
+                    Some("cache") | Some("caches") => InfoCache,
+                    Some("branches") | Some("bpred") => InfoBranches,
+                    _ => {
+                        println!("Usage: info <memory|devices|icache|pipeline|cache|branches>");
+                        Nothing
+                    }
+                };
+            }
             st => {
                 println!("CMD: {}", st);
                 return Nothing;
@@ -123,6 +533,145 @@ fn parse_cmd(s: String) -> Cmd {
     Nothing
 }
 
+/// A memory location whose value stops execution when it changes.
+struct Watchpoint {
+    addr: u64,
+    size: usize,
+    value: Option<u64>,
+}
+
+/// Checks breakpoints and watchpoints after a step, returning true if execution should stop.
+fn hit_stop(rvcpu: &cpu::CPU, breakpoints: &[u64], watchpoints: &mut [Watchpoint]) -> bool {
+    let mut stop = false;
+    for w in watchpoints.iter_mut() {
+        let value = rvcpu.read_mem(w.addr as usize, w.size).ok();
+        if value != w.value {
+            println!("Watchpoint {} changed: {:X?} -> {:X?}", rvcpu.describe(w.addr), w.value, value);
+            w.value = value;
+            stop = true;
+        }
+    }
+    if breakpoints.contains(&rvcpu.pc()) {
+        println!("Breakpoint at {}", rvcpu.describe(rvcpu.pc()));
+        stop = true;
+    }
+    stop
+}
+
+/// Counts executed instructions against the step limit.
+struct StepCounter {
+    count: usize,
+    limit: usize,
+}
+
+impl StepCounter {
+    /// Counts one instruction, returning false once the limit was passed.
+    fn tick(&mut self) -> bool {
+        self.count += 1;
+        self.count <= self.limit
+    }
+
+    /// Instructions left before the limit is passed.
+    fn remaining(&self) -> usize {
+        self.limit.saturating_sub(self.count).saturating_add(1)
+    }
+}
+
+fn print_pipeline(stats: &PipelineStats) {
+    let cpi = if stats.instructions > 0 { stats.cycles as f64 / stats.instructions as f64 } else { 0.0 };
+    println!("Pipeline: {} instructions in {} cycles (CPI {:.2})", stats.instructions, stats.cycles, cpi);
+    println!("Stalls: {} data, {} structural, {} control, {} fill",
+             stats.data_stalls, stats.structural_stalls, stats.control_stalls, stats.fill_cycles);
+}
+
+fn print_caches(caches: &Hierarchy) {
+    let rate = |misses: u64, total: u64| if total > 0 { misses as f64 * 100.0 / total as f64 } else { 0.0 };
+    for cache in caches.caches() {
+        let (c, s) = (cache.config(), &cache.stats);
+        println!("{}: {} KiB, {}-way, {} byte lines", cache.name, c.size / 1024, c.ways, c.line);
+        println!("\t{} reads, {} misses ({:.2}%)", s.reads, s.read_misses, rate(s.read_misses, s.reads));
+        println!("\t{} writes, {} misses ({:.2}%), {} writebacks",
+                 s.writes, s.write_misses, rate(s.write_misses, s.writes), s.writebacks);
+    }
+}
+
+/// Totals by kind of branch, then the branches mispredicted most often.
+fn print_branches(rvcpu: &cpu::CPU, predictor: &Predictor) {
+    let rate = |s: &BranchStats| if s.executed > 0 { s.mispredicted as f64 * 100.0 / s.executed as f64 } else { 0.0 };
+    let c = predictor.config();
+    println!("Branch predictor: {:?}, {} entries, {} history bits, {} BTB entries, {} deep RAS",
+             c.direction, c.entries, c.history, c.btb, c.ras);
+    let t = &predictor.totals;
+    for (name, s) in [("Conditional", &t.conditional), ("Jumps", &t.jumps), ("Returns", &t.returns)] {
+        println!("{}: {} executed, {} taken, {} mispredicted ({:.2}%)", name, s.executed, s.taken, s.mispredicted, rate(s));
+    }
+    let mut worst: Vec<(&u64, &BranchStats)> = predictor.branches.iter().filter(|(_, s)| s.mispredicted > 0).collect();
+    worst.sort_by(|a, b| b.1.mispredicted.cmp(&a.1.mispredicted).then(a.0.cmp(b.0)));
+    for (pc, s) in worst.iter().take(10) {
+        println!("\t{}: {}/{} mispredicted ({:.2}%), {} taken",
+                 rvcpu.describe(**pc), s.mispredicted, s.executed, rate(s), s.taken);
+    }
+}
+
+/// Steps one instruction, returning false once the CPU stopped or the step limit was hit.
+fn step_inst(rvcpu: &mut cpu::CPU, steps: &mut StepCounter) -> bool {
+    rvcpu.step();
+    steps.tick() && rvcpu.is_running()
+}
+
+/// Steps until execution reaches the start of a different source line, a breakpoint or a watchpoint.
+/// With `over_calls`, calls are run until they return to the instruction after them.
+fn step_line(rvcpu: &mut cpu::CPU, lines: &LineTable, breakpoints: &[u64], watchpoints: &mut [Watchpoint], steps: &mut StepCounter, over_calls: bool) {
+    let start = lines.find(rvcpu.pc()).map(|row| (row.file, row.line));
+    let mut return_to = None;
+    loop {
+        if over_calls && return_to.is_none() {
+            // The stack pointer tells a recursive call's return from this one's
+            return_to = rvcpu.call_return().map(|ret| (ret, rvcpu.read_reg(2)));
+        }
+        if !step_inst(rvcpu, steps) {
+            return;
+        }
+        let pc = rvcpu.pc();
+        if hit_stop(rvcpu, breakpoints, watchpoints) {
+            return;
+        }
+        if let Some((ret, sp)) = return_to {
+            if pc != ret || rvcpu.read_reg(2) < sp {
+                continue;
+            }
+        }
+        return_to = None;
+        match lines.find(pc) {
+            Some(row) if row.is_stmt && Some((row.file, row.line)) != start => return,
+            _ => {}
+        }
+    }
+}
+
+/// Prints the source lines around `pc`, marking the current one.
+fn print_source(lines: &LineTable, pc: u64, context: u64) {
+    let row = match lines.find(pc) {
+        Some(row) => row,
+        None => {
+            println!("No line information for 0x{:X}", pc);
+            return;
+        }
+    };
+    let path = lines.file_name(row.file);
+    println!("{}:{}", path, row.line);
+    let src = match fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(_) => return,
+    };
+    let first = row.line.saturating_sub(context).max(1);
+    for (n, text) in src.lines().enumerate().skip(first as usize - 1).take((context * 2 + 1) as usize) {
+        let n = n as u64 + 1;
+        let marker = if n == row.line { "=>" } else { "  " };
+        println!("{} {:>4} {}", marker, n, text);
+    }
+}
+
 fn main() {
     let buffer: Vec<u8>;
     let args: Vec<String> = env::args().collect();
@@ -130,6 +679,11 @@ fn main() {
     // parse all args
     let mut pargs: Vec<Flags> = vec!();
     for i in 1..args.len() {
+        if args[i] == "--" {
+            // Everything after -- is passed on to the program untouched
+            pargs.extend(args[i+1..].iter().map(|a| File{path: a.clone()}));
+            break;
+        }
         let mut a = parse_arg(&args, i);
         pargs.append(&mut a);
     }
@@ -154,19 +708,166 @@ fn main() {
         buffer = fs::read("tests/firmware.bin").expect("Could not find firmware!");
     }
 
-    let mut rvcpu = cpu::CPU::new(buffer);
-    let mut i = 0;
+    // A board from --machine, either built in or described in a file
+    let machine = pargs.iter().find_map(|f| match f {
+        Machine{ spec } => Some(MachineConfig::load(spec).expect("Error loading machine configuration!")),
+        _ => None,
+    });
+    let console_input = !pargs.contains(&Interactive);
+    let new_cpu = |buffer: Vec<u8>, entry: Option<u64>| match &machine {
+        Some(config) => machine::build(config, buffer, entry, console_input).expect("Error building machine!"),
+        None => cpu::CPU::new(buffer),
+    };
+
+    let mut rvcpu;
+    // Restored once everything is set up, so the saved state wins
+    let mut snapshot = None;
+    let mut lines = LineTable::default();
+    // First address after the loaded program, where a semihosted heap can start
+    let mut program_end = buffer.len() as u64;
+    let mut entry = None;
+    if Elf::is_elf(&buffer) {
+        let mut elf = Elf::parse(buffer).expect("Error parsing ELF!");
+        if pargs.contains(&UserMode) {
+            rvcpu = cpu::CPU::with_memory(0, USER_MEM_SIZE, vec!());
+            rvcpu.load_elf(&elf).expect("Error loading ELF!");
+            // The program file and any further files make up argv
+            let argv: Vec<String> = pargs.iter().filter_map(|f| match f {
+                File{ path } => Some(path.clone()),
+                _ => None,
+            }).collect();
+            let envp: Vec<String> = env::vars().map(|(k, v)| format!("{}={}", k, v)).collect();
+            let linux = LinuxUser::setup(&mut rvcpu, &elf, &argv, &envp, USER_MEM_SIZE as u64)
+                .expect("Error setting up process!");
+            rvcpu.set_linux(linux);
+        } else {
+            entry = Some(elf.entry);
+            rvcpu = new_cpu(vec!(), entry);
+            rvcpu.load_elf(&elf).expect("Error loading ELF!");
+            // A configured reset vector wins over the entry point, e.g. to start in a boot ROM
+            if let Some(pc) = machine.as_ref().and_then(|m| m.reset_vector) {
+                rvcpu.set_pc(pc);
+            }
+        }
+        program_end = elf.segments.iter().map(|s| s.paddr + s.mem_size as u64).max().unwrap_or(program_end);
+        rvcpu.set_symbols(std::mem::take(&mut elf.symbols));
+        match elf.line_table() {
+            Ok(table) => lines = table,
+            Err(e) => println!("Ignoring line information: {}", e),
+        }
+    } else if SnapshotReader::is_snapshot(&buffer) {
+        rvcpu = new_cpu(vec!(), None);
+        snapshot = Some(buffer);
+    } else {
+        rvcpu = new_cpu(buffer, None);
+        program_end += rvcpu.dram_range().0 as u64;
+    }
+    let mut breakpoints: Vec<u64> = vec!();
+    let mut watchpoints: Vec<Watchpoint> = vec!();
+    if pargs.contains(&Semihost) {
+        // The program file and any further files make up the command line
+        let cmdline: Vec<String> = pargs.iter().filter_map(|f| match f {
+            File{ path } => Some(path.clone()),
+            _ => None,
+        }).collect();
+        let (base, size) = rvcpu.dram_range();
+        let mem_top = (base + size) as u64;
+        rvcpu.set_semihosting(Semihosting::new(cmdline.join(" "), program_end, mem_top, TARGET_STACK_SIZE));
+    }
+    if let Some(config) = &machine {
+        let read = |path: &String| fs::read(path).expect("Error opening file!");
+        let dtb = pargs.iter().find_map(|f| match f {
+            Dtb{ path } => Some(read(path)),
+            _ => None,
+        });
+        let initrd = pargs.iter().find_map(|f| match f {
+            Initrd{ path } => Some(read(path)),
+            _ => None,
+        });
+        let bootargs = pargs.iter().find_map(|f| match f {
+            BootArgs{ args } => Some(args.as_str()),
+            _ => None,
+        }).unwrap_or("");
+        let info = machine::boot(&mut rvcpu, config, dtb, initrd.as_deref(), bootargs).expect("Error setting up boot!");
+        // The boot ROM runs in M-mode, which the SBI stands in for, so the payload starts right away
+        if config.boot_rom.is_some() && pargs.contains(&BuiltinSbi) {
+            rvcpu.set_pc(entry.unwrap_or(rvcpu.dram_range().0 as u64));
+            rvcpu.set_reg(11, info.dtb);
+        }
+        println!("Device tree at 0x{:X} ({} bytes)", info.dtb, info.dtb_size);
+        if let Some((start, end)) = info.initrd {
+            println!("Initrd at 0x{:X}-0x{:X}", start, end);
+        }
+        if let Some(DumpDtb{ path }) = pargs.iter().find(|f| matches!(f, DumpDtb{..})) {
+            let blob = rvcpu.read_bytes(info.dtb as usize, info.dtb_size).expect("Error reading device tree!");
+            fs::write(path, blob).expect("Error writing device tree!");
+        }
+    }
+    if pargs.contains(&BuiltinSbi) {
+        // The payload expects its hart ID in a0
+        rvcpu.set_reg(10, rvcpu.read_csr(0xF14));
+        rvcpu.set_sbi(Sbi::new());
+    }
+    if pargs.contains(&Jit) {
+        rvcpu.set_jit(true);
+    }
+    // Stepping by hand shows every instruction too
+    if pargs.contains(&Trace) || pargs.contains(&Interactive) {
+        rvcpu.set_trace(true);
+    }
+    // A machine description may already have configured its own
+    if pargs.contains(&Pipeline) && rvcpu.pipeline_stats().is_none() {
+        rvcpu.set_pipeline(Some(PipelineConfig::default()));
+    }
+    if pargs.contains(&Caches) && rvcpu.caches().is_none() {
+        rvcpu.set_caches(Some(HierarchyConfig::default()));
+    }
+    if let Some(BranchPredictor{ kind }) = pargs.iter().find(|f| matches!(f, BranchPredictor{..})) {
+        rvcpu.set_predictor(Some(config::predictor(kind).expect("Error setting up the branch predictor!")));
+    }
+    // HTIF is used when the program has a tohost symbol or one was given on the command line
+    let tohost = pargs.iter().find_map(|f| match f {
+        ToHost{ addr } => Some(*addr),
+        _ => None,
+    }).or_else(|| rvcpu.symbols().lookup("tohost").filter(|_| !pargs.contains(&UserMode)));
+    if let Some(tohost) = tohost {
+        let fromhost = pargs.iter().find_map(|f| match f {
+            FromHost{ addr } => Some(*addr),
+            _ => None,
+        }).or_else(|| rvcpu.symbols().lookup("fromhost"));
+        let (base, size) = rvcpu.dram_range();
+        let heap_end = ((base + size) as u64).saturating_sub(TARGET_STACK_SIZE);
+        rvcpu.set_htif(Htif::new(tohost, fromhost, program_end, heap_end));
+    }
+    if let Some(snapshot) = &snapshot {
+        rvcpu.restore(snapshot).expect("Error restoring snapshot!");
+    }
+    // In interactive mode the user decides how far the program runs
+    let unlimited = pargs.contains(&UserMode) || pargs.contains(&Semihost) || pargs.contains(&BuiltinSbi)
+        || machine.is_some() || tohost.is_some() || snapshot.is_some() || pargs.contains(&Interactive);
+    let limit = if unlimited { usize::MAX } else { STEP_LIMIT };
+    let mut steps = StepCounter { count: 0, limit };
     while rvcpu.is_running() {
-        // Enables stepping and printing of regs if we passed in the -i flag
-        // TODO: Breakpoints and continuous operation.
+        // Enables stepping, breakpoints and printing of regs if we passed in the -i flag
         if pargs.contains(&Interactive) {
             let input = get_input();
-            let cmd = parse_cmd(input);
+            let cmd = parse_cmd(input, rvcpu.symbols(), &lines);
             println!("Command: {:?}", cmd);
             match cmd {
-                Step => {
-                    i+=1;
+                Step | Next if !lines.is_empty() => {
+                    step_line(&mut rvcpu, &lines, &breakpoints, &mut watchpoints, &mut steps, cmd == Next);
+                    print_source(&lines, rvcpu.pc(), 0);
+                }
+                Step | Next | StepInst => {
+                    steps.tick();
                     rvcpu.step();
+                    hit_stop(&rvcpu, &[], &mut watchpoints);
+                    if !lines.is_empty() {
+                        print_source(&lines, rvcpu.pc(), 0);
+                    }
+                }
+                List => {
+                    print_source(&lines, rvcpu.pc(), 5);
                 }
                 PrintAll => {
                     rvcpu.print_all();
@@ -177,16 +878,228 @@ fn main() {
                 Cmd::PrintMemRegion { addr, len } => {
                     rvcpu.print_mem_reg(addr, len);
                 }
+                SetReg { reg, val } => {
+                    rvcpu.set_reg(reg, val);
+                }
+                SetPc { val } => {
+                    rvcpu.set_pc(val);
+                }
+                SetCsr { csr, val } => {
+                    if rvcpu.set_csr(csr, val).is_err() {
+                        println!("Not valid csr: 0x{:X}", csr);
+                    }
+                }
+                WriteMem { addr, size, val } => {
+                    if rvcpu.write_mem(addr, size, val).is_err() {
+                        println!("Error writing to 0x{:X}", addr);
+                    }
+                }
+                FillMem { addr, len, val } => {
+                    if rvcpu.fill_mem(addr, len, val).is_err() {
+                        println!("Error filling 0x{:X}..0x{:X}", addr, addr.wrapping_add(len));
+                    }
+                }
+                LoadFile { path, addr } => {
+                    match fs::read(&path) {
+                        Ok(data) => {
+                            if rvcpu.load_mem(addr, &data).is_err() {
+                                println!("Error loading {} to 0x{:X}", path, addr);
+                            }
+                        }
+                        Err(e) => {
+                            println!("Error opening {}: {}", path, e);
+                        }
+                    }
+                }
+                Examine { addr, count, fmt } => {
+                    rvcpu.examine(addr.unwrap_or(rvcpu.pc() as usize), count, fmt);
+                }
+                Break { addr } => {
+                    if !breakpoints.contains(&addr) {
+                        breakpoints.push(addr);
+                    }
+                }
+                Delete { addr } => {
+                    breakpoints.retain(|b| *b != addr);
+                }
+                Continue => {
+                    while step_inst(&mut rvcpu, &mut steps) {
+                        if hit_stop(&rvcpu, &breakpoints, &mut watchpoints) {
+                            if !lines.is_empty() {
+                                print_source(&lines, rvcpu.pc(), 0);
+                            }
+                            break;
+                        }
+                    }
+                }
+                Watch { addr, size } => {
+                    let value = rvcpu.read_mem(addr as usize, size).ok();
+                    watchpoints.push(Watchpoint { addr, size, value });
+                }
+                Unwatch { addr } => {
+                    watchpoints.retain(|w| w.addr != addr);
+                }
+                Record { action } => {
+                    let result = match action {
+                        RecordAction::Start => {
+                            rvcpu.start_recording();
+                            Ok(())
+                        }
+                        RecordAction::Stop => {
+                            rvcpu.stop_recording();
+                            Ok(())
+                        }
+                        RecordAction::Save { path } => rvcpu.save_recording(&path),
+                        RecordAction::Load { path } => rvcpu.load_recording(&path),
+                    };
+                    if let Err(e) = result {
+                        println!("{}", e);
+                    }
+                }
+                SaveSnapshot { path } => {
+                    if let Err(e) = rvcpu.save_snapshot(&path) {
+                        println!("{}", e);
+                    }
+                }
+                LoadSnapshot { path } => {
+                    if let Err(e) = rvcpu.load_snapshot(&path) {
+                        println!("{}", e);
+                    }
+                }
+                ReverseStep => {
+                    if !rvcpu.reverse_step() {
+                        println!("No recorded history to step back over");
+                    } else if !lines.is_empty() {
+                        print_source(&lines, rvcpu.pc(), 0);
+                    }
+                    hit_stop(&rvcpu, &[], &mut watchpoints);
+                }
+                ReverseContinue => {
+                    if !rvcpu.is_recording() {
+                        println!("Not recording");
+                    }
+                    while rvcpu.reverse_step() {
+                        if hit_stop(&rvcpu, &breakpoints, &mut watchpoints) {
+                            if !lines.is_empty() {
+                                print_source(&lines, rvcpu.pc(), 0);
+                            }
+                            break;
+                        }
+                    }
+                }
+                Disassemble { count } => {
+                    // Centre the listing on pc
+                    let start = (rvcpu.pc() as usize).wrapping_sub(count / 2 * 4);
+                    rvcpu.disassemble(start, count);
+                }
+                InfoDevices => {
+                    for (base, size, desc) in rvcpu.device_info() {
+                        println!("0x{:08X}-0x{:08X}: {}", base, base + size, desc);
+                    }
+                }
+                InfoPipeline => match rvcpu.pipeline_stats() {
+                    Some(stats) => print_pipeline(&stats),
+                    None => println!("No pipeline model, every instruction takes one cycle"),
+                },
+                InfoCache => match rvcpu.caches() {
+                    Some(caches) => print_caches(caches),
+                    None => println!("No cache model"),
+                },
+                InfoBranches => match rvcpu.predictor() {
+                    Some(predictor) => print_branches(&rvcpu, predictor),
+                    None => println!("No branch predictor model"),
+                },
+                InfoIcache => {
+                    let (hits, misses, invalidations, pages) = rvcpu.icache_stats();
+                    let rate = if hits + misses > 0 { hits as f64 * 100.0 / (hits + misses) as f64 } else { 0.0 };
+                    println!("Decoded instruction cache: {} hits, {} misses ({:.1}% hit rate)", hits, misses, rate);
+                    println!("{} pages cached, {} invalidated by stores", pages, invalidations);
+                    let (translated, executed, chained, native, blocks) = rvcpu.block_stats();
+                    println!("Translated blocks: {} cached, {} translated, {} run ({} through chain links)",
+                             blocks, translated, executed, chained);
+                    println!("{} instructions run as native code", native);
+                }
+                ResetDevices => {
+                    rvcpu.reset_devices();
+                    println!("Devices reset");
+                }
+                InfoMemory => {
+                    let stats = rvcpu.memory_stats();
+                    if stats.is_empty() {
+                        println!("No sparse memory regions");
+                    }
+                    for (base, size, pages) in stats {
+                        let total = size.div_ceil(4096);
+                        println!("0x{:08X}-0x{:08X}: {}/{} pages resident ({} KiB)",
+                                 base, base + size, pages, total, pages * 4);
+                    }
+                }
                 Nothing => {}
             }
         }
         else {
-            i+=1;
-            rvcpu.step();
+            steps.count = steps.count.saturating_add(rvcpu.run(steps.remaining()));
         }
-        if i > 500 {
+        if steps.count > steps.limit {
             break;
         }
     }
     println!("CPU STATE: {}", rvcpu);
+    if let Some(stats) = rvcpu.pipeline_stats() {
+        print_pipeline(&stats);
+    }
+    if let Some(caches) = rvcpu.caches() {
+        print_caches(caches);
+    }
+    if let Some(predictor) = rvcpu.predictor() {
+        print_branches(&rvcpu, predictor);
+    }
+    if let Some(code) = rvcpu.exit_code() {
+        println!("Program exited with status {}", code);
+        std::process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CSRs can be set by name as well as by number.
+    #[test]
+    fn set_csr() {
+        let syms = SymbolTable::default();
+        assert_eq!(parse_set(&["set", "csr", "mstatus", "5"], &syms), SetCsr { csr: 0x300, val: 5 });
+        assert_eq!(parse_set(&["set", "csr", "0x300", "5"], &syms), SetCsr { csr: 0x300, val: 5 });
+        assert_eq!(parse_set(&["set", "mstatus", "5"], &syms), SetCsr { csr: 0x300, val: 5 });
+        assert_eq!(parse_set(&["set", "csr", "nosuchcsr", "5"], &syms), Nothing);
+    }
+
+    /// Addresses are numbers or symbols with an optional offset.
+    #[test]
+    fn addresses() {
+        use crate::elf::{Symbol, SymbolKind};
+        let sym = |name: &str, addr| Symbol { name: name.to_string(), addr, size: 0, kind: SymbolKind::Func };
+        let syms = SymbolTable::new(vec!(sym("main", 0x8000_0000), sym("beef", 0x8000_1000)));
+        assert_eq!(parse_addr("main", &syms), Some(0x8000_0000));
+        assert_eq!(parse_addr("main+8", &syms), Some(0x8000_0008));
+        assert_eq!(parse_addr("main+0x10", &syms), Some(0x8000_0010));
+        assert_eq!(parse_addr("0x80", &syms), Some(0x80));
+        assert_eq!(parse_addr("80", &syms), Some(80));
+        assert_eq!(parse_addr("ff", &syms), Some(0xFF));
+        assert_eq!(parse_addr("-1", &syms), Some(u64::MAX));
+        // Symbols win over hex numbers of the same spelling
+        assert_eq!(parse_addr("beef", &syms), Some(0x8000_1000));
+        assert_eq!(parse_addr("nosuch", &syms), None);
+        assert_eq!(parse_addr("main+", &syms), None);
+    }
+
+    /// A fill far larger than memory fails instead of allocating it first.
+    #[test]
+    fn fill_unmapped() {
+        let mut cpu = cpu::CPU::with_memory(0x8000_0000, 0x1000, vec!());
+        assert!(cpu.fill_mem(0, 0xFFFF_FFFF_FFFF, 0).is_err());
+        assert!(cpu.fill_mem(0x8000_0000, 0x2000, 0).is_err());
+        assert!(cpu.fill_mem(0x8000_0000, 0x1000, 0xAB).is_ok());
+        assert_eq!(cpu.read_mem(0x8000_0FFF, 8), Ok(0xAB));
+    }
 }
@@ -0,0 +1,202 @@
+use std::fs::{File, OpenOptions};
+
+use memmap2::{MmapMut, MmapOptions};
+
+use crate::bus::Device;
+use crate::dram::{access_len, restore_pages, save_pages};
+use crate::snapshot::SnapshotReader;
+
+/// RAM backed by a host file mapped into memory. Only the pages the guest touches
+/// are read in, so large images load instantly. A private mapping is copy-on-write
+/// and leaves the file alone. A shared one writes guest stores through to the file,
+/// where other tools can watch them and where they stay after the run.
+#[derive(Debug)]
+pub struct MappedMemory {
+    map: MmapMut,
+    path: String,
+    shared: bool,
+}
+
+impl MappedMemory {
+    /// Maps `size` bytes of `path`. A shared mapping creates or grows the file as needed.
+    pub fn open(path: &str, size: usize, shared: bool) -> Result<MappedMemory, String> {
+        let err = |e: std::io::Error| format!("Error mapping {}: {}", path, e);
+        let file = if shared {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+                .open(path).map_err(err)?;
+            if file.metadata().map_err(err)?.len() < size as u64 {
+                file.set_len(size as u64).map_err(err)?;
+            }
+            file
+        } else {
+            let file = File::open(path).map_err(err)?;
+            let len = file.metadata().map_err(err)?.len();
+            if len < size as u64 {
+                return Err(format!("{} is {} bytes, too small for a private mapping of {} bytes", path, len, size));
+            }
+            file
+        };
+        // The file may change under us if something else writes to it, which is the
+        // point of a shared mapping: the guest sees it like memory written by a device.
+        let map = unsafe {
+            if shared {
+                MmapOptions::new().len(size).map_mut(&file)
+            } else {
+                MmapOptions::new().len(size).map_copy(&file)
+            }
+        }.map_err(err)?;
+        Ok(Self {
+            map,
+            path: path.to_string(),
+            shared,
+        })
+    }
+
+    fn slice(&self, addr: usize, len: usize) -> Result<&[u8], ()> {
+        let end = addr.checked_add(len).ok_or(())?;
+        self.map.get(addr..end).ok_or(())
+    }
+
+    fn slice_mut(&mut self, addr: usize, len: usize) -> Result<&mut [u8], ()> {
+        let end = addr.checked_add(len).ok_or(())?;
+        self.map.get_mut(addr..end).ok_or(())
+    }
+}
+
+impl Device for MappedMemory {
+    fn write(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
+        let len = access_len(size)?;
+        self.slice_mut(addr, len)?.copy_from_slice(&val.to_le_bytes()[..len]);
+        Ok(())
+    }
+
+    fn read(&self, addr: usize, size: usize) -> Result<u64, ()> {
+        let len = access_len(size)?;
+        let mut bytes = [0; 8];
+        bytes[..len].copy_from_slice(self.slice(addr, len)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), ()> {
+        buf.copy_from_slice(self.slice(addr, buf.len())?);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), ()> {
+        self.slice_mut(addr, data.len())?.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "mapped ram"
+    }
+
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        format!("{}, {}", self.path, if self.shared { "shared" } else { "private" })
+    }
+
+    fn save(&self, out: &mut Vec<u8>) {
+        save_pages(&self.map, out);
+    }
+
+    /// Restores into the mapping, so for a shared one the file ends up matching the snapshot.
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let size = r.u64()? as usize;
+        if size != self.map.len() {
+            return Err(format!("Snapshot has {} bytes of memory for {}, which maps {}", size, self.path, self.map.len()));
+        }
+        self.map.fill(0);
+        restore_pages(&mut self.map, r)
+    }
+}
+
+impl Drop for MappedMemory {
+    fn drop(&mut self) {
+        if self.shared {
+            if let Err(e) = self.map.flush() {
+                println!("Error writing back {}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::snapshot::SnapshotWriter;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("riscv-emu-mapped-{}-{}", name, std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
+    /// A private mapping reads the file but keeps stores to itself.
+    #[test]
+    fn private() {
+        let path = temp_path("private");
+        fs::write(&path, [0x11u8; 0x2000]).unwrap();
+        {
+            let mut mem = MappedMemory::open(&path, 0x1000, false).unwrap();
+            assert_eq!(mem.read(0xFF8, 64), Ok(0x1111_1111_1111_1111));
+            mem.write(0, 32, 0xDEAD_BEEF).unwrap();
+            assert_eq!(mem.read(0, 32), Ok(0xDEAD_BEEF));
+            assert_eq!(mem.read(0xFFC, 64), Err(()));
+        }
+        assert_eq!(fs::read(&path).unwrap(), vec![0x11u8; 0x2000]);
+        assert!(MappedMemory::open(&path, 0x3000, false).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(MappedMemory::open(&path, 0x1000, false).is_err());
+    }
+
+    /// A shared mapping creates and grows the file and writes stores through to it.
+    #[test]
+    fn shared() {
+        let path = temp_path("shared");
+        let _ = fs::remove_file(&path);
+        {
+            let mut mem = MappedMemory::open(&path, 0x1000, true).unwrap();
+            assert_eq!(mem.read(0x800, 64), Ok(0));
+            mem.write_bytes(0x10, b"stays").unwrap();
+        }
+        let data = fs::read(&path).unwrap();
+        assert_eq!(data.len(), 0x1000);
+        assert_eq!(&data[0x10..0x15], b"stays");
+        {
+            let mem = MappedMemory::open(&path, 0x2000, true).unwrap();
+            let mut buf = [0; 5];
+            mem.read_bytes(0x10, &mut buf).unwrap();
+            assert_eq!(&buf, b"stays");
+        }
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0x2000);
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Restoring a snapshot into a shared mapping leaves the file matching it.
+    #[test]
+    fn restore() {
+        let path = temp_path("restore");
+        let _ = fs::remove_file(&path);
+        let mut data = vec!();
+        {
+            let mut mem = MappedMemory::open(&path, 0x2000, true).unwrap();
+            mem.write(0x1008, 64, 42).unwrap();
+            mem.save(&mut data);
+            mem.write(0x1008, 64, 7).unwrap();
+            mem.write(0, 8, 1).unwrap();
+            let mut snapshot = SnapshotWriter::new();
+            snapshot.section(b"MEM ", |out| out.extend_from_slice(&data));
+            let snapshot = snapshot.finish();
+            let mut r = SnapshotReader::new(&snapshot).unwrap();
+            mem.restore(&mut r.section(b"MEM ").unwrap()).unwrap();
+        }
+        let file = fs::read(&path).unwrap();
+        assert_eq!(file[0], 0);
+        assert_eq!(&file[0x1008..0x1010], &42u64.to_le_bytes());
+        fs::remove_file(&path).unwrap();
+    }
+}
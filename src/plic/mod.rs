@@ -0,0 +1,202 @@
+use std::cell::Cell;
+
+use crate::bus::{Device, IrqLines};
+use crate::events::Events;
+use crate::fdt::{FdtWriter, PHANDLE_CPU_INTC, PHANDLE_PLIC};
+use crate::snapshot::SnapshotReader;
+
+/// Interrupt sources, numbered from 1. Source 0 means "no interrupt".
+pub const PLIC_SOURCES: usize = 96;
+/// One hart with an M-mode and an S-mode context.
+const CONTEXTS: usize = 2;
+
+const PRIORITY: usize = 0x0;
+const PENDING: usize = 0x1000;
+const ENABLE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+pub const PLIC_SIZE: usize = 0x60_0000;
+
+const IRQ_M_EXT: u32 = 11;
+const IRQ_S_EXT: u32 = 9;
+
+/// Platform-level interrupt controller. Claiming an interrupt happens on a read,
+/// so the interrupt state lives in cells.
+#[derive(Debug)]
+pub struct Plic {
+    priority: [u32; PLIC_SOURCES],
+    pending: Cell<u128>,
+    claimed: Cell<u128>,
+    enable: [u128; CONTEXTS],
+    threshold: [u32; CONTEXTS],
+    events: Events,
+}
+
+impl Plic {
+    pub fn new() -> Plic {
+        Self {
+            priority: [0; PLIC_SOURCES],
+            pending: Cell::new(0),
+            claimed: Cell::new(0),
+            enable: [0; CONTEXTS],
+            threshold: [0; CONTEXTS],
+            events: Events::detached(),
+        }
+    }
+
+    /// Raised source lines become pending unless already claimed, and the best pending
+    /// interrupt of each context drives the hart's external interrupt lines.
+    fn update(&mut self, irq: &mut IrqLines) {
+        let raised = irq.external() & !self.claimed.get() & !1;
+        self.pending.set(self.pending.get() | raised);
+        irq.set_hart(IRQ_M_EXT, self.best(0) != 0);
+        irq.set_hart(IRQ_S_EXT, self.best(1) != 0);
+    }
+
+    /// The highest priority pending interrupt enabled for `ctx` above its threshold.
+    fn best(&self, ctx: usize) -> usize {
+        let ready = self.pending.get() & !self.claimed.get() & self.enable[ctx];
+        (1..PLIC_SOURCES)
+            .filter(|&i| ready & (1 << i) != 0 && self.priority[i] > self.threshold[ctx])
+            .max_by_key(|&i| (self.priority[i], std::cmp::Reverse(i)))
+            .unwrap_or(0)
+    }
+}
+
+impl Device for Plic {
+    fn write(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
+        if size != 32 {
+            return Err(());
+        }
+        let val = val as u32;
+        match addr {
+            a if a < PENDING => self.priority[(a - PRIORITY) / 4 % PLIC_SOURCES] = val & 7,
+            a if a < ENABLE => {}
+            a if a < ENABLE + CONTEXTS * ENABLE_STRIDE => {
+                let (ctx, word) = ((a - ENABLE) / ENABLE_STRIDE, (a - ENABLE) % ENABLE_STRIDE / 4);
+                if word < PLIC_SOURCES / 32 {
+                    let mask = 0xFFFF_FFFFu128 << (word * 32);
+                    self.enable[ctx] = (self.enable[ctx] & !mask) | ((val as u128) << (word * 32));
+                }
+            }
+            a if (CONTEXT..CONTEXT + CONTEXTS * CONTEXT_STRIDE).contains(&a) => {
+                let (ctx, reg) = ((a - CONTEXT) / CONTEXT_STRIDE, (a - CONTEXT) % CONTEXT_STRIDE);
+                match reg {
+                    0 => self.threshold[ctx] = val & 7,
+                    // Completing an interrupt lets it be claimed again
+                    4 if (val as usize) < PLIC_SOURCES => {
+                        self.claimed.set(self.claimed.get() & !(1 << val));
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+        self.events.schedule_in(0);
+        Ok(())
+    }
+
+    fn read(&self, addr: usize, size: usize) -> Result<u64, ()> {
+        if size != 32 {
+            return Err(());
+        }
+        let val = match addr {
+            a if a < PENDING => self.priority[(a - PRIORITY) / 4 % PLIC_SOURCES],
+            a if a < PENDING + PLIC_SOURCES / 8 => (self.pending.get() >> ((a - PENDING) / 4 * 32)) as u32,
+            a if a < ENABLE => 0,
+            a if a < ENABLE + CONTEXTS * ENABLE_STRIDE => {
+                let (ctx, word) = ((a - ENABLE) / ENABLE_STRIDE, (a - ENABLE) % ENABLE_STRIDE / 4);
+                if word < PLIC_SOURCES / 32 { (self.enable[ctx] >> (word * 32)) as u32 } else { 0 }
+            }
+            a if (CONTEXT..CONTEXT + CONTEXTS * CONTEXT_STRIDE).contains(&a) => {
+                let (ctx, reg) = ((a - CONTEXT) / CONTEXT_STRIDE, (a - CONTEXT) % CONTEXT_STRIDE);
+                match reg {
+                    0 => self.threshold[ctx],
+                    4 => {
+                        let irq = self.best(ctx);
+                        if irq != 0 {
+                            self.claimed.set(self.claimed.get() | 1 << irq);
+                            self.pending.set(self.pending.get() & !(1 << irq));
+                            self.events.schedule_in(0);
+                        }
+                        irq as u32
+                    }
+                    _ => 0,
+                }
+            }
+            _ => 0,
+        };
+        Ok(val as u64)
+    }
+
+    fn name(&self) -> &str {
+        "plic"
+    }
+
+    fn describe(&self) -> String {
+        format!("{} sources", PLIC_SOURCES - 1)
+    }
+
+    fn reset(&mut self) {
+        let events = std::mem::replace(&mut self.events, Events::detached());
+        *self = Plic { events, ..Plic::new() };
+        self.events.schedule_in(0);
+    }
+
+    fn attach(&mut self, events: Events) {
+        self.events = events;
+    }
+
+    fn tick(&mut self, _now: u64, irq: &mut IrqLines) {
+        self.update(irq);
+    }
+
+    fn irq_changed(&mut self, irq: &mut IrqLines) {
+        self.update(irq);
+    }
+
+    fn save(&self, out: &mut Vec<u8>) {
+        for p in self.priority.iter() {
+            out.extend_from_slice(&p.to_le_bytes());
+        }
+        out.extend_from_slice(&self.pending.get().to_le_bytes());
+        out.extend_from_slice(&self.claimed.get().to_le_bytes());
+        for ctx in 0..CONTEXTS {
+            out.extend_from_slice(&self.enable[ctx].to_le_bytes());
+            out.extend_from_slice(&self.threshold[ctx].to_le_bytes());
+        }
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let u128 = |r: &mut SnapshotReader| -> Result<u128, String> {
+            Ok(r.u64()? as u128 | (r.u64()? as u128) << 64)
+        };
+        for p in self.priority.iter_mut() {
+            *p = r.u32()?;
+        }
+        self.pending.set(u128(r)?);
+        self.claimed.set(u128(r)?);
+        for ctx in 0..CONTEXTS {
+            self.enable[ctx] = u128(r)?;
+            self.threshold[ctx] = r.u32()?;
+        }
+        self.events.schedule_in(0);
+        Ok(())
+    }
+
+    fn dt_node(&self, base: usize, size: usize, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("plic@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_reg(base as u64, size as u64);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_u32("riscv,ndev", PLIC_SOURCES as u32 - 1);
+        // Context 0 is M-mode and context 1 S-mode external interrupts
+        fdt.property_cells("interrupts-extended", &[PHANDLE_CPU_INTC, IRQ_M_EXT, PHANDLE_CPU_INTC, IRQ_S_EXT]);
+        fdt.property_u32("phandle", PHANDLE_PLIC);
+        fdt.end_node();
+    }
+}
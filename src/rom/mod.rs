@@ -0,0 +1,128 @@
+use crate::bus::Device;
+use crate::dram::DRAM;
+use crate::snapshot::SnapshotReader;
+
+/// Size of the generated boot ROM, like QEMU's mask ROM.
+pub const BOOT_ROM_SIZE: usize = 0x1000;
+
+/// The boot ROM program. It puts the hart ID in a0, the device tree address in a1 and
+/// jumps to the payload. The two addresses follow the code.
+const BOOT_CODE: [u32; 6] = [
+    0x00000297, // auipc t0, 0
+    0xF1402573, // csrr  a0, mhartid
+    0x0202B583, // ld    a1, 32(t0)
+    0x0182B283, // ld    t0, 24(t0)
+    0x00028067, // jr    t0
+    0,
+];
+
+/// Read-only memory. Reads behave like DRAM, writes fail with an access fault.
+#[derive(Debug)]
+pub struct Rom {
+    mem: DRAM,
+}
+
+impl Rom {
+    /// `size` bytes of ROM holding `data` at the start.
+    pub fn new(size: usize, data: Vec<u8>) -> Rom {
+        Self {
+            mem: DRAM::new(size, data),
+        }
+    }
+
+    /// A boot ROM that starts the payload at `entry` with the device tree at `dtb`.
+    pub fn boot(entry: u64, dtb: u64) -> Rom {
+        let mut data: Vec<u8> = BOOT_CODE.iter().flat_map(|i| i.to_le_bytes()).collect();
+        data.extend_from_slice(&entry.to_le_bytes());
+        data.extend_from_slice(&dtb.to_le_bytes());
+        Rom::new(BOOT_ROM_SIZE, data)
+    }
+}
+
+impl Device for Rom {
+    fn write(&mut self, _addr: usize, _size: usize, _val: u64) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn read(&self, addr: usize, size: usize) -> Result<u64, ()> {
+        self.mem.read(addr, size)
+    }
+
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), ()> {
+        self.mem.read_bytes(addr, buf)
+    }
+
+    fn name(&self) -> &str {
+        "rom"
+    }
+
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    /// The contents never change, so there is nothing to save.
+    fn save(&self, _out: &mut Vec<u8>) {}
+
+    fn restore(&mut self, _r: &mut SnapshotReader) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    const DRAM_BASE: usize = 0x8000_0000;
+    const ROM_BASE: usize = 0x1000;
+
+    /// Reads see the contents, writes of any kind are rejected and change nothing.
+    #[test]
+    fn read_only() {
+        let mut rom = Rom::new(0x100, vec![1, 2, 3, 4]);
+        assert_eq!(rom.read(0, 32), Ok(0x0403_0201));
+        assert_eq!(rom.read(0xF8, 64), Ok(0));
+        assert_eq!(rom.read(0xFC, 64), Err(()));
+        assert_eq!(rom.write(0, 8, 0xFF), Err(()));
+        assert_eq!(rom.write_bytes(0, &[0xFF; 4]), Err(()));
+        let mut buf = [0; 4];
+        rom.read_bytes(0, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+    }
+
+    /// The boot ROM hands over to the payload with the hart ID and device tree.
+    #[test]
+    fn boot() {
+        let mut cpu = CPU::with_memory(DRAM_BASE, 0x1000, vec!());
+        cpu.map_device(ROM_BASE, BOOT_ROM_SIZE, Box::new(Rom::boot(DRAM_BASE as u64 + 0x40, 0x8765_4320)));
+        cpu.set_pc(ROM_BASE as u64);
+        for _ in 0..5 {
+            cpu.step();
+        }
+        assert_eq!(cpu.pc(), DRAM_BASE as u64 + 0x40);
+        assert_eq!(cpu.read_reg(10), 0);
+        assert_eq!(cpu.read_reg(11), 0x8765_4320);
+    }
+
+    /// A store to ROM raises a store access fault, and the debugger can't write it either.
+    #[test]
+    fn store_fault() {
+        let code: Vec<u8> = [
+            0x000012B7u32, // lui t0, 1
+            0x0052B023, // sd t0, 0(t0)
+        ].iter().flat_map(|i| i.to_le_bytes()).collect();
+        let mut cpu = CPU::with_memory(DRAM_BASE, 0x1000, code);
+        cpu.map_device(ROM_BASE, BOOT_ROM_SIZE, Box::new(Rom::boot(DRAM_BASE as u64, 0)));
+        // mtvec
+        cpu.set_csr(0x305, DRAM_BASE as u64 + 0x100).unwrap();
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc(), DRAM_BASE as u64 + 0x100);
+        // mcause is a store access fault, mtval the address
+        assert_eq!(cpu.read_csr(0x342), 7);
+        assert_eq!(cpu.read_csr(0x343), ROM_BASE as u64);
+        assert_eq!(cpu.read_mem(ROM_BASE, 32), Ok(BOOT_CODE[0] as u64));
+        assert_eq!(cpu.store_bytes(ROM_BASE, &[0; 4]), Err(()));
+        assert_eq!(cpu.read_mem(ROM_BASE, 32), Ok(BOOT_CODE[0] as u64));
+    }
+}
@@ -0,0 +1,257 @@
+// A built-in SBI (RISC-V Supervisor Binary Interface) implementation, so supervisor
+// mode kernels can run without loading OpenSBI first. It takes the place of M-mode
+// firmware: an `ecall` from S-mode is an SBI call, with the extension in a7, the
+// function in a6 and arguments in a0-a5. Results are returned as an error code in
+// a0 and a value in a1. Timer and IPI requests raise interrupts delegated to S-mode.
+
+use std::io::{self, Write};
+
+use crate::cpu::CPU;
+use crate::snapshot::{put_option, SnapshotReader};
+use crate::uart::console_input;
+
+pub const CSR_TIME: usize = 0xC01;
+const CSR_MIP: usize = 0x344;
+const CSR_MVENDORID: usize = 0xF11;
+const CSR_MARCHID: usize = 0xF12;
+const CSR_MIMPID: usize = 0xF13;
+const CSR_MHARTID: usize = 0xF14;
+
+const MIP_SSIP: u64 = 1 << 1;
+const MIP_STIP: u64 = 1 << 5;
+
+/// Frequency of the `time` counter.
+pub const TIMEBASE_FREQ: u64 = 10_000_000;
+
+const SPEC_VERSION: u64 = 2 << 24;
+/// Not an assigned implementation ID, chosen so kernels can tell they run here.
+const IMPL_ID: u64 = 0x5256_454D;
+const IMPL_VERSION: u64 = 1;
+
+const EXT_LEGACY_SET_TIMER: u64 = 0x00;
+const EXT_LEGACY_PUTCHAR: u64 = 0x01;
+const EXT_LEGACY_GETCHAR: u64 = 0x02;
+const EXT_LEGACY_SHUTDOWN: u64 = 0x08;
+const EXT_BASE: u64 = 0x10;
+const EXT_TIME: u64 = 0x5449_4D45;
+const EXT_IPI: u64 = 0x0073_5049;
+const EXT_RFENCE: u64 = 0x5246_4E43;
+const EXT_HSM: u64 = 0x0048_534D;
+const EXT_SRST: u64 = 0x5352_5354;
+const EXT_DBCN: u64 = 0x4442_434E;
+
+const EXTENSIONS: [u64; 11] = [
+    EXT_LEGACY_SET_TIMER, EXT_LEGACY_PUTCHAR, EXT_LEGACY_GETCHAR, EXT_LEGACY_SHUTDOWN,
+    EXT_BASE, EXT_TIME, EXT_IPI, EXT_RFENCE, EXT_HSM, EXT_SRST, EXT_DBCN,
+];
+
+const SUCCESS: i64 = 0;
+const ERR_NOT_SUPPORTED: i64 = -2;
+const ERR_INVALID_PARAM: i64 = -3;
+const ERR_ALREADY_AVAILABLE: i64 = -6;
+
+const HSM_STARTED: u64 = 0;
+
+const RESET_SHUTDOWN: u64 = 0;
+const REASON_SYSTEM_FAILURE: u64 = 1;
+
+#[derive(Debug)]
+pub struct Sbi {
+    timer: Option<u64>,
+    exit_code: Option<i32>,
+}
+
+impl Sbi {
+    pub fn new() -> Sbi {
+        Self {
+            timer: None,
+            exit_code: None,
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Saves the timer deadline and exit status.
+    pub fn save(&self, out: &mut Vec<u8>) {
+        put_option(out, self.timer);
+        put_option(out, self.exit_code.map(|c| c as u64));
+    }
+
+    pub fn restore(r: &mut SnapshotReader) -> Result<Sbi, String> {
+        Ok(Self {
+            timer: r.option()?,
+            exit_code: r.option()?.map(|c| c as i32),
+        })
+    }
+
+    /// Raises the supervisor timer interrupt once the programmed time has passed.
+    /// Returns true if it did.
+    pub fn poll(&mut self, cpu: &mut CPU) -> bool {
+        match self.timer {
+            Some(deadline) if cpu.read_csr(CSR_TIME) >= deadline => {
+                self.timer = None;
+                cpu.write_csr(CSR_MIP, cpu.read_csr(CSR_MIP) | MIP_STIP);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Handles the call in a7/a6. Returns false if the payload shut the system down.
+    pub fn call(&mut self, cpu: &mut CPU) -> Result<bool, String> {
+        let (ext, fid) = (cpu.read_reg(17), cpu.read_reg(16));
+        let a: Vec<u64> = (10..16).map(|r| cpu.read_reg(r)).collect();
+        let hartid = cpu.read_csr(CSR_MHARTID);
+        // Legacy extensions only return a value in a0
+        let legacy = match ext {
+            EXT_LEGACY_SET_TIMER => Some(self.set_timer(cpu, a[0])),
+            EXT_LEGACY_PUTCHAR => {
+                print_console(&[a[0] as u8]);
+                Some(0)
+            }
+            // -1 when nothing has been typed, Linux polls it from a timer
+            EXT_LEGACY_GETCHAR => Some(console_input().map_or(u64::MAX, |c| c as u64)),
+            EXT_LEGACY_SHUTDOWN => {
+                self.exit_code = Some(0);
+                return Ok(false);
+            }
+            _ => None,
+        };
+        if let Some(ret) = legacy {
+            cpu.write_reg(10, ret);
+            return Ok(true);
+        }
+
+        let (error, value) = match (ext, fid) {
+            (EXT_BASE, 0) => (SUCCESS, SPEC_VERSION),
+            (EXT_BASE, 1) => (SUCCESS, IMPL_ID),
+            (EXT_BASE, 2) => (SUCCESS, IMPL_VERSION),
+            (EXT_BASE, 3) => (SUCCESS, EXTENSIONS.contains(&a[0]) as u64),
+            (EXT_BASE, 4) => (SUCCESS, cpu.read_csr(CSR_MVENDORID)),
+            (EXT_BASE, 5) => (SUCCESS, cpu.read_csr(CSR_MARCHID)),
+            (EXT_BASE, 6) => (SUCCESS, cpu.read_csr(CSR_MIMPID)),
+            (EXT_TIME, 0) => (SUCCESS, self.set_timer(cpu, a[0])),
+            (EXT_IPI, 0) => {
+                // hart_mask is relative to hart_mask_base, a base of -1 means all harts
+                if a[1] == u64::MAX || in_mask(a[0], a[1], hartid) {
+                    cpu.write_csr(CSR_MIP, cpu.read_csr(CSR_MIP) | MIP_SSIP);
+                }
+                (SUCCESS, 0)
+            }
+            // There is only this hart. Translations aren't cached, but decoded and
+            // translated code is, so remote fence.i and sfence.vma drop it like local ones.
+            (EXT_RFENCE, 0..=6) => {
+                cpu.flush_code();
+                (SUCCESS, 0)
+            }
+            (EXT_HSM, 0) if a[0] == hartid => (ERR_ALREADY_AVAILABLE, 0),
+            (EXT_HSM, 0) | (EXT_HSM, 2) if a[0] != hartid => (ERR_INVALID_PARAM, 0),
+            (EXT_HSM, 1) => {
+                // Stopping the only hart leaves nothing running
+                self.exit_code = Some(0);
+                return Ok(false);
+            }
+            (EXT_HSM, 2) => (SUCCESS, HSM_STARTED),
+            // Suspending returns straight away, as if an interrupt woke the hart
+            (EXT_HSM, 3) => (SUCCESS, 0),
+            (EXT_SRST, 0) if a[0] <= 2 && a[1] <= 1 => {
+                if a[0] != RESET_SHUTDOWN {
+                    println!("SBI: reboot is not supported, shutting down instead");
+                }
+                self.exit_code = Some((a[1] == REASON_SYSTEM_FAILURE) as i32);
+                return Ok(false);
+            }
+            (EXT_SRST, 0) => (ERR_INVALID_PARAM, 0),
+            (EXT_DBCN, 0) => {
+                match cpu.read_bytes(a[1] as usize, a[0] as usize) {
+                    Ok(data) => {
+                        print_console(&data);
+                        (SUCCESS, data.len() as u64)
+                    }
+                    Err(_) => (ERR_INVALID_PARAM, 0),
+                }
+            }
+            (EXT_DBCN, 1) => {
+                // Never read more than the buffer can take
+                let data = read_console((a[0] as usize).min(cpu.mapped_len(a[1] as usize)));
+                match cpu.store_bytes(a[1] as usize, &data) {
+                    Ok(_) => (SUCCESS, data.len() as u64),
+                    Err(_) => (ERR_INVALID_PARAM, 0),
+                }
+            }
+            (EXT_DBCN, 2) => {
+                print_console(&[a[0] as u8]);
+                (SUCCESS, 0)
+            }
+            _ if EXTENSIONS.contains(&ext) => (ERR_NOT_SUPPORTED, 0),
+            _ => {
+                println!("Unimplemented SBI call: extension 0x{:X} function {}", ext, fid);
+                (ERR_NOT_SUPPORTED, 0)
+            }
+        };
+        cpu.write_reg(10, error as u64);
+        cpu.write_reg(11, value);
+        Ok(true)
+    }
+
+    /// Programs the next timer event, clearing a pending timer interrupt.
+    fn set_timer(&mut self, cpu: &mut CPU, deadline: u64) -> u64 {
+        self.timer = Some(deadline);
+        cpu.write_csr(CSR_MIP, cpu.read_csr(CSR_MIP) & !MIP_STIP);
+        SUCCESS as u64
+    }
+}
+
+fn in_mask(mask: u64, base: u64, hartid: u64) -> bool {
+    hartid >= base && hartid - base < 64 && mask & (1 << (hartid - base)) != 0
+}
+
+fn print_console(data: &[u8]) {
+    let _ = io::stdout().write_all(data);
+    let _ = io::stdout().flush();
+}
+
+/// Takes up to `len` bytes of the input typed so far, without waiting for more.
+fn read_console(len: usize) -> Vec<u8> {
+    std::iter::from_fn(console_input).take(len).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Calls `ext`/`fid` with `args` in a0-a5 and returns a0 and a1.
+    fn call(cpu: &mut CPU, ext: u64, fid: u64, args: &[u64]) -> (u64, u64) {
+        cpu.set_reg(17, ext);
+        cpu.set_reg(16, fid);
+        for (i, a) in args.iter().enumerate() {
+            cpu.set_reg(10 + i, *a);
+        }
+        assert_eq!(Sbi::new().call(cpu), Ok(true));
+        (cpu.read_reg(10), cpu.read_reg(11))
+    }
+
+    /// Reading the console returns straight away, with whatever was typed so far.
+    #[test]
+    fn console_input_does_not_block() {
+        let mut cpu = CPU::new(vec![0; 64]);
+        let (c, _) = call(&mut cpu, EXT_LEGACY_GETCHAR, 0, &[]);
+        assert!(c == u64::MAX || c < 256);
+        let (error, len) = call(&mut cpu, EXT_DBCN, 1, &[16, 0x8000_0000, 0]);
+        assert_eq!(error, SUCCESS as u64);
+        assert!(len <= 16);
+    }
+
+    /// A remote fence.i drops the decoded instructions.
+    #[test]
+    fn remote_fence_i() {
+        let mut cpu = CPU::new(vec![0x13, 0, 0, 0]); // nop
+        cpu.step();
+        assert_eq!(cpu.icache_stats().3, 1);
+        let (error, _) = call(&mut cpu, EXT_RFENCE, 0, &[0, u64::MAX]);
+        assert_eq!(error, SUCCESS as u64);
+        assert_eq!(cpu.icache_stats().3, 0);
+    }
+}
@@ -0,0 +1,437 @@
+// RISC-V semihosting: the ARM semihosting operations, requested with the
+// `slli x0,x0,0x1f; ebreak; srai x0,x0,7` sequence. a0 holds the operation,
+// a1 the parameter (usually a pointer to a block of XLEN sized words).
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cpu::CPU;
+use crate::linux::OpenFile;
+use crate::snapshot::{put_blob, put_option, SnapshotReader};
+
+/// slli x0, x0, 0x1f
+pub const SEMIHOST_ENTRY: u32 = 0x01f01013;
+/// srai x0, x0, 7
+pub const SEMIHOST_EXIT: u32 = 0x40705013;
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_READC: u64 = 0x07;
+const SYS_ISERROR: u64 = 0x08;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0A;
+const SYS_FLEN: u64 = 0x0C;
+const SYS_REMOVE: u64 = 0x0E;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_HEAPINFO: u64 = 0x16;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+const EBADF: u64 = 9;
+const EFAULT: u64 = 14;
+
+#[derive(Debug)]
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(OpenFile),
+}
+
+#[derive(Debug)]
+pub struct Semihosting {
+    handles: Vec<Option<Handle>>,
+    cmdline: String,
+    heap_base: u64,
+    heap_limit: u64,
+    stack_base: u64,
+    start: Instant,
+    errno: u64,
+    exit_code: Option<i32>,
+}
+
+impl Semihosting {
+    /// `heap_base` is the first free address after the program, `mem_top` the end of RAM
+    /// where the stack starts. Both are reported through SYS_HEAPINFO.
+    pub fn new(cmdline: String, heap_base: u64, mem_top: u64, stack_size: u64) -> Semihosting {
+        Self {
+            handles: vec!(),
+            cmdline,
+            heap_base,
+            heap_limit: mem_top.saturating_sub(stack_size),
+            stack_base: mem_top,
+            start: Instant::now(),
+            errno: 0,
+            exit_code: None,
+        }
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    /// Saves the command line, memory layout, errno, exit status and open handles.
+    pub fn save(&self, out: &mut Vec<u8>) {
+        put_blob(out, self.cmdline.as_bytes());
+        for v in [self.heap_base, self.heap_limit, self.stack_base, self.errno] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        put_option(out, self.exit_code.map(|c| c as u64));
+        out.extend_from_slice(&(self.handles.len() as u64).to_le_bytes());
+        for h in &self.handles {
+            match h {
+                None => out.push(0),
+                Some(Handle::Stdin) => out.push(1),
+                Some(Handle::Stdout) => out.push(2),
+                Some(Handle::Stderr) => out.push(3),
+                Some(Handle::File(f)) => {
+                    out.push(4);
+                    f.save(out);
+                }
+            }
+        }
+    }
+
+    pub fn restore(r: &mut SnapshotReader) -> Result<Semihosting, String> {
+        let cmdline = r.string()?;
+        let (heap_base, heap_limit, stack_base) = (r.u64()?, r.u64()?, r.u64()?);
+        let mut semihosting = Semihosting::new(cmdline, heap_base, stack_base, 0);
+        semihosting.heap_limit = heap_limit;
+        semihosting.errno = r.u64()?;
+        semihosting.exit_code = r.option()?.map(|c| c as i32);
+        for _ in 0..r.u64()? {
+            semihosting.handles.push(match r.u8()? {
+                0 => None,
+                1 => Some(Handle::Stdin),
+                2 => Some(Handle::Stdout),
+                3 => Some(Handle::Stderr),
+                4 => Some(Handle::File(OpenFile::restore(r)?)),
+                t => return Err(format!("Unknown handle type {} in snapshot", t)),
+            });
+        }
+        Ok(semihosting)
+    }
+
+    fn arg(cpu: &CPU, block: u64, i: u64) -> Result<u64, ()> {
+        cpu.read_mem((block + i * 8) as usize, 64)
+    }
+
+    /// Services the request in a0/a1. Returns the value for a0, or None if the program exited.
+    pub fn call(&mut self, cpu: &mut CPU) -> Result<Option<u64>, String> {
+        let op = cpu.read_reg(10);
+        let param = cpu.read_reg(11);
+        let ret = match op {
+            SYS_EXIT | SYS_EXIT_EXTENDED => {
+                // RV32 passes the reason directly, RV64 a block of reason and status
+                let (reason, status) = match (Semihosting::arg(cpu, param, 0), Semihosting::arg(cpu, param, 1)) {
+                    (Ok(reason), Ok(status)) => (reason, status),
+                    _ => (param, 0),
+                };
+                let code = if reason == ADP_STOPPED_APPLICATION_EXIT { status as i32 } else { 1 };
+                self.exit_code = Some(code);
+                return Ok(None);
+            }
+            _ => self.handle(cpu, op, param),
+        };
+        match ret {
+            Ok(val) => Ok(Some(val)),
+            Err(errno) => {
+                self.errno = errno;
+                Ok(Some(u64::MAX))
+            }
+        }
+    }
+
+    fn handle(&mut self, cpu: &mut CPU, op: u64, param: u64) -> Result<u64, u64> {
+        let arg = |i| Semihosting::arg(cpu, param, i).map_err(|_| EFAULT);
+        match op {
+            SYS_OPEN => {
+                let (path, mode, len) = (arg(0)?, arg(1)?, arg(2)?);
+                let path = cpu.read_bytes(path as usize, len as usize).map_err(|_| EFAULT)?;
+                let path = String::from_utf8_lossy(&path).into_owned();
+                let handle = if path == ":tt" {
+                    // The console: read modes are stdin, write modes stdout and append stderr
+                    match mode {
+                        0..=3 => Handle::Stdin,
+                        4..=7 => Handle::Stdout,
+                        _ => Handle::Stderr,
+                    }
+                } else {
+                    let mut opts = OpenOptions::new();
+                    match mode {
+                        0 | 1 => opts.read(true),
+                        2 | 3 => opts.read(true).write(true),
+                        4 | 5 => opts.write(true).create(true).truncate(true),
+                        6 | 7 => opts.read(true).write(true).create(true).truncate(true),
+                        8 | 9 => opts.append(true).create(true),
+                        _ => opts.read(true).append(true).create(true),
+                    };
+                    let (read, write, append) = (!matches!(mode, 4 | 5 | 8 | 9), mode >= 2, mode >= 8);
+                    let file = opts.open(&path).map_err(|e| errno(&e))?;
+                    Handle::File(OpenFile::new(file, &path, read, write, append))
+                };
+                let h = match self.handles.iter().position(|h| h.is_none()) {
+                    Some(h) => h,
+                    None => {
+                        self.handles.push(None);
+                        self.handles.len() - 1
+                    }
+                };
+                self.handles[h] = Some(handle);
+                // Handle 0 reads as failure to some libraries, so handles start at 1
+                Ok(h as u64 + 1)
+            }
+            SYS_CLOSE => {
+                self.handle_mut(arg(0)?)?;
+                self.handles[arg(0)? as usize - 1] = None;
+                Ok(0)
+            }
+            SYS_WRITEC => {
+                let c = cpu.read_mem(param as usize, 8).map_err(|_| EFAULT)?;
+                print_console(&[c as u8]);
+                Ok(0)
+            }
+            SYS_WRITE0 => {
+                let mut s = vec!();
+                let mut addr = param as usize;
+                loop {
+                    match cpu.read_mem(addr, 8) {
+                        Ok(0) => break,
+                        Ok(c) => s.push(c as u8),
+                        Err(_) => return Err(EFAULT),
+                    }
+                    addr = addr.wrapping_add(1);
+                }
+                print_console(&s);
+                Ok(0)
+            }
+            SYS_WRITE => {
+                let (h, buf, len) = (arg(0)?, arg(1)?, arg(2)?);
+                let data = cpu.read_bytes(buf as usize, len as usize).map_err(|_| EFAULT)?;
+                let written = match self.handle_mut(h)? {
+                    Handle::Stdout => io::stdout().write(&data).and_then(|n| io::stdout().flush().map(|_| n)),
+                    Handle::Stderr => io::stderr().write(&data),
+                    Handle::File(f) => f.file.write(&data),
+                    Handle::Stdin => return Err(EBADF),
+                };
+                // Returns the number of bytes *not* written
+                Ok(len - written.map_err(|e| errno(&e))? as u64)
+            }
+            SYS_READ => {
+                let (h, buf, len) = (arg(0)?, arg(1)?, arg(2)?);
+                // Reading less than asked is allowed, so read no more than fits in the buffer
+                let mapped = cpu.mapped_len(buf as usize) as u64;
+                if mapped == 0 && len != 0 {
+                    return Err(EFAULT);
+                }
+                let mut data = vec![0u8; len.min(mapped) as usize];
+                let n = match self.handle_mut(h)? {
+                    Handle::Stdin => io::stdin().read(&mut data),
+                    Handle::File(f) => f.file.read(&mut data),
+                    _ => return Err(EBADF),
+                }.map_err(|e| errno(&e))?;
+                cpu.store_bytes(buf as usize, &data[..n]).map_err(|_| EFAULT)?;
+                Ok(len - n as u64)
+            }
+            SYS_READC => {
+                let mut c = [0u8; 1];
+                match io::stdin().read(&mut c) {
+                    Ok(1) => Ok(c[0] as u64),
+                    _ => Ok(u64::MAX),
+                }
+            }
+            SYS_ISERROR => Ok(((arg(0)? as i64) < 0) as u64),
+            SYS_ISTTY => {
+                match self.handle_mut(arg(0)?)? {
+                    Handle::File(_) => Ok(0),
+                    _ => Ok(1),
+                }
+            }
+            SYS_SEEK => {
+                let pos = arg(1)?;
+                match self.handle_mut(arg(0)?)? {
+                    Handle::File(f) => f.file.seek(SeekFrom::Start(pos)).map(|_| 0).map_err(|e| errno(&e)),
+                    _ => Err(EBADF),
+                }
+            }
+            SYS_FLEN => {
+                match self.handle_mut(arg(0)?)? {
+                    Handle::File(f) => f.file.metadata().map(|m| m.len()).map_err(|e| errno(&e)),
+                    _ => Err(EBADF),
+                }
+            }
+            SYS_REMOVE => {
+                let path = cpu.read_bytes(arg(0)? as usize, arg(1)? as usize).map_err(|_| EFAULT)?;
+                fs::remove_file(String::from_utf8_lossy(&path).as_ref()).map(|_| 0).map_err(|e| errno(&e))
+            }
+            SYS_CLOCK => Ok(self.start.elapsed().as_millis() as u64 / 10),
+            SYS_TIME => Ok(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+            SYS_ERRNO => Ok(self.errno),
+            SYS_GET_CMDLINE => {
+                let (buf, len) = (arg(0)?, arg(1)?);
+                let cmdline = format!("{}\0", self.cmdline).into_bytes();
+                if cmdline.len() as u64 > len {
+                    return Err(EFAULT);
+                }
+                cpu.store_bytes(buf as usize, &cmdline).map_err(|_| EFAULT)?;
+                let len = (cmdline.len() as u64 - 1).to_le_bytes();
+                cpu.store_bytes((param + 8) as usize, &len).map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            SYS_HEAPINFO => {
+                let block = cpu.read_mem(param as usize, 64).map_err(|_| EFAULT)?;
+                let info: Vec<u8> = [self.heap_base, self.heap_limit, self.stack_base, self.heap_limit].iter()
+                    .flat_map(|v| v.to_le_bytes())
+                    .collect();
+                cpu.store_bytes(block as usize, &info).map_err(|_| EFAULT)?;
+                Ok(0)
+            }
+            _ => {
+                println!("Unimplemented semihosting operation 0x{:X}", op);
+                Ok(u64::MAX)
+            }
+        }
+    }
+
+    fn handle_mut(&mut self, h: u64) -> Result<&mut Handle, u64> {
+        match self.handles.get_mut((h as usize).wrapping_sub(1)) {
+            Some(Some(handle)) => Ok(handle),
+            _ => Err(EBADF),
+        }
+    }
+}
+
+fn print_console(data: &[u8]) {
+    let _ = io::stdout().write_all(data);
+    let _ = io::stdout().flush();
+}
+
+fn errno(e: &io::Error) -> u64 {
+    e.raw_os_error().unwrap_or(EBADF as i32) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::SnapshotWriter;
+
+    /// Where the parameter block goes.
+    const BLOCK: u64 = 0x100;
+
+    fn setup() -> (CPU, Semihosting) {
+        let cpu = CPU::with_memory(0, 0x10000, vec!());
+        (cpu, Semihosting::new("prog -v".to_string(), 0x4000, 0x10000, 0x1000))
+    }
+
+    /// Runs `op` with its parameter block holding `args`.
+    fn call(sh: &mut Semihosting, cpu: &mut CPU, op: u64, args: &[u64]) -> Option<u64> {
+        let block: Vec<u8> = args.iter().flat_map(|a| a.to_le_bytes()).collect();
+        cpu.store_bytes(BLOCK as usize, &block).unwrap();
+        cpu.set_reg(10, op);
+        cpu.set_reg(11, BLOCK);
+        sh.call(cpu).unwrap()
+    }
+
+    /// A file is created, written, sought, read back, measured and removed.
+    #[test]
+    fn file_ops() {
+        let (mut cpu, mut sh) = setup();
+        let path = std::env::temp_dir().join(format!("riscv-emu-semihosting-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        cpu.load_mem(0x1000, path.as_bytes()).unwrap();
+        cpu.load_mem(0x2000, b"hello world").unwrap();
+        let len = path.len() as u64;
+
+        // Mode 6 is "w+"
+        let h = call(&mut sh, &mut cpu, SYS_OPEN, &[0x1000, 6, len]).unwrap();
+        assert_eq!(h, 1);
+        assert_eq!(call(&mut sh, &mut cpu, SYS_WRITE, &[h, 0x2000, 11]), Some(0));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_FLEN, &[h]), Some(11));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_ISTTY, &[h]), Some(0));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_SEEK, &[h, 6]), Some(0));
+        // Reading past the end reports the bytes left unread
+        assert_eq!(call(&mut sh, &mut cpu, SYS_READ, &[h, 0x3000, 8]), Some(3));
+        assert_eq!(cpu.read_bytes(0x3000, 5).unwrap(), b"world");
+        assert_eq!(call(&mut sh, &mut cpu, SYS_CLOSE, &[h]), Some(0));
+        assert_eq!(fs::read(&path).unwrap(), b"hello world");
+
+        assert_eq!(call(&mut sh, &mut cpu, SYS_REMOVE, &[0x1000, len]), Some(0));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_OPEN, &[0x1000, 0, len]), Some(u64::MAX));
+        assert!(call(&mut sh, &mut cpu, SYS_ERRNO, &[]).unwrap() != 0);
+    }
+
+    /// Closed and unknown handles fail with EBADF, and the console is a tty.
+    #[test]
+    fn handles() {
+        let (mut cpu, mut sh) = setup();
+        cpu.load_mem(0x1000, b":tt").unwrap();
+        let h = call(&mut sh, &mut cpu, SYS_OPEN, &[0x1000, 4, 3]).unwrap();
+        assert_eq!(call(&mut sh, &mut cpu, SYS_ISTTY, &[h]), Some(1));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_CLOSE, &[h]), Some(0));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_CLOSE, &[h]), Some(u64::MAX));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_ERRNO, &[]), Some(EBADF));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_FLEN, &[7]), Some(u64::MAX));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_ISERROR, &[u64::MAX]), Some(1));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_ISERROR, &[3]), Some(0));
+    }
+
+    /// The command line and heap layout are written to guest buffers.
+    #[test]
+    fn cmdline_and_heapinfo() {
+        let (mut cpu, mut sh) = setup();
+        assert_eq!(call(&mut sh, &mut cpu, SYS_GET_CMDLINE, &[0x2000, 64]), Some(0));
+        assert_eq!(cpu.read_bytes(0x2000, 8).unwrap(), b"prog -v\0");
+        assert_eq!(cpu.read_mem(BLOCK as usize + 8, 64), Ok(7));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_GET_CMDLINE, &[0x2000, 4]), Some(u64::MAX));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_ERRNO, &[]), Some(EFAULT));
+
+        assert_eq!(call(&mut sh, &mut cpu, SYS_HEAPINFO, &[0x3000]), Some(0));
+        let word = |i: usize| cpu.read_mem(0x3000 + i * 8, 64).unwrap();
+        assert_eq!((word(0), word(1), word(2), word(3)), (0x4000, 0xF000, 0x10000, 0xF000));
+    }
+
+    /// A restored session keeps its handles, file positions and errno.
+    #[test]
+    fn save_restore() {
+        let (mut cpu, mut sh) = setup();
+        let path = std::env::temp_dir().join(format!("riscv-emu-semihosting-save-{}", std::process::id()));
+        fs::write(&path, b"hello").unwrap();
+        let path = path.to_str().unwrap().to_string();
+        cpu.load_mem(0x1000, path.as_bytes()).unwrap();
+        let h = call(&mut sh, &mut cpu, SYS_OPEN, &[0x1000, 0, path.len() as u64]).unwrap();
+        assert_eq!(call(&mut sh, &mut cpu, SYS_READ, &[h, 0x2000, 2]), Some(0));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_CLOSE, &[7]), Some(u64::MAX));
+
+        let mut w = SnapshotWriter::new();
+        w.section(b"SEMI", |out| sh.save(out));
+        let data = w.finish();
+        let mut r = SnapshotReader::new(&data).unwrap();
+        let mut restored = Semihosting::restore(&mut r.section(b"SEMI").unwrap()).unwrap();
+        assert_eq!(call(&mut restored, &mut cpu, SYS_ERRNO, &[]), Some(EBADF));
+        assert_eq!(call(&mut restored, &mut cpu, SYS_READ, &[h, 0x2000, 8]), Some(5));
+        assert_eq!(cpu.read_bytes(0x2000, 3).unwrap(), b"llo");
+        assert_eq!(call(&mut restored, &mut cpu, SYS_GET_CMDLINE, &[0x3000, 64]), Some(0));
+        assert_eq!(cpu.read_bytes(0x3000, 8).unwrap(), b"prog -v\0");
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// An application exit reports its status, anything else exits with 1.
+    #[test]
+    fn exit() {
+        let (mut cpu, mut sh) = setup();
+        assert_eq!(call(&mut sh, &mut cpu, SYS_EXIT, &[ADP_STOPPED_APPLICATION_EXIT, 7]), None);
+        assert_eq!(sh.exit_code(), Some(7));
+        assert_eq!(call(&mut sh, &mut cpu, SYS_EXIT_EXTENDED, &[0x20023, 7]), None);
+        assert_eq!(sh.exit_code(), Some(1));
+    }
+}
@@ -0,0 +1,130 @@
+use std::convert::TryInto;
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"RVSNAPSH";
+/// Bumped whenever any section changes layout. Other versions are rejected.
+/// 2: privilege level.
+/// 3: LR/SC reservation.
+/// 4: cycle count.
+/// 5: instret count.
+pub const SNAPSHOT_VERSION: u32 = 5;
+
+/// Builds a snapshot file out of tagged sections, one per component.
+pub struct SnapshotWriter {
+    data: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> SnapshotWriter {
+        let mut data = SNAPSHOT_MAGIC.to_vec();
+        data.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        Self {
+            data,
+        }
+    }
+
+    /// Writes a section called `tag` with the payload produced by `f`.
+    pub fn section<F: FnOnce(&mut Vec<u8>)>(&mut self, tag: &[u8; 4], f: F) {
+        let mut payload = vec!();
+        f(&mut payload);
+        self.data.extend_from_slice(tag);
+        self.data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        self.data.extend_from_slice(&payload);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Writes the length of `b` followed by its bytes, as read back by `SnapshotReader::blob`.
+pub fn put_blob(out: &mut Vec<u8>, b: &[u8]) {
+    out.extend_from_slice(&(b.len() as u64).to_le_bytes());
+    out.extend_from_slice(b);
+}
+
+/// Writes a flag and the value, as read back by `SnapshotReader::option`.
+pub fn put_option(out: &mut Vec<u8>, val: Option<u64>) {
+    out.push(val.is_some() as u8);
+    out.extend_from_slice(&val.unwrap_or(0).to_le_bytes());
+}
+
+/// Reads values back in the order they were written.
+pub struct SnapshotReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn is_snapshot(data: &[u8]) -> bool {
+        data.len() >= 8 && &data[..8] == SNAPSHOT_MAGIC
+    }
+
+    /// Checks the header of a snapshot file and positions at its first section.
+    pub fn new(data: &'a [u8]) -> Result<SnapshotReader<'a>, String> {
+        if !SnapshotReader::is_snapshot(data) {
+            return Err("Not a snapshot file".to_string());
+        }
+        let mut reader = Self { data, pos: 8 };
+        let version = reader.u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("Unsupported snapshot version {}", version));
+        }
+        Ok(reader)
+    }
+
+    /// Returns a reader over the next section, which must be called `tag`.
+    pub fn section(&mut self, tag: &[u8; 4]) -> Result<SnapshotReader<'a>, String> {
+        let found = self.bytes(4)?;
+        if found != tag {
+            return Err(format!("Expected snapshot section {:?}, found {:?}",
+                               String::from_utf8_lossy(tag), String::from_utf8_lossy(found)));
+        }
+        let len = self.u64()? as usize;
+        Ok(SnapshotReader { data: self.bytes(len)?, pos: 0 })
+    }
+
+    /// Like `section`, but returns None and stays put if the next section isn't `tag`.
+    pub fn optional_section(&mut self, tag: &[u8; 4]) -> Result<Option<SnapshotReader<'a>>, String> {
+        if self.data.get(self.pos..self.pos + 4) != Some(&tag[..]) {
+            return Ok(None);
+        }
+        self.section(tag).map(Some)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.pos
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let b = self.data.get(self.pos..self.pos.saturating_add(n)).ok_or("Snapshot is truncated")?;
+        self.pos += n;
+        Ok(b)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub fn blob(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u64()? as usize;
+        self.bytes(len)
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        Ok(String::from_utf8_lossy(self.blob()?).into_owned())
+    }
+
+    pub fn option(&mut self) -> Result<Option<u64>, String> {
+        let some = self.u8()? != 0;
+        let val = self.u64()?;
+        Ok(if some { Some(val) } else { None })
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use crate::bus::Device;
+use crate::dram::access_len;
+use crate::snapshot::SnapshotReader;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Memory that only allocates a 4 KiB page the first time it is written.
+/// Untouched pages read as zeros, so large address spaces cost nothing until used.
+#[derive(Debug)]
+pub struct SparseMemory {
+    size: usize,
+    pages: HashMap<usize, Box<[u8; PAGE_SIZE]>>,
+}
+
+impl SparseMemory {
+    pub fn new(size: usize, buffer: Vec<u8>) -> SparseMemory {
+        let mut mem = Self {
+            size,
+            pages: HashMap::new(),
+        };
+        for (i, chunk) in buffer.chunks(PAGE_SIZE).enumerate() {
+            if chunk.iter().any(|b| *b != 0) {
+                mem.page_mut(i)[..chunk.len()].copy_from_slice(chunk);
+            }
+        }
+        mem
+    }
+
+    fn check(&self, addr: usize, len: usize) -> Result<(), ()> {
+        match addr.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    fn page_mut(&mut self, page: usize) -> &mut [u8; PAGE_SIZE] {
+        self.pages.entry(page).or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+}
+
+impl Device for SparseMemory {
+    fn write(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
+        let len = access_len(size)?;
+        self.write_bytes(addr, &val.to_le_bytes()[..len])
+    }
+
+    fn read(&self, addr: usize, size: usize) -> Result<u64, ()> {
+        let len = access_len(size)?;
+        let mut bytes = [0; 8];
+        self.read_bytes(addr, &mut bytes[..len])?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Copies page by page, as the range may straddle pages.
+    fn read_bytes(&self, addr: usize, buf: &mut [u8]) -> Result<(), ()> {
+        self.check(addr, buf.len())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let a = addr + done;
+            let off = a % PAGE_SIZE;
+            let n = (PAGE_SIZE - off).min(buf.len() - done);
+            match self.pages.get(&(a / PAGE_SIZE)) {
+                Some(page) => buf[done..done + n].copy_from_slice(&page[off..off + n]),
+                None => buf[done..done + n].fill(0),
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: usize, data: &[u8]) -> Result<(), ()> {
+        self.check(addr, data.len())?;
+        let mut done = 0;
+        while done < data.len() {
+            let a = addr + done;
+            let off = a % PAGE_SIZE;
+            let n = (PAGE_SIZE - off).min(data.len() - done);
+            self.page_mut(a / PAGE_SIZE)[off..off + n].copy_from_slice(&data[done..done + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// Same layout as DRAM, so snapshots can be restored into either.
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&(self.size as u64).to_le_bytes());
+        let mut pages: Vec<_> = self.pages.iter().collect();
+        pages.sort_by_key(|(i, _)| **i);
+        for (i, page) in pages {
+            if page.iter().any(|b| *b != 0) {
+                out.extend_from_slice(&(*i as u64).to_le_bytes());
+                out.extend_from_slice(&page[..PAGE_SIZE.min(self.size - i * PAGE_SIZE)]);
+            }
+        }
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        let size = r.u64()? as usize;
+        if size != self.size {
+            return Err(format!("Snapshot has {} bytes of memory for a {} byte RAM", size, self.size));
+        }
+        self.pages.clear();
+        while r.remaining() > 0 {
+            let page = r.u64()? as usize;
+            let len = PAGE_SIZE.min(self.size.saturating_sub(page.saturating_mul(PAGE_SIZE)));
+            if len == 0 {
+                return Err(format!("Snapshot page {} is outside of memory", page));
+            }
+            let data = r.bytes(len)?;
+            self.page_mut(page)[..len].copy_from_slice(data);
+        }
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "sparse ram"
+    }
+
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    fn describe(&self) -> String {
+        format!("{} pages resident", self.pages.len())
+    }
+
+    fn resident_pages(&self) -> Option<usize> {
+        Some(self.pages.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dram::DRAM;
+    use crate::snapshot::SnapshotWriter;
+
+    /// Saves `from` and restores it into `to`.
+    fn copy(from: &dyn Device, to: &mut dyn Device) -> Result<(), String> {
+        let mut w = SnapshotWriter::new();
+        w.section(b"MEM ", |out| from.save(out));
+        let data = w.finish();
+        to.restore(&mut SnapshotReader::new(&data)?.section(b"MEM ")?)
+    }
+
+    /// Pages are only allocated by writes, and only for non-zero initial contents.
+    #[test]
+    fn lazy_pages() {
+        let mut buffer = vec![0u8; 3 * PAGE_SIZE];
+        buffer[2 * PAGE_SIZE] = 1;
+        let mut mem = SparseMemory::new(1 << 40, buffer);
+        assert_eq!(mem.resident_pages(), Some(1));
+        assert_eq!(mem.read(2 * PAGE_SIZE, 8), Ok(1));
+        assert_eq!(mem.read(0, 64), Ok(0));
+        assert_eq!(mem.read((1 << 40) - 8, 64), Ok(0));
+        assert_eq!(mem.resident_pages(), Some(1));
+        mem.write((1 << 40) - 8, 64, 0x1122_3344_5566_7788).unwrap();
+        assert_eq!(mem.read((1 << 40) - 8, 64), Ok(0x1122_3344_5566_7788));
+        assert_eq!(mem.resident_pages(), Some(2));
+    }
+
+    /// Accesses straddling a page boundary touch both pages.
+    #[test]
+    fn straddling() {
+        let mut mem = SparseMemory::new(4 * PAGE_SIZE, vec!());
+        mem.write(PAGE_SIZE - 4, 64, 0x0102_0304_0506_0708).unwrap();
+        assert_eq!(mem.resident_pages(), Some(2));
+        assert_eq!(mem.read(PAGE_SIZE - 4, 64), Ok(0x0102_0304_0506_0708));
+        assert_eq!(mem.read(PAGE_SIZE, 32), Ok(0x0102_0304));
+        let data: Vec<u8> = (0..=255).cycle().take(2 * PAGE_SIZE + 100).collect();
+        mem.write_bytes(PAGE_SIZE + 50, &data).unwrap();
+        let mut back = vec![0; data.len() + 20];
+        mem.read_bytes(PAGE_SIZE + 40, &mut back).unwrap();
+        assert_eq!(&back[..10], &[0; 10]);
+        assert_eq!(&back[10..10 + data.len()], &data[..]);
+        assert_eq!(&back[10 + data.len()..], &[0; 10]);
+    }
+
+    /// Nothing is read or written past the end.
+    #[test]
+    fn bounds() {
+        let mut mem = SparseMemory::new(PAGE_SIZE + 16, vec!());
+        assert_eq!(mem.read(PAGE_SIZE + 8, 64), Ok(0));
+        assert_eq!(mem.read(PAGE_SIZE + 12, 64), Err(()));
+        assert_eq!(mem.write(PAGE_SIZE + 16, 8, 1), Err(()));
+        assert_eq!(mem.write_bytes(usize::MAX, &[1, 2]), Err(()));
+        assert_eq!(mem.read(0, 12), Err(()));
+        assert_eq!(mem.resident_pages(), Some(0));
+    }
+
+    /// Snapshots use the DRAM layout, so they restore into either kind of memory,
+    /// but only of the same size.
+    #[test]
+    fn snapshots() {
+        let mut sparse = SparseMemory::new(3 * PAGE_SIZE, vec!());
+        sparse.write(PAGE_SIZE + 8, 64, 42).unwrap();
+        sparse.write(2 * PAGE_SIZE, 8, 0).unwrap();
+        let mut dram = DRAM::new(3 * PAGE_SIZE, vec!());
+        copy(&sparse, &mut dram).unwrap();
+        assert_eq!(dram.read(PAGE_SIZE + 8, 64), Ok(42));
+
+        dram.write(16, 16, 7).unwrap();
+        let mut back = SparseMemory::new(3 * PAGE_SIZE, vec![1; 3 * PAGE_SIZE]);
+        copy(&dram, &mut back).unwrap();
+        assert_eq!(back.read(16, 16), Ok(7));
+        assert_eq!(back.read(PAGE_SIZE + 8, 64), Ok(42));
+        assert_eq!(back.read(2 * PAGE_SIZE, 64), Ok(0));
+        assert_eq!(back.resident_pages(), Some(2));
+
+        let mut small = SparseMemory::new(PAGE_SIZE, vec!());
+        assert!(copy(&sparse, &mut small).is_err());
+    }
+}
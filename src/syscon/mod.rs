@@ -0,0 +1,79 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::bus::Device;
+use crate::fdt::{FdtWriter, PHANDLE_SYSCON};
+use crate::snapshot::SnapshotReader;
+
+const FINISHER_FAIL: u64 = 0x3333;
+const FINISHER_PASS: u64 = 0x5555;
+const FINISHER_RESET: u64 = 0x7777;
+
+pub const SYSCON_SIZE: usize = 0x1000;
+
+/// The SiFive test finisher that backs the syscon poweroff and reboot nodes.
+/// Writing a command stops the machine with an exit status.
+#[derive(Debug)]
+pub struct Syscon {
+    halt: Rc<Cell<Option<i32>>>,
+}
+
+impl Syscon {
+    /// `halt` receives the exit status when the guest powers off.
+    pub fn new(halt: Rc<Cell<Option<i32>>>) -> Syscon {
+        Self {
+            halt,
+        }
+    }
+}
+
+impl Device for Syscon {
+    fn write(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
+        if addr != 0 || size != 32 {
+            return Err(());
+        }
+        match val & 0xFFFF {
+            FINISHER_PASS => self.halt.set(Some(0)),
+            FINISHER_FAIL => self.halt.set(Some((val >> 16) as i32)),
+            FINISHER_RESET => {
+                println!("syscon: reboot is not supported, powering off instead");
+                self.halt.set(Some(0));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn read(&self, _addr: usize, size: usize) -> Result<u64, ()> {
+        if size != 32 {
+            return Err(());
+        }
+        Ok(0)
+    }
+
+    fn name(&self) -> &str {
+        "syscon"
+    }
+
+    fn save(&self, _out: &mut Vec<u8>) {}
+
+    fn restore(&mut self, _r: &mut SnapshotReader) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn dt_node(&self, base: usize, size: usize, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("test@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        fdt.property_reg(base as u64, size as u64);
+        fdt.property_u32("phandle", PHANDLE_SYSCON);
+        fdt.end_node();
+        for (name, value) in &[("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)] {
+            fdt.begin_node(name);
+            fdt.property_string("compatible", &format!("syscon-{}", name));
+            fdt.property_u32("regmap", PHANDLE_SYSCON);
+            fdt.property_u32("offset", 0);
+            fdt.property_u32("value", *value as u32);
+            fdt.end_node();
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+use crate::bus::{Device, IrqLines};
+use crate::events::Events;
+use crate::fdt::{FdtWriter, PHANDLE_PLIC};
+use crate::snapshot::SnapshotReader;
+
+const RBR_THR: usize = 0;
+const IER: usize = 1;
+const IIR_FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+const MSR: usize = 6;
+const SCR: usize = 7;
+
+const LCR_DLAB: u8 = 0x80;
+const LSR_DR: u8 = 0x01;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+const IER_RDI: u8 = 0x01;
+const IER_THRI: u8 = 0x02;
+const IIR_NO_INT: u8 = 0x01;
+const IIR_THRI: u8 = 0x02;
+const IIR_RDI: u8 = 0x04;
+
+pub const UART_SIZE: usize = 0x100;
+
+/// How often to look for input from the host, in cycles.
+const RX_POLL_CYCLES: u64 = 10_000;
+
+/// The input clock of QEMU's UART, which sets the baud rate divisors.
+const CLOCK_FREQ: u32 = 3_686_400;
+
+/// Returns the next byte typed on the host, or None if there is none yet. Stdin is
+/// read on a separate thread, started by the first call, so the guest can poll
+/// without blocking. The UART and the SBI console share it.
+pub fn console_input() -> Option<u8> {
+    static INPUT: OnceLock<Mutex<Receiver<u8>>> = OnceLock::new();
+    let input = INPUT.get_or_init(|| {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 256];
+            loop {
+                match io::stdin().read(&mut buf) {
+                    Ok(n) if n > 0 && buf[..n].iter().all(|b| tx.send(*b).is_ok()) => {}
+                    _ => break,
+                }
+            }
+        });
+        Mutex::new(rx)
+    });
+    input.lock().ok()?.try_recv().ok()
+}
+
+/// A 16550 UART without FIFOs. Output goes to stdout; input comes from `console_input`.
+#[derive(Debug)]
+pub struct Uart {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    rbr: Cell<Option<u8>>,
+    /// Whether to take input from stdin
+    stdin: bool,
+    irq: u32,
+    events: Events,
+    /// The cycle of the pending input poll
+    next_poll: u64,
+}
+
+impl Uart {
+    /// Without `stdin` the UART never receives anything, which leaves stdin to the debugger.
+    /// `irq` is its interrupt line on the PLIC.
+    pub fn new(stdin: bool, irq: u32) -> Uart {
+        Self {
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            rbr: Cell::new(None),
+            stdin,
+            irq,
+            events: Events::detached(),
+            next_poll: 0,
+        }
+    }
+
+    /// Moves the next input byte into the receive register if it is empty.
+    fn fill(&self) {
+        if self.stdin && self.rbr.get().is_none() {
+            self.rbr.set(console_input());
+        }
+    }
+}
+
+impl Device for Uart {
+    fn write(&mut self, addr: usize, size: usize, val: u64) -> Result<(), ()> {
+        if size != 8 {
+            return Err(());
+        }
+        let val = val as u8;
+        match addr {
+            RBR_THR if self.lcr & LCR_DLAB != 0 => self.divisor = (self.divisor & 0xFF00) | val as u16,
+            IER if self.lcr & LCR_DLAB != 0 => self.divisor = (self.divisor & 0xFF) | (val as u16) << 8,
+            RBR_THR => {
+                let _ = io::stdout().write_all(&[val]);
+                let _ = io::stdout().flush();
+            }
+            IER => {
+                self.ier = val & 0x0F;
+                self.events.schedule_in(0);
+            }
+            LCR => self.lcr = val,
+            MCR => self.mcr = val,
+            SCR => self.scr = val,
+            IIR_FCR | LSR | MSR => {}
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn read(&self, addr: usize, size: usize) -> Result<u64, ()> {
+        if size != 8 {
+            return Err(());
+        }
+        self.fill();
+        let val = match addr {
+            RBR_THR if self.lcr & LCR_DLAB != 0 => self.divisor as u8,
+            IER if self.lcr & LCR_DLAB != 0 => (self.divisor >> 8) as u8,
+            RBR_THR => {
+                self.events.schedule_in(0);
+                self.rbr.take().unwrap_or(0)
+            }
+            IER => self.ier,
+            IIR_FCR => {
+                if self.ier & IER_RDI != 0 && self.rbr.get().is_some() {
+                    IIR_RDI
+                } else if self.ier & IER_THRI != 0 {
+                    IIR_THRI
+                } else {
+                    IIR_NO_INT
+                }
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => LSR_THRE | LSR_TEMT | if self.rbr.get().is_some() { LSR_DR } else { 0 },
+            MSR => 0,
+            SCR => self.scr,
+            _ => return Err(()),
+        };
+        Ok(val as u64)
+    }
+
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn describe(&self) -> String {
+        format!("ns16550a, irq {}", self.irq)
+    }
+
+    /// Input that arrived while the guest wasn't polling is still taken.
+    fn reset(&mut self) {
+        self.ier = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.scr = 0;
+        self.divisor = 0;
+        self.next_poll = 0;
+        self.events.schedule_in(0);
+    }
+
+    /// Starts looking for input.
+    fn attach(&mut self, events: Events) {
+        events.schedule_in(0);
+        self.events = events;
+    }
+
+    fn tick(&mut self, now: u64, irq: &mut IrqLines) {
+        self.fill();
+        let pending = (self.ier & IER_RDI != 0 && self.rbr.get().is_some()) || self.ier & IER_THRI != 0;
+        irq.set(self.irq, pending);
+        // Register accesses tick the UART too, but only the poll schedules the next
+        // one. Polls fall on multiples of RX_POLL_CYCLES, so a restore or reset that
+        // forgets the pending one picks the same next poll again.
+        if now >= self.next_poll {
+            self.next_poll = (now / RX_POLL_CYCLES + 1) * RX_POLL_CYCLES;
+            self.events.schedule_at(self.next_poll);
+        }
+    }
+
+    fn save(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.ier, self.lcr, self.mcr, self.scr]);
+        out.extend_from_slice(&(self.divisor as u32).to_le_bytes());
+    }
+
+    fn restore(&mut self, r: &mut SnapshotReader) -> Result<(), String> {
+        self.ier = r.u8()?;
+        self.lcr = r.u8()?;
+        self.mcr = r.u8()?;
+        self.scr = r.u8()?;
+        self.divisor = r.u32()? as u16;
+        // The event queue was cleared, the poll is scheduled again
+        self.next_poll = 0;
+        self.events.schedule_in(0);
+        Ok(())
+    }
+
+    fn dt_node(&self, base: usize, size: usize, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("serial@{:x}", base));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_reg(base as u64, size as u64);
+        fdt.property_u32("clock-frequency", CLOCK_FREQ);
+        fdt.property_u32("interrupts", self.irq);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.end_node();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventQueue;
+
+    /// Register accesses wake the UART, but leave only one poll pending.
+    #[test]
+    fn single_poll() {
+        let queue = EventQueue::new();
+        let mut uart = Uart::new(false, 10);
+        uart.attach(Events::new(queue.clone(), 0));
+        let mut irq = IrqLines::default();
+        for now in (0..100_000).step_by(100) {
+            queue.borrow_mut().set_now(now);
+            loop {
+                let due = queue.borrow_mut().pop_due();
+                if due.is_none() {
+                    break;
+                }
+                uart.tick(now, &mut irq);
+            }
+            if now % 700 == 0 {
+                uart.write(IER, 8, IER_THRI as u64).unwrap();
+            }
+        }
+        queue.borrow_mut().set_now(u64::MAX);
+        let pending = std::iter::from_fn(|| queue.borrow_mut().pop_due()).count();
+        assert_eq!(pending, 1);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::bus::Device;
+use crate::fdt::{FdtWriter, PHANDLE_PLIC};
+use crate::snapshot::SnapshotReader;
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+
+/// "virt" in little endian.
+const MAGIC: u64 = 0x7472_6976;
+
+pub const VIRTIO_SIZE: usize = 0x1000;
+
+/// A virtio-mmio transport slot. No backends exist yet, so every slot reports
+/// device ID 0, which drivers treat as an empty slot.
+#[derive(Debug)]
+pub struct VirtioMmio {
+    irq: u32,
+}
+
+impl VirtioMmio {
+    /// `irq` is the slot's interrupt line on the PLIC.
+    pub fn new(irq: u32) -> VirtioMmio {
+        Self {
+            irq,
+        }
+    }
+}
+
+impl Device for VirtioMmio {
+    fn write(&mut self, _addr: usize, size: usize, _val: u64) -> Result<(), ()> {
+        if size != 32 {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    fn read(&self, addr: usize, size: usize) -> Result<u64, ()> {
+        if size != 32 {
+            return Err(());
+        }
+        Ok(match addr {
+            MAGIC_VALUE => MAGIC,
+            VERSION => 2,
+            // Including the device ID
+            _ => 0,
+        })
+    }
+
+    fn name(&self) -> &str {
+        "virtio-mmio"
+    }
+
+    fn describe(&self) -> String {
+        format!("empty, irq {}", self.irq)
+    }
+
+    fn save(&self, _out: &mut Vec<u8>) {}
+
+    fn restore(&mut self, _r: &mut SnapshotReader) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn dt_node(&self, base: usize, size: usize, fdt: &mut FdtWriter) {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_reg(base as u64, size as u64);
+        fdt.property_u32("interrupts", self.irq);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.end_node();
+    }
+}